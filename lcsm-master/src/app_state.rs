@@ -2,7 +2,10 @@ use sea_orm::DatabaseConnection;
 use std::sync::Arc;
 use typed_container::Container;
 
-use crate::services::{AuthServiceRef, PermissionServiceRef, UserServiceRef};
+use crate::services::{
+    AuthServiceRef, EventServiceRef, MigrationServiceRef, PermissionServiceRef, UserServiceRef,
+    WebhookServiceRef,
+};
 
 pub type AppStateRef = Arc<AppState>;
 
@@ -11,6 +14,9 @@ pub struct AppState {
     pub auth_service: AuthServiceRef,
     pub permission_service: PermissionServiceRef,
     pub user_service: UserServiceRef,
+    pub migration_service: MigrationServiceRef,
+    pub event_service: EventServiceRef,
+    pub webhook_service: WebhookServiceRef,
 }
 
 impl From<Container<'_>> for AppState {
@@ -20,6 +26,9 @@ impl From<Container<'_>> for AppState {
             database_connection: value.get(),
             permission_service: value.get(),
             user_service: value.get(),
+            migration_service: value.get(),
+            event_service: value.get(),
+            webhook_service: value.get(),
         }
     }
 }
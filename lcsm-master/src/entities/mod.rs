@@ -1,3 +1,5 @@
 pub mod permission;
 pub mod slave;
 pub mod user;
+pub mod webhook;
+pub mod webhook_delivery;
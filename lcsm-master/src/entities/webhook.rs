@@ -0,0 +1,57 @@
+use sea_orm::{FromJsonQueryResult, entity::prelude::*};
+use serde::{Deserialize, Serialize};
+
+/// An endpoint events are posted to.
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[sea_orm(table_name = "webhooks", rename_all = "camelCase")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub id: i32,
+    pub name: String,
+    pub url: String,
+    /// Key of the HMAC-SHA256 signature sent along with every delivery, never returned.
+    #[serde(skip_serializing)]
+    pub secret: String,
+    pub events: EventFilter,
+    pub enabled: bool,
+}
+
+/// Event types delivered to a webhook, `instance.*` matches every instance event and an empty
+/// filter matches everything.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize, FromJsonQueryResult)]
+pub struct EventFilter(pub Vec<String>);
+
+impl EventFilter {
+    pub fn matches(&self, event_type: &str) -> bool {
+        self.0.is_empty()
+            || self.0.iter().any(|x| match x.strip_suffix('*') {
+                Some(prefix) => event_type.starts_with(prefix),
+                None => x == event_type,
+            })
+    }
+}
+
+impl Model {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.name.trim().is_empty() {
+            return Err("name must not be empty".to_owned());
+        }
+        if !self.url.starts_with("http://") && !self.url.starts_with("https://") {
+            return Err("url must be an http or https url".to_owned());
+        }
+        if self.secret.is_empty() {
+            return Err("secret must not be empty".to_owned());
+        }
+        if self.events.0.iter().any(|x| x.trim().is_empty()) {
+            return Err("event filters must not be empty".to_owned());
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
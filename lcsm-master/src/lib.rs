@@ -1,6 +1,7 @@
 mod app_state;
 pub mod entities;
 pub mod errors;
+pub mod migrations;
 pub mod routes;
 pub mod services;
 pub use app_state::*;
@@ -2,8 +2,13 @@ use std::{env, sync::Arc, time::Duration};
 
 use axum::Router;
 use lcsm_master::{
-    AppState, AppStateRef, routes,
-    services::{AuthService, AuthServiceRef, PermissionService, UserService},
+    AppState, AppStateRef,
+    migrations::run_migrations,
+    routes,
+    services::{
+        AuthService, AuthServiceRef, EventService, MigrationService, PermissionService,
+        SlaveWatcher, SlaveWatcherRef, UserService, WebhookService,
+    },
 };
 use sea_orm::{ConnectOptions, Database, DatabaseConnection};
 use tokio::net::TcpListener;
@@ -26,7 +31,12 @@ async fn build_database_connection() -> DatabaseConnection {
         options
     };
 
-    Database::connect(options).await.expect("failed to open db")
+    let database_connection = Database::connect(options).await.expect("failed to open db");
+    run_migrations(&database_connection)
+        .await
+        .expect("failed to migrate db");
+
+    database_connection
 }
 
 fn build_auth_service() -> AuthServiceRef {
@@ -48,9 +58,20 @@ async fn build_app() -> Router {
     c.register_service(build_auth_service());
     c.register_constructor(|c| Arc::new(PermissionService::new(c.get())));
     c.register_constructor(|c| Arc::new(UserService::new(c.get())));
+    c.register_constructor(|c| Arc::new(MigrationService::new(c.get())));
+    c.register_constructor(|_| Arc::new(EventService::new()));
+    c.register_constructor(|c| Arc::new(WebhookService::new(c.get())));
+    c.register_constructor(|c| Arc::new(SlaveWatcher::new(c.get(), c.get())));
     c.register_constructor(|c| Arc::new(AppState::from(c)));
 
-    let app_state = c.get();
+    let app_state: AppStateRef = c.get();
+    // start background services
+    WebhookService::start(
+        app_state.webhook_service.clone(),
+        app_state.event_service.subscribe(),
+    );
+    SlaveWatcher::start(c.get::<SlaveWatcherRef>());
+
     // build app
     let app = Router::new();
     let app = build_routes(app, &app_state);
@@ -0,0 +1,23 @@
+use sea_orm::{ConnectionTrait, DatabaseConnection, DbErr, EntityTrait, Schema};
+
+use crate::entities::{webhook, webhook_delivery};
+
+/// Brings the database up to date, every step must be idempotent.
+pub async fn run_migrations(db: &DatabaseConnection) -> Result<(), DbErr> {
+    create_table(db, webhook::Entity).await?;
+    create_table(db, webhook_delivery::Entity).await?;
+
+    Ok(())
+}
+
+/// Creates the table of the entity with all of its current columns, unless it exists.
+async fn create_table<E: EntityTrait>(db: &DatabaseConnection, entity: E) -> Result<(), DbErr> {
+    let backend = db.get_database_backend();
+    let statement = Schema::new(backend)
+        .create_table_from_entity(entity)
+        .if_not_exists()
+        .to_owned();
+
+    db.execute(backend.build(&statement)).await?;
+    Ok(())
+}
@@ -1,12 +1,51 @@
-use axum::{Router, middleware};
+use axum::{
+    Json, Router,
+    extract::{Path, State},
+    middleware,
+    response::Response,
+    routing::post,
+};
+use tower::ServiceBuilder;
+use tracing::instrument;
 
-use crate::{AppStateRef, services::auth};
+use crate::{
+    AppStateRef, api_error,
+    services::{MigrateRequest, MigrateResult, auth, permission_control},
+};
 
 pub fn get_routes(state: &AppStateRef) -> Router {
+    let auth_middleware =
+        middleware::from_fn_with_state(state.auth_service.clone(), auth::jwt_middleware);
+    let admin_middleware = middleware::from_fn_with_state(
+        state.permission_service.clone(),
+        permission_control::admin_middleware,
+    );
+
     Router::new()
-        .route_layer(middleware::from_fn_with_state(
-            state.auth_service.clone(),
-            auth::jwt_middleware,
-        ))
+        // ---
+        .route("/{slave_id}/{id}/migrate", post(migrate_instance))
+        .route_layer(
+            ServiceBuilder::new()
+                .layer(auth_middleware)
+                .layer(admin_middleware),
+        )
         .with_state(state.clone())
 }
+
+#[instrument(skip(state))]
+pub async fn migrate_instance(
+    State(state): State<AppStateRef>,
+    Path((slave_id, id)): Path<(i32, i32)>,
+    Json(request): Json<MigrateRequest>,
+) -> Result<Json<MigrateResult>, Response> {
+    let result = state
+        .migration_service
+        .migrate_instance(slave_id, id, request)
+        .await
+        .map_err(|e| {
+            tracing::error!("migrate instance: {}", e);
+            api_error!(e.to_string(), e.status_code())
+        })?;
+
+    Ok(Json(result))
+}
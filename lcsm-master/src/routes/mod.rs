@@ -4,6 +4,7 @@ mod instances;
 mod processes;
 mod slaves;
 mod users;
+mod webhooks;
 use crate::AppStateRef;
 
 pub fn get_routes(state: &AppStateRef) -> Router {
@@ -12,4 +13,5 @@ pub fn get_routes(state: &AppStateRef) -> Router {
         .nest("/slave/", slaves::get_routes(state))
         .nest("/process/", processes::get_routes(state))
         .nest("/instance/", instances::get_routes(state))
+        .nest("/webhook/", webhooks::get_routes(state))
 }
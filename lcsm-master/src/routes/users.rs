@@ -14,13 +14,14 @@ use sea_orm::{
     ModelTrait, PaginatorTrait, QueryFilter, Set,
 };
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 use tower::ServiceBuilder;
 use tracing::instrument;
 
 use crate::{
     AppStateRef, api_error,
     entities::user,
-    services::{auth, permission_control},
+    services::{Event, EventKind, auth, permission_control},
     trace_error,
     transfer::{PaginationOptions, PaginationResponse},
 };
@@ -272,6 +273,10 @@ pub async fn update_user(
         StatusCode::INTERNAL_SERVER_ERROR
     ))?;
 
+    if updated_user.user_type != user.user_type || updated_user.banned != user.banned {
+        publish_permission_change(&state, &updated_user);
+    }
+
     Ok(Json(UserResponse::from(updated_user)))
 }
 
@@ -320,6 +325,11 @@ pub async fn login(
             StatusCode::INTERNAL_SERVER_ERROR
         ))?;
 
+    state.event_service.publish(Event::new(
+        EventKind::UserLogin,
+        json!({ "userId": user.id, "name": user.name }),
+    ));
+
     Ok(Json(LoginResponse {
         access_token: token,
     }))
@@ -340,7 +350,7 @@ pub async fn ban_user(
     }
 
     // set ban
-    state
+    let user = state
         .user_service
         .set_user_banned(id, true)
         .await
@@ -348,6 +358,7 @@ pub async fn ban_user(
             "set user banned",
             StatusCode::INTERNAL_SERVER_ERROR
         ))?;
+    publish_permission_change(&state, &user);
 
     Ok(())
 }
@@ -358,7 +369,7 @@ pub async fn unban_user(
     Path(id): Path<i32>,
 ) -> Result<(), Response> {
     // set ban
-    state
+    let user = state
         .user_service
         .set_user_banned(id, false)
         .await
@@ -366,6 +377,19 @@ pub async fn unban_user(
             "set user banned",
             StatusCode::INTERNAL_SERVER_ERROR
         ))?;
+    publish_permission_change(&state, &user);
 
     Ok(())
 }
+
+fn publish_permission_change(state: &AppStateRef, user: &user::Model) {
+    state.event_service.publish(Event::new(
+        EventKind::UserPermissionChanged,
+        json!({
+            "userId": user.id,
+            "name": user.name,
+            "userType": user.user_type,
+            "banned": user.banned,
+        }),
+    ));
+}
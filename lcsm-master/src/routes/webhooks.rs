@@ -0,0 +1,266 @@
+use axum::{
+    Json, Router,
+    extract::{Path, Query, State},
+    http::StatusCode,
+    middleware,
+    response::Response,
+    routing::{get, post},
+};
+use json_patch::Patch;
+use sea_orm::{
+    ActiveModelTrait,
+    ActiveValue::{NotSet, Unchanged},
+    ColumnTrait, EntityTrait, IntoActiveModel, ModelTrait, PaginatorTrait, QueryFilter, QueryOrder,
+};
+use serde::Deserialize;
+use tower::ServiceBuilder;
+use tracing::instrument;
+
+use crate::{
+    AppStateRef, api_error,
+    entities::{
+        webhook::{self, EventFilter},
+        webhook_delivery,
+    },
+    services::{auth, permission_control},
+    trace_error,
+    transfer::{PaginationOptions, PaginationResponse},
+};
+
+pub fn get_routes(state: &AppStateRef) -> Router {
+    let auth_middleware =
+        middleware::from_fn_with_state(state.auth_service.clone(), auth::jwt_middleware);
+    let admin_middleware = middleware::from_fn_with_state(
+        state.permission_service.clone(),
+        permission_control::admin_middleware,
+    );
+
+    Router::new()
+        // ---
+        .route("/", get(get_webhooks).post(create_webhook))
+        .route(
+            "/{id}",
+            get(get_webhook)
+                .patch(update_webhook)
+                .delete(delete_webhook),
+        )
+        .route("/{id}/deliveries", get(get_deliveries))
+        .route("/{id}/test", post(test_webhook))
+        .route_layer(
+            ServiceBuilder::new()
+                .layer(auth_middleware)
+                .layer(admin_middleware),
+        )
+        .with_state(state.clone())
+}
+
+async fn find_webhook(state: &AppStateRef, id: i32) -> Result<webhook::Model, Response> {
+    webhook::Entity::find_by_id(id)
+        .one(&state.database_connection)
+        .await
+        .map_err(trace_error!(
+            "find webhook",
+            StatusCode::INTERNAL_SERVER_ERROR
+        ))?
+        .ok_or(api_error!(StatusCode::NOT_FOUND))
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CreateWebhookRequest {
+    pub name: String,
+    pub url: String,
+    pub secret: String,
+    /// Every event is delivered without a filter.
+    #[serde(default)]
+    pub events: Vec<String>,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+#[instrument(skip(state, request))]
+pub async fn create_webhook(
+    State(state): State<AppStateRef>,
+    Json(request): Json<CreateWebhookRequest>,
+) -> Result<Json<webhook::Model>, Response> {
+    let new_webhook = webhook::Model {
+        id: 0,
+        name: request.name,
+        url: request.url,
+        secret: request.secret,
+        events: EventFilter(request.events),
+        enabled: request.enabled,
+    };
+    new_webhook
+        .validate()
+        .map_err(|e| api_error!(e, StatusCode::BAD_REQUEST))?;
+
+    let mut new_webhook = new_webhook.into_active_model();
+    new_webhook.id = NotSet;
+    let created_webhook = new_webhook
+        .insert(&state.database_connection)
+        .await
+        .map_err(trace_error!(
+            "insert webhook",
+            StatusCode::INTERNAL_SERVER_ERROR
+        ))?;
+
+    Ok(Json(created_webhook))
+}
+
+#[instrument(skip(state))]
+pub async fn get_webhook(
+    State(state): State<AppStateRef>,
+    Path(id): Path<i32>,
+) -> Result<Json<webhook::Model>, Response> {
+    Ok(Json(find_webhook(&state, id).await?))
+}
+
+#[instrument(skip(state))]
+pub async fn get_webhooks(
+    State(state): State<AppStateRef>,
+    Query(pagination): Query<PaginationOptions>,
+) -> Result<Json<PaginationResponse<webhook::Model>>, Response> {
+    let db = &state.database_connection;
+    let page = pagination.page.unwrap_or(1);
+    let page_size = pagination.page_size.unwrap_or(10);
+
+    let paginator = webhook::Entity::find().paginate(db, page_size);
+    let num = paginator.num_items_and_pages().await.map_err(trace_error!(
+        "num_items_and_pages",
+        StatusCode::INTERNAL_SERVER_ERROR
+    ))?;
+
+    let models = paginator.fetch_page(page - 1).await.map_err(trace_error!(
+        "fetch_page",
+        StatusCode::INTERNAL_SERVER_ERROR
+    ))?;
+
+    Ok(Json(PaginationResponse {
+        page_count: num.number_of_pages,
+        total: num.number_of_items,
+        data: models,
+    }))
+}
+
+#[instrument(skip(state, patch))]
+pub async fn update_webhook(
+    State(state): State<AppStateRef>,
+    Path(id): Path<i32>,
+    Json(patch): Json<Patch>,
+) -> Result<Json<webhook::Model>, Response> {
+    let db = &state.database_connection;
+    let the_webhook = find_webhook(&state, id).await?;
+
+    let mut webhook_json = serde_json::to_value(&the_webhook).map_err(trace_error!(
+        "to serde value",
+        StatusCode::INTERNAL_SERVER_ERROR
+    ))?;
+    // the secret isn't serialized, but can still be patched
+    webhook_json["secret"] = the_webhook.secret.clone().into();
+
+    json_patch::patch(&mut webhook_json, &patch)
+        .map_err(trace_error!("load json patch", StatusCode::BAD_REQUEST))?;
+
+    let updated_webhook: webhook::Model = serde_json::from_value(webhook_json)
+        .map_err(trace_error!("load patched model", StatusCode::BAD_REQUEST))?;
+    updated_webhook
+        .validate()
+        .map_err(|e| api_error!(e, StatusCode::BAD_REQUEST))?;
+
+    let mut active_model = updated_webhook.into_active_model().reset_all();
+    active_model.id = Unchanged(id);
+
+    let updated_webhook = active_model.update(db).await.map_err(trace_error!(
+        "update webhook",
+        StatusCode::INTERNAL_SERVER_ERROR
+    ))?;
+
+    Ok(Json(updated_webhook))
+}
+
+/// The delivery log of the webhook goes with it.
+#[instrument(skip(state))]
+pub async fn delete_webhook(
+    State(state): State<AppStateRef>,
+    Path(id): Path<i32>,
+) -> Result<StatusCode, Response> {
+    let db = &state.database_connection;
+    let the_webhook = find_webhook(&state, id).await?;
+
+    webhook_delivery::Entity::delete_many()
+        .filter(webhook_delivery::Column::WebhookId.eq(id))
+        .exec(db)
+        .await
+        .map_err(trace_error!(
+            "delete deliveries",
+            StatusCode::INTERNAL_SERVER_ERROR
+        ))?;
+    the_webhook.delete(db).await.map_err(trace_error!(
+        "delete webhook",
+        StatusCode::INTERNAL_SERVER_ERROR
+    ))?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeliveriesQuery {
+    pub status: Option<String>,
+}
+
+#[instrument(skip(state))]
+pub async fn get_deliveries(
+    State(state): State<AppStateRef>,
+    Path(id): Path<i32>,
+    Query(pagination): Query<PaginationOptions>,
+    Query(query): Query<DeliveriesQuery>,
+) -> Result<Json<PaginationResponse<webhook_delivery::Model>>, Response> {
+    let db = &state.database_connection;
+    let page = pagination.page.unwrap_or(1);
+    let page_size = pagination.page_size.unwrap_or(10);
+
+    find_webhook(&state, id).await?;
+
+    let mut paginator = webhook_delivery::Entity::find()
+        .filter(webhook_delivery::Column::WebhookId.eq(id))
+        .order_by_desc(webhook_delivery::Column::Id);
+    if let Some(status) = query.status {
+        paginator = paginator.filter(webhook_delivery::Column::Status.eq(status));
+    }
+
+    let paginator = paginator.paginate(db, page_size);
+    let num = paginator.num_items_and_pages().await.map_err(trace_error!(
+        "num_items_and_pages",
+        StatusCode::INTERNAL_SERVER_ERROR
+    ))?;
+
+    let models = paginator.fetch_page(page - 1).await.map_err(trace_error!(
+        "fetch_page",
+        StatusCode::INTERNAL_SERVER_ERROR
+    ))?;
+
+    Ok(Json(PaginationResponse {
+        page_count: num.number_of_pages,
+        total: num.number_of_items,
+        data: models,
+    }))
+}
+
+/// Fires a `webhook.test` event at the webhook and returns the delivery after the first try.
+#[instrument(skip(state))]
+pub async fn test_webhook(
+    State(state): State<AppStateRef>,
+    Path(id): Path<i32>,
+) -> Result<Json<webhook_delivery::Model>, Response> {
+    let delivery = state.webhook_service.test_fire(id).await.map_err(|e| {
+        tracing::error!("test webhook: {}", e);
+        api_error!(e.to_string(), e.status_code())
+    })?;
+
+    Ok(Json(delivery))
+}
@@ -0,0 +1,83 @@
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::Value;
+use tokio::sync::broadcast;
+
+pub type EventServiceRef = Arc<EventService>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    InstanceStarted,
+    InstanceStopped,
+    InstanceCrashed,
+    SlaveOnline,
+    SlaveOffline,
+    UserLogin,
+    UserPermissionChanged,
+    /// Only sent by the test-fire of a webhook.
+    WebhookTest,
+}
+
+impl EventKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::InstanceStarted => "instance.started",
+            Self::InstanceStopped => "instance.stopped",
+            Self::InstanceCrashed => "instance.crashed",
+            Self::SlaveOnline => "slave.online",
+            Self::SlaveOffline => "slave.offline",
+            Self::UserLogin => "user.login",
+            Self::UserPermissionChanged => "user.permissionChanged",
+            Self::WebhookTest => "webhook.test",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Event {
+    #[serde(rename = "type")]
+    pub event_type: String,
+    pub occurred_at: DateTime<Utc>,
+    pub data: Value,
+}
+
+impl Event {
+    pub fn new(kind: EventKind, data: Value) -> Self {
+        Self {
+            event_type: kind.as_str().to_owned(),
+            occurred_at: Utc::now(),
+            data,
+        }
+    }
+}
+
+/// Passes events happening anywhere on the master or its slaves to whoever is interested.
+pub struct EventService {
+    sender: broadcast::Sender<Event>,
+}
+
+impl Default for EventService {
+    fn default() -> Self {
+        Self {
+            sender: broadcast::channel(256).0,
+        }
+    }
+}
+
+impl EventService {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn publish(&self, event: Event) {
+        tracing::debug!("Event {}: {}", event.event_type, event.data);
+        _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+}
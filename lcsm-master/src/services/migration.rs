@@ -0,0 +1,206 @@
+use std::{fmt::Display, sync::Arc, time::Duration};
+
+use axum::http::StatusCode;
+use sea_orm::{DatabaseConnection, DbErr, EntityTrait};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::entities::slave;
+
+pub type MigrationServiceRef = Arc<MigrationService>;
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MigrateRequest {
+    pub target_slave_id: i32,
+    /// Defaults to the name on the source slave.
+    pub name: Option<String>,
+    /// Defaults to a directory in the instances path of the target slave.
+    pub work_dir: Option<String>,
+    /// Deletes the instance on the source slave once it was imported, its work dir is kept.
+    /// The migration fails if that isn't possible, e.g. because the instance was started
+    /// meanwhile, the imported instance is left on the target then.
+    #[serde(default)]
+    pub remove_source: bool,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MigrateResult {
+    /// The import result of the target slave, with the new instance and its missing secrets.
+    pub imported: Value,
+    pub source_removed: bool,
+}
+
+/// Moves instances between slaves by streaming the export bundle of one into the import of
+/// another, the bundle is never stored on the master.
+pub struct MigrationService {
+    database_connection: DatabaseConnection,
+    client: reqwest::Client,
+}
+
+impl MigrationService {
+    pub fn new(database_connection: DatabaseConnection) -> Self {
+        Self {
+            database_connection,
+            client: reqwest::Client::builder()
+                .connect_timeout(Duration::from_secs(10))
+                .build()
+                .expect("failed to build http client"),
+        }
+    }
+
+    /// A running instance is refused by the source slave, it has to be stopped first.
+    pub async fn migrate_instance(
+        &self,
+        source_slave_id: i32,
+        instance_id: i32,
+        request: MigrateRequest,
+    ) -> Result<MigrateResult, MigrationError> {
+        if source_slave_id == request.target_slave_id {
+            return Err(MigrationError::Invalid(
+                "source and target slave are the same".to_owned(),
+            ));
+        }
+        let source = self.find_slave(source_slave_id).await?;
+        let target = self.find_slave(request.target_slave_id).await?;
+
+        let export = self
+            .client
+            .get(slave_url(&source, &format!("instance/{}/export", instance_id)))
+            .bearer_auth(&source.slave_token)
+            .send()
+            .await?;
+        let export = check_status(&source, export)?;
+
+        let mut query = Vec::new();
+        if let Some(name) = &request.name {
+            query.push(("name", name));
+        }
+        if let Some(work_dir) = &request.work_dir {
+            query.push(("workDir", work_dir));
+        }
+        let import = self
+            .client
+            .post(slave_url(&target, "instance/import"))
+            .bearer_auth(&target.slave_token)
+            .query(&query)
+            .body(reqwest::Body::wrap_stream(export.bytes_stream()))
+            .send()
+            .await?;
+        let imported: Value = check_status(&target, import)?.json().await?;
+
+        if request.remove_source {
+            let removal = self
+                .client
+                .delete(slave_url(&source, &format!("instance/{}", instance_id)))
+                .bearer_auth(&source.slave_token)
+                .send()
+                .await
+                .map_err(MigrationError::from)
+                .and_then(|x| check_status(&source, x));
+
+            // the instance must not be left running on both slaves unnoticed
+            if let Err(e) = removal {
+                return Err(MigrationError::SourceNotRemoved {
+                    imported_id: imported["instance"]["id"].as_i64(),
+                    error: Box::new(e),
+                });
+            }
+        }
+
+        Ok(MigrateResult {
+            imported,
+            source_removed: request.remove_source,
+        })
+    }
+
+    async fn find_slave(&self, id: i32) -> Result<slave::Model, MigrationError> {
+        slave::Entity::find_by_id(id)
+            .one(&self.database_connection)
+            .await?
+            .ok_or(MigrationError::SlaveNotFound(id))
+    }
+}
+
+fn slave_url(the_slave: &slave::Model, path: &str) -> String {
+    format!("{}/{}", the_slave.slave_url.trim_end_matches('/'), path)
+}
+
+fn check_status(
+    the_slave: &slave::Model,
+    response: reqwest::Response,
+) -> Result<reqwest::Response, MigrationError> {
+    let status = response.status();
+    if !status.is_success() {
+        return Err(MigrationError::SlaveError {
+            slave_id: the_slave.id,
+            status: StatusCode::from_u16(status.as_u16()).unwrap_or(StatusCode::BAD_GATEWAY),
+        });
+    }
+
+    Ok(response)
+}
+
+#[derive(Debug)]
+pub enum MigrationError {
+    SlaveNotFound(i32),
+    Invalid(String),
+    SlaveError { slave_id: i32, status: StatusCode },
+    RequestError(reqwest::Error),
+    DbErr(DbErr),
+    /// The instance was imported, but removing it from the source failed.
+    SourceNotRemoved {
+        imported_id: Option<i64>,
+        error: Box<MigrationError>,
+    },
+}
+
+impl MigrationError {
+    /// Client errors of a slave, like a running or missing instance, are passed on.
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            Self::SlaveNotFound(_) => StatusCode::NOT_FOUND,
+            Self::Invalid(_) => StatusCode::BAD_REQUEST,
+            Self::SlaveError { status, .. } if status.is_client_error() => *status,
+            Self::SlaveError { .. } | Self::RequestError(_) => StatusCode::BAD_GATEWAY,
+            Self::DbErr(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::SourceNotRemoved { error, .. } => error.status_code(),
+        }
+    }
+}
+
+impl Display for MigrationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::SlaveNotFound(id) => write!(f, "Slave {} not found", id),
+            Self::Invalid(e) => write!(f, "Invalid migration: {}", e),
+            Self::SlaveError { slave_id, status } => {
+                write!(f, "Slave {} responded with {}", slave_id, status)
+            }
+            Self::RequestError(e) => e.fmt(f),
+            Self::DbErr(e) => e.fmt(f),
+            Self::SourceNotRemoved { imported_id, error } => {
+                write!(f, "Instance was imported")?;
+                if let Some(id) = imported_id {
+                    write!(f, " as instance {}", id)?;
+                }
+                write!(f, ", but removing it from the source failed: {}", error)
+            }
+        }
+    }
+}
+
+impl std::error::Error for MigrationError {}
+
+impl From<reqwest::Error> for MigrationError {
+    fn from(value: reqwest::Error) -> Self {
+        Self::RequestError(value)
+    }
+}
+
+impl From<DbErr> for MigrationError {
+    fn from(value: DbErr) -> Self {
+        Self::DbErr(value)
+    }
+}
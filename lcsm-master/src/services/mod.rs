@@ -1,6 +1,14 @@
 pub mod auth;
 pub mod permission_control;
 pub use auth::{AuthService, AuthServiceRef};
+mod events;
+pub use events::*;
+mod migration;
+pub use migration::*;
 pub use permission_control::{PermissionService, PermissionServiceRef};
+mod slave_watcher;
+pub use slave_watcher::*;
 mod user;
 pub use user::*;
+mod webhooks;
+pub use webhooks::*;
@@ -0,0 +1,227 @@
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Utc};
+use futures::{SinkExt, StreamExt};
+use sea_orm::{DatabaseConnection, EntityTrait};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::{net::TcpStream, task::JoinHandle};
+use tokio_tungstenite::{
+    MaybeTlsStream, WebSocketStream,
+    tungstenite::{self, Message, client::IntoClientRequest, http::header::AUTHORIZATION},
+};
+use tracing::Instrument;
+
+use crate::{
+    entities::slave,
+    services::{Event, EventKind, EventServiceRef},
+};
+
+pub type SlaveWatcherRef = Arc<SlaveWatcher>;
+
+/// How often the slave list is reloaded to pick up added, changed and deleted slaves.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(30);
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(5);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+/// A slave that didn't answer a ping in three intervals is considered gone.
+const PING_INTERVAL: Duration = Duration::from_secs(20);
+
+/// The process state events sent by the slaves.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ProcessStateEvent {
+    instance_id: i32,
+    previous_state: String,
+    state: String,
+    exit_code: Option<i32>,
+    at: DateTime<Utc>,
+}
+
+/// Follows the process events of every slave, turning them and the connection state into
+/// events on the master.
+pub struct SlaveWatcher {
+    database_connection: DatabaseConnection,
+    event_service: EventServiceRef,
+}
+
+impl SlaveWatcher {
+    pub fn new(database_connection: DatabaseConnection, event_service: EventServiceRef) -> Self {
+        Self {
+            database_connection,
+            event_service,
+        }
+    }
+
+    pub fn start(self: Arc<Self>) {
+        tokio::spawn(
+            async move {
+                let mut watching = HashMap::<i32, (slave::Model, JoinHandle<()>)>::new();
+                let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+
+                loop {
+                    interval.tick().await;
+
+                    let slaves = match slave::Entity::find().all(&self.database_connection).await {
+                        Ok(v) => v,
+                        Err(e) => {
+                            tracing::error!("Failed to load slaves: {}", e);
+                            continue;
+                        }
+                    };
+
+                    // a changed slave is watched again with its new url and token
+                    watching.retain(|_, (watched, handle)| {
+                        let keep = slaves.contains(watched);
+                        if !keep {
+                            handle.abort();
+                        }
+                        keep
+                    });
+                    for the_slave in slaves {
+                        if watching.contains_key(&the_slave.id) {
+                            continue;
+                        }
+
+                        let handle =
+                            tokio::spawn(self.clone().watch(the_slave.clone()).in_current_span());
+                        watching.insert(the_slave.id, (the_slave, handle));
+                    }
+                }
+            }
+            .instrument(tracing::info_span!(parent: None, "slave watcher")),
+        );
+    }
+
+    /// Only changes are reported, not whether the slave is online when watching begins.
+    async fn watch(self: Arc<Self>, the_slave: slave::Model) {
+        let mut online = None;
+        let mut delay = MIN_RECONNECT_DELAY;
+
+        loop {
+            match connect(&the_slave).await {
+                Ok(socket) => {
+                    self.set_online(&the_slave, &mut online, true);
+                    delay = MIN_RECONNECT_DELAY;
+
+                    self.forward_events(&the_slave, socket).await;
+                    self.set_online(&the_slave, &mut online, false);
+                }
+                Err(e) => {
+                    tracing::debug!("Failed to connect to slave {}: {}", the_slave.id, e);
+                    self.set_online(&the_slave, &mut online, false);
+                }
+            }
+
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+        }
+    }
+
+    fn set_online(&self, the_slave: &slave::Model, online: &mut Option<bool>, value: bool) {
+        let previous = online.replace(value);
+        if previous.is_none_or(|x| x == value) {
+            return;
+        }
+
+        let kind = if value {
+            EventKind::SlaveOnline
+        } else {
+            EventKind::SlaveOffline
+        };
+        self.event_service.publish(Event::new(
+            kind,
+            json!({ "slaveId": the_slave.id, "slaveName": the_slave.name }),
+        ));
+    }
+
+    /// Returns once the connection is closed or stopped answering.
+    async fn forward_events(
+        &self,
+        the_slave: &slave::Model,
+        socket: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    ) {
+        let (mut sink, mut stream) = socket.split();
+        let mut ping = tokio::time::interval(PING_INTERVAL);
+        let mut last_seen = Instant::now();
+
+        loop {
+            tokio::select! {
+                _ = ping.tick() => {
+                    if last_seen.elapsed() > PING_INTERVAL * 3 {
+                        tracing::warn!("Slave {} stopped answering", the_slave.id);
+                        break;
+                    }
+                    if sink.send(Message::Ping(Default::default())).await.is_err() {
+                        break;
+                    }
+                }
+                message = stream.next() => {
+                    last_seen = Instant::now();
+                    match message {
+                        Some(Ok(Message::Text(text))) => self.publish_process_event(the_slave, &text),
+                        Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+
+    fn publish_process_event(&self, the_slave: &slave::Model, text: &str) {
+        let event = match serde_json::from_str::<ProcessStateEvent>(text) {
+            Ok(v) => v,
+            Err(e) => {
+                tracing::warn!("Unexpected event from slave {}: {}", the_slave.id, e);
+                return;
+            }
+        };
+
+        let kind = match (event.previous_state.as_str(), event.state.as_str()) {
+            ("starting", "running") => EventKind::InstanceStarted,
+            (_, "stopped") => EventKind::InstanceStopped,
+            (_, "crashed") => EventKind::InstanceCrashed,
+            _ => return,
+        };
+
+        let mut published = Event::new(
+            kind,
+            json!({
+                "slaveId": the_slave.id,
+                "slaveName": the_slave.name,
+                "instanceId": event.instance_id,
+                "previousState": event.previous_state,
+                "state": event.state,
+                "exitCode": event.exit_code,
+            }),
+        );
+        published.occurred_at = event.at;
+        self.event_service.publish(published);
+    }
+}
+
+async fn connect(
+    the_slave: &slave::Model,
+) -> Result<WebSocketStream<MaybeTlsStream<TcpStream>>, tungstenite::Error> {
+    // http -> ws and https -> wss
+    let url = format!(
+        "ws{}/process/events",
+        the_slave
+            .slave_url
+            .trim_end_matches('/')
+            .strip_prefix("http")
+            .unwrap_or_default()
+    );
+
+    let mut request = url.into_client_request()?;
+    let authorization = format!("Bearer {}", the_slave.slave_token)
+        .parse()
+        .map_err(|e| tungstenite::Error::HttpFormat(tungstenite::http::Error::from(e)))?;
+    request.headers_mut().insert(AUTHORIZATION, authorization);
+
+    let (socket, _) = tokio_tungstenite::connect_async(request).await?;
+    Ok(socket)
+}
@@ -53,12 +53,10 @@ impl UserService {
         Ok(user)
     }
 
-    pub async fn set_user_banned(&self, user_id: i32, banned: bool) -> Result<(), DbErr> {
+    pub async fn set_user_banned(&self, user_id: i32, banned: bool) -> Result<user::Model, DbErr> {
         let mut user = self.find_user_by_id(user_id).await?.into_active_model();
         user.banned = ActiveValue::Set(banned);
-        user.update(&self.database_connection).await?;
-
-        Ok(())
+        user.update(&self.database_connection).await
     }
 
     pub async fn find_user_by_id(&self, user_id: i32) -> Result<user::Model, DbErr> {
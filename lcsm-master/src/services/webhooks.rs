@@ -0,0 +1,459 @@
+use std::{fmt::Display, sync::Arc, time::Duration};
+
+use axum::http::StatusCode;
+use chrono::{TimeDelta, Utc};
+use hmac::{Hmac, Mac};
+use sea_orm::{
+    ActiveModelTrait, ActiveValue::NotSet, ColumnTrait, DatabaseConnection, DbErr, EntityTrait,
+    IntoActiveModel, QueryFilter, Set,
+};
+use serde_json::json;
+use sha2::Sha256;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::Instrument;
+
+use crate::{
+    entities::{webhook, webhook_delivery},
+    services::{Event, EventKind},
+};
+
+pub type WebhookServiceRef = Arc<WebhookService>;
+
+pub const DELIVERY_PENDING: &str = "pending";
+pub const DELIVERY_SUCCEEDED: &str = "succeeded";
+pub const DELIVERY_FAILED: &str = "failed";
+
+/// A delivery is given up after this many attempts.
+const MAX_ATTEMPTS: i32 = 6;
+/// Doubled after every failed attempt.
+const FIRST_RETRY_DELAY: Duration = Duration::from_secs(5);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Response bodies are only kept for failed attempts, and only this much.
+const MAX_ERROR_BODY: usize = 1024;
+
+/// Posts events to the webhooks interested in them and logs every delivery.
+///
+/// Each request carries the event type in `X-Lcsm-Event`, the delivery id in
+/// `X-Lcsm-Delivery` and `sha256=<hex HMAC of the body>` in `X-Lcsm-Signature`.
+pub struct WebhookService {
+    database_connection: DatabaseConnection,
+    client: reqwest::Client,
+}
+
+impl WebhookService {
+    pub fn new(database_connection: DatabaseConnection) -> Self {
+        Self {
+            database_connection,
+            client: reqwest::Client::builder()
+                .timeout(REQUEST_TIMEOUT)
+                .build()
+                .expect("failed to build http client"),
+        }
+    }
+
+    /// Delivers published events, deliveries still pending from before a restart are resumed.
+    pub fn start(self: Arc<Self>, mut events: broadcast::Receiver<Event>) {
+        tokio::spawn(
+            async move {
+                self.resume_pending().await;
+
+                loop {
+                    let event = match events.recv().await {
+                        Ok(v) => v,
+                        Err(RecvError::Lagged(n)) => {
+                            tracing::warn!("Missed {} events, they are not delivered", n);
+                            continue;
+                        }
+                        Err(RecvError::Closed) => break,
+                    };
+
+                    if let Err(e) = self.dispatch(&event).await {
+                        tracing::error!("Failed to dispatch event {}: {}", event.event_type, e);
+                    }
+                }
+            }
+            .instrument(tracing::info_span!(parent: None, "webhooks")),
+        );
+    }
+
+    async fn resume_pending(self: &Arc<Self>) {
+        let pending = webhook_delivery::Entity::find()
+            .filter(webhook_delivery::Column::Status.eq(DELIVERY_PENDING))
+            .all(&self.database_connection)
+            .await;
+
+        match pending {
+            Ok(pending) => {
+                for delivery in pending {
+                    tokio::spawn(self.clone().run(delivery).in_current_span());
+                }
+            }
+            Err(e) => tracing::error!("Failed to load pending deliveries: {}", e),
+        }
+    }
+
+    async fn dispatch(self: &Arc<Self>, event: &Event) -> Result<(), DbErr> {
+        let webhooks = webhook::Entity::find()
+            .filter(webhook::Column::Enabled.eq(true))
+            .all(&self.database_connection)
+            .await?;
+
+        for the_webhook in webhooks {
+            if !the_webhook.events.matches(&event.event_type) {
+                continue;
+            }
+
+            let delivery = self.enqueue(&the_webhook, event).await?;
+            tokio::spawn(self.clone().run(delivery).in_current_span());
+        }
+
+        Ok(())
+    }
+
+    async fn enqueue(
+        &self,
+        the_webhook: &webhook::Model,
+        event: &Event,
+    ) -> Result<webhook_delivery::Model, DbErr> {
+        let payload = serde_json::to_string(event).map_err(|e| DbErr::Custom(e.to_string()))?;
+
+        webhook_delivery::ActiveModel {
+            id: NotSet,
+            webhook_id: Set(the_webhook.id),
+            event_type: Set(event.event_type.clone()),
+            payload: Set(payload),
+            status: Set(DELIVERY_PENDING.to_owned()),
+            attempts: Set(0),
+            response_status: Set(None),
+            error: Set(None),
+            created_at: Set(Utc::now()),
+            last_attempt_at: Set(None),
+            next_attempt_at: Set(Some(Utc::now())),
+        }
+        .insert(&self.database_connection)
+        .await
+    }
+
+    /// Sends a test event to the webhook right away, whether it is enabled or not.
+    /// Returns the delivery after the first attempt, retries continue in the background.
+    pub async fn test_fire(
+        self: &Arc<Self>,
+        webhook_id: i32,
+    ) -> Result<webhook_delivery::Model, WebhookError> {
+        let the_webhook = webhook::Entity::find_by_id(webhook_id)
+            .one(&self.database_connection)
+            .await?
+            .ok_or(WebhookError::NotFound)?;
+
+        let event = Event::new(
+            EventKind::WebhookTest,
+            json!({ "webhookId": the_webhook.id, "name": the_webhook.name }),
+        );
+        let delivery = self.enqueue(&the_webhook, &event).await?;
+        let delivery = self.attempt(delivery).await?;
+
+        if delivery.status == DELIVERY_PENDING {
+            tokio::spawn(self.clone().run(delivery.clone()).in_current_span());
+        }
+        Ok(delivery)
+    }
+
+    /// Attempts the delivery until it succeeded or ran out of attempts.
+    async fn run(self: Arc<Self>, mut delivery: webhook_delivery::Model) {
+        while delivery.status == DELIVERY_PENDING {
+            if let Some(delay) = delivery
+                .next_attempt_at
+                .and_then(|x| (x - Utc::now()).to_std().ok())
+            {
+                tokio::time::sleep(delay).await;
+            }
+
+            delivery = match self.attempt(delivery).await {
+                Ok(v) => v,
+                Err(e) => {
+                    tracing::error!("Failed to record webhook delivery: {}", e);
+                    return;
+                }
+            };
+        }
+    }
+
+    async fn attempt(
+        &self,
+        mut delivery: webhook_delivery::Model,
+    ) -> Result<webhook_delivery::Model, DbErr> {
+        let Some(the_webhook) = webhook::Entity::find_by_id(delivery.webhook_id)
+            .one(&self.database_connection)
+            .await?
+        else {
+            // the delivery log was deleted along with the webhook, there is nothing to record
+            delivery.status = DELIVERY_FAILED.to_owned();
+            return Ok(delivery);
+        };
+        let attempts = delivery.attempts + 1;
+
+        let result = self.send(&the_webhook, &delivery).await;
+        if let Err((_, e)) = &result {
+            tracing::warn!(
+                "Delivery {} to webhook {} failed: {}",
+                delivery.id,
+                delivery.webhook_id,
+                e
+            );
+        }
+
+        let now = Utc::now();
+        let mut model = delivery.into_active_model();
+        model.attempts = Set(attempts);
+        model.last_attempt_at = Set(Some(now));
+        match result {
+            Ok(status) => {
+                model.status = Set(DELIVERY_SUCCEEDED.to_owned());
+                model.response_status = Set(Some(status));
+                model.error = Set(None);
+                model.next_attempt_at = Set(None);
+            }
+            Err((status, e)) => {
+                let retry = attempts < MAX_ATTEMPTS;
+                let delay = FIRST_RETRY_DELAY * 2u32.pow(attempts as u32 - 1);
+
+                model.status = Set(if retry {
+                    DELIVERY_PENDING
+                } else {
+                    DELIVERY_FAILED
+                }
+                .to_owned());
+                model.response_status = Set(status);
+                model.error = Set(Some(e));
+                model.next_attempt_at = Set(retry
+                    .then(|| TimeDelta::from_std(delay).ok())
+                    .flatten()
+                    .map(|x| now + x));
+            }
+        }
+
+        model.update(&self.database_connection).await
+    }
+
+    /// Returns the response status, or why the attempt failed along with the status if the
+    /// webhook responded at all.
+    async fn send(
+        &self,
+        the_webhook: &webhook::Model,
+        delivery: &webhook_delivery::Model,
+    ) -> Result<i32, (Option<i32>, String)> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(the_webhook.secret.as_bytes())
+            .map_err(|e| (None, e.to_string()))?;
+        mac.update(delivery.payload.as_bytes());
+        let signature = format!("sha256={}", hex::encode(mac.finalize().into_bytes()));
+
+        let response = self
+            .client
+            .post(&the_webhook.url)
+            .header("Content-Type", "application/json")
+            .header("User-Agent", "lcsm-master")
+            .header("X-Lcsm-Event", &delivery.event_type)
+            .header("X-Lcsm-Delivery", delivery.id.to_string())
+            .header("X-Lcsm-Signature", signature)
+            .body(delivery.payload.clone())
+            .send()
+            .await
+            .map_err(|e| (None, e.to_string()))?;
+
+        let status = response.status();
+        if status.is_success() {
+            return Ok(status.as_u16() as i32);
+        }
+
+        let mut body = response.text().await.unwrap_or_default();
+        if let Some((end, _)) = body.char_indices().nth(MAX_ERROR_BODY) {
+            body.truncate(end);
+        }
+        Err((
+            Some(status.as_u16() as i32),
+            format!("responded with {}: {}", status, body)
+                .trim_end_matches([' ', ':'])
+                .to_owned(),
+        ))
+    }
+}
+
+#[derive(Debug)]
+pub enum WebhookError {
+    NotFound,
+    DbErr(DbErr),
+}
+
+impl WebhookError {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            Self::NotFound => StatusCode::NOT_FOUND,
+            Self::DbErr(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl Display for WebhookError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotFound => write!(f, "Webhook not found"),
+            Self::DbErr(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for WebhookError {}
+
+impl From<DbErr> for WebhookError {
+    fn from(value: DbErr) -> Self {
+        Self::DbErr(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        Mutex,
+        atomic::{AtomicUsize, Ordering},
+    };
+
+    use axum::{
+        Router,
+        body::Bytes,
+        extract::State,
+        http::{HeaderMap, StatusCode},
+        routing::post,
+    };
+    use sea_orm::{ConnectOptions, Database};
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::migrations::run_migrations;
+
+    const SECRET: &str = "hunter2";
+
+    #[derive(Default)]
+    struct Receiver {
+        calls: AtomicUsize,
+        requests: Mutex<Vec<(HeaderMap, Bytes)>>,
+    }
+
+    /// Records every request and fails the first two with a 500 until the test ends.
+    async fn serve_receiver() -> (String, Arc<Receiver>) {
+        async fn receive(
+            State(receiver): State<Arc<Receiver>>,
+            headers: HeaderMap,
+            body: Bytes,
+        ) -> StatusCode {
+            receiver.requests.lock().unwrap().push((headers, body));
+            match receiver.calls.fetch_add(1, Ordering::SeqCst) {
+                0 | 1 => StatusCode::INTERNAL_SERVER_ERROR,
+                _ => StatusCode::NO_CONTENT,
+            }
+        }
+
+        let receiver = Arc::new(Receiver::default());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let app = Router::new()
+            .route("/hook", post(receive))
+            .with_state(receiver.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        (format!("http://{}/hook", address), receiver)
+    }
+
+    async fn webhook_service(url: &str) -> (Arc<WebhookService>, webhook::Model) {
+        // every connection would get its own in-memory database
+        let mut options = ConnectOptions::new("sqlite::memory:");
+        options.max_connections(1).sqlx_logging(false);
+        let database_connection = Database::connect(options).await.unwrap();
+        run_migrations(&database_connection).await.unwrap();
+
+        let the_webhook = webhook::ActiveModel {
+            id: NotSet,
+            name: Set("test".to_owned()),
+            url: Set(url.to_owned()),
+            secret: Set(SECRET.to_owned()),
+            events: Set(Default::default()),
+            enabled: Set(true),
+        }
+        .insert(&database_connection)
+        .await
+        .unwrap();
+
+        (
+            Arc::new(WebhookService::new(database_connection)),
+            the_webhook,
+        )
+    }
+
+    fn delay(delivery: &webhook_delivery::Model) -> Duration {
+        (delivery.next_attempt_at.unwrap() - delivery.last_attempt_at.unwrap())
+            .to_std()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn signs_and_retries_with_backoff() {
+        let (url, receiver) = serve_receiver().await;
+        let (service, the_webhook) = webhook_service(&url).await;
+        let event = Event::new(
+            EventKind::WebhookTest,
+            json!({ "webhookId": the_webhook.id }),
+        );
+        let delivery = service.enqueue(&the_webhook, &event).await.unwrap();
+
+        let delivery = service.attempt(delivery).await.unwrap();
+        assert_eq!(delivery.status, DELIVERY_PENDING);
+        assert_eq!(delivery.attempts, 1);
+        assert_eq!(delivery.response_status, Some(500));
+        assert_eq!(delay(&delivery), FIRST_RETRY_DELAY);
+
+        let delivery = service.attempt(delivery).await.unwrap();
+        assert_eq!(delivery.status, DELIVERY_PENDING);
+        assert_eq!(delivery.attempts, 2);
+        assert_eq!(delay(&delivery), FIRST_RETRY_DELAY * 2);
+
+        let delivery = service.attempt(delivery).await.unwrap();
+        assert_eq!(delivery.status, DELIVERY_SUCCEEDED);
+        assert_eq!(delivery.attempts, 3);
+        assert_eq!(delivery.response_status, Some(204));
+        assert_eq!(delivery.error, None);
+        assert_eq!(delivery.next_attempt_at, None);
+
+        let requests = receiver.requests.lock().unwrap();
+        assert_eq!(requests.len(), 3);
+        for (headers, body) in requests.iter() {
+            assert_eq!(body, delivery.payload.as_bytes());
+            assert_eq!(headers["x-lcsm-event"], delivery.event_type.as_str());
+            assert_eq!(headers["x-lcsm-delivery"], delivery.id.to_string().as_str());
+
+            let mut mac = Hmac::<Sha256>::new_from_slice(SECRET.as_bytes()).unwrap();
+            mac.update(body);
+            let signature = headers["x-lcsm-signature"].to_str().unwrap();
+            let signature = hex::decode(signature.strip_prefix("sha256=").unwrap()).unwrap();
+            mac.verify_slice(&signature).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_the_last_attempt() {
+        // nothing listens on the discard port
+        let (service, the_webhook) = webhook_service("http://127.0.0.1:9/hook").await;
+        let event = Event::new(
+            EventKind::WebhookTest,
+            json!({ "webhookId": the_webhook.id }),
+        );
+        let mut delivery = service.enqueue(&the_webhook, &event).await.unwrap();
+
+        for attempt in 1..=MAX_ATTEMPTS {
+            delivery = service.attempt(delivery).await.unwrap();
+            assert_eq!(delivery.attempts, attempt);
+        }
+        assert_eq!(delivery.status, DELIVERY_FAILED);
+        assert_eq!(delivery.response_status, None);
+        assert!(delivery.error.is_some());
+        assert_eq!(delivery.next_attempt_at, None);
+    }
+}
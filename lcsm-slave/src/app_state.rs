@@ -6,28 +6,83 @@ use std::{
 
 use sea_orm::DatabaseConnection;
 
-use crate::services::{LogService, ProcessManagementService};
+use crate::services::{
+    BackupService, CloneService, CrashReportService, DiskQuotaService, HealthService,
+    InstallerService, LimitService, LogService, MetricsService, OutputRuleService,
+    ProcessManagementService, RconService, RunAsService, RunHistoryService, SandboxService,
+    SchedulerService, SecretService,
+};
 
 pub type AppStateRef = Arc<AppState>;
 
 pub struct AppState {
     pub log_path: PathBuf,
+    pub backup_path: PathBuf,
+    /// Default parent of work dirs created from templates, work dirs of instances running as
+    /// another user must be below it.
+    pub instance_path: PathBuf,
+    /// Exported and uploaded bundles while they are transferred.
+    pub bundle_path: PathBuf,
+    pub crash_report_path: PathBuf,
 
     pub database: DatabaseConnection,
     pub process_manager: ProcessManagementService,
     pub log_manager: LogService,
+    pub backup_manager: BackupService,
+    pub run_history: RunHistoryService,
+    pub scheduler: SchedulerService,
+    pub secret_manager: SecretService,
+    pub metrics: MetricsService,
+    pub limit_manager: LimitService,
+    pub run_as_manager: RunAsService,
+    pub sandbox_manager: SandboxService,
+    pub quota_manager: DiskQuotaService,
+    pub health_manager: HealthService,
+    pub rcon_manager: RconService,
+    pub clone_manager: CloneService,
+    pub installer: InstallerService,
+    pub crash_report_manager: CrashReportService,
+    pub output_rule_manager: OutputRuleService,
 }
 
 impl AppState {
-    pub fn new(database: DatabaseConnection, data_path: impl AsRef<Path>) -> Self {
+    pub fn new(
+        database: DatabaseConnection,
+        data_path: impl AsRef<Path>,
+        secret_key: Option<impl AsRef<[u8]>>,
+        cgroup_root: Option<PathBuf>,
+        run_as_manager: RunAsService,
+        installer: InstallerService,
+    ) -> Self {
         let data_path = data_path.as_ref();
         let log_path = data_path.join("logs");
+        let backup_path = data_path.join("backups");
+        let crash_report_path = data_path.join("crash-reports");
 
         Self {
+            run_history: RunHistoryService::new(database.clone()),
             database,
             process_manager: ProcessManagementService::new(),
             log_path: log_path.clone(),
             log_manager: LogService::new(log_path),
+            backup_path: backup_path.clone(),
+            backup_manager: BackupService::new(backup_path),
+            instance_path: data_path.join("instances"),
+            bundle_path: data_path.join("bundles"),
+            crash_report_path: crash_report_path.clone(),
+            scheduler: SchedulerService::new(),
+            secret_manager: SecretService::new(secret_key),
+            metrics: MetricsService::new(),
+            limit_manager: LimitService::new(cgroup_root),
+            run_as_manager,
+            sandbox_manager: SandboxService::new(data_path.join("sandbox")),
+            quota_manager: DiskQuotaService::new(),
+            health_manager: HealthService::new(),
+            rcon_manager: RconService::new(),
+            clone_manager: CloneService::new(),
+            installer,
+            crash_report_manager: CrashReportService::new(crash_report_path),
+            output_rule_manager: OutputRuleService::new(),
         }
     }
 
@@ -36,6 +91,24 @@ impl AppState {
             fs::create_dir(&self.log_path)?
         };
 
+        if !fs::exists(&self.backup_path)? {
+            fs::create_dir(&self.backup_path)?
+        };
+
+        if !fs::exists(&self.instance_path)? {
+            fs::create_dir(&self.instance_path)?
+        };
+
+        if !fs::exists(&self.bundle_path)? {
+            fs::create_dir(&self.bundle_path)?
+        };
+
+        if !fs::exists(&self.crash_report_path)? {
+            fs::create_dir(&self.crash_report_path)?
+        };
+
+        self.sandbox_manager.ensure_path_created()?;
+
         Ok(())
     }
 }
@@ -0,0 +1,37 @@
+use sea_orm::{FromJsonQueryResult, entity::prelude::*};
+use serde::{Deserialize, Serialize};
+
+/// Evidence kept from a crashed process, its files live in the crash report path.
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[sea_orm(table_name = "crash_reports", rename_all = "camelCase")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub id: i32,
+    pub instance_id: i32,
+    /// The run recording the crash in the run history.
+    pub run_id: Option<i32>,
+    pub exit_code: Option<i32>,
+    /// When the crashed process was started.
+    pub started_at: DateTimeUtc,
+    pub crashed_at: DateTimeUtc,
+    /// Bytes of output kept from the end of the log.
+    pub output_size: i64,
+    pub files: CrashFiles,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize, FromJsonQueryResult)]
+pub struct CrashFiles(pub Vec<CrashFile>);
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CrashFile {
+    /// Relative to the work dir.
+    pub path: String,
+    pub size: u64,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -1,6 +1,8 @@
-use sea_orm::entity::prelude::*;
+use sea_orm::{FromJsonQueryResult, entity::prelude::*};
 use serde::{Deserialize, Serialize};
 
+use crate::services::SecretService;
+
 #[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 #[sea_orm(table_name = "instances", rename_all = "camelCase")]
@@ -10,11 +12,704 @@ pub struct Model {
     pub name: String,
     pub launch_command: String,
     pub work_dir: String,
-    pub arguments: String,
+    pub arguments: Arguments,
     pub use_shell: bool,
+    #[serde(default = "default_inherit_env")]
+    pub inherit_env: bool,
+    #[serde(default)]
+    pub environment: Environment,
+    #[serde(default)]
+    pub autostart: bool,
+    /// Seconds to wait before this instance is started on boot.
+    #[serde(default)]
+    pub autostart_delay: i32,
+    /// Instances with a lower order are started first on boot.
+    #[serde(default)]
+    pub autostart_order: i32,
+    /// Instances on this slave that must be running before this one starts.
+    #[serde(default)]
+    pub dependencies: Dependencies,
+    #[serde(default)]
+    pub limits: ResourceLimits,
+    /// Must be in the allow-list configured for the slave.
+    #[serde(default)]
+    pub run_as_user: Option<String>,
+    #[serde(default)]
+    pub run_as_group: Option<String>,
+    #[serde(default)]
+    pub sandbox: Sandbox,
+    #[serde(default)]
+    pub disk_quota: DiskQuota,
+    #[serde(default)]
+    pub health_checks: HealthChecks,
+    #[serde(default)]
+    pub minecraft: Minecraft,
+    #[serde(default)]
+    pub hooks: Hooks,
+    #[serde(default)]
+    pub crash_reports: CrashReportOptions,
+    /// Checked against every line the process prints.
+    #[serde(default)]
+    pub output_rules: OutputRules,
+}
+
+fn default_inherit_env() -> bool {
+    true
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
 pub enum Relation {}
 
 impl ActiveModelBehavior for ActiveModel {}
+
+/// Launch arguments, the legacy newline-separated string is still accepted on input.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, FromJsonQueryResult)]
+pub struct Arguments(pub Vec<String>);
+
+impl Arguments {
+    pub fn from_lines(value: &str) -> Self {
+        Self(value.lines().map(|x| x.to_owned()).collect())
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        match self.0.iter().position(|x| x.contains('\0')) {
+            Some(index) => Err(format!("argument {} contains a NUL byte", index)),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Arguments {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum ArgumentsRepr {
+            List(Vec<String>),
+            Lines(String),
+        }
+
+        Ok(match ArgumentsRepr::deserialize(deserializer)? {
+            ArgumentsRepr::List(v) => Self(v),
+            ArgumentsRepr::Lines(v) => Self::from_lines(&v),
+        })
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize, FromJsonQueryResult)]
+pub struct Dependencies(pub Vec<i32>);
+
+/// Optional resource limits, enforced through cgroups v2. Without cgroups only the open files
+/// limit is enforced, as an rlimit.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize, FromJsonQueryResult)]
+#[serde(rename_all = "camelCase")]
+pub struct ResourceLimits {
+    /// Bytes of memory.
+    pub memory_max: Option<u64>,
+    /// Percent of one CPU, `150` allows one and a half cores.
+    pub cpu_quota: Option<u32>,
+    /// Relative CPU share from 1 to 10000, the default weight is 100.
+    pub cpu_weight: Option<u32>,
+    pub pids_max: Option<u64>,
+    pub open_files: Option<u64>,
+}
+
+impl ResourceLimits {
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.memory_max == Some(0) || self.pids_max == Some(0) || self.open_files == Some(0) {
+            return Err("limits must be greater than 0".to_owned());
+        }
+        if self.cpu_quota == Some(0) {
+            return Err("cpu quota must be greater than 0".to_owned());
+        }
+        if self.cpu_weight.is_some_and(|x| !(1..=10000).contains(&x)) {
+            return Err("cpu weight must be between 1 and 10000".to_owned());
+        }
+
+        Ok(())
+    }
+}
+
+/// Opt-in isolation in fresh mount, PID and IPC namespaces, only the work dir is writable.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize, FromJsonQueryResult)]
+#[serde(rename_all = "camelCase")]
+pub struct Sandbox {
+    pub enabled: bool,
+    /// Leaves the process with a loopback interface only.
+    #[serde(default)]
+    pub isolate_network: bool,
+    /// Host paths made visible read-only in addition to the system directories.
+    #[serde(default)]
+    pub read_only_paths: Vec<String>,
+}
+
+impl Sandbox {
+    pub fn validate(&self) -> Result<(), String> {
+        for path in &self.read_only_paths {
+            if !path.starts_with('/') || path.contains('\0') {
+                return Err(format!("invalid read-only path `{}`", path));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Bytes the work dir may use, checked by a periodic scan.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize, FromJsonQueryResult)]
+#[serde(rename_all = "camelCase")]
+pub struct DiskQuota {
+    /// Crossing it only warns.
+    pub soft_limit: Option<u64>,
+    /// Crossing it refuses starts and triggers `hard_limit_action`.
+    pub hard_limit: Option<u64>,
+    #[serde(default)]
+    pub hard_limit_action: HardLimitAction,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum HardLimitAction {
+    /// Keeps a running process alive, but it can't be started again.
+    #[default]
+    Refuse,
+    /// Stops the running process as well.
+    Stop,
+}
+
+impl DiskQuota {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.soft_limit == Some(0) || self.hard_limit == Some(0) {
+            return Err("disk quota must be greater than 0".to_owned());
+        }
+        if let (Some(soft), Some(hard)) = (self.soft_limit, self.hard_limit)
+            && soft > hard
+        {
+            return Err("soft disk quota must not exceed the hard one".to_owned());
+        }
+
+        Ok(())
+    }
+}
+
+/// Probes deciding when a process is ready and whether it is still alive.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize, FromJsonQueryResult)]
+#[serde(rename_all = "camelCase")]
+pub struct HealthChecks {
+    /// The process stays `starting` until this probe succeeds.
+    pub readiness: Option<Probe>,
+    /// Seconds the readiness probe may take to pass before the process is killed as crashed,
+    /// 0 waits forever.
+    #[serde(default = "default_readiness_timeout")]
+    pub readiness_timeout: u32,
+    /// Checked once the process is ready.
+    pub liveness: Option<Probe>,
+    /// Restarts the instance once the liveness probe reaches its failure threshold.
+    #[serde(default)]
+    pub restart_on_failure: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Probe {
+    #[serde(flatten)]
+    pub check: ProbeCheck,
+    /// Seconds to wait before the first check.
+    #[serde(default)]
+    pub initial_delay: u32,
+    /// Seconds between checks.
+    #[serde(default = "default_probe_interval")]
+    pub interval: u32,
+    /// Seconds a single check may take.
+    #[serde(default = "default_probe_timeout")]
+    pub timeout: u32,
+    /// Consecutive failures before the probe counts as failed.
+    #[serde(default = "default_probe_failure_threshold")]
+    pub failure_threshold: u32,
+    /// Consecutive successes before the probe counts as passed.
+    #[serde(default = "default_probe_success_threshold")]
+    pub success_threshold: u32,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(
+    tag = "type",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum ProbeCheck {
+    /// Succeeds if a connection can be opened.
+    Tcp {
+        #[serde(default = "default_host")]
+        host: String,
+        port: u16,
+    },
+    /// Succeeds on the expected status, or any 2xx if none is given.
+    Http {
+        url: String,
+        expected_status: Option<u16>,
+    },
+    /// Succeeds if a line of output matched the pattern since the previous check.
+    Output { pattern: String },
+}
+
+fn default_readiness_timeout() -> u32 {
+    300
+}
+
+fn default_probe_interval() -> u32 {
+    10
+}
+
+fn default_probe_timeout() -> u32 {
+    5
+}
+
+fn default_probe_failure_threshold() -> u32 {
+    3
+}
+
+fn default_probe_success_threshold() -> u32 {
+    1
+}
+
+fn default_host() -> String {
+    "127.0.0.1".to_owned()
+}
+
+impl HealthChecks {
+    pub fn validate(&self) -> Result<(), String> {
+        for probe in [&self.readiness, &self.liveness].into_iter().flatten() {
+            probe.validate()?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Probe {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.interval == 0 || self.timeout == 0 {
+            return Err("probe interval and timeout must be greater than 0".to_owned());
+        }
+        if self.failure_threshold == 0 || self.success_threshold == 0 {
+            return Err("probe thresholds must be greater than 0".to_owned());
+        }
+
+        match &self.check {
+            ProbeCheck::Tcp { host, .. } if host.is_empty() => {
+                Err("probe host must not be empty".to_owned())
+            }
+            ProbeCheck::Http { url, .. }
+                if !(url.starts_with("http://") || url.starts_with("https://")) =>
+            {
+                Err(format!("invalid probe url `{}`", url))
+            }
+            ProbeCheck::Output { pattern } => regex::Regex::new(pattern)
+                .map(|_| ())
+                .map_err(|e| format!("invalid probe pattern: {}", e)),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Integrations for instances running a Minecraft server.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize, FromJsonQueryResult)]
+#[serde(rename_all = "camelCase")]
+pub struct Minecraft {
+    /// Where the Server List Ping is sent to, the status is unavailable without it.
+    pub status: Option<ServerAddress>,
+    /// Also works for Source engine servers.
+    #[serde(default)]
+    pub rcon: Option<RconSettings>,
+}
+
+/// The password is always sealed in the database and never returned.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RconSettings {
+    #[serde(default = "default_host")]
+    pub host: String,
+    #[serde(default = "default_rcon_port")]
+    pub port: u16,
+    pub password: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerAddress {
+    #[serde(default = "default_host")]
+    pub host: String,
+    #[serde(default = "default_minecraft_port")]
+    pub port: u16,
+}
+
+fn default_minecraft_port() -> u16 {
+    25565
+}
+
+fn default_rcon_port() -> u16 {
+    25575
+}
+
+impl Minecraft {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.status.as_ref().is_some_and(|x| x.host.is_empty()) {
+            return Err("status host must not be empty".to_owned());
+        }
+        if let Some(rcon) = &self.rcon {
+            if rcon.host.is_empty() {
+                return Err("rcon host must not be empty".to_owned());
+            }
+            if rcon.password.as_ref().is_none_or(|x| x.is_empty()) {
+                return Err("rcon password must not be empty".to_owned());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Commands run by the slave around the lifecycle of the process, output goes to the run
+/// history. Pre-start and pre-stop run before the start or stop continues, the others run
+/// once the process state changed.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize, FromJsonQueryResult)]
+#[serde(rename_all = "camelCase")]
+pub struct Hooks {
+    pub pre_start: Option<Hook>,
+    /// Runs once the process is running, after its readiness probe passed if it has one.
+    #[serde(default)]
+    pub post_start: Option<Hook>,
+    /// Runs before requested stops and restarts.
+    #[serde(default)]
+    pub pre_stop: Option<Hook>,
+    #[serde(default)]
+    pub post_stop: Option<Hook>,
+    #[serde(default)]
+    pub on_crash: Option<Hook>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Hook {
+    /// Run with `sh -c` in the work dir, as the identity of the instance.
+    pub command: String,
+    /// Seconds until the hook is killed and counted as failed.
+    #[serde(default = "default_hook_timeout")]
+    pub timeout: u32,
+    /// Pre-start only, a failed hook prevents the start.
+    #[serde(default)]
+    pub abort_on_failure: bool,
+}
+
+fn default_hook_timeout() -> u32 {
+    60
+}
+
+/// Longest timeout a hook may have.
+const MAX_HOOK_TIMEOUT: u32 = 3600;
+
+impl Hooks {
+    pub fn validate(&self) -> Result<(), String> {
+        let hooks = [
+            ("preStart", &self.pre_start),
+            ("postStart", &self.post_start),
+            ("preStop", &self.pre_stop),
+            ("postStop", &self.post_stop),
+            ("onCrash", &self.on_crash),
+        ];
+        for (name, hook) in hooks {
+            let Some(hook) = hook else {
+                continue;
+            };
+
+            if hook.command.trim().is_empty() || hook.command.contains('\0') {
+                return Err(format!("invalid command of the {} hook", name));
+            }
+            if !(1..=MAX_HOOK_TIMEOUT).contains(&hook.timeout) {
+                return Err(format!(
+                    "timeout of the {} hook must be between 1 and {} seconds",
+                    name, MAX_HOOK_TIMEOUT
+                ));
+            }
+            if hook.abort_on_failure && name != "preStart" {
+                return Err(format!("the {} hook can't abort anything", name));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// What is kept when the process exits with a failure.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, FromJsonQueryResult)]
+#[serde(rename_all = "camelCase")]
+pub struct CrashReportOptions {
+    /// Kilobytes from the end of the log.
+    #[serde(default = "default_crash_output_tail")]
+    pub output_tail: u32,
+    /// Globs relative to the work dir, like `crash-reports/*.txt` or `hs_err_pid*.log`.
+    /// Matching files are kept if they changed while the process ran.
+    #[serde(default)]
+    pub files: Vec<String>,
+}
+
+impl Default for CrashReportOptions {
+    fn default() -> Self {
+        Self {
+            output_tail: default_crash_output_tail(),
+            files: Vec::new(),
+        }
+    }
+}
+
+fn default_crash_output_tail() -> u32 {
+    64
+}
+
+/// Most output a crash report may keep, in kilobytes.
+const MAX_CRASH_OUTPUT_TAIL: u32 = 4096;
+
+impl CrashReportOptions {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.output_tail > MAX_CRASH_OUTPUT_TAIL {
+            return Err(format!(
+                "crash report output tail must be at most {} KB",
+                MAX_CRASH_OUTPUT_TAIL
+            ));
+        }
+        for pattern in &self.files {
+            let escapes = pattern.starts_with('/') || pattern.split('/').any(|x| x == "..");
+            if pattern.is_empty() || escapes {
+                return Err(format!("invalid crash report file pattern `{}`", pattern));
+            }
+            globset::Glob::new(pattern)
+                .map_err(|e| format!("invalid crash report file pattern `{}`: {}", pattern, e))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize, FromJsonQueryResult)]
+pub struct OutputRules(pub Vec<OutputRule>);
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OutputRule {
+    /// Identifies the rule in stored matches, unique per instance.
+    pub name: String,
+    /// Regex searched for in each line.
+    pub pattern: String,
+    #[serde(default)]
+    pub severity: Severity,
+    /// Seconds in which further matches are only counted, not acted on again.
+    #[serde(default = "default_rule_cooldown")]
+    pub cooldown: u32,
+    #[serde(default)]
+    pub action: OutputRuleAction,
+}
+
+fn default_rule_cooldown() -> u32 {
+    60
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Severity {
+    Info,
+    #[default]
+    Warning,
+    Critical,
+}
+
+impl Severity {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Info => "info",
+            Self::Warning => "warning",
+            Self::Critical => "critical",
+        }
+    }
+}
+
+/// Every match is stored and sent as an event, other actions are run on top of that.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum OutputRuleAction {
+    #[default]
+    Event,
+    /// Writes a command to the console of the process.
+    Command {
+        command: String,
+    },
+    Restart,
+    Backup,
+}
+
+impl OutputRuleAction {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Event => "event",
+            Self::Command { .. } => "command",
+            Self::Restart => "restart",
+            Self::Backup => "backup",
+        }
+    }
+}
+
+impl OutputRules {
+    pub fn validate(&self) -> Result<(), String> {
+        for (index, rule) in self.0.iter().enumerate() {
+            if rule.name.trim().is_empty() {
+                return Err(format!("output rule {} has no name", index));
+            }
+            if self.0[..index].iter().any(|x| x.name == rule.name) {
+                return Err(format!("output rule `{}` is defined twice", rule.name));
+            }
+            regex::Regex::new(&rule.pattern)
+                .map_err(|e| format!("invalid pattern of output rule `{}`: {}", rule.name, e))?;
+            if let OutputRuleAction::Command { command } = &rule.action
+                && (command.trim().is_empty() || command.contains('\n'))
+            {
+                return Err(format!("invalid command of output rule `{}`", rule.name));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize, FromJsonQueryResult)]
+pub struct Environment(pub Vec<EnvironmentVariable>);
+
+/// A variable passed to the process, a `null` value removes the inherited variable.
+/// Values of secret variables are sealed in the database and never returned.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EnvironmentVariable {
+    pub name: String,
+    pub value: Option<String>,
+    #[serde(default)]
+    pub secret: bool,
+}
+
+impl Environment {
+    pub fn validate(&self) -> Result<(), String> {
+        for variable in &self.0 {
+            if variable.name.is_empty() || variable.name.contains(['=', '\0']) {
+                return Err(format!("invalid variable name `{}`", variable.name));
+            }
+
+            if variable.value.as_ref().is_some_and(|x| x.contains('\0')) {
+                return Err(format!("invalid value of variable `{}`", variable.name));
+            }
+
+            if variable.secret && variable.value.is_none() {
+                return Err(format!("secret variable `{}` has no value", variable.name));
+            }
+
+            // also catches a secret flipped to non-secret, which would keep its sealed value
+            if !variable.secret
+                && variable
+                    .value
+                    .as_ref()
+                    .is_some_and(|x| SecretService::is_sealed(x))
+            {
+                return Err(format!(
+                    "variable `{}` can't stop being secret without a new value",
+                    variable.name
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Model {
+    pub fn validate(&self) -> Result<(), String> {
+        self.arguments.validate()?;
+        self.environment.validate()?;
+        self.limits.validate()?;
+        self.sandbox.validate()?;
+        self.disk_quota.validate()?;
+        self.health_checks.validate()?;
+        self.minecraft.validate()?;
+        self.hooks.validate()?;
+        self.crash_reports.validate()?;
+        self.output_rules.validate()?;
+
+        if self.sandbox.enabled && !self.work_dir.starts_with('/') {
+            return Err("sandboxed instances need an absolute work dir".to_owned());
+        }
+        if self.sandbox.enabled && self.run_as_user.is_none() {
+            return Err("sandboxed instances need a run as user".to_owned());
+        }
+
+        if self.autostart_delay < 0 {
+            return Err("autostart delay must not be negative".to_owned());
+        }
+
+        Ok(())
+    }
+
+    /// Hides secret variables and passwords, use it before sending the model to clients.
+    pub fn redacted(mut self) -> Self {
+        for variable in self.environment.0.iter_mut() {
+            if variable.secret {
+                variable.value = None;
+            }
+        }
+        if let Some(rcon) = self.minecraft.rcon.as_mut() {
+            rcon.password = None;
+        }
+
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn arguments_accept_lists_and_legacy_lines() {
+        let list: Arguments = serde_json::from_str(r#"["-Xmx2G", "-jar", "server.jar"]"#).unwrap();
+        assert_eq!(list.0, vec!["-Xmx2G", "-jar", "server.jar"]);
+
+        let lines: Arguments = serde_json::from_str(r#""-Xmx2G\n-jar\nserver.jar""#).unwrap();
+        assert_eq!(lines, list);
+
+        // arguments with spaces survive both forms
+        let list: Arguments = serde_json::from_str(r#"["--motd", "hello world"]"#).unwrap();
+        assert_eq!(list.0, vec!["--motd", "hello world"]);
+        let lines: Arguments = serde_json::from_str(r#""--motd\nhello world""#).unwrap();
+        assert_eq!(lines, list);
+
+        let empty: Arguments = serde_json::from_str(r#""""#).unwrap();
+        assert!(empty.0.is_empty());
+        assert!(serde_json::from_str::<Arguments>("42").is_err());
+    }
+
+    #[test]
+    fn arguments_are_serialized_as_a_list() {
+        let arguments = Arguments::from_lines("-jar\r\nserver.jar\n");
+        assert_eq!(
+            serde_json::to_string(&arguments).unwrap(),
+            r#"["-jar","server.jar"]"#
+        );
+    }
+
+    #[test]
+    fn arguments_reject_nul_bytes() {
+        assert!(Arguments(vec!["nogui".to_owned()]).validate().is_ok());
+        assert!(Arguments(vec!["no\0gui".to_owned()]).validate().is_err());
+    }
+}
@@ -1 +1,7 @@
+pub mod crash_report;
+pub mod installation;
 pub mod instance;
+pub mod output_match;
+pub mod run_history;
+pub mod schedule;
+pub mod template;
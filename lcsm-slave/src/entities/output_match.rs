@@ -0,0 +1,26 @@
+use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// A line of output that fired one of the output rules of an instance.
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[sea_orm(table_name = "output_matches", rename_all = "camelCase")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub id: i32,
+    pub instance_id: i32,
+    pub rule: String,
+    pub severity: String,
+    pub action: String,
+    pub line: String,
+    /// Matches of the rule swallowed by its cooldown since it fired last.
+    pub suppressed: i32,
+    /// The run executing the action, events have none.
+    pub run_id: Option<i32>,
+    pub matched_at: DateTimeUtc,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -0,0 +1,206 @@
+use std::{collections::HashSet, path::Component, sync::LazyLock};
+
+use regex::Regex;
+use sea_orm::{FromJsonQueryResult, entity::prelude::*};
+use serde::{Deserialize, Serialize};
+
+use crate::entities::instance::{Arguments, Environment};
+
+/// `{{ name }}` in launch settings, environment values and files is replaced by the variable.
+pub static PLACEHOLDER: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\{\{\s*([A-Za-z_][A-Za-z0-9_]*)\s*\}\}").unwrap());
+
+/// Variables every template can use without declaring them.
+pub const BUILTIN_VARIABLES: [&str; 2] = ["instanceName", "workDir"];
+
+/// Launch settings and work dir contents new instances are provisioned from.
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[sea_orm(table_name = "templates", rename_all = "camelCase")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub id: i32,
+    pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    pub launch_command: String,
+    pub arguments: Arguments,
+    pub use_shell: bool,
+    #[serde(default = "default_inherit_env")]
+    pub inherit_env: bool,
+    /// Values of secret variables are sealed in the database and never returned.
+    #[serde(default)]
+    pub environment: Environment,
+    #[serde(default)]
+    pub files: TemplateFiles,
+    #[serde(default)]
+    pub variables: TemplateVariables,
+}
+
+fn default_inherit_env() -> bool {
+    true
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize, FromJsonQueryResult)]
+pub struct TemplateFiles(pub Vec<TemplateFile>);
+
+/// Written into the work dir when an instance is provisioned.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TemplateFile {
+    /// Relative to the work dir, may contain placeholders.
+    pub path: String,
+    pub content: String,
+    #[serde(default)]
+    pub executable: bool,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize, FromJsonQueryResult)]
+pub struct TemplateVariables(pub Vec<TemplateVariable>);
+
+/// A value asked from the user when provisioning.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TemplateVariable {
+    pub name: String,
+    /// Shown to the user, e.g. `Server port`.
+    pub prompt: String,
+    /// Variables without a default must be supplied.
+    #[serde(default)]
+    pub default: Option<String>,
+    /// Regex the whole value must match.
+    #[serde(default)]
+    pub pattern: Option<String>,
+    /// Hints clients to mask the input.
+    #[serde(default)]
+    pub secret: bool,
+}
+
+impl TemplateVariable {
+    fn compile_pattern(&self) -> Result<Option<Regex>, String> {
+        self.pattern
+            .as_ref()
+            .map(|x| Regex::new(&format!("^(?:{})$", x)))
+            .transpose()
+            .map_err(|e| format!("invalid pattern of variable `{}`: {}", self.name, e))
+    }
+
+    pub fn check(&self, value: &str) -> Result<(), String> {
+        if let Some(pattern) = self.compile_pattern()?
+            && !pattern.is_match(value)
+        {
+            return Err(format!(
+                "value of variable `{}` doesn't match `{}`",
+                self.name,
+                self.pattern.as_deref().unwrap_or_default()
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+fn is_variable_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|x| x.is_ascii_alphabetic() || x == '_')
+        && chars.all(|x| x.is_ascii_alphanumeric() || x == '_')
+}
+
+impl TemplateVariables {
+    pub fn validate(&self) -> Result<(), String> {
+        let mut names = HashSet::new();
+        for variable in &self.0 {
+            if !is_variable_name(&variable.name) {
+                return Err(format!("invalid variable name `{}`", variable.name));
+            }
+            if BUILTIN_VARIABLES.contains(&variable.name.as_str()) {
+                return Err(format!("variable `{}` is built in", variable.name));
+            }
+            if !names.insert(variable.name.as_str()) {
+                return Err(format!("duplicated variable `{}`", variable.name));
+            }
+
+            variable.compile_pattern()?;
+            if let Some(default) = &variable.default {
+                variable.check(default)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Whether a path stays inside the directory it is relative to.
+pub fn is_relative_path(path: &str) -> bool {
+    !path.is_empty()
+        && !path.contains('\0')
+        && std::path::Path::new(path)
+            .components()
+            .all(|x| matches!(x, Component::Normal(_)))
+}
+
+impl Model {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.name.trim().is_empty() {
+            return Err("template name must not be empty".to_owned());
+        }
+
+        self.arguments.validate()?;
+        self.environment.validate()?;
+        self.variables.validate()?;
+
+        let mut paths = HashSet::new();
+        for file in &self.files.0 {
+            if !is_relative_path(&file.path) {
+                return Err(format!("file path `{}` must be relative", file.path));
+            }
+            if !paths.insert(file.path.as_str()) {
+                return Err(format!("duplicated file `{}`", file.path));
+            }
+        }
+
+        // every placeholder must be resolvable
+        for text in self.renderable_texts() {
+            for captures in PLACEHOLDER.captures_iter(text) {
+                let name = &captures[1];
+                if !BUILTIN_VARIABLES.contains(&name)
+                    && !self.variables.0.iter().any(|x| x.name == name)
+                {
+                    return Err(format!("undeclared variable `{}`", name));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Hides secret variables, use it before sending the model to clients.
+    pub fn redacted(mut self) -> Self {
+        for variable in self.environment.0.iter_mut() {
+            if variable.secret {
+                variable.value = None;
+            }
+        }
+
+        self
+    }
+
+    fn renderable_texts(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(self.launch_command.as_str())
+            .chain(self.arguments.0.iter().map(|x| x.as_str()))
+            .chain(self.environment.0.iter().filter_map(|x| x.value.as_deref()))
+            .chain(
+                self.files
+                    .0
+                    .iter()
+                    .flat_map(|x| [x.path.as_str(), x.content.as_str()]),
+            )
+    }
+}
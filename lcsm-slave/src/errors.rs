@@ -1,3 +1,5 @@
+use axum::http::StatusCode;
+
 #[macro_export]
 macro_rules! trace_error {
     ($msg:expr, $status_code:expr) => {
@@ -11,3 +13,15 @@ macro_rules! trace_error {
 }
 
 pub use trace_error;
+
+/// Errors that know which status code they should be reported with.
+pub trait StatusCodeError: std::fmt::Display {
+    fn status_code(&self) -> StatusCode;
+}
+
+pub fn trace_status_error<E: StatusCodeError>(msg: &str) -> impl FnOnce(E) -> StatusCode + '_ {
+    move |e| {
+        tracing::error!("{}: {}", msg, e);
+        e.status_code()
+    }
+}
@@ -3,6 +3,7 @@ mod app_state;
 pub use app_state::*;
 pub mod entities;
 pub mod errors;
+pub mod migrations;
 pub mod routes;
 pub mod services;
 pub mod transfer;
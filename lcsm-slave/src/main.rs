@@ -1,7 +1,16 @@
 use std::{env, path::PathBuf, sync::Arc, time::Duration};
 
 use axum::Router;
-use lcsm_slave::{AppState, AppStateRef, routes};
+use lcsm_slave::{
+    AppState, AppStateRef,
+    migrations::run_migrations,
+    routes,
+    services::{
+        CrashReportService, DiskQuotaService, HealthService, HookService, InstallerService,
+        MetricsService, OutputRuleService, ProcessManagementService, RunAsService,
+        SchedulerService, autostart_instances,
+    },
+};
 use sea_orm::{ConnectOptions, Database, DatabaseConnection};
 use tokio::net::TcpListener;
 use tower::ServiceBuilder;
@@ -21,7 +30,12 @@ async fn build_database_connection() -> DatabaseConnection {
         options
     };
 
-    Database::connect(options).await.expect("failed to open db")
+    let database = Database::connect(options).await.expect("failed to open db");
+    run_migrations(&database)
+        .await
+        .expect("failed to migrate db");
+
+    database
 }
 
 fn build_services(app: Router) -> Router {
@@ -38,6 +52,19 @@ fn build_routes(app: Router, state: &AppStateRef) -> Router {
     app.merge(routes::get_routes(state))
 }
 
+fn build_run_as_service() -> RunAsService {
+    let allow_list = |name| {
+        env::var(name)
+            .map(|x| RunAsService::parse_allow_list(&x))
+            .unwrap_or_default()
+    };
+
+    RunAsService::new(
+        allow_list("LCSM_RUN_AS_USERS"),
+        allow_list("LCSM_RUN_AS_GROUPS"),
+    )
+}
+
 fn get_data_path() -> PathBuf {
     match env::var("LCSM_DATA_PATH") {
         Ok(path) => PathBuf::from(&path),
@@ -48,16 +75,37 @@ fn get_data_path() -> PathBuf {
 }
 
 async fn build_app() -> Router {
+    let secret_key = env::var("LCSM_SECRET_KEY").ok();
+    if secret_key.is_none() {
+        tracing::warn!(
+            "LCSM_SECRET_KEY is not set, instances with secrets can't be saved or started"
+        );
+    }
+
     // build state
     let app_state = Arc::new(AppState::new(
         build_database_connection().await,
         get_data_path(),
+        secret_key,
+        env::var("LCSM_CGROUP_ROOT").ok().map(PathBuf::from),
+        build_run_as_service(),
+        InstallerService::new(env::var("LCSM_INSTALLER_INDEX").ok()),
     ));
 
     app_state
         .ensure_path_created()
         .expect("ensure path created");
 
+    ProcessManagementService::start(app_state.clone());
+    SchedulerService::start(app_state.clone());
+    MetricsService::start(app_state.clone());
+    DiskQuotaService::start(app_state.clone());
+    HealthService::start(app_state.clone());
+    HookService::start(app_state.clone());
+    CrashReportService::start(app_state.clone());
+    OutputRuleService::start(app_state.clone());
+    autostart_instances(app_state.clone());
+
     // build app
     let app = Router::new();
     let app = build_routes(app, &app_state);
@@ -0,0 +1,250 @@
+use sea_orm::{
+    ColumnTrait, ConnectionTrait, DatabaseConnection, DbBackend, DbErr, EntityTrait,
+    FromQueryResult, Schema, Statement, TransactionTrait, Value,
+    sea_query::{Alias, Table},
+};
+
+use crate::entities::{
+    crash_report, installation,
+    instance::{self, Arguments},
+    output_match, run_history, schedule, template,
+};
+
+/// Brings data written by older versions up to date, every step must be idempotent.
+pub async fn run_migrations(db: &DatabaseConnection) -> Result<(), DbErr> {
+    // a fresh database has none of the tables yet
+    create_table(db, instance::Entity).await?;
+    create_table(db, schedule::Entity).await?;
+    create_table(db, run_history::Entity).await?;
+    create_table(db, template::Entity).await?;
+    create_table(db, installation::Entity).await?;
+    create_table(db, crash_report::Entity).await?;
+    create_table(db, output_match::Entity).await?;
+
+    convert_arguments_to_json(db).await?;
+
+    add_column(db, instance::Column::InheritEnv, true).await?;
+    add_column(db, instance::Column::Environment, "[]").await?;
+    add_column(db, instance::Column::Autostart, false).await?;
+    add_column(db, instance::Column::AutostartDelay, 0).await?;
+    add_column(db, instance::Column::AutostartOrder, 0).await?;
+    add_column(db, instance::Column::Dependencies, "[]").await?;
+    add_column(db, instance::Column::Limits, "{}").await?;
+    add_column(db, instance::Column::RunAsUser, None::<String>).await?;
+    add_column(db, instance::Column::RunAsGroup, None::<String>).await?;
+    add_column(db, instance::Column::Sandbox, r#"{"enabled":false}"#).await?;
+    add_column(db, instance::Column::DiskQuota, "{}").await?;
+    add_column(db, instance::Column::HealthChecks, "{}").await?;
+    add_column(db, instance::Column::Minecraft, "{}").await?;
+    add_column(db, instance::Column::Hooks, "{}").await?;
+    add_column(db, instance::Column::CrashReports, "{}").await?;
+    add_column(db, instance::Column::OutputRules, "[]").await?;
+
+    Ok(())
+}
+
+/// Creates the table of the entity with all of its current columns, unless it exists.
+async fn create_table<E: EntityTrait>(db: &DatabaseConnection, entity: E) -> Result<(), DbErr> {
+    let backend = db.get_database_backend();
+    let statement = Schema::new(backend)
+        .create_table_from_entity(entity)
+        .if_not_exists()
+        .to_owned();
+
+    db.execute(backend.build(&statement)).await?;
+    Ok(())
+}
+
+/// Adds a column that was introduced after the table was created, existing rows get `default`.
+async fn add_column<C>(
+    db: &DatabaseConnection,
+    column: C,
+    default: impl Into<Value>,
+) -> Result<(), DbErr>
+where
+    C: ColumnTrait,
+    C::EntityName: EntityTrait<Column = C>,
+{
+    let backend = db.get_database_backend();
+    let table = column.entity_name().to_string();
+    if has_column(db, &table, column.as_str()).await? {
+        return Ok(());
+    }
+
+    let statement = Table::alter()
+        .table(Alias::new(&table))
+        .add_column(
+            Schema::new(backend)
+                .get_column_def::<C::EntityName>(column)
+                .default(default.into())
+                .to_owned(),
+        )
+        .to_owned();
+    db.execute(backend.build(&statement)).await?;
+
+    tracing::info!("Added column {}.{}", table, column.as_str());
+    Ok(())
+}
+
+async fn has_column(db: &DatabaseConnection, table: &str, column: &str) -> Result<bool, DbErr> {
+    let backend = db.get_database_backend();
+    let statement = match backend {
+        DbBackend::Sqlite => Statement::from_sql_and_values(
+            backend,
+            r#"SELECT COUNT(*) AS "count" FROM pragma_table_info(?) WHERE "name" = ?"#,
+            [table.into(), column.into()],
+        ),
+        DbBackend::Postgres => Statement::from_sql_and_values(
+            backend,
+            "SELECT COUNT(*) AS count FROM information_schema.columns \
+             WHERE table_name = $1 AND column_name = $2",
+            [table.into(), column.into()],
+        ),
+        DbBackend::MySql => Statement::from_sql_and_values(
+            backend,
+            "SELECT COUNT(*) AS count FROM information_schema.columns \
+             WHERE table_schema = DATABASE() AND table_name = ? AND column_name = ?",
+            [table.into(), column.into()],
+        ),
+    };
+
+    let count = db
+        .query_one(statement)
+        .await?
+        .map(|x| x.try_get::<i64>("", "count"))
+        .transpose()?;
+    Ok(count.unwrap_or_default() > 0)
+}
+
+#[derive(FromQueryResult)]
+struct RawArguments {
+    id: i32,
+    arguments: String,
+}
+
+/// `instances.arguments` used to be a newline-separated string, it is a JSON array now.
+async fn convert_arguments_to_json(db: &DatabaseConnection) -> Result<(), DbErr> {
+    let backend = db.get_database_backend();
+    let txn = db.begin().await?;
+
+    let rows = RawArguments::find_by_statement(Statement::from_string(
+        backend,
+        r#"SELECT "id", CAST("arguments" AS TEXT) AS "arguments" FROM "instances""#,
+    ))
+    .all(&txn)
+    .await?;
+
+    for row in rows {
+        if serde_json::from_str::<Vec<String>>(&row.arguments).is_ok() {
+            continue;
+        }
+
+        let converted = serde_json::to_string(&Arguments::from_lines(&row.arguments).0)
+            .map_err(|e| DbErr::Custom(e.to_string()))?;
+
+        txn.execute(Statement::from_sql_and_values(
+            backend,
+            match backend {
+                DbBackend::Postgres => r#"UPDATE "instances" SET "arguments" = $1 WHERE "id" = $2"#,
+                _ => r#"UPDATE "instances" SET "arguments" = ? WHERE "id" = ?"#,
+            },
+            [converted.into(), row.id.into()],
+        ))
+        .await?;
+
+        tracing::info!("Converted arguments of instance {} to JSON", row.id);
+    }
+
+    // postgres decodes JSON columns strictly, so the column type has to change as well
+    if backend == DbBackend::Postgres {
+        let column_type = txn
+            .query_one(Statement::from_string(
+                backend,
+                "SELECT data_type FROM information_schema.columns \
+                 WHERE table_name = 'instances' AND column_name = 'arguments'",
+            ))
+            .await?
+            .map(|x| x.try_get::<String>("", "data_type"))
+            .transpose()?;
+
+        if column_type.is_some_and(|x| x != "jsonb" && x != "json") {
+            txn.execute(Statement::from_string(
+                backend,
+                r#"ALTER TABLE "instances" ALTER COLUMN "arguments" TYPE jsonb USING "arguments"::jsonb"#,
+            ))
+            .await?;
+        }
+    }
+
+    txn.commit().await
+}
+
+#[cfg(test)]
+mod tests {
+    use sea_orm::{Database, QueryOrder};
+
+    use super::*;
+
+    /// The instances table as the first release created it.
+    async fn legacy_database() -> DatabaseConnection {
+        let db = Database::connect("sqlite::memory:").await.unwrap();
+        db.execute_unprepared(
+            r#"CREATE TABLE "instances" (
+                "id" integer NOT NULL PRIMARY KEY AUTOINCREMENT,
+                "name" varchar NOT NULL,
+                "launchCommand" varchar NOT NULL,
+                "workDir" varchar NOT NULL,
+                "arguments" varchar NOT NULL,
+                "useShell" boolean NOT NULL
+            )"#,
+        )
+        .await
+        .unwrap();
+        db.execute_unprepared(
+            r#"INSERT INTO "instances" ("name", "launchCommand", "workDir", "arguments", "useShell")
+            VALUES
+                ('lines', 'java', '/srv/a', '-Xmx2G' || char(10) || '-jar' || char(10) || 'server.jar', false),
+                ('spaces', 'sh', '/srv/b', '-c' || char(10) || 'echo hello world', false),
+                ('empty', 'sleep', '/srv/c', '', true),
+                ('json', 'java', '/srv/d', '["-jar","server.jar"]', false)"#,
+        )
+        .await
+        .unwrap();
+        db
+    }
+
+    #[tokio::test]
+    async fn legacy_arguments_are_converted() {
+        let db = legacy_database().await;
+
+        run_migrations(&db).await.unwrap();
+        // running again leaves converted rows alone
+        run_migrations(&db).await.unwrap();
+
+        let instances = instance::Entity::find()
+            .order_by_asc(instance::Column::Id)
+            .all(&db)
+            .await
+            .unwrap();
+        let arguments = instances
+            .iter()
+            .map(|x| x.arguments.0.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            arguments,
+            vec![
+                vec!["-Xmx2G", "-jar", "server.jar"],
+                vec!["-c", "echo hello world"],
+                vec![],
+                vec!["-jar", "server.jar"],
+            ]
+        );
+
+        // columns added later get their defaults
+        let the_instance = &instances[0];
+        assert!(the_instance.inherit_env);
+        assert!(the_instance.environment.0.is_empty());
+        assert!(the_instance.dependencies.0.is_empty());
+        assert!(!the_instance.sandbox.enabled);
+    }
+}
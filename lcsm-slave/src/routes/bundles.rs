@@ -0,0 +1,64 @@
+use axum::{
+    Json, Router,
+    body::Body,
+    extract::{Path, Query, Request, State},
+    http::{StatusCode, header},
+    response::IntoResponse,
+    routing::{get, post},
+};
+use tower::ServiceExt;
+use tower_http::services::ServeFile;
+use tracing::instrument;
+
+use crate::{
+    AppStateRef,
+    errors::trace_status_error,
+    services::{ExportOptions, ImportOptions, ImportResult, export_instance, import_instance},
+};
+
+pub fn get_routes(state_ref: &AppStateRef) -> Router {
+    Router::new()
+        .route("/{id}/export", get(export_bundle))
+        .route("/import", post(import_bundle))
+        .with_state(state_ref.clone())
+}
+
+#[instrument(skip(state, request))]
+async fn export_bundle(
+    State(state): State<AppStateRef>,
+    Path(id): Path<u64>,
+    Query(options): Query<ExportOptions>,
+    request: Request,
+) -> Result<impl IntoResponse, StatusCode> {
+    let path = export_instance(&state, id, options)
+        .await
+        .map_err(trace_status_error("export instance"))?;
+
+    // the opened file stays readable after it is unlinked
+    let mut response = ServeFile::new(&path).oneshot(request).await;
+    if let Err(e) = tokio::fs::remove_file(&path).await {
+        tracing::warn!("Failed to remove {}: {}", path.display(), e);
+    }
+
+    let disposition = format!("attachment; filename=\"instance-{}.tar\"", id);
+    if let (Ok(response), Ok(disposition)) = (response.as_mut(), disposition.parse()) {
+        response
+            .headers_mut()
+            .insert(header::CONTENT_DISPOSITION, disposition);
+    }
+    Ok(response)
+}
+
+/// The body is the bundle as returned by the export.
+#[instrument(skip(state, body))]
+async fn import_bundle(
+    State(state): State<AppStateRef>,
+    Query(options): Query<ImportOptions>,
+    body: Body,
+) -> Result<Json<ImportResult>, StatusCode> {
+    let res = import_instance(&state, options, body.into_data_stream())
+        .await
+        .map_err(trace_status_error("import instance"))?;
+
+    Ok(Json(res))
+}
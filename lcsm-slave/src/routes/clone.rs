@@ -0,0 +1,60 @@
+use axum::{
+    Json, Router,
+    extract::{Path, State, WebSocketUpgrade},
+    http::StatusCode,
+    response::IntoResponse,
+    routing::{any, get, post},
+};
+use tracing::instrument;
+
+use crate::{
+    AppStateRef,
+    errors::trace_status_error,
+    routes::processes::events_ws_handler,
+    services::{CloneProgress, CloneRequest, clone_instance},
+};
+
+pub fn get_routes(state_ref: &AppStateRef) -> Router {
+    Router::new()
+        .route("/{id}/clone", post(start_clone))
+        .route("/clone-jobs/{job_id}", get(get_clone_job))
+        .route("/clone-events", any(clone_events_ws_connect))
+        .with_state(state_ref.clone())
+}
+
+/// Responds as soon as the copy started, progress is reported through the job.
+#[instrument(skip(state))]
+async fn start_clone(
+    State(state): State<AppStateRef>,
+    Path(id): Path<u64>,
+    Json(payload): Json<CloneRequest>,
+) -> Result<(StatusCode, Json<CloneProgress>), StatusCode> {
+    let progress = clone_instance(&state, id, payload)
+        .await
+        .map_err(trace_status_error("clone instance"))?;
+
+    Ok((StatusCode::ACCEPTED, Json(progress)))
+}
+
+#[instrument(skip(state))]
+async fn get_clone_job(
+    State(state): State<AppStateRef>,
+    Path(job_id): Path<u64>,
+) -> Result<Json<CloneProgress>, StatusCode> {
+    let progress = state
+        .clone_manager
+        .get_job(job_id)
+        .await
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    Ok(Json(progress))
+}
+
+#[instrument(skip(state))]
+async fn clone_events_ws_connect(
+    State(state): State<AppStateRef>,
+    ws: WebSocketUpgrade,
+) -> impl IntoResponse {
+    let events = state.clone_manager.subscribe_events();
+    ws.on_upgrade(move |ws| events_ws_handler(ws, events))
+}
@@ -0,0 +1,71 @@
+use axum::{
+    Json, Router,
+    extract::{Path, Query, State},
+    http::StatusCode,
+    routing::get,
+};
+use json_patch::PatchOperation;
+use sea_orm::EntityTrait;
+use serde::Deserialize;
+use tracing::instrument;
+
+use crate::{
+    AppStateRef,
+    entities::instance,
+    errors::{trace_error, trace_status_error},
+    services::{ConfigDocument, ConfigFormat, patch_config, read_config},
+};
+
+pub fn get_routes(state_ref: &AppStateRef) -> Router {
+    Router::new()
+        .route("/{id}/config", get(get_config).patch(update_config))
+        .with_state(state_ref.clone())
+}
+
+#[derive(Debug, Deserialize)]
+struct ConfigQuery {
+    /// Relative to the work dir, e.g. `server.properties`.
+    pub path: String,
+    /// Guessed from the file extension if not given.
+    pub format: Option<ConfigFormat>,
+}
+
+async fn find_instance(state: &AppStateRef, id: i32) -> Result<instance::Model, StatusCode> {
+    instance::Entity::find_by_id(id)
+        .one(&state.database)
+        .await
+        .map_err(trace_error!(
+            "find instance",
+            StatusCode::INTERNAL_SERVER_ERROR
+        ))?
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+#[instrument(skip(state))]
+async fn get_config(
+    State(state): State<AppStateRef>,
+    Path(id): Path<i32>,
+    Query(query): Query<ConfigQuery>,
+) -> Result<Json<ConfigDocument>, StatusCode> {
+    let the_instance = find_instance(&state, id).await?;
+    let document = read_config(&the_instance.work_dir, &query.path, query.format)
+        .await
+        .map_err(trace_status_error("read config"))?;
+
+    Ok(Json(document))
+}
+
+#[instrument(skip(state))]
+async fn update_config(
+    State(state): State<AppStateRef>,
+    Path(id): Path<i32>,
+    Query(query): Query<ConfigQuery>,
+    Json(patch_ops): Json<Vec<PatchOperation>>,
+) -> Result<Json<ConfigDocument>, StatusCode> {
+    let the_instance = find_instance(&state, id).await?;
+    let document = patch_config(&state, &the_instance, &query.path, query.format, &patch_ops)
+        .await
+        .map_err(trace_status_error("patch config"))?;
+
+    Ok(Json(document))
+}
@@ -0,0 +1,154 @@
+use axum::{
+    Json, Router,
+    extract::{Path, Query, Request, State},
+    http::StatusCode,
+    response::IntoResponse,
+    routing::get,
+};
+use sea_orm::{ColumnTrait, EntityTrait, PaginatorTrait, QueryFilter, QueryOrder};
+use serde::Deserialize;
+use tower::ServiceExt;
+use tower_http::services::ServeFile;
+use tracing::instrument;
+
+use crate::{
+    AppStateRef,
+    entities::crash_report,
+    errors::trace_error,
+    transfer::{PaginationOptions, PaginationResponse},
+};
+
+pub fn get_routes(state_ref: &AppStateRef) -> Router {
+    Router::new()
+        .route("/{id}/crash-reports", get(get_crash_reports))
+        .route(
+            "/{id}/crash-reports/{report_id}",
+            get(get_crash_report).delete(delete_crash_report),
+        )
+        .route(
+            "/{id}/crash-reports/{report_id}/output",
+            get(fetch_crash_output),
+        )
+        .route(
+            "/{id}/crash-reports/{report_id}/files/{*path}",
+            get(fetch_crash_file),
+        )
+        .with_state(state_ref.clone())
+}
+
+async fn find_crash_report(
+    state: &AppStateRef,
+    id: i32,
+    report_id: i32,
+) -> Result<crash_report::Model, StatusCode> {
+    crash_report::Entity::find_by_id(report_id)
+        .one(&state.database)
+        .await
+        .map_err(trace_error!(
+            "one from db",
+            StatusCode::INTERNAL_SERVER_ERROR
+        ))?
+        .filter(|x| x.instance_id == id)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CrashReportsQuery {
+    pub run_id: Option<i32>,
+}
+
+#[instrument(skip(state))]
+async fn get_crash_reports(
+    State(state): State<AppStateRef>,
+    Path(id): Path<i32>,
+    Query(pagination): Query<PaginationOptions>,
+    Query(query): Query<CrashReportsQuery>,
+) -> Result<Json<PaginationResponse<crash_report::Model>>, StatusCode> {
+    let db = &state.database;
+    let page = pagination.page.unwrap_or(1);
+    let page_size = pagination.page_size.unwrap_or(10);
+
+    let mut paginator = crash_report::Entity::find()
+        .filter(crash_report::Column::InstanceId.eq(id))
+        .order_by_desc(crash_report::Column::CrashedAt);
+    if let Some(run_id) = query.run_id {
+        paginator = paginator.filter(crash_report::Column::RunId.eq(run_id));
+    }
+
+    let paginator = paginator.paginate(db, page_size);
+    let num = paginator.num_items_and_pages().await.map_err(trace_error!(
+        "num_items_and_pages",
+        StatusCode::INTERNAL_SERVER_ERROR
+    ))?;
+
+    let models = paginator.fetch_page(page - 1).await.map_err(trace_error!(
+        "fetch_page",
+        StatusCode::INTERNAL_SERVER_ERROR
+    ))?;
+
+    Ok(Json(PaginationResponse {
+        page_count: num.number_of_pages,
+        total: num.number_of_items,
+        data: models,
+    }))
+}
+
+#[instrument(skip(state))]
+async fn get_crash_report(
+    State(state): State<AppStateRef>,
+    Path((id, report_id)): Path<(i32, i32)>,
+) -> Result<Json<crash_report::Model>, StatusCode> {
+    Ok(Json(find_crash_report(&state, id, report_id).await?))
+}
+
+#[instrument(skip(state))]
+async fn delete_crash_report(
+    State(state): State<AppStateRef>,
+    Path((id, report_id)): Path<(i32, i32)>,
+) -> Result<StatusCode, StatusCode> {
+    let the_report = find_crash_report(&state, id, report_id).await?;
+    state
+        .crash_report_manager
+        .delete_report(&state.database, the_report)
+        .await
+        .map_err(trace_error!(
+            "delete crash report",
+            StatusCode::INTERNAL_SERVER_ERROR
+        ))?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[instrument(skip(state, request))]
+async fn fetch_crash_output(
+    State(state): State<AppStateRef>,
+    Path((id, report_id)): Path<(i32, i32)>,
+    request: Request,
+) -> Result<impl IntoResponse, StatusCode> {
+    let the_report = find_crash_report(&state, id, report_id).await?;
+    let path = state.crash_report_manager.get_output_path(the_report.id);
+
+    Ok(ServeFile::new(path).oneshot(request).await)
+}
+
+/// Only the files listed in the report are served.
+#[instrument(skip(state, request))]
+async fn fetch_crash_file(
+    State(state): State<AppStateRef>,
+    Path((id, report_id, path)): Path<(i32, i32, String)>,
+    request: Request,
+) -> Result<impl IntoResponse, StatusCode> {
+    let the_report = find_crash_report(&state, id, report_id).await?;
+    let file = the_report
+        .files
+        .0
+        .iter()
+        .find(|x| x.path == path)
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let path = state
+        .crash_report_manager
+        .get_file_path(the_report.id, &file.path);
+
+    Ok(ServeFile::new(path).oneshot(request).await)
+}
@@ -0,0 +1,72 @@
+use axum::{
+    Json, Router,
+    extract::{Path, Query, State, WebSocketUpgrade},
+    http::StatusCode,
+    response::IntoResponse,
+    routing::{any, get},
+};
+use sea_orm::EntityTrait;
+use serde::Deserialize;
+use tracing::instrument;
+
+use crate::{
+    AppStateRef,
+    entities::instance,
+    errors::trace_error,
+    routes::processes::events_ws_handler,
+    services::{DiskQuotaService, DiskUsage},
+};
+
+pub fn get_routes(state_ref: &AppStateRef) -> Router {
+    Router::new()
+        .route("/{id}/disk-usage", get(get_disk_usage))
+        .route("/quota-events", any(quota_events_ws_connect))
+        .with_state(state_ref.clone())
+}
+
+#[derive(Debug, Deserialize)]
+struct DiskUsageQuery {
+    /// Scans now instead of returning the cached usage.
+    #[serde(default)]
+    pub refresh: bool,
+}
+
+#[instrument(skip(state))]
+async fn get_disk_usage(
+    State(state): State<AppStateRef>,
+    Path(id): Path<i32>,
+    Query(query): Query<DiskUsageQuery>,
+) -> Result<Json<DiskUsage>, StatusCode> {
+    if !query.refresh
+        && let Some(usage) = state.quota_manager.get_usage(id as u64).await
+    {
+        return Ok(Json(usage));
+    }
+
+    let the_instance = instance::Entity::find_by_id(id)
+        .one(&state.database)
+        .await
+        .map_err(trace_error!(
+            "find instance",
+            StatusCode::INTERNAL_SERVER_ERROR
+        ))?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let usage = DiskQuotaService::scan(&state, &the_instance)
+        .await
+        .map_err(trace_error!(
+            "scan work dir",
+            StatusCode::INTERNAL_SERVER_ERROR
+        ))?;
+
+    Ok(Json(usage))
+}
+
+#[instrument(skip(state))]
+async fn quota_events_ws_connect(
+    State(state): State<AppStateRef>,
+    ws: WebSocketUpgrade,
+) -> impl IntoResponse {
+    let events = state.quota_manager.subscribe_events();
+    ws.on_upgrade(move |ws| events_ws_handler(ws, events))
+}
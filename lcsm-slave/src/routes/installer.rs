@@ -0,0 +1,79 @@
+use axum::{
+    Json, Router,
+    extract::{Path, Query, State},
+    http::StatusCode,
+    routing::{get, post},
+};
+use serde::Deserialize;
+use tracing::instrument;
+
+use crate::{
+    AppStateRef,
+    entities::installation,
+    errors::trace_status_error,
+    services::{
+        InstallRequest, InstallationStatus, RepositoryIndex, install_package, installation_status,
+    },
+};
+
+/// Routes of an instance, merged into `/instance`.
+pub fn get_routes(state_ref: &AppStateRef) -> Router {
+    Router::new()
+        .route("/{id}/installation", get(get_installation))
+        .route("/{id}/install", post(install))
+        .with_state(state_ref.clone())
+}
+
+/// Routes of the repository index, nested at `/installer`.
+pub fn get_index_routes(state_ref: &AppStateRef) -> Router {
+    Router::new()
+        .route("/packages", get(get_packages))
+        .with_state(state_ref.clone())
+}
+
+#[derive(Debug, Deserialize)]
+struct PackagesQuery {
+    /// Skips the cached index.
+    #[serde(default)]
+    refresh: bool,
+}
+
+#[instrument(skip(state))]
+async fn get_packages(
+    State(state): State<AppStateRef>,
+    Query(query): Query<PackagesQuery>,
+) -> Result<Json<RepositoryIndex>, StatusCode> {
+    let index = state
+        .installer
+        .get_index(query.refresh)
+        .await
+        .map_err(trace_status_error("get repository index"))?;
+
+    Ok(Json(index.as_ref().clone()))
+}
+
+#[instrument(skip(state))]
+async fn get_installation(
+    State(state): State<AppStateRef>,
+    Path(id): Path<u64>,
+) -> Result<Json<InstallationStatus>, StatusCode> {
+    let status = installation_status(&state, id)
+        .await
+        .map_err(trace_status_error("get installation"))?;
+
+    Ok(Json(status))
+}
+
+/// Responds once the artifact is downloaded and verified.
+#[instrument(skip(state))]
+async fn install(
+    State(state): State<AppStateRef>,
+    Path(id): Path<u64>,
+    Json(payload): Json<InstallRequest>,
+) -> Result<Json<installation::Model>, StatusCode> {
+    let res = install_package(&state, id, payload)
+        .await
+        .map_err(trace_status_error("install package"))?;
+
+    Ok(Json(res))
+}
@@ -1,7 +1,13 @@
+use std::io;
+
 use crate::{
     AppStateRef,
     entities::instance,
-    errors::trace_error,
+    errors::{trace_error, trace_status_error},
+    services::{
+        ProvisionRequest, RunAsService, dependents_of, load_dependency_graph, provision_instance,
+        validate_dependencies,
+    },
     transfer::{PaginationOptions, PaginationResponse},
 };
 
@@ -9,7 +15,7 @@ use axum::{
     Json, Router,
     extract::{Path, Query, State},
     http::StatusCode,
-    routing::get,
+    routing::{get, post},
 };
 use axum_extra::extract::Query as ExtraQuery;
 use json_patch::{PatchOperation, patch as apply_json_patch};
@@ -25,6 +31,7 @@ use tracing::instrument;
 pub fn get_routes(state_ref: &AppStateRef) -> Router {
     Router::new()
         .route("/", get(get_instances).put(create_instance))
+        .route("/from-template", post(create_instance_from_template))
         .route(
             "/{id}",
             get(get_instance)
@@ -69,7 +76,7 @@ async fn get_instances(
     Ok(Json(PaginationResponse {
         page_count: num.number_of_pages,
         total: num.number_of_items,
-        data: models,
+        data: models.into_iter().map(|x| x.redacted()).collect(),
     }))
 }
 
@@ -87,15 +94,45 @@ async fn get_instance(
     .map_err(trace_error!("one from db", StatusCode::BAD_REQUEST))?
     .ok_or(StatusCode::NOT_FOUND)?;
 
-    Ok(Json(it))
+    Ok(Json(it.redacted()))
 }
 
 #[instrument(skip(state))]
 async fn create_instance(
     State(state): State<AppStateRef>,
-    Json(payload): Json<instance::Model>,
+    Json(mut payload): Json<instance::Model>,
 ) -> Result<Json<instance::Model>, StatusCode> {
     let db = &state.database;
+
+    payload
+        .validate()
+        .map_err(trace_error!("validate instance", StatusCode::BAD_REQUEST))?;
+    validate_dependencies(db, None, &payload.dependencies.0)
+        .await
+        .map_err(trace_status_error("validate dependencies"))?;
+    let run_as = state
+        .run_as_manager
+        .resolve(
+            payload.run_as_user.as_deref(),
+            payload.run_as_group.as_deref(),
+        )
+        .map_err(trace_error!("resolve run as", StatusCode::BAD_REQUEST))?;
+    if let Some(run_as) = run_as {
+        RunAsService::prepare_work_dir(&payload.work_dir, &state.instance_path, run_as)
+            .await
+            .map_err(|e| {
+                tracing::error!("prepare work dir: {}", e);
+                match e.kind() {
+                    io::ErrorKind::InvalidInput => StatusCode::BAD_REQUEST,
+                    _ => StatusCode::INTERNAL_SERVER_ERROR,
+                }
+            })?;
+    }
+    state
+        .secret_manager
+        .seal_instance(&mut payload)
+        .map_err(trace_status_error("seal secrets"))?;
+
     let active = instance::ActiveModel {
         id: NotSet, // empty the id
         ..payload.into()
@@ -105,7 +142,19 @@ async fn create_instance(
         .insert(db)
         .await
         .map_err(trace_error!("insert", StatusCode::INTERNAL_SERVER_ERROR))?;
-    Ok(Json(res))
+    Ok(Json(res.redacted()))
+}
+
+#[instrument(skip(state))]
+async fn create_instance_from_template(
+    State(state): State<AppStateRef>,
+    Json(payload): Json<ProvisionRequest>,
+) -> Result<Json<instance::Model>, StatusCode> {
+    let res = provision_instance(&state, payload)
+        .await
+        .map_err(trace_status_error("provision instance"))?;
+
+    Ok(Json(res.redacted()))
 }
 
 #[instrument(skip(state))]
@@ -137,8 +186,39 @@ async fn update_instance(
         .map_err(trace_error!("apply_json_patch", StatusCode::BAD_REQUEST))?;
 
     // check
-    let updated: instance::Model = serde_json::from_value(value)
+    let mut updated: instance::Model = serde_json::from_value(value)
         .map_err(trace_error!("get new model", StatusCode::BAD_REQUEST))?;
+    updated
+        .validate()
+        .map_err(trace_error!("validate instance", StatusCode::BAD_REQUEST))?;
+    validate_dependencies(db, Some(id), &updated.dependencies.0)
+        .await
+        .map_err(trace_status_error("validate dependencies"))?;
+    let run_as = state
+        .run_as_manager
+        .resolve(
+            updated.run_as_user.as_deref(),
+            updated.run_as_group.as_deref(),
+        )
+        .map_err(trace_error!("resolve run as", StatusCode::BAD_REQUEST))?;
+    let identity_changed = updated.run_as_user != model.run_as_user
+        || updated.run_as_group != model.run_as_group
+        || updated.work_dir != model.work_dir;
+    if let Some(run_as) = run_as.filter(|_| identity_changed) {
+        RunAsService::prepare_work_dir(&updated.work_dir, &state.instance_path, run_as)
+            .await
+            .map_err(|e| {
+                tracing::error!("prepare work dir: {}", e);
+                match e.kind() {
+                    io::ErrorKind::InvalidInput => StatusCode::BAD_REQUEST,
+                    _ => StatusCode::INTERNAL_SERVER_ERROR,
+                }
+            })?;
+    }
+    state
+        .secret_manager
+        .seal_instance(&mut updated)
+        .map_err(trace_status_error("seal secrets"))?;
 
     let mut updated = updated.into_active_model().reset_all();
     updated.id = Unchanged(id);
@@ -147,7 +227,7 @@ async fn update_instance(
         "update to db",
         StatusCode::INTERNAL_SERVER_ERROR
     ))?;
-    Ok(Json(res))
+    Ok(Json(res.redacted()))
 }
 
 #[instrument(skip(state))]
@@ -156,6 +236,16 @@ async fn delete_instance(
     Path(id): Path<i32>,
 ) -> Result<StatusCode, StatusCode> {
     let db = &state.database;
+
+    // refuse to leave dangling dependencies behind
+    let graph = load_dependency_graph(db).await.map_err(trace_error!(
+        "load dependency graph",
+        StatusCode::INTERNAL_SERVER_ERROR
+    ))?;
+    if !dependents_of(&graph, id).is_empty() {
+        return Err(StatusCode::CONFLICT);
+    }
+
     let res = instance::Entity::delete_by_id(id)
         .exec(db)
         .await
@@ -2,11 +2,39 @@ use axum::Router;
 
 use crate::AppStateRef;
 
+mod bundles;
+mod clone;
+mod config_files;
+mod crash_reports;
+mod disk_usage;
+mod installer;
 mod instances;
+mod output_matches;
 mod processes;
+mod rcon;
+mod runs;
+mod schedules;
+mod server_status;
+mod templates;
 
 pub fn get_routes(state_ref: &AppStateRef) -> Router {
     Router::new()
-        .nest("/instance", instances::get_routes(state_ref))
+        .nest(
+            "/instance",
+            instances::get_routes(state_ref)
+                .merge(schedules::get_routes(state_ref))
+                .merge(runs::get_routes(state_ref))
+                .merge(disk_usage::get_routes(state_ref))
+                .merge(server_status::get_routes(state_ref))
+                .merge(rcon::get_routes(state_ref))
+                .merge(config_files::get_routes(state_ref))
+                .merge(clone::get_routes(state_ref))
+                .merge(bundles::get_routes(state_ref))
+                .merge(installer::get_routes(state_ref))
+                .merge(crash_reports::get_routes(state_ref))
+                .merge(output_matches::get_routes(state_ref)),
+        )
         .nest("/process", processes::get_routes(state_ref))
+        .nest("/template", templates::get_routes(state_ref))
+        .nest("/installer", installer::get_index_routes(state_ref))
 }
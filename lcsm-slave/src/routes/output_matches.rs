@@ -0,0 +1,81 @@
+use axum::{
+    Json, Router,
+    extract::{Path, Query, State, WebSocketUpgrade},
+    http::StatusCode,
+    response::IntoResponse,
+    routing::{any, get},
+};
+use sea_orm::{ColumnTrait, EntityTrait, PaginatorTrait, QueryFilter, QueryOrder};
+use serde::Deserialize;
+use tracing::instrument;
+
+use crate::{
+    AppStateRef,
+    entities::output_match,
+    errors::trace_error,
+    routes::processes::events_ws_handler,
+    transfer::{PaginationOptions, PaginationResponse},
+};
+
+pub fn get_routes(state_ref: &AppStateRef) -> Router {
+    Router::new()
+        .route("/{id}/output-matches", get(get_output_matches))
+        .route("/output-events", any(output_events_ws_connect))
+        .with_state(state_ref.clone())
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct OutputMatchesQuery {
+    pub rule: Option<String>,
+    pub severity: Option<String>,
+}
+
+#[instrument(skip(state))]
+async fn get_output_matches(
+    State(state): State<AppStateRef>,
+    Path(id): Path<i32>,
+    Query(pagination): Query<PaginationOptions>,
+    Query(query): Query<OutputMatchesQuery>,
+) -> Result<Json<PaginationResponse<output_match::Model>>, StatusCode> {
+    let db = &state.database;
+    let page = pagination.page.unwrap_or(1);
+    let page_size = pagination.page_size.unwrap_or(10);
+
+    let mut paginator = output_match::Entity::find()
+        .filter(output_match::Column::InstanceId.eq(id))
+        .order_by_desc(output_match::Column::Id);
+    if let Some(rule) = query.rule {
+        paginator = paginator.filter(output_match::Column::Rule.eq(rule));
+    }
+    if let Some(severity) = query.severity {
+        paginator = paginator.filter(output_match::Column::Severity.eq(severity));
+    }
+
+    let paginator = paginator.paginate(db, page_size);
+    let num = paginator.num_items_and_pages().await.map_err(trace_error!(
+        "num_items_and_pages",
+        StatusCode::INTERNAL_SERVER_ERROR
+    ))?;
+
+    let models = paginator.fetch_page(page - 1).await.map_err(trace_error!(
+        "fetch_page",
+        StatusCode::INTERNAL_SERVER_ERROR
+    ))?;
+
+    Ok(Json(PaginationResponse {
+        page_count: num.number_of_pages,
+        total: num.number_of_items,
+        data: models,
+    }))
+}
+
+/// Sends every stored match of any instance as it happens.
+#[instrument(skip(state))]
+async fn output_events_ws_connect(
+    State(state): State<AppStateRef>,
+    ws: WebSocketUpgrade,
+) -> impl IntoResponse {
+    let events = state.output_rule_manager.subscribe_events();
+    ws.on_upgrade(move |ws| events_ws_handler(ws, events))
+}
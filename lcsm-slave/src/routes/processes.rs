@@ -1,19 +1,23 @@
-use std::ffi::OsString;
+use std::time::Duration;
 
 use axum::{
-    Router,
+    Json, Router,
     extract::{
         Path, Request, State, WebSocketUpgrade,
         ws::{Message, WebSocket},
     },
     http::StatusCode,
     response::IntoResponse,
-    routing::{any, get, put},
+    routing::{any, get, post, put},
 };
-
-use sea_orm::EntityTrait;
+use chrono::{DateTime, Utc};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
 use tokio::{
-    sync::{broadcast::error::RecvError, mpsc, oneshot},
+    sync::{
+        broadcast::{self, error::RecvError},
+        mpsc, oneshot,
+    },
     task::JoinError,
 };
 use tower::ServiceExt;
@@ -22,120 +26,239 @@ use tracing::{Instrument, instrument};
 
 use crate::{
     AppStateRef,
-    entities::instance,
-    errors::trace_error,
-    services::{ProcessRef, ProcessState},
+    errors::{trace_error, trace_status_error},
+    services::{
+        CaptureOptions, CapturedOutput, EffectiveLimits, HealthStatus, ProcessRef, ProcessStatus,
+        ResourceSnapshot, execute_command, start_instance, stop_instance,
+    },
 };
 
 use futures::{SinkExt, StreamExt};
 
 pub fn get_routes(state_ref: &AppStateRef) -> Router {
     Router::new()
+        .route("/", get(list_processes))
         .route(
             "/{id}",
             put(start_process).delete(kill_process).get(process_state),
         )
         .route("/{id}/terminal", any(terminal_ws_connect))
         .route("/{id}/logs", get(fetch_process_log))
+        .route("/{id}/metrics", get(process_metrics))
+        .route("/{id}/command", post(execute_process_command))
+        .route("/events", any(process_events_ws_connect))
         .with_state(state_ref.clone())
 }
 
-async fn get_alive_process(state: &AppStateRef, id: u64) -> Option<ProcessRef> {
-    let process = state.process_manager.get_process(id).await;
-    if process.is_none() {
-        return None;
-    }
-
-    let process = process.unwrap();
-    let state = {
-        let process_read = process.read().await;
-        process_read.state().await
-    };
-    if state == ProcessState::Dead {
-        return None;
-    }
+#[instrument(skip(state))]
+async fn start_process(
+    State(state): State<AppStateRef>,
+    Path(id): Path<u64>,
+) -> Result<(), StatusCode> {
+    start_instance(&state, id)
+        .await
+        .map_err(trace_status_error("start instance"))?;
 
-    return Some(process);
+    Ok(())
 }
 
 #[instrument(skip(state))]
-async fn start_process(
+async fn kill_process(
     State(state): State<AppStateRef>,
     Path(id): Path<u64>,
 ) -> Result<(), StatusCode> {
-    // is the process existed?
-    let process = get_alive_process(&state, id).await;
+    stop_instance(&state, id)
+        .await
+        .map_err(trace_status_error("stop instance"))?;
 
-    if process.is_some() {
-        return Err(StatusCode::CONFLICT);
-    }
+    Ok(())
+}
+
+/// Longest capture window a client may ask for.
+const MAX_COMMAND_WINDOW_MS: u64 = 60_000;
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CommandRequest {
+    pub command: String,
+    /// How long output is collected, the time to wait for `until` when it is given.
+    #[serde(default = "default_command_window")]
+    pub window_ms: u64,
+    /// Stops collecting as soon as the output matches this regex.
+    pub until: Option<String>,
+    #[serde(default)]
+    pub include_stderr: bool,
+}
+
+fn default_command_window() -> u64 {
+    1000
+}
 
-    // start process
-    let db = &state.database;
-    let the_instance = instance::Entity::find_by_id(
-        i32::try_from(id).map_err(trace_error!("parse id", StatusCode::BAD_REQUEST))?,
+#[instrument(skip(state))]
+async fn execute_process_command(
+    State(state): State<AppStateRef>,
+    Path(id): Path<u64>,
+    Json(payload): Json<CommandRequest>,
+) -> Result<Json<CapturedOutput>, StatusCode> {
+    if payload.window_ms > MAX_COMMAND_WINDOW_MS {
+        tracing::error!("capture window of {}ms is too long", payload.window_ms);
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let until = payload
+        .until
+        .as_deref()
+        .map(Regex::new)
+        .transpose()
+        .map_err(trace_error!("parse until pattern", StatusCode::BAD_REQUEST))?;
+
+    let captured = execute_command(
+        &state,
+        id,
+        &payload.command,
+        CaptureOptions {
+            window: Duration::from_millis(payload.window_ms),
+            until,
+            include_stderr: payload.include_stderr,
+        },
     )
-    .one(db)
     .await
-    .map_err(trace_error!(
-        "one from db",
-        StatusCode::INTERNAL_SERVER_ERROR
-    ))?
-    .ok_or(StatusCode::NOT_FOUND)?;
-
-    let arguments = the_instance
-        .arguments
-        .lines()
-        .map(|x| x.into())
-        .collect::<Vec<OsString>>();
-
-    let process_ref = state
-        .process_manager
-        .new_process(
-            id,
-            the_instance.launch_command,
-            arguments,
-            the_instance.work_dir,
-            the_instance.use_shell,
+    .map_err(trace_status_error("execute command"))?;
+
+    Ok(Json(captured))
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ProcessStatusResponse {
+    pub instance_id: u64,
+    #[serde(flatten)]
+    pub status: ProcessStatus,
+    pub pid: Option<u32>,
+    pub started_at: DateTime<Utc>,
+    pub uptime_secs: Option<i64>,
+    pub resources: Option<ResourceSnapshot>,
+    pub limits: EffectiveLimits,
+    pub health: Option<HealthStatus>,
+}
+
+async fn build_status_response(
+    state: &AppStateRef,
+    id: u64,
+    process: ProcessRef,
+) -> ProcessStatusResponse {
+    let (status, pid, started_at, limits) = {
+        let process = process.read().await;
+        (
+            process.status(),
+            process.pid(),
+            process.started_at(),
+            process.limits().clone(),
         )
-        .await
-        .map_err(trace_error!(
-            "spawn process",
-            StatusCode::INTERNAL_SERVER_ERROR
-        ))?;
+    };
 
-    _ = state.log_manager.begin_log(id, process_ref).await;
+    let alive = status.state.is_alive();
+    ProcessStatusResponse {
+        instance_id: id,
+        uptime_secs: alive.then(|| (Utc::now() - started_at).num_seconds()),
+        resources: if alive {
+            state.metrics.get_latest(id).await
+        } else {
+            None
+        },
+        status,
+        pid,
+        started_at,
+        limits,
+        health: state.health_manager.get_status(id).await,
+    }
+}
 
-    Ok(())
+#[instrument(skip(state))]
+async fn list_processes(State(state): State<AppStateRef>) -> Json<Vec<ProcessStatusResponse>> {
+    let mut processes = state.process_manager.list_processes().await;
+    processes.sort_by_key(|(id, _)| *id);
+
+    let mut response = Vec::with_capacity(processes.len());
+    for (id, process) in processes {
+        response.push(build_status_response(&state, id, process).await);
+    }
+
+    Json(response)
 }
 
 #[instrument(skip(state))]
-async fn kill_process(
+async fn process_state(
     State(state): State<AppStateRef>,
     Path(id): Path<u64>,
-) -> Result<(), StatusCode> {
-    let process = get_alive_process(&state, id)
+) -> Result<Json<ProcessStatusResponse>, StatusCode> {
+    let process = state
+        .process_manager
+        .get_process(id)
         .await
         .ok_or(StatusCode::NOT_FOUND)?;
 
-    process.read().await.kill().await.map_err(trace_error!(
-        "kill process",
-        StatusCode::INTERNAL_SERVER_ERROR
-    ))?;
+    Ok(Json(build_status_response(&state, id, process).await))
+}
 
-    Ok(())
+#[instrument(skip(state))]
+async fn process_events_ws_connect(
+    State(state): State<AppStateRef>,
+    ws: WebSocketUpgrade,
+) -> impl IntoResponse {
+    let events = state.process_manager.subscribe_events();
+    ws.on_upgrade(move |ws| events_ws_handler(ws, events))
+}
+
+/// Forwards broadcast events to the socket as JSON text messages.
+#[instrument(skip(socket, events))]
+pub(super) async fn events_ws_handler<T: Serialize + Clone>(
+    mut socket: WebSocket,
+    mut events: broadcast::Receiver<T>,
+) {
+    loop {
+        tokio::select! {
+            event = events.recv() => {
+                let event = match event {
+                    Ok(v) => v,
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break,
+                };
+
+                let message = match serde_json::to_string(&event) {
+                    Ok(v) => v,
+                    Err(e) => {
+                        tracing::error!("serialize event: {}", e);
+                        continue;
+                    }
+                };
+
+                if socket.send(Message::text(message)).await.is_err() {
+                    break;
+                }
+            }
+            message = socket.recv() => {
+                // nothing is expected from the client except closing
+                match message {
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    _ => {}
+                }
+            }
+        }
+    }
 }
 
 #[instrument(skip(state))]
-async fn process_state(
+async fn process_metrics(
     State(state): State<AppStateRef>,
     Path(id): Path<u64>,
-) -> Result<(), StatusCode> {
-    get_alive_process(&state, id)
+) -> Result<Json<Vec<ResourceSnapshot>>, StatusCode> {
+    state
+        .process_manager
+        .get_alive_process(id)
         .await
         .ok_or(StatusCode::NOT_FOUND)?;
 
-    Ok(())
+    Ok(Json(state.metrics.get_history(id).await))
 }
 
 #[instrument(skip(state))]
@@ -144,7 +267,9 @@ async fn terminal_ws_connect(
     Path(id): Path<u64>,
     ws: WebSocketUpgrade,
 ) -> Result<impl IntoResponse, StatusCode> {
-    let process = get_alive_process(&state, id)
+    let process = state
+        .process_manager
+        .get_alive_process(id)
         .await
         .ok_or(StatusCode::NOT_FOUND)?;
 
@@ -0,0 +1,39 @@
+use axum::{
+    Json, Router,
+    extract::{Path, State},
+    http::StatusCode,
+    routing::post,
+};
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+
+use crate::{AppStateRef, errors::trace_status_error, services::rcon_command};
+
+pub fn get_routes(state_ref: &AppStateRef) -> Router {
+    Router::new()
+        .route("/{id}/rcon", post(execute_rcon_command))
+        .with_state(state_ref.clone())
+}
+
+#[derive(Debug, Deserialize)]
+struct RconCommandRequest {
+    pub command: String,
+}
+
+#[derive(Serialize)]
+struct RconCommandResponse {
+    pub output: String,
+}
+
+#[instrument(skip(state))]
+async fn execute_rcon_command(
+    State(state): State<AppStateRef>,
+    Path(id): Path<u64>,
+    Json(payload): Json<RconCommandRequest>,
+) -> Result<Json<RconCommandResponse>, StatusCode> {
+    let output = rcon_command(&state, id, &payload.command)
+        .await
+        .map_err(trace_status_error("rcon command"))?;
+
+    Ok(Json(RconCommandResponse { output }))
+}
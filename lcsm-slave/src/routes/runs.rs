@@ -0,0 +1,68 @@
+use axum::{
+    Json, Router,
+    extract::{Path, Query, State},
+    http::StatusCode,
+    routing::get,
+};
+use sea_orm::{ColumnTrait, EntityTrait, PaginatorTrait, QueryFilter, QueryOrder};
+use serde::Deserialize;
+use tracing::instrument;
+
+use crate::{
+    AppStateRef,
+    entities::run_history,
+    errors::trace_error,
+    transfer::{PaginationOptions, PaginationResponse},
+};
+
+pub fn get_routes(state_ref: &AppStateRef) -> Router {
+    Router::new()
+        .route("/{id}/runs", get(get_runs))
+        .with_state(state_ref.clone())
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RunsQuery {
+    pub schedule_id: Option<i32>,
+    pub trigger: Option<String>,
+}
+
+#[instrument(skip(state))]
+async fn get_runs(
+    State(state): State<AppStateRef>,
+    Path(id): Path<i32>,
+    Query(pagination): Query<PaginationOptions>,
+    Query(query): Query<RunsQuery>,
+) -> Result<Json<PaginationResponse<run_history::Model>>, StatusCode> {
+    let db = &state.database;
+    let page = pagination.page.unwrap_or(1);
+    let page_size = pagination.page_size.unwrap_or(10);
+
+    let mut paginator = run_history::Entity::find()
+        .filter(run_history::Column::InstanceId.eq(id))
+        .order_by_desc(run_history::Column::StartedAt);
+    if let Some(schedule_id) = query.schedule_id {
+        paginator = paginator.filter(run_history::Column::ScheduleId.eq(schedule_id));
+    }
+    if let Some(trigger) = query.trigger {
+        paginator = paginator.filter(run_history::Column::Trigger.eq(trigger));
+    }
+
+    let paginator = paginator.paginate(db, page_size);
+    let num = paginator.num_items_and_pages().await.map_err(trace_error!(
+        "num_items_and_pages",
+        StatusCode::INTERNAL_SERVER_ERROR
+    ))?;
+
+    let models = paginator.fetch_page(page - 1).await.map_err(trace_error!(
+        "fetch_page",
+        StatusCode::INTERNAL_SERVER_ERROR
+    ))?;
+
+    Ok(Json(PaginationResponse {
+        page_count: num.number_of_pages,
+        total: num.number_of_items,
+        data: models,
+    }))
+}
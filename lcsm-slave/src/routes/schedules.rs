@@ -0,0 +1,196 @@
+use axum::{
+    Json, Router,
+    extract::{Path, Query, State},
+    http::StatusCode,
+    routing::get,
+};
+use chrono::Utc;
+use json_patch::{PatchOperation, patch as apply_json_patch};
+use sea_orm::{
+    ActiveModelTrait,
+    ActiveValue::{NotSet, Set, Unchanged},
+    ColumnTrait, EntityTrait, IntoActiveModel, PaginatorTrait, QueryFilter,
+};
+use serde_json::Value;
+use tracing::instrument;
+
+use crate::{
+    AppStateRef,
+    entities::{instance, schedule},
+    errors::trace_error,
+    services::{next_fire_time, validate_schedule},
+    transfer::{PaginationOptions, PaginationResponse},
+};
+
+pub fn get_routes(state_ref: &AppStateRef) -> Router {
+    Router::new()
+        .route("/{id}/schedules", get(get_schedules).put(create_schedule))
+        .route(
+            "/{id}/schedules/{schedule_id}",
+            get(get_schedule)
+                .patch(update_schedule)
+                .delete(delete_schedule),
+        )
+        .with_state(state_ref.clone())
+}
+
+async fn find_schedule(
+    state: &AppStateRef,
+    id: i32,
+    schedule_id: i32,
+) -> Result<schedule::Model, StatusCode> {
+    schedule::Entity::find_by_id(schedule_id)
+        .filter(schedule::Column::InstanceId.eq(id))
+        .one(&state.database)
+        .await
+        .map_err(trace_error!(
+            "one from db",
+            StatusCode::INTERNAL_SERVER_ERROR
+        ))?
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+#[instrument(skip(state))]
+async fn get_schedules(
+    State(state): State<AppStateRef>,
+    Path(id): Path<i32>,
+    Query(pagination): Query<PaginationOptions>,
+) -> Result<Json<PaginationResponse<schedule::Model>>, StatusCode> {
+    let db = &state.database;
+    let page = pagination.page.unwrap_or(1);
+    let page_size = pagination.page_size.unwrap_or(10);
+
+    let paginator = schedule::Entity::find()
+        .filter(schedule::Column::InstanceId.eq(id))
+        .paginate(db, page_size);
+    let num = paginator.num_items_and_pages().await.map_err(trace_error!(
+        "num_items_and_pages",
+        StatusCode::INTERNAL_SERVER_ERROR
+    ))?;
+
+    let models = paginator.fetch_page(page - 1).await.map_err(trace_error!(
+        "fetch_page",
+        StatusCode::INTERNAL_SERVER_ERROR
+    ))?;
+
+    Ok(Json(PaginationResponse {
+        page_count: num.number_of_pages,
+        total: num.number_of_items,
+        data: models,
+    }))
+}
+
+#[instrument(skip(state))]
+async fn get_schedule(
+    State(state): State<AppStateRef>,
+    Path((id, schedule_id)): Path<(i32, i32)>,
+) -> Result<Json<schedule::Model>, StatusCode> {
+    Ok(Json(find_schedule(&state, id, schedule_id).await?))
+}
+
+#[instrument(skip(state))]
+async fn create_schedule(
+    State(state): State<AppStateRef>,
+    Path(id): Path<i32>,
+    Json(payload): Json<schedule::Model>,
+) -> Result<Json<schedule::Model>, StatusCode> {
+    let db = &state.database;
+
+    // the instance must exist
+    instance::Entity::find_by_id(id)
+        .one(db)
+        .await
+        .map_err(trace_error!(
+            "one from db",
+            StatusCode::INTERNAL_SERVER_ERROR
+        ))?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    validate_schedule(&payload)
+        .map_err(trace_error!("validate schedule", StatusCode::BAD_REQUEST))?;
+
+    let next_run_at = next_fire_time(&payload, Utc::now());
+    let active = schedule::ActiveModel {
+        id: NotSet, // empty the id
+        instance_id: Set(id),
+        last_run_at: Set(None),
+        next_run_at: Set(next_run_at),
+        ..payload.into()
+    };
+
+    let res = active
+        .insert(db)
+        .await
+        .map_err(trace_error!("insert", StatusCode::INTERNAL_SERVER_ERROR))?;
+
+    state.scheduler.notify_changed();
+    Ok(Json(res))
+}
+
+#[instrument(skip(state))]
+async fn update_schedule(
+    State(state): State<AppStateRef>,
+    Path((id, schedule_id)): Path<(i32, i32)>,
+    Json(patch_ops): Json<Value>,
+) -> Result<Json<schedule::Model>, StatusCode> {
+    let db = &state.database;
+    let model = find_schedule(&state, id, schedule_id).await?;
+
+    // preapre
+    let mut value = serde_json::to_value(&model).map_err(trace_error!(
+        "to serde value",
+        StatusCode::INTERNAL_SERVER_ERROR
+    ))?;
+    let patch_ops_vec: Vec<PatchOperation> = serde_json::from_value(patch_ops)
+        .map_err(trace_error!("parse json patch", StatusCode::BAD_REQUEST))?;
+
+    // apply
+    apply_json_patch(&mut value, &patch_ops_vec)
+        .map_err(trace_error!("apply_json_patch", StatusCode::BAD_REQUEST))?;
+
+    // check
+    let updated: schedule::Model = serde_json::from_value(value)
+        .map_err(trace_error!("get new model", StatusCode::BAD_REQUEST))?;
+    validate_schedule(&updated)
+        .map_err(trace_error!("validate schedule", StatusCode::BAD_REQUEST))?;
+
+    // the timing may be changed, plan the next run again
+    let next_run_at = next_fire_time(&updated, Utc::now());
+
+    let mut updated = updated.into_active_model().reset_all();
+    updated.id = Unchanged(schedule_id);
+    updated.instance_id = Unchanged(id);
+    updated.last_run_at = Unchanged(model.last_run_at);
+    updated.next_run_at = Set(next_run_at);
+
+    let res = updated.update(db).await.map_err(trace_error!(
+        "update to db",
+        StatusCode::INTERNAL_SERVER_ERROR
+    ))?;
+
+    state.scheduler.notify_changed();
+    Ok(Json(res))
+}
+
+#[instrument(skip(state))]
+async fn delete_schedule(
+    State(state): State<AppStateRef>,
+    Path((id, schedule_id)): Path<(i32, i32)>,
+) -> Result<StatusCode, StatusCode> {
+    let db = &state.database;
+    let res = schedule::Entity::delete_many()
+        .filter(schedule::Column::Id.eq(schedule_id))
+        .filter(schedule::Column::InstanceId.eq(id))
+        .exec(db)
+        .await
+        .map_err(trace_error!(
+            "exec delete",
+            StatusCode::INTERNAL_SERVER_ERROR
+        ))?;
+    if res.rows_affected == 0 {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    state.scheduler.notify_changed();
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,67 @@
+use axum::{
+    Json, Router,
+    extract::{Path, State},
+    http::StatusCode,
+    routing::get,
+};
+use chrono::{DateTime, Utc};
+use sea_orm::EntityTrait;
+use serde::Serialize;
+use tracing::instrument;
+
+use crate::{
+    AppStateRef,
+    entities::instance,
+    errors::trace_error,
+    services::{ServerStatus, query_server_status},
+};
+
+pub fn get_routes(state_ref: &AppStateRef) -> Router {
+    Router::new()
+        .route("/{id}/status", get(get_server_status))
+        .with_state(state_ref.clone())
+}
+
+/// An unreachable server is reported as offline rather than as an error.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ServerStatusResponse {
+    pub online: bool,
+    pub checked_at: DateTime<Utc>,
+    #[serde(flatten)]
+    pub status: Option<ServerStatus>,
+    pub error: Option<String>,
+}
+
+#[instrument(skip(state))]
+async fn get_server_status(
+    State(state): State<AppStateRef>,
+    Path(id): Path<i32>,
+) -> Result<Json<ServerStatusResponse>, StatusCode> {
+    let the_instance = instance::Entity::find_by_id(id)
+        .one(&state.database)
+        .await
+        .map_err(trace_error!(
+            "find instance",
+            StatusCode::INTERNAL_SERVER_ERROR
+        ))?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    // the integration is opt-in
+    let address = the_instance.minecraft.status.ok_or(StatusCode::NOT_FOUND)?;
+
+    let (status, error) = match query_server_status(&address).await {
+        Ok(v) => (Some(v), None),
+        Err(e) => {
+            tracing::debug!("query status of instance {}: {}", id, e);
+            (None, Some(e.to_string()))
+        }
+    };
+
+    Ok(Json(ServerStatusResponse {
+        online: status.is_some(),
+        checked_at: Utc::now(),
+        status,
+        error,
+    }))
+}
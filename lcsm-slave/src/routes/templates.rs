@@ -0,0 +1,164 @@
+use axum::{
+    Json, Router,
+    extract::{Path, Query, State},
+    http::StatusCode,
+    routing::get,
+};
+use json_patch::{PatchOperation, patch as apply_json_patch};
+use sea_orm::{
+    ActiveModelTrait,
+    ActiveValue::{NotSet, Unchanged},
+    EntityTrait, IntoActiveModel, PaginatorTrait,
+};
+use serde_json::Value;
+use tracing::instrument;
+
+use crate::{
+    AppStateRef,
+    entities::template,
+    errors::{trace_error, trace_status_error},
+    transfer::{PaginationOptions, PaginationResponse},
+};
+
+pub fn get_routes(state_ref: &AppStateRef) -> Router {
+    Router::new()
+        .route("/", get(get_templates).put(create_template))
+        .route(
+            "/{id}",
+            get(get_template)
+                .patch(update_template)
+                .delete(delete_template),
+        )
+        .with_state(state_ref.clone())
+}
+
+async fn find_template(state: &AppStateRef, id: i32) -> Result<template::Model, StatusCode> {
+    template::Entity::find_by_id(id)
+        .one(&state.database)
+        .await
+        .map_err(trace_error!(
+            "one from db",
+            StatusCode::INTERNAL_SERVER_ERROR
+        ))?
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+#[instrument(skip(state))]
+async fn get_templates(
+    State(state): State<AppStateRef>,
+    Query(pagination): Query<PaginationOptions>,
+) -> Result<Json<PaginationResponse<template::Model>>, StatusCode> {
+    let db = &state.database;
+    let page = pagination.page.unwrap_or(1);
+    let page_size = pagination.page_size.unwrap_or(10);
+
+    let paginator = template::Entity::find().paginate(db, page_size);
+    let num = paginator.num_items_and_pages().await.map_err(trace_error!(
+        "num_items_and_pages",
+        StatusCode::INTERNAL_SERVER_ERROR
+    ))?;
+
+    let models = paginator.fetch_page(page - 1).await.map_err(trace_error!(
+        "fetch_page",
+        StatusCode::INTERNAL_SERVER_ERROR
+    ))?;
+
+    Ok(Json(PaginationResponse {
+        page_count: num.number_of_pages,
+        total: num.number_of_items,
+        data: models.into_iter().map(|x| x.redacted()).collect(),
+    }))
+}
+
+#[instrument(skip(state))]
+async fn get_template(
+    State(state): State<AppStateRef>,
+    Path(id): Path<i32>,
+) -> Result<Json<template::Model>, StatusCode> {
+    Ok(Json(find_template(&state, id).await?.redacted()))
+}
+
+#[instrument(skip(state))]
+async fn create_template(
+    State(state): State<AppStateRef>,
+    Json(mut payload): Json<template::Model>,
+) -> Result<Json<template::Model>, StatusCode> {
+    payload
+        .validate()
+        .map_err(trace_error!("validate template", StatusCode::BAD_REQUEST))?;
+    state
+        .secret_manager
+        .seal_environment(&mut payload.environment)
+        .map_err(trace_status_error("seal secrets"))?;
+
+    let active = template::ActiveModel {
+        id: NotSet, // empty the id
+        ..payload.into()
+    };
+
+    let res = active
+        .insert(&state.database)
+        .await
+        .map_err(trace_error!("insert", StatusCode::INTERNAL_SERVER_ERROR))?;
+    Ok(Json(res.redacted()))
+}
+
+#[instrument(skip(state))]
+async fn update_template(
+    State(state): State<AppStateRef>,
+    Path(id): Path<i32>,
+    Json(patch_ops): Json<Value>,
+) -> Result<Json<template::Model>, StatusCode> {
+    let model = find_template(&state, id).await?;
+
+    // preapre
+    let mut value = serde_json::to_value(&model).map_err(trace_error!(
+        "to serde value",
+        StatusCode::INTERNAL_SERVER_ERROR
+    ))?;
+    let patch_ops_vec: Vec<PatchOperation> = serde_json::from_value(patch_ops)
+        .map_err(trace_error!("parse json patch", StatusCode::BAD_REQUEST))?;
+
+    // apply
+    apply_json_patch(&mut value, &patch_ops_vec)
+        .map_err(trace_error!("apply_json_patch", StatusCode::BAD_REQUEST))?;
+
+    // check
+    let mut updated: template::Model = serde_json::from_value(value)
+        .map_err(trace_error!("get new model", StatusCode::BAD_REQUEST))?;
+    updated
+        .validate()
+        .map_err(trace_error!("validate template", StatusCode::BAD_REQUEST))?;
+    state
+        .secret_manager
+        .seal_environment(&mut updated.environment)
+        .map_err(trace_status_error("seal secrets"))?;
+
+    let mut updated = updated.into_active_model().reset_all();
+    updated.id = Unchanged(id);
+
+    let res = updated.update(&state.database).await.map_err(trace_error!(
+        "update to db",
+        StatusCode::INTERNAL_SERVER_ERROR
+    ))?;
+    Ok(Json(res.redacted()))
+}
+
+#[instrument(skip(state))]
+async fn delete_template(
+    State(state): State<AppStateRef>,
+    Path(id): Path<i32>,
+) -> Result<StatusCode, StatusCode> {
+    let res = template::Entity::delete_by_id(id)
+        .exec(&state.database)
+        .await
+        .map_err(trace_error!(
+            "exec delete",
+            StatusCode::INTERNAL_SERVER_ERROR
+        ))?;
+    if res.rows_affected == 0 {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
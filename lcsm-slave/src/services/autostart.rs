@@ -0,0 +1,98 @@
+use std::{collections::HashMap, time::Duration};
+
+use sea_orm::{ColumnTrait, DbErr, EntityTrait, QueryFilter};
+use tracing::Instrument;
+
+use crate::{
+    AppStateRef,
+    entities::instance,
+    services::{
+        InstanceControlError, RUN_TRIGGER_AUTOSTART, RunDescriptor, load_dependency_graph,
+        prioritized_order, start_instance,
+    },
+};
+
+/// Starts every instance flagged with `autostart`, one after another by their order.
+pub fn autostart_instances(state: AppStateRef) {
+    tokio::spawn(
+        async move {
+            if let Err(e) = run_autostart(&state).await {
+                tracing::error!("Autostart error: {}", e);
+            }
+        }
+        .instrument(tracing::info_span!(parent: None, "autostart")),
+    );
+}
+
+async fn run_autostart(state: &AppStateRef) -> Result<(), DbErr> {
+    let instances = instance::Entity::find()
+        .filter(instance::Column::Autostart.eq(true))
+        .all(&state.database)
+        .await?
+        .into_iter()
+        .map(|x| (x.id, x))
+        .collect::<HashMap<_, _>>();
+
+    // dependencies go first, the configured order only breaks ties
+    let graph = load_dependency_graph(&state.database).await?;
+    let order = match prioritized_order(
+        &graph,
+        instances.values().map(|x| (x.id, x.autostart_order)),
+    ) {
+        Ok(v) => v,
+        Err(e) => {
+            tracing::error!("Autostart order error: {}", e);
+            return Ok(());
+        }
+    };
+
+    tracing::info!("Autostarting {} instance(s)", order.len());
+
+    for the_instance in order.into_iter().map(|x| &instances[&x]) {
+        if the_instance.autostart_delay > 0 {
+            tokio::time::sleep(Duration::from_secs(the_instance.autostart_delay as u64)).await;
+        }
+
+        // a run that can't be recorded doesn't keep the instance or the ones after it down
+        let run = state
+            .run_history
+            .begin_run(RunDescriptor {
+                instance_id: the_instance.id,
+                schedule_id: None,
+                trigger: RUN_TRIGGER_AUTOSTART,
+                action: "start",
+                scheduled_at: None,
+            })
+            .await
+            .inspect_err(|e| {
+                tracing::error!(
+                    "Failed to record autostart of instance {}: {}",
+                    the_instance.id,
+                    e
+                )
+            })
+            .ok();
+
+        let result = match start_instance(state, the_instance.id as u64).await {
+            Ok(_) => Ok(None),
+            // started earlier as a dependency of another instance
+            Err(InstanceControlError::AlreadyRunning) => Ok(Some("already running".to_owned())),
+            Err(e) => Err(e.to_string()),
+        };
+        if let Err(e) = &result {
+            tracing::error!("Failed to autostart instance {}: {}", the_instance.id, e);
+        }
+
+        if let Some(run) = run
+            && let Err(e) = state.run_history.finish_run(run, result).await
+        {
+            tracing::error!(
+                "Failed to record autostart of instance {}: {}",
+                the_instance.id,
+                e
+            );
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,108 @@
+use std::{
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+};
+
+use chrono::Utc;
+use flate2::{Compression, write::GzEncoder};
+
+/// Older backups of an instance are deleted once it has more than this.
+const KEEP_BACKUPS: usize = 10;
+
+pub struct BackupService {
+    backup_path: PathBuf,
+}
+
+impl BackupService {
+    pub fn new(backup_path: PathBuf) -> Self {
+        Self { backup_path }
+    }
+
+    pub fn get_backup_dir(&self, id: u64) -> PathBuf {
+        self.backup_path.join(id.to_string())
+    }
+
+    /// Archives the work dir of an instance into `<backup dir>/<id>/<timestamp>.tar.gz`, only
+    /// the latest [`KEEP_BACKUPS`] backups are kept.
+    pub async fn create_backup(
+        &self,
+        id: u64,
+        work_dir: impl AsRef<Path>,
+    ) -> Result<PathBuf, io::Error> {
+        let backup_dir = self.get_backup_dir(id);
+        let work_dir = work_dir.as_ref().to_path_buf();
+
+        tokio::task::spawn_blocking(move || {
+            fs::create_dir_all(&backup_dir)?;
+
+            let (file, backup_file) = create_backup_file(&backup_dir)?;
+            let result = (|| {
+                let encoder = GzEncoder::new(file, Compression::default());
+                let mut archive = tar::Builder::new(encoder);
+                // a symlink planted in the work dir must not pull in the rest of the host
+                archive.follow_symlinks(false);
+                archive.append_dir_all(".", &work_dir)?;
+                archive.into_inner()?.finish()?;
+                Ok(())
+            })();
+            if let Err(e) = result {
+                _ = fs::remove_file(&backup_file);
+                return Err(e);
+            }
+
+            if let Err(e) = prune_backups(&backup_dir) {
+                tracing::warn!(
+                    "Failed to delete old backups in {}: {}",
+                    backup_dir.display(),
+                    e
+                );
+            }
+
+            Ok(backup_file)
+        })
+        .await
+        .map_err(io::Error::other)?
+    }
+}
+
+/// Backups taken within the same second get a counter appended.
+fn create_backup_file(backup_dir: &Path) -> Result<(File, PathBuf), io::Error> {
+    let timestamp = Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+
+    let mut attempt = 0;
+    loop {
+        let file_name = match attempt {
+            0 => format!("{}.tar.gz", timestamp),
+            n => format!("{}-{}.tar.gz", timestamp, n),
+        };
+        let path = backup_dir.join(file_name);
+        match File::create_new(&path) {
+            Ok(file) => return Ok((file, path)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => attempt += 1,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn prune_backups(backup_dir: &Path) -> Result<(), io::Error> {
+    let mut backups = Vec::new();
+    for entry in fs::read_dir(backup_dir)? {
+        let entry = entry?;
+        if !entry.file_name().to_string_lossy().ends_with(".tar.gz") {
+            continue;
+        }
+        let metadata = entry.metadata()?;
+        if metadata.is_file() {
+            backups.push((metadata.modified()?, entry.path()));
+        }
+    }
+
+    // newest first
+    backups.sort_unstable_by(|a, b| b.cmp(a));
+    for (_, path) in backups.into_iter().skip(KEEP_BACKUPS) {
+        fs::remove_file(path)?;
+    }
+
+    Ok(())
+}
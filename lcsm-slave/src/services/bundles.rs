@@ -0,0 +1,518 @@
+use std::{
+    fmt::Display,
+    fs::{self, File},
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+
+use axum::http::StatusCode;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use flate2::{Compression, read::GzDecoder, write::GzEncoder};
+use futures::{Stream, StreamExt};
+use sea_orm::{
+    ActiveModelTrait, ActiveValue::NotSet, ColumnTrait, DbErr, EntityTrait, QueryFilter, Set,
+    TransactionTrait,
+};
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+
+use crate::{
+    AppState,
+    entities::{instance, schedule},
+    errors::StatusCodeError,
+    services::{
+        DiskQuotaService, RunAsService, SecretError, WhileRunning, create_empty_work_dir,
+        discard_work_dir, new_work_dir, next_fire_time, validate_schedule, with_saving_paused,
+    },
+};
+
+/// Bumped whenever a bundle written by this version can't be read by older ones.
+pub const BUNDLE_FORMAT_VERSION: u32 = 1;
+
+/// A bundle is a plain tar holding the manifest followed by a gzipped tar of the work dir.
+const MANIFEST_NAME: &str = "manifest.json";
+const WORK_DIR_ARCHIVE_NAME: &str = "workdir.tar.gz";
+
+/// Bigger manifests are rejected before they are parsed.
+const MAX_MANIFEST_SIZE: u64 = 4 * 1024 * 1024;
+
+/// Uploads are spooled into the bundle path, bigger ones are cut off.
+const MAX_BUNDLE_SIZE: u64 = 32 * 1024 * 1024 * 1024;
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BundleManifest {
+    pub format_version: u32,
+    pub exported_at: DateTime<Utc>,
+    /// Redacted, secrets have to be set again after the import.
+    pub instance: instance::Model,
+    #[serde(default)]
+    pub schedules: Vec<BundleSchedule>,
+}
+
+/// A schedule without the fields tied to the exporting slave.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BundleSchedule {
+    pub cron_expression: String,
+    pub timezone: String,
+    pub action: String,
+    pub payload: Option<String>,
+    pub enabled: bool,
+    pub missed_run_policy: String,
+}
+
+impl From<schedule::Model> for BundleSchedule {
+    fn from(value: schedule::Model) -> Self {
+        Self {
+            cron_expression: value.cron_expression,
+            timezone: value.timezone,
+            action: value.action,
+            payload: value.payload,
+            enabled: value.enabled,
+            missed_run_policy: value.missed_run_policy,
+        }
+    }
+}
+
+impl BundleSchedule {
+    /// The instance is filled in when it is inserted.
+    fn into_model(self) -> schedule::Model {
+        schedule::Model {
+            id: 0,
+            instance_id: 0,
+            cron_expression: self.cron_expression,
+            timezone: self.timezone,
+            action: self.action,
+            payload: self.payload,
+            enabled: self.enabled,
+            missed_run_policy: self.missed_run_policy,
+            last_run_at: None,
+            next_run_at: None,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportOptions {
+    #[serde(default)]
+    pub while_running: WhileRunning,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImportOptions {
+    /// Defaults to the name in the bundle.
+    pub name: Option<String>,
+    /// Defaults to a directory named after the instance in the instances path.
+    pub work_dir: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImportResult {
+    pub instance: instance::Model,
+    pub schedules: Vec<schedule::Model>,
+    /// Secrets left out of the bundle, e.g. `environment.TOKEN` or `minecraft.rcon`. Secret
+    /// variables and the RCON settings are dropped until they are set again.
+    pub missing_secrets: Vec<String>,
+}
+
+#[derive(Debug)]
+pub enum BundleError {
+    NotFound,
+    Invalid(String),
+    SourceRunning,
+    WorkDirNotEmpty(PathBuf),
+    TooLarge,
+    DbErr(DbErr),
+    IoError(io::Error),
+    QuotaExceeded(String),
+    SecretError(SecretError),
+}
+
+impl StatusCodeError for BundleError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Self::NotFound => StatusCode::NOT_FOUND,
+            Self::Invalid(_) => StatusCode::BAD_REQUEST,
+            Self::SourceRunning | Self::WorkDirNotEmpty(_) => StatusCode::CONFLICT,
+            Self::TooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::QuotaExceeded(_) => StatusCode::INSUFFICIENT_STORAGE,
+            Self::SecretError(e) => e.status_code(),
+            Self::DbErr(_) | Self::IoError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl Display for BundleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotFound => write!(f, "Instance not found"),
+            Self::Invalid(e) => write!(f, "Invalid bundle: {}", e),
+            Self::SourceRunning => write!(f, "Instance is running"),
+            Self::WorkDirNotEmpty(path) => {
+                write!(f, "Work dir {} exists and is not empty", path.display())
+            }
+            Self::TooLarge => write!(f, "Bundle is larger than {} bytes", MAX_BUNDLE_SIZE),
+            Self::QuotaExceeded(e) => write!(f, "Disk quota exceeded: {}", e),
+            Self::DbErr(e) => e.fmt(f),
+            Self::IoError(e) => e.fmt(f),
+            Self::SecretError(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for BundleError {}
+
+impl From<DbErr> for BundleError {
+    fn from(value: DbErr) -> Self {
+        Self::DbErr(value)
+    }
+}
+
+impl From<io::Error> for BundleError {
+    fn from(value: io::Error) -> Self {
+        Self::IoError(value)
+    }
+}
+
+/// Writes the bundle of an instance into the bundle path, the caller removes it once sent.
+pub async fn export_instance(
+    state: &AppState,
+    id: u64,
+    options: ExportOptions,
+) -> Result<PathBuf, BundleError> {
+    let the_instance =
+        instance::Entity::find_by_id(i32::try_from(id).map_err(|_| BundleError::NotFound)?)
+            .one(&state.database)
+            .await?
+            .ok_or(BundleError::NotFound)?;
+    if the_instance.work_dir.is_empty() {
+        return Err(BundleError::Invalid("instance has no work dir".to_owned()));
+    }
+
+    let running = state.process_manager.get_alive_process(id).await.is_some();
+    if running && options.while_running == WhileRunning::Refuse {
+        return Err(BundleError::SourceRunning);
+    }
+
+    let schedules = schedule::Entity::find()
+        .filter(schedule::Column::InstanceId.eq(the_instance.id))
+        .all(&state.database)
+        .await?;
+    let manifest = BundleManifest {
+        format_version: BUNDLE_FORMAT_VERSION,
+        exported_at: Utc::now(),
+        instance: the_instance.clone().redacted(),
+        schedules: schedules.into_iter().map(|x| x.into()).collect(),
+    };
+    let manifest = serde_json::to_vec_pretty(&manifest).map_err(io::Error::other)?;
+
+    let path = state.bundle_path.join(format!(
+        "{}-{}.tar",
+        id,
+        Utc::now().format("%Y%m%dT%H%M%S%.fZ")
+    ));
+    let write = tokio::task::spawn_blocking({
+        let path = path.clone();
+        let work_dir = PathBuf::from(&the_instance.work_dir);
+        move || write_bundle(&path, &manifest, &work_dir)
+    });
+    let result = with_saving_paused(state, &the_instance, write)
+        .await
+        .map_err(io::Error::other)?;
+
+    if let Err(e) = result {
+        _ = tokio::fs::remove_file(&path).await;
+        return Err(e.into());
+    }
+
+    Ok(path)
+}
+
+fn write_bundle(path: &Path, manifest: &[u8], work_dir: &Path) -> Result<(), io::Error> {
+    // the size of an entry goes before its data, so the work dir is archived first
+    let archive_path = path.with_extension("tar.gz");
+    let result = (|| {
+        let encoder = GzEncoder::new(File::create_new(&archive_path)?, Compression::default());
+        let mut archive = tar::Builder::new(encoder);
+        archive.follow_symlinks(false);
+        archive.append_dir_all(".", work_dir)?;
+        archive.into_inner()?.finish()?;
+
+        let mut bundle = tar::Builder::new(File::create_new(path)?);
+        let mut header = tar::Header::new_gnu();
+        header.set_size(manifest.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(Utc::now().timestamp() as u64);
+        bundle.append_data(&mut header, MANIFEST_NAME, manifest)?;
+        bundle.append_path_with_name(&archive_path, WORK_DIR_ARCHIVE_NAME)?;
+        bundle.into_inner()?.sync_all()
+    })();
+    _ = fs::remove_file(&archive_path);
+
+    result
+}
+
+/// Creates an instance with its schedules and work dir from an uploaded bundle.
+pub async fn import_instance<E: Display>(
+    state: &AppState,
+    options: ImportOptions,
+    mut body: impl Stream<Item = Result<Bytes, E>> + Unpin,
+) -> Result<ImportResult, BundleError> {
+    // the body is spooled to disk, the archive is read with blocking io
+    let path = state.bundle_path.join(format!(
+        "import-{}.tar",
+        Utc::now().format("%Y%m%dT%H%M%S%.fZ")
+    ));
+    let result = async {
+        let mut file = tokio::fs::File::create_new(&path).await?;
+        let mut size = 0;
+        while let Some(chunk) = body.next().await {
+            let chunk = chunk.map_err(|e| BundleError::Invalid(format!("read upload: {}", e)))?;
+            size += chunk.len() as u64;
+            if size > MAX_BUNDLE_SIZE {
+                return Err(BundleError::TooLarge);
+            }
+            file.write_all(&chunk).await?;
+        }
+        file.flush().await?;
+        drop(file);
+
+        import_bundle(state, options, &path).await
+    }
+    .await;
+
+    _ = tokio::fs::remove_file(&path).await;
+    result
+}
+
+async fn import_bundle(
+    state: &AppState,
+    options: ImportOptions,
+    path: &Path,
+) -> Result<ImportResult, BundleError> {
+    let manifest = tokio::task::spawn_blocking({
+        let path = path.to_path_buf();
+        move || read_manifest(&path)
+    })
+    .await
+    .map_err(io::Error::other)??;
+    if manifest.format_version > BUNDLE_FORMAT_VERSION {
+        return Err(BundleError::Invalid(format!(
+            "format version {} is not supported",
+            manifest.format_version
+        )));
+    }
+
+    let mut model = manifest.instance;
+    // not saved on this slave yet
+    model.id = 0;
+    model.name = options.name.unwrap_or(model.name);
+    let work_dir = new_work_dir(state, &model.name, options.work_dir.as_deref())
+        .map_err(BundleError::Invalid)?;
+    model.work_dir = work_dir.to_string_lossy().into_owned();
+    // ids of other instances only mean something on the exporting slave
+    model.dependencies = Default::default();
+    let missing_secrets = drop_missing_secrets(&mut model);
+
+    model.validate().map_err(BundleError::Invalid)?;
+    let run_as = state
+        .run_as_manager
+        .resolve(model.run_as_user.as_deref(), model.run_as_group.as_deref())
+        .map_err(BundleError::Invalid)?;
+    let mut schedules = Vec::with_capacity(manifest.schedules.len());
+    for the_schedule in manifest.schedules {
+        let the_schedule = the_schedule.into_model();
+        validate_schedule(&the_schedule).map_err(BundleError::Invalid)?;
+        schedules.push(the_schedule);
+    }
+    state
+        .secret_manager
+        .seal_instance(&mut model)
+        .map_err(BundleError::SecretError)?;
+
+    let size = tokio::task::spawn_blocking({
+        let path = path.to_path_buf();
+        move || work_dir_size(&path)
+    })
+    .await
+    .map_err(io::Error::other)??;
+    DiskQuotaService::check_write(state, &model, size)
+        .await
+        .map_err(BundleError::QuotaExceeded)?;
+
+    let created = create_empty_work_dir(&work_dir)
+        .await
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::DirectoryNotEmpty => BundleError::WorkDirNotEmpty(work_dir.clone()),
+            _ => e.into(),
+        })?;
+    let result = async {
+        tokio::task::spawn_blocking({
+            let path = path.to_path_buf();
+            let work_dir = work_dir.clone();
+            move || unpack_work_dir(&path, &work_dir)
+        })
+        .await
+        .map_err(io::Error::other)??;
+        if let Some(run_as) = run_as {
+            RunAsService::prepare_work_dir(&work_dir, &state.instance_path, run_as).await?;
+        }
+
+        insert_models(state, model, schedules).await
+    }
+    .await;
+
+    match result {
+        Ok((instance, schedules)) => {
+            state.scheduler.notify_changed();
+            Ok(ImportResult {
+                instance: instance.redacted(),
+                schedules,
+                missing_secrets,
+            })
+        }
+        Err(e) => {
+            // leave the work dir as it was found
+            if let Err(e) = discard_work_dir(&work_dir, created).await {
+                tracing::warn!("Failed to clean up {}: {}", work_dir.display(), e);
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Removes the settings whose secrets were redacted on export and lists them.
+fn drop_missing_secrets(model: &mut instance::Model) -> Vec<String> {
+    let mut missing = Vec::new();
+
+    model.environment.0.retain(|variable| {
+        if variable.secret && variable.value.is_none() {
+            missing.push(format!("environment.{}", variable.name));
+            return false;
+        }
+        true
+    });
+    if model
+        .minecraft
+        .rcon
+        .as_ref()
+        .is_some_and(|x| x.password.is_none())
+    {
+        model.minecraft.rcon = None;
+        missing.push("minecraft.rcon".to_owned());
+    }
+
+    missing
+}
+
+async fn insert_models(
+    state: &AppState,
+    model: instance::Model,
+    schedules: Vec<schedule::Model>,
+) -> Result<(instance::Model, Vec<schedule::Model>), BundleError> {
+    let txn = state.database.begin().await?;
+
+    let active = instance::ActiveModel {
+        id: NotSet,
+        ..model.into()
+    };
+    let the_instance = active.insert(&txn).await?;
+
+    let now = Utc::now();
+    let mut inserted = Vec::with_capacity(schedules.len());
+    for the_schedule in schedules {
+        let next_run_at = next_fire_time(&the_schedule, now);
+        let active = schedule::ActiveModel {
+            id: NotSet,
+            instance_id: Set(the_instance.id),
+            next_run_at: Set(next_run_at),
+            ..the_schedule.into()
+        };
+        inserted.push(active.insert(&txn).await?);
+    }
+
+    txn.commit().await?;
+    Ok((the_instance, inserted))
+}
+
+/// The manifest is always the first entry.
+fn read_manifest(path: &Path) -> Result<BundleManifest, BundleError> {
+    let mut bundle = tar::Archive::new(File::open(path)?);
+    let invalid = |e: io::Error| BundleError::Invalid(e.to_string());
+
+    let mut entry = bundle
+        .entries()
+        .map_err(invalid)?
+        .next()
+        .ok_or_else(|| BundleError::Invalid("bundle is empty".to_owned()))?
+        .map_err(invalid)?;
+    if entry.path().map_err(invalid)?.as_os_str() != MANIFEST_NAME {
+        return Err(BundleError::Invalid(format!(
+            "first entry is not {}",
+            MANIFEST_NAME
+        )));
+    }
+    if entry.size() > MAX_MANIFEST_SIZE {
+        return Err(BundleError::Invalid("manifest is too large".to_owned()));
+    }
+
+    let mut content = Vec::with_capacity(entry.size() as usize);
+    entry.read_to_end(&mut content).map_err(invalid)?;
+    serde_json::from_slice(&content)
+        .map_err(|e| BundleError::Invalid(format!("parse manifest: {}", e)))
+}
+
+/// Calls `f` with the work dir archive of the bundle.
+fn with_work_dir_archive<T>(
+    path: &Path,
+    f: impl FnOnce(&mut tar::Archive<GzDecoder<tar::Entry<File>>>) -> Result<T, BundleError>,
+) -> Result<T, BundleError> {
+    let mut bundle = tar::Archive::new(File::open(path)?);
+    let invalid = |e: io::Error| BundleError::Invalid(e.to_string());
+
+    for entry in bundle.entries().map_err(invalid)? {
+        let entry = entry.map_err(invalid)?;
+        if entry.path().map_err(invalid)?.as_os_str() != WORK_DIR_ARCHIVE_NAME {
+            continue;
+        }
+
+        return f(&mut tar::Archive::new(GzDecoder::new(entry)));
+    }
+
+    Err(BundleError::Invalid(format!(
+        "{} is missing",
+        WORK_DIR_ARCHIVE_NAME
+    )))
+}
+
+/// Bytes the files of the work dir take once unpacked.
+fn work_dir_size(path: &Path) -> Result<u64, BundleError> {
+    with_work_dir_archive(path, |archive| {
+        let invalid = |e: io::Error| BundleError::Invalid(format!("read work dir: {}", e));
+        let mut size = 0u64;
+        for entry in archive.entries().map_err(invalid)? {
+            size = size.saturating_add(entry.map_err(invalid)?.size());
+        }
+
+        Ok(size)
+    })
+}
+
+fn unpack_work_dir(path: &Path, work_dir: &Path) -> Result<(), BundleError> {
+    with_work_dir_archive(path, |archive| {
+        // entries escaping the work dir are refused by `unpack`, modes lose setuid, setgid
+        // and sticky bits
+        archive.set_overwrite(false);
+        archive.unpack(work_dir).map_err(|e| match e.kind() {
+            io::ErrorKind::StorageFull
+            | io::ErrorKind::QuotaExceeded
+            | io::ErrorKind::PermissionDenied => e.into(),
+            _ => BundleError::Invalid(format!("unpack work dir: {}", e)),
+        })
+    })
+}
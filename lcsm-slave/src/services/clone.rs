@@ -0,0 +1,586 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    fs, io,
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use axum::http::StatusCode;
+use chrono::{DateTime, TimeDelta, Utc};
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use sea_orm::{ActiveModelTrait, ActiveValue::NotSet, DbErr, EntityTrait};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{RwLock, broadcast};
+use tracing::Instrument;
+
+use crate::{
+    AppState, AppStateRef,
+    entities::instance,
+    errors::StatusCodeError,
+    services::{
+        DiskQuotaService, ProcessState, RunAsService, create_empty_work_dir, discard_work_dir,
+        new_work_dir, rcon_command,
+    },
+};
+
+/// How often the progress of a running copy is published.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Finished jobs are forgotten after this long.
+const JOB_RETENTION: TimeDelta = TimeDelta::hours(1);
+
+/// What to do when the source instance is running.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum WhileRunning {
+    #[default]
+    Refuse,
+    /// Copies anyway, with world saving paused over RCON when it is configured.
+    Snapshot,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CloneRequest {
+    pub name: String,
+    /// Defaults to a directory named after the clone in the instances path.
+    #[serde(default)]
+    pub work_dir: Option<String>,
+    /// Globs relative to the work dir, a trailing `/` only matches directories and patterns
+    /// without a `/` match at any depth, e.g. `logs/` or `world*`.
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    #[serde(default)]
+    pub while_running: WhileRunning,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum CloneState {
+    Copying,
+    Completed,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CloneProgress {
+    pub job_id: u64,
+    pub source_id: u64,
+    pub state: CloneState,
+    pub work_dir: String,
+    /// Known once the source has been scanned.
+    pub total_files: u64,
+    pub total_bytes: u64,
+    pub copied_files: u64,
+    pub copied_bytes: u64,
+    /// The new instance, set once the copy completed.
+    pub instance_id: Option<i32>,
+    pub error: Option<String>,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug)]
+pub enum CloneError {
+    NotFound,
+    Invalid(String),
+    SourceRunning,
+    WorkDirNotEmpty(PathBuf),
+    DbErr(DbErr),
+    IoError(io::Error),
+}
+
+impl StatusCodeError for CloneError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Self::NotFound => StatusCode::NOT_FOUND,
+            Self::Invalid(_) => StatusCode::BAD_REQUEST,
+            Self::SourceRunning | Self::WorkDirNotEmpty(_) => StatusCode::CONFLICT,
+            Self::DbErr(_) | Self::IoError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl Display for CloneError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotFound => write!(f, "Instance not found"),
+            Self::Invalid(e) => write!(f, "Invalid clone request: {}", e),
+            Self::SourceRunning => write!(f, "Instance is running"),
+            Self::WorkDirNotEmpty(path) => {
+                write!(f, "Work dir {} exists and is not empty", path.display())
+            }
+            Self::DbErr(e) => e.fmt(f),
+            Self::IoError(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for CloneError {}
+
+impl From<DbErr> for CloneError {
+    fn from(value: DbErr) -> Self {
+        Self::DbErr(value)
+    }
+}
+
+impl From<io::Error> for CloneError {
+    fn from(value: io::Error) -> Self {
+        Self::IoError(value)
+    }
+}
+
+pub struct CloneService {
+    jobs: RwLock<HashMap<u64, CloneProgress>>,
+    next_job_id: AtomicU64,
+    events: broadcast::Sender<CloneProgress>,
+}
+
+impl Default for CloneService {
+    fn default() -> Self {
+        Self {
+            jobs: RwLock::new(HashMap::new()),
+            next_job_id: AtomicU64::new(1),
+            events: broadcast::channel(64).0,
+        }
+    }
+}
+
+impl CloneService {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn subscribe_events(&self) -> broadcast::Receiver<CloneProgress> {
+        self.events.subscribe()
+    }
+
+    pub async fn get_job(&self, job_id: u64) -> Option<CloneProgress> {
+        self.jobs.read().await.get(&job_id).cloned()
+    }
+
+    async fn publish(&self, progress: &CloneProgress) {
+        self.jobs
+            .write()
+            .await
+            .insert(progress.job_id, progress.clone());
+        _ = self.events.send(progress.clone());
+    }
+}
+
+/// Starts copying an instance and its work dir, the returned job reports the progress.
+pub async fn clone_instance(
+    state: &AppStateRef,
+    id: u64,
+    request: CloneRequest,
+) -> Result<CloneProgress, CloneError> {
+    let source = instance::Entity::find_by_id(i32::try_from(id).map_err(|_| CloneError::NotFound)?)
+        .one(&state.database)
+        .await?
+        .ok_or(CloneError::NotFound)?;
+
+    if source.work_dir.is_empty() {
+        return Err(CloneError::Invalid("instance has no work dir".to_owned()));
+    }
+    let source_dir = tokio::fs::canonicalize(&source.work_dir).await?;
+    let target_dir = new_work_dir(state, &request.name, request.work_dir.as_deref())
+        .map_err(CloneError::Invalid)?;
+    if target_dir.starts_with(&source_dir) {
+        return Err(CloneError::Invalid(
+            "work dir must not be inside the source".to_owned(),
+        ));
+    }
+    let excludes = Excludes::new(&request.exclude).map_err(CloneError::Invalid)?;
+
+    let running = state.process_manager.get_alive_process(id).await.is_some();
+    if running && request.while_running == WhileRunning::Refuse {
+        return Err(CloneError::SourceRunning);
+    }
+
+    let created = create_empty_work_dir(&target_dir)
+        .await
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::DirectoryNotEmpty => CloneError::WorkDirNotEmpty(target_dir.clone()),
+            _ => e.into(),
+        })?;
+
+    let progress = CloneProgress {
+        job_id: state
+            .clone_manager
+            .next_job_id
+            .fetch_add(1, Ordering::Relaxed),
+        source_id: id,
+        state: CloneState::Copying,
+        work_dir: target_dir.to_string_lossy().into_owned(),
+        total_files: 0,
+        total_bytes: 0,
+        copied_files: 0,
+        copied_bytes: 0,
+        instance_id: None,
+        error: None,
+        started_at: Utc::now(),
+        finished_at: None,
+    };
+
+    // forget old jobs
+    let retain_after = Utc::now() - JOB_RETENTION;
+    state
+        .clone_manager
+        .jobs
+        .write()
+        .await
+        .retain(|_, x| x.finished_at.is_none_or(|x| x > retain_after));
+    state.clone_manager.publish(&progress).await;
+
+    let job = CloneJob {
+        state: state.clone(),
+        source,
+        source_dir,
+        target_dir,
+        created,
+        name: request.name,
+        excludes,
+        counters: Default::default(),
+    };
+    tokio::spawn(
+        job.run(progress.clone())
+            .instrument(tracing::info_span!(parent: None, "clone", id)),
+    );
+
+    Ok(progress)
+}
+
+/// Keeps the files of a running instance consistent while `f` copies them: the process is
+/// marked as backing up and world saving is paused over RCON when it is configured.
+pub(crate) async fn with_saving_paused<T>(
+    state: &AppState,
+    source: &instance::Model,
+    f: impl Future<Output = T>,
+) -> T {
+    let id = source.id as u64;
+    let Some(process) = state.process_manager.get_alive_process(id).await else {
+        return f.await;
+    };
+
+    let previous_state = {
+        let process = process.read().await;
+        let previous_state = process.state();
+        process.set_state(ProcessState::BackingUp);
+        previous_state
+    };
+    let pause_saving = source.minecraft.rcon.is_some();
+    if pause_saving {
+        send_rcon(state, id, "save-off").await;
+        send_rcon(state, id, "save-all flush").await;
+    }
+
+    let result = f.await;
+
+    if pause_saving {
+        send_rcon(state, id, "save-on").await;
+    }
+    // a stop or restart requested meanwhile wins
+    process
+        .read()
+        .await
+        .set_state_from(ProcessState::BackingUp, previous_state);
+
+    result
+}
+
+async fn send_rcon(state: &AppState, id: u64, command: &str) {
+    if let Err(e) = rcon_command(state, id, command).await {
+        tracing::warn!("Failed to run `{}` on instance {}: {}", command, id, e);
+    }
+}
+
+#[derive(Default)]
+struct Counters {
+    total_files: AtomicU64,
+    total_bytes: AtomicU64,
+    copied_files: AtomicU64,
+    copied_bytes: AtomicU64,
+}
+
+struct CloneJob {
+    state: AppStateRef,
+    source: instance::Model,
+    source_dir: PathBuf,
+    target_dir: PathBuf,
+    created: bool,
+    name: String,
+    excludes: Excludes,
+    counters: Arc<Counters>,
+}
+
+impl CloneJob {
+    async fn run(self, mut progress: CloneProgress) {
+        let result = self.copy(&mut progress).await;
+        let result = match result {
+            Ok(_) => self.insert_instance().await,
+            Err(e) => Err(e),
+        };
+
+        self.update(&mut progress);
+        progress.finished_at = Some(Utc::now());
+        match result {
+            Ok(instance_id) => {
+                progress.state = CloneState::Completed;
+                progress.instance_id = Some(instance_id);
+            }
+            Err(e) => {
+                tracing::error!("Failed to clone instance {}: {}", self.source.id, e);
+                if let Err(e) = discard_work_dir(&self.target_dir, self.created).await {
+                    tracing::warn!("Failed to clean up {}: {}", self.target_dir.display(), e);
+                }
+                progress.state = CloneState::Failed;
+                progress.error = Some(e);
+            }
+        }
+
+        self.state.clone_manager.publish(&progress).await;
+    }
+
+    async fn copy(&self, progress: &mut CloneProgress) -> Result<(), String> {
+        tokio::task::spawn_blocking({
+            let source_dir = self.source_dir.clone();
+            let excludes = self.excludes.clone();
+            let counters = self.counters.clone();
+            move || count_tree(&source_dir, &excludes, &counters)
+        })
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| format!("scan work dir: {}", e))?;
+
+        // the clone starts out with the quota of the source
+        let mut target = self.source.clone();
+        target.id = 0;
+        target.work_dir = self.target_dir.to_string_lossy().into_owned();
+        let total_bytes = self.counters.total_bytes.load(Ordering::Relaxed);
+        DiskQuotaService::check_write(&self.state, &target, total_bytes).await?;
+
+        let mut copy = tokio::task::spawn_blocking({
+            let source_dir = self.source_dir.clone();
+            let target_dir = self.target_dir.clone();
+            let excludes = self.excludes.clone();
+            let counters = self.counters.clone();
+            move || copy_tree(&source_dir, &target_dir, &excludes, &counters)
+        });
+        let result = with_saving_paused(&self.state, &self.source, async {
+            let mut interval = tokio::time::interval(PROGRESS_INTERVAL);
+            loop {
+                tokio::select! {
+                    result = &mut copy => break result,
+                    _ = interval.tick() => {
+                        self.update(progress);
+                        self.state.clone_manager.publish(progress).await;
+                    }
+                }
+            }
+        })
+        .await;
+
+        result
+            .map_err(|e| e.to_string())?
+            .map_err(|e| format!("copy work dir: {}", e))
+    }
+
+    /// The clone starts out like the source, except that it isn't started on boot.
+    async fn insert_instance(&self) -> Result<i32, String> {
+        let mut model = self.source.clone();
+        model.name = self.name.clone();
+        model.work_dir = self.target_dir.to_string_lossy().into_owned();
+        model.autostart = false;
+
+        let run_as = self
+            .state
+            .run_as_manager
+            .resolve(model.run_as_user.as_deref(), model.run_as_group.as_deref())?;
+        if let Some(run_as) = run_as {
+            RunAsService::prepare_work_dir(&self.target_dir, &self.state.instance_path, run_as)
+                .await
+                .map_err(|e| format!("prepare work dir: {}", e))?;
+        }
+
+        let active = instance::ActiveModel {
+            id: NotSet,
+            ..model.into()
+        };
+        let res = active
+            .insert(&self.state.database)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        Ok(res.id)
+    }
+
+    fn update(&self, progress: &mut CloneProgress) {
+        let counters = &self.counters;
+        progress.total_files = counters.total_files.load(Ordering::Relaxed);
+        progress.total_bytes = counters.total_bytes.load(Ordering::Relaxed);
+        progress.copied_files = counters.copied_files.load(Ordering::Relaxed);
+        progress.copied_bytes = counters.copied_bytes.load(Ordering::Relaxed);
+    }
+}
+
+/// Paths left out of a clone.
+#[derive(Clone)]
+struct Excludes {
+    any: GlobSet,
+    dirs_only: GlobSet,
+}
+
+impl Excludes {
+    fn new(patterns: &[String]) -> Result<Self, String> {
+        let mut any = GlobSetBuilder::new();
+        let mut dirs_only = GlobSetBuilder::new();
+        for pattern in patterns {
+            let dir_only = pattern.ends_with('/');
+            let trimmed = pattern.trim_end_matches('/');
+            if trimmed.is_empty() {
+                return Err(format!("invalid exclude pattern `{}`", pattern));
+            }
+            let glob = match trimmed.strip_prefix('/') {
+                Some(anchored) => anchored.to_owned(),
+                None if trimmed.contains('/') => trimmed.to_owned(),
+                None => format!("**/{}", trimmed),
+            };
+
+            let glob = GlobBuilder::new(&glob)
+                .literal_separator(true)
+                .build()
+                .map_err(|e| format!("invalid exclude pattern `{}`: {}", pattern, e))?;
+            if dir_only {
+                dirs_only.add(glob);
+            } else {
+                any.add(glob);
+            }
+        }
+
+        Ok(Self {
+            any: any.build().map_err(|e| e.to_string())?,
+            dirs_only: dirs_only.build().map_err(|e| e.to_string())?,
+        })
+    }
+
+    fn is_match(&self, path: &Path, is_dir: bool) -> bool {
+        self.any.is_match(path) || (is_dir && self.dirs_only.is_match(path))
+    }
+}
+
+/// Walks the source without following symlinks, calling `visit` with the relative path of
+/// every entry that isn't excluded. Excluded directories are not descended into.
+fn walk(
+    source: &Path,
+    excludes: &Excludes,
+    mut visit: impl FnMut(&Path, &fs::Metadata) -> Result<(), io::Error>,
+) -> Result<(), io::Error> {
+    let mut pending = vec![PathBuf::new()];
+    while let Some(relative) = pending.pop() {
+        for entry in fs::read_dir(source.join(&relative))? {
+            let entry = entry?;
+            let path = relative.join(entry.file_name());
+            // the server may delete files while it is copied
+            let metadata = match entry.path().symlink_metadata() {
+                Ok(v) => v,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            if excludes.is_match(&path, metadata.is_dir()) {
+                continue;
+            }
+
+            visit(&path, &metadata)?;
+            if metadata.is_dir() {
+                pending.push(path);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn count_tree(source: &Path, excludes: &Excludes, counters: &Counters) -> Result<(), io::Error> {
+    walk(source, excludes, |_, metadata| {
+        if metadata.is_file() {
+            counters.total_files.fetch_add(1, Ordering::Relaxed);
+            counters
+                .total_bytes
+                .fetch_add(metadata.len(), Ordering::Relaxed);
+        }
+        Ok(())
+    })
+}
+
+fn copy_tree(
+    source: &Path,
+    target: &Path,
+    excludes: &Excludes,
+    counters: &Counters,
+) -> Result<(), io::Error> {
+    let mut dirs = Vec::new();
+    walk(source, excludes, |path, metadata| {
+        let from = source.join(path);
+        let to = target.join(path);
+
+        if metadata.is_dir() {
+            fs::create_dir(&to)?;
+            dirs.push((to, metadata.permissions()));
+        } else if metadata.is_symlink() {
+            std::os::unix::fs::symlink(fs::read_link(&from)?, &to)?;
+        } else if metadata.is_file() {
+            let Some(copied) = copy_file(&from, &to)? else {
+                return Ok(());
+            };
+            counters.copied_files.fetch_add(1, Ordering::Relaxed);
+            counters.copied_bytes.fetch_add(copied, Ordering::Relaxed);
+        }
+        // sockets, fifos and devices are left out
+
+        Ok(())
+    })?;
+
+    // only once the contents are in, a read-only directory can't be written to afterwards,
+    // children come after their parent in the walk
+    for (dir, permissions) in dirs.into_iter().rev() {
+        fs::set_permissions(dir, permissions)?;
+    }
+
+    Ok(())
+}
+
+/// Copies a regular file along with its permissions, `None` if it was deleted or replaced
+/// by something else since the walk.
+fn copy_file(from: &Path, to: &Path) -> Result<Option<u64>, io::Error> {
+    // a symlink swapped in must not be followed, and a fifo must not hang the open
+    let source = match fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOFOLLOW | libc::O_NONBLOCK)
+        .open(from)
+    {
+        Ok(v) => v,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) if e.raw_os_error() == Some(libc::ELOOP) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let metadata = source.metadata()?;
+    if !metadata.is_file() {
+        return Ok(None);
+    }
+
+    let mut target = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(to)?;
+    let copied = io::copy(&mut &source, &mut target)?;
+    target.set_permissions(metadata.permissions())?;
+    Ok(Some(copied))
+}
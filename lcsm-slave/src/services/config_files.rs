@@ -0,0 +1,860 @@
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsString,
+    fmt::Display,
+    io,
+    os::unix::fs::{MetadataExt, fchown},
+    path::{Component, Path, PathBuf},
+};
+
+use axum::http::StatusCode;
+use json_patch::{PatchOperation, patch as apply_json_patch};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tokio::io::AsyncWriteExt;
+use toml_edit::{ArrayOfTables, DocumentMut, InlineTable, Item, Table, TableLike};
+
+use crate::{AppState, entities::instance, errors::StatusCodeError, services::DiskQuotaService};
+
+/// Configs are small, anything larger is most likely not one.
+const MAX_CONFIG_SIZE: u64 = 4 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ConfigFormat {
+    /// Java `.properties`, every value is a string.
+    Properties,
+    Yaml,
+    Toml,
+    Json,
+}
+
+impl ConfigFormat {
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "properties" => Some(Self::Properties),
+            "yml" | "yaml" => Some(Self::Yaml),
+            "toml" => Some(Self::Toml),
+            "json" => Some(Self::Json),
+            _ => None,
+        }
+    }
+
+    /// YAML is written back from scratch, so its comments and formatting are lost on patching.
+    pub fn preserves_comments(self) -> bool {
+        self != Self::Yaml
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfigDocument {
+    pub path: String,
+    pub format: ConfigFormat,
+    pub preserves_comments: bool,
+    pub content: Value,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    NotFound,
+    InvalidPath(String),
+    UnknownFormat,
+    TooLarge,
+    /// The file on disk isn't valid in its format.
+    Parse(String),
+    Patch(String),
+    /// The patched content can't be written in the format of the file.
+    Unrepresentable(String),
+    QuotaExceeded(String),
+    IoError(io::Error),
+}
+
+impl StatusCodeError for ConfigError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Self::NotFound => StatusCode::NOT_FOUND,
+            Self::InvalidPath(_)
+            | Self::UnknownFormat
+            | Self::Patch(_)
+            | Self::Unrepresentable(_) => StatusCode::BAD_REQUEST,
+            Self::TooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::Parse(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::QuotaExceeded(_) => StatusCode::INSUFFICIENT_STORAGE,
+            Self::IoError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotFound => write!(f, "Config file not found"),
+            Self::InvalidPath(e) => write!(f, "Invalid config path: {}", e),
+            Self::UnknownFormat => write!(f, "Unknown config format"),
+            Self::TooLarge => write!(f, "Config file is too large"),
+            Self::Parse(e) => write!(f, "Failed to parse config: {}", e),
+            Self::Patch(e) => write!(f, "Failed to apply patch: {}", e),
+            Self::Unrepresentable(e) => write!(f, "Unrepresentable config: {}", e),
+            Self::QuotaExceeded(e) => write!(f, "Disk quota exceeded: {}", e),
+            Self::IoError(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl From<io::Error> for ConfigError {
+    fn from(value: io::Error) -> Self {
+        match value.kind() {
+            io::ErrorKind::NotFound => Self::NotFound,
+            _ => Self::IoError(value),
+        }
+    }
+}
+
+/// A config file located inside a work dir.
+struct ConfigFile {
+    relative: String,
+    /// Symlinks resolved, so writes replace the file they point to.
+    path: PathBuf,
+    format: ConfigFormat,
+}
+
+impl ConfigFile {
+    async fn open(
+        work_dir: &str,
+        relative: &str,
+        format: Option<ConfigFormat>,
+    ) -> Result<Self, ConfigError> {
+        if work_dir.is_empty() {
+            return Err(ConfigError::InvalidPath(
+                "instance has no work dir".to_owned(),
+            ));
+        }
+
+        let relative_path = Path::new(relative);
+        if relative.is_empty()
+            || !relative_path
+                .components()
+                .all(|x| matches!(x, Component::Normal(_)))
+        {
+            return Err(ConfigError::InvalidPath(
+                "path must be relative to the work dir".to_owned(),
+            ));
+        }
+
+        let format = format
+            .or_else(|| ConfigFormat::from_path(relative_path))
+            .ok_or(ConfigError::UnknownFormat)?;
+
+        let work_dir = tokio::fs::canonicalize(work_dir).await?;
+        let path = tokio::fs::canonicalize(work_dir.join(relative_path)).await?;
+        if !path.starts_with(&work_dir) {
+            return Err(ConfigError::InvalidPath(
+                "path leads outside of the work dir".to_owned(),
+            ));
+        }
+
+        Ok(Self {
+            relative: relative.to_owned(),
+            path,
+            format,
+        })
+    }
+
+    async fn read(&self) -> Result<Vec<u8>, ConfigError> {
+        let metadata = tokio::fs::metadata(&self.path).await?;
+        if !metadata.is_file() {
+            return Err(ConfigError::InvalidPath("path is not a file".to_owned()));
+        }
+        if metadata.len() > MAX_CONFIG_SIZE {
+            return Err(ConfigError::TooLarge);
+        }
+
+        Ok(tokio::fs::read(&self.path).await?)
+    }
+
+    /// Replaces the file atomically, keeping its mode and owner.
+    async fn write(&self, data: &[u8]) -> Result<(), ConfigError> {
+        let metadata = tokio::fs::metadata(&self.path).await?;
+
+        let mut temp_name = OsString::from(".");
+        temp_name.push(self.path.file_name().unwrap_or_default());
+        temp_name.push(".lcsm-tmp");
+        let temp_path = self.path.with_file_name(temp_name);
+
+        let result = async {
+            let mut file = match create_temp_file(&temp_path).await {
+                // left behind by an earlier write, removing a symlink doesn't touch its target
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                    tokio::fs::remove_file(&temp_path).await?;
+                    create_temp_file(&temp_path).await?
+                }
+                other => other?,
+            };
+            file.write_all(data).await?;
+            if let Err(e) = fchown(&file, Some(metadata.uid()), Some(metadata.gid())) {
+                tracing::warn!("Failed to keep owner of {}: {}", self.path.display(), e);
+            }
+            file.set_permissions(metadata.permissions()).await?;
+            file.sync_all().await?;
+            tokio::fs::rename(&temp_path, &self.path).await
+        }
+        .await;
+
+        if result.is_err() {
+            _ = tokio::fs::remove_file(&temp_path).await;
+        }
+        Ok(result?)
+    }
+
+    fn document(&self, content: Value) -> ConfigDocument {
+        ConfigDocument {
+            path: self.relative.clone(),
+            format: self.format,
+            preserves_comments: self.format.preserves_comments(),
+            content,
+        }
+    }
+}
+
+/// Creates a file only the slave can access, never following a symlink planted in its place.
+async fn create_temp_file(path: &Path) -> Result<tokio::fs::File, io::Error> {
+    tokio::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(path)
+        .await
+}
+
+/// Reads a config file of a work dir as JSON, the format is guessed from the extension if not given.
+pub async fn read_config(
+    work_dir: &str,
+    path: &str,
+    format: Option<ConfigFormat>,
+) -> Result<ConfigDocument, ConfigError> {
+    let file = ConfigFile::open(work_dir, path, format).await?;
+    let data = file.read().await?;
+    let content = decode(file.format, &data)?;
+
+    Ok(file.document(content))
+}
+
+/// Applies a JSON Patch to the JSON form of a config file and writes the changes back.
+///
+/// Only the entries that changed are rewritten where the format allows it, so comments and
+/// ordering of everything else stay as they are.
+pub async fn patch_config(
+    state: &AppState,
+    the_instance: &instance::Model,
+    path: &str,
+    format: Option<ConfigFormat>,
+    operations: &[PatchOperation],
+) -> Result<ConfigDocument, ConfigError> {
+    let file = ConfigFile::open(&the_instance.work_dir, path, format).await?;
+    let data = file.read().await?;
+    let content = decode(file.format, &data)?;
+
+    let mut patched = content.clone();
+    apply_json_patch(&mut patched, operations).map_err(|e| ConfigError::Patch(e.to_string()))?;
+    if patched == content {
+        return Ok(file.document(content));
+    }
+
+    let encoded = encode(file.format, &data, &patched)?;
+    let growth = encoded.len().saturating_sub(data.len()) as u64;
+    DiskQuotaService::check_write(state, the_instance, growth)
+        .await
+        .map_err(ConfigError::QuotaExceeded)?;
+    file.write(&encoded).await?;
+
+    // read back, since values may have been coerced to what the format supports
+    Ok(file.document(decode(file.format, &encoded)?))
+}
+
+fn decode(format: ConfigFormat, data: &[u8]) -> Result<Value, ConfigError> {
+    match format {
+        ConfigFormat::Properties => Ok(Properties::parse(data).to_json()),
+        ConfigFormat::Yaml => {
+            let value: serde_yaml_ng::Value =
+                serde_yaml_ng::from_slice(data).map_err(|e| ConfigError::Parse(e.to_string()))?;
+            serde_json::to_value(value).map_err(|e| ConfigError::Parse(e.to_string()))
+        }
+        ConfigFormat::Toml => {
+            let document = parse_toml(data)?;
+            Ok(table_to_json(document.as_table()))
+        }
+        ConfigFormat::Json => {
+            serde_json::from_slice(data).map_err(|e| ConfigError::Parse(e.to_string()))
+        }
+    }
+}
+
+/// Produces the new file content, `original` is reused as far as the format allows.
+fn encode(format: ConfigFormat, original: &[u8], content: &Value) -> Result<Vec<u8>, ConfigError> {
+    match format {
+        ConfigFormat::Properties => {
+            let mut properties = Properties::parse(original);
+            properties.update(expect_object(content)?)?;
+            Ok(properties.to_bytes())
+        }
+        ConfigFormat::Yaml => serde_yaml_ng::to_string(content)
+            .map(String::into_bytes)
+            .map_err(|e| ConfigError::Unrepresentable(e.to_string())),
+        ConfigFormat::Toml => {
+            let mut document = parse_toml(original)?;
+            merge_toml_table(document.as_table_mut(), expect_object(content)?)?;
+            Ok(document.to_string().into_bytes())
+        }
+        ConfigFormat::Json => {
+            let mut data = serde_json::to_vec_pretty(content)
+                .map_err(|e| ConfigError::Unrepresentable(e.to_string()))?;
+            data.push(b'\n');
+            Ok(data)
+        }
+    }
+}
+
+fn expect_object(content: &Value) -> Result<&Map<String, Value>, ConfigError> {
+    content
+        .as_object()
+        .ok_or_else(|| ConfigError::Unrepresentable("the root must be an object".to_owned()))
+}
+
+fn parse_toml(data: &[u8]) -> Result<DocumentMut, ConfigError> {
+    std::str::from_utf8(data)
+        .map_err(|e| ConfigError::Parse(e.to_string()))?
+        .parse::<DocumentMut>()
+        .map_err(|e| ConfigError::Parse(e.to_string()))
+}
+
+fn table_to_json(table: &dyn TableLike) -> Value {
+    Value::Object(
+        table
+            .iter()
+            .map(|(key, item)| (key.to_owned(), toml_item_to_json(item)))
+            .collect(),
+    )
+}
+
+fn toml_item_to_json(item: &Item) -> Value {
+    match item {
+        Item::None => Value::Null,
+        Item::Value(value) => toml_value_to_json(value),
+        Item::Table(table) => table_to_json(table),
+        Item::ArrayOfTables(tables) => {
+            Value::Array(tables.iter().map(|x| table_to_json(x)).collect())
+        }
+    }
+}
+
+fn toml_value_to_json(value: &toml_edit::Value) -> Value {
+    match value {
+        toml_edit::Value::String(x) => Value::String(x.value().clone()),
+        toml_edit::Value::Integer(x) => Value::from(*x.value()),
+        toml_edit::Value::Float(x) => Value::from(*x.value()),
+        toml_edit::Value::Boolean(x) => Value::Bool(*x.value()),
+        toml_edit::Value::Datetime(x) => Value::String(x.value().to_string()),
+        toml_edit::Value::Array(x) => Value::Array(x.iter().map(toml_value_to_json).collect()),
+        toml_edit::Value::InlineTable(x) => table_to_json(x),
+    }
+}
+
+fn json_to_toml_value(value: &Value) -> Result<toml_edit::Value, ConfigError> {
+    Ok(match value {
+        Value::Null => {
+            return Err(ConfigError::Unrepresentable(
+                "TOML has no null, remove the key instead".to_owned(),
+            ));
+        }
+        Value::Bool(x) => (*x).into(),
+        Value::Number(x) => match x.as_i64() {
+            Some(x) => x.into(),
+            None => x.as_f64().unwrap_or_default().into(),
+        },
+        Value::String(x) => x.into(),
+        Value::Array(x) => {
+            toml_edit::Value::Array(x.iter().map(json_to_toml_value).collect::<Result<_, _>>()?)
+        }
+        Value::Object(x) => {
+            let mut table = InlineTable::new();
+            for (key, value) in x {
+                table.insert(key, json_to_toml_value(value)?);
+            }
+            toml_edit::Value::InlineTable(table)
+        }
+    })
+}
+
+/// New objects become tables and arrays of objects become arrays of tables.
+fn json_to_toml_item(value: &Value) -> Result<Item, ConfigError> {
+    match value {
+        Value::Object(x) => {
+            let mut table = Table::new();
+            merge_toml_table(&mut table, x)?;
+            Ok(Item::Table(table))
+        }
+        Value::Array(x) if !x.is_empty() && x.iter().all(Value::is_object) => {
+            let mut tables = ArrayOfTables::new();
+            for value in x {
+                let mut table = Table::new();
+                merge_toml_table(&mut table, expect_object(value)?)?;
+                tables.push(table);
+            }
+            Ok(Item::ArrayOfTables(tables))
+        }
+        other => Ok(Item::Value(json_to_toml_value(other)?)),
+    }
+}
+
+/// Brings a table in line with `content`, leaving unchanged entries and their comments alone.
+fn merge_toml_table(
+    table: &mut dyn TableLike,
+    content: &Map<String, Value>,
+) -> Result<(), ConfigError> {
+    let removed = table
+        .iter()
+        .map(|(key, _)| key.to_owned())
+        .filter(|x| !content.contains_key(x))
+        .collect::<Vec<_>>();
+    for key in removed {
+        table.remove(&key);
+    }
+
+    for (key, value) in content {
+        match table.get_mut(key) {
+            Some(item) => merge_toml_item(item, value)?,
+            None => {
+                table.insert(key, json_to_toml_item(value)?);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn merge_toml_item(item: &mut Item, content: &Value) -> Result<(), ConfigError> {
+    if toml_item_to_json(item) == *content {
+        return Ok(());
+    }
+
+    if let (Some(table), Value::Object(content)) = (item.as_table_like_mut(), content) {
+        return merge_toml_table(table, content);
+    }
+
+    if let (Item::ArrayOfTables(tables), Value::Array(content)) = (&mut *item, content)
+        && tables.len() == content.len()
+        && content.iter().all(Value::is_object)
+    {
+        for (table, content) in tables.iter_mut().zip(content) {
+            merge_toml_table(table, expect_object(content)?)?;
+        }
+        return Ok(());
+    }
+
+    if let Item::Value(old) = item {
+        let mut value = json_to_toml_value(content)?;
+        // dates are strings in JSON, keep them dates if they still parse as one
+        if old.is_datetime()
+            && let Some(datetime) = content
+                .as_str()
+                .and_then(|x| x.parse::<toml_edit::Datetime>().ok())
+        {
+            value = datetime.into();
+        }
+        *value.decor_mut() = old.decor().clone();
+        *old = value;
+        return Ok(());
+    }
+
+    *item = json_to_toml_item(content)?;
+    Ok(())
+}
+
+/// A `.properties` file kept line by line, so comments and ordering survive updates.
+struct Properties {
+    lines: Vec<PropertiesLine>,
+    line_ending: &'static str,
+    /// Files that aren't valid UTF-8 are ISO-8859-1, which is what Java reads by default.
+    latin1: bool,
+}
+
+enum PropertiesLine {
+    Entry {
+        key: String,
+        value: String,
+        raw: String,
+    },
+    /// Comments and blank lines.
+    Other(String),
+}
+
+impl Properties {
+    fn parse(data: &[u8]) -> Self {
+        let (text, latin1) = match std::str::from_utf8(data) {
+            Ok(v) => (v.to_owned(), false),
+            Err(_) => (data.iter().map(|x| *x as char).collect(), true),
+        };
+        let line_ending = if text.contains("\r\n") { "\r\n" } else { "\n" };
+
+        let mut lines = Vec::new();
+        let mut physical = text.lines();
+        while let Some(line) = physical.next() {
+            let trimmed = line.trim_start();
+            if trimmed.is_empty() || trimmed.starts_with(['#', '!']) {
+                lines.push(PropertiesLine::Other(line.to_owned()));
+                continue;
+            }
+
+            // an odd number of trailing backslashes continues the line
+            let mut raw = line.to_owned();
+            let mut logical = line.to_owned();
+            while ends_with_continuation(&logical) {
+                logical.pop();
+                let Some(next) = physical.next() else {
+                    break;
+                };
+                raw.push_str(line_ending);
+                raw.push_str(next);
+                logical.push_str(next.trim_start());
+            }
+
+            let (key, value) = split_entry(&logical);
+            lines.push(PropertiesLine::Entry { key, value, raw });
+        }
+
+        Self {
+            lines,
+            line_ending,
+            latin1,
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        let mut map = Map::new();
+        for line in &self.lines {
+            if let PropertiesLine::Entry { key, value, .. } = line {
+                map.insert(key.clone(), Value::String(value.clone()));
+            }
+        }
+
+        Value::Object(map)
+    }
+
+    fn update(&mut self, content: &Map<String, Value>) -> Result<(), ConfigError> {
+        let values = content
+            .iter()
+            .map(|(key, value)| match value {
+                Value::String(x) => Ok((key.as_str(), x.clone())),
+                Value::Number(x) => Ok((key.as_str(), x.to_string())),
+                Value::Bool(x) => Ok((key.as_str(), x.to_string())),
+                _ => Err(ConfigError::Unrepresentable(format!(
+                    "value of `{}` must be a string, number or boolean",
+                    key
+                ))),
+            })
+            .collect::<Result<HashMap<_, _>, _>>()?;
+
+        let mut written = HashSet::new();
+        let mut lines = Vec::with_capacity(self.lines.len());
+        for line in self.lines.drain(..) {
+            let PropertiesLine::Entry { key, value, raw } = line else {
+                lines.push(line);
+                continue;
+            };
+
+            // duplicated keys collapse into the first occurrence
+            let Some(new_value) = values.get(key.as_str()) else {
+                continue;
+            };
+            if !written.insert(key.clone()) {
+                continue;
+            }
+            if *new_value == value {
+                lines.push(PropertiesLine::Entry { key, value, raw });
+            } else {
+                lines.push(PropertiesLine::entry(key, new_value.clone()));
+            }
+        }
+        for key in content.keys() {
+            if !written.contains(key) {
+                lines.push(PropertiesLine::entry(
+                    key.clone(),
+                    values[key.as_str()].clone(),
+                ));
+            }
+        }
+
+        self.lines = lines;
+        Ok(())
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut text = String::new();
+        for line in &self.lines {
+            match line {
+                PropertiesLine::Entry { raw, .. } => text.push_str(raw),
+                PropertiesLine::Other(raw) => text.push_str(raw),
+            }
+            text.push_str(self.line_ending);
+        }
+
+        if self.latin1 {
+            // new lines are escaped to ASCII, so every char fits
+            text.chars().map(|x| x as u8).collect()
+        } else {
+            text.into_bytes()
+        }
+    }
+}
+
+impl PropertiesLine {
+    fn entry(key: String, value: String) -> Self {
+        let raw = format!(
+            "{}={}",
+            escape_properties(&key, true),
+            escape_properties(&value, false)
+        );
+        Self::Entry { key, value, raw }
+    }
+}
+
+fn ends_with_continuation(line: &str) -> bool {
+    line.chars().rev().take_while(|x| *x == '\\').count() % 2 == 1
+}
+
+/// Splits a logical line at the first unescaped `=`, `:` or whitespace.
+fn split_entry(line: &str) -> (String, String) {
+    let line = line.trim_start();
+    let mut chars = line.char_indices();
+    let mut key_end = line.len();
+    while let Some((index, c)) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '=' | ':' | ' ' | '\t' | '\x0c' => {
+                key_end = index;
+                break;
+            }
+            _ => {}
+        }
+    }
+
+    let rest = line[key_end..].trim_start_matches([' ', '\t', '\x0c']);
+    let rest = rest
+        .strip_prefix(['=', ':'])
+        .unwrap_or(rest)
+        .trim_start_matches([' ', '\t', '\x0c']);
+
+    (
+        unescape_properties(&line[..key_end]),
+        unescape_properties(rest),
+    )
+}
+
+fn unescape_properties(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('t') => result.push('\t'),
+            Some('n') => result.push('\n'),
+            Some('r') => result.push('\r'),
+            Some('f') => result.push('\x0c'),
+            Some('u') => {
+                let code: String = chars.by_ref().take(4).collect();
+                let code = u16::from_str_radix(&code, 16).unwrap_or(0xfffd);
+                // surrogate pairs are written as two escapes
+                let mut units = vec![code];
+                if (0xd800..0xdc00).contains(&code) {
+                    let mut lookahead = chars.clone();
+                    if lookahead.next() == Some('\\') && lookahead.next() == Some('u') {
+                        let low: String = lookahead.by_ref().take(4).collect();
+                        if let Ok(low) = u16::from_str_radix(&low, 16) {
+                            units.push(low);
+                            chars = lookahead;
+                        }
+                    }
+                }
+                result.extend(char::decode_utf16(units).map(|x| x.unwrap_or('\u{fffd}')));
+            }
+            Some(other) => result.push(other),
+            None => {}
+        }
+    }
+
+    result
+}
+
+/// Escapes like `Properties.store` does, non-ASCII chars become `\uXXXX` so any reader copes.
+fn escape_properties(text: &str, is_key: bool) -> String {
+    let mut result = String::with_capacity(text.len());
+    for (index, c) in text.chars().enumerate() {
+        match c {
+            '\\' => result.push_str("\\\\"),
+            '\t' => result.push_str("\\t"),
+            '\n' => result.push_str("\\n"),
+            '\r' => result.push_str("\\r"),
+            '\x0c' => result.push_str("\\f"),
+            ' ' if is_key || index == 0 => result.push_str("\\ "),
+            '=' | ':' | '#' | '!' if is_key => {
+                result.push('\\');
+                result.push(c);
+            }
+            ' '..='~' => result.push(c),
+            _ => {
+                let mut units = [0; 2];
+                for unit in c.encode_utf16(&mut units) {
+                    result.push_str(&format!("\\u{:04X}", unit));
+                }
+            }
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    /// Decodes `original`, lets `change` edit the content and encodes it again.
+    fn merge(format: ConfigFormat, original: &str, change: impl FnOnce(&mut Value)) -> String {
+        let mut content = decode(format, original.as_bytes()).unwrap();
+        change(&mut content);
+        String::from_utf8(encode(format, original.as_bytes(), &content).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn properties_are_decoded() {
+        let content = decode(
+            ConfigFormat::Properties,
+            b"# comment\n! also a comment\nmotd=A \\u00e9 server\nlevel-name : world\nspaced\\ key value\nlong=first \\\n    second\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            content,
+            json!({
+                "motd": "A \u{e9} server",
+                "level-name": "world",
+                "spaced key": "value",
+                "long": "first second",
+            })
+        );
+    }
+
+    #[test]
+    fn properties_keep_comments_and_order() {
+        let original = "#Minecraft server properties\r\nmotd=A Minecraft Server\r\n\r\n# the port\r\nserver-port=25565\r\nlong=first \\\r\n    second\r\nremoved=yes\r\n";
+
+        let merged = merge(ConfigFormat::Properties, original, |content| {
+            content["server-port"] = json!(25566);
+            content["online-mode"] = json!(false);
+            content["motd"] = json!("Caf\u{e9} = open");
+            content.as_object_mut().unwrap().remove("removed");
+        });
+
+        assert_eq!(
+            merged,
+            "#Minecraft server properties\r\nmotd=Caf\\u00E9 = open\r\n\r\n# the port\r\nserver-port=25566\r\nlong=first \\\r\n    second\r\nonline-mode=false\r\n"
+        );
+    }
+
+    #[test]
+    fn properties_stay_latin1() {
+        let original = b"motd=Caf\xe9\nmax-players=20\n";
+
+        let mut content = decode(ConfigFormat::Properties, original).unwrap();
+        assert_eq!(content["motd"], "Caf\u{e9}");
+
+        content["max-players"] = json!("30");
+        let encoded = encode(ConfigFormat::Properties, original, &content).unwrap();
+        assert_eq!(encoded, b"motd=Caf\xe9\nmax-players=30\n");
+    }
+
+    #[test]
+    fn properties_reject_nested_values() {
+        let content = json!({ "motd": { "nested": true } });
+        assert!(matches!(
+            encode(ConfigFormat::Properties, b"", &content),
+            Err(ConfigError::Unrepresentable(_))
+        ));
+    }
+
+    #[test]
+    fn toml_keeps_comments_and_formatting() {
+        let original = r#"# top comment
+name = "server" # trailing comment
+
+[database]
+# connection settings
+host = "localhost"
+port = 5432
+created = 2024-01-01T00:00:00Z
+
+[[worlds]]
+name = "overworld"
+
+[[worlds]]
+name = "nether"
+"#;
+
+        let merged = merge(ConfigFormat::Toml, original, |content| {
+            content["database"]["port"] = json!(5433);
+            content["database"]["created"] = json!("2025-06-01T12:00:00Z");
+            content["worlds"][1]["name"] = json!("the_nether");
+            content["limits"] = json!({ "players": 20 });
+            content["database"].as_object_mut().unwrap().remove("host");
+        });
+
+        assert_eq!(
+            merged,
+            r#"# top comment
+name = "server" # trailing comment
+
+[database]
+port = 5433
+created = 2025-06-01T12:00:00Z
+
+[[worlds]]
+name = "overworld"
+
+[[worlds]]
+name = "the_nether"
+
+[limits]
+players = 20
+"#
+        );
+    }
+
+    #[test]
+    fn toml_round_trips_unchanged_content() {
+        let original = "a = 1 # one\nb = [1, 2,   3]\n[t]\nc = { d = true }\n";
+
+        assert_eq!(merge(ConfigFormat::Toml, original, |_| {}), original);
+    }
+
+    #[test]
+    fn toml_rejects_null() {
+        let original = b"a = 1\n";
+        let content = json!({ "a": null });
+
+        assert!(matches!(
+            encode(ConfigFormat::Toml, original, &content),
+            Err(ConfigError::Unrepresentable(_))
+        ));
+    }
+}
@@ -0,0 +1,352 @@
+use std::{
+    collections::BTreeSet,
+    fs,
+    io::{self, Read, Seek, SeekFrom},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use globset::{GlobBuilder, GlobSetBuilder};
+use sea_orm::{
+    ActiveModelTrait, ActiveValue::NotSet, ColumnTrait, DatabaseConnection, DbErr, EntityTrait,
+    IntoActiveModel, QueryFilter, QueryOrder, QuerySelect, Set,
+};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::Instrument;
+
+use crate::{
+    AppState, AppStateRef,
+    entities::{
+        crash_report::{self, CrashFile, CrashFiles},
+        instance,
+    },
+    services::{
+        ProcessState, ProcessStateEvent, RUN_STATUS_FAILED, RUN_TRIGGER_CRASH, RunDescriptor,
+    },
+};
+
+/// Gives the log worker time to write the last output before the tail is read.
+const OUTPUT_SETTLE_DELAY: Duration = Duration::from_secs(1);
+/// Older reports of an instance are deleted.
+const MAX_REPORTS_PER_INSTANCE: u64 = 20;
+const MAX_REPORT_FILES: usize = 32;
+/// Larger files are left out of a report.
+const MAX_REPORT_FILE_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Keeps the end of the output and the crash files of processes exiting with a failure.
+pub struct CrashReportService {
+    report_path: PathBuf,
+}
+
+impl CrashReportService {
+    pub fn new(report_path: PathBuf) -> Self {
+        Self { report_path }
+    }
+
+    pub fn get_report_path(&self, id: i32) -> PathBuf {
+        self.report_path.join(id.to_string())
+    }
+
+    pub fn get_output_path(&self, id: i32) -> PathBuf {
+        self.get_report_path(id).join("output.log")
+    }
+
+    /// `path` has to be one of the files of the report.
+    pub fn get_file_path(&self, id: i32, path: &str) -> PathBuf {
+        self.get_report_path(id).join("files").join(path)
+    }
+
+    pub fn start(state: AppStateRef) {
+        let mut events = state.process_manager.subscribe_events();
+        tokio::spawn(
+            async move {
+                loop {
+                    let event = match events.recv().await {
+                        Ok(v) => v,
+                        Err(RecvError::Lagged(n)) => {
+                            tracing::warn!("Missed {} process events, crashes may be lost", n);
+                            continue;
+                        }
+                        Err(RecvError::Closed) => break,
+                    };
+                    if event.state != ProcessState::Crashed {
+                        continue;
+                    }
+
+                    let state = state.clone();
+                    tokio::spawn(
+                        async move {
+                            tokio::time::sleep(OUTPUT_SETTLE_DELAY).await;
+                            if let Err(e) = capture_report(&state, &event).await {
+                                tracing::error!(
+                                    "Failed to capture crash report of instance {}: {}",
+                                    event.instance_id,
+                                    e
+                                );
+                            }
+                        }
+                        .in_current_span(),
+                    );
+                }
+            }
+            .instrument(tracing::info_span!(parent: None, "crash reporter")),
+        );
+    }
+
+    pub async fn delete_report(
+        &self,
+        database: &DatabaseConnection,
+        the_report: crash_report::Model,
+    ) -> Result<(), DbErr> {
+        let path = self.get_report_path(the_report.id);
+        the_report.into_active_model().delete(database).await?;
+
+        if let Err(e) = tokio::fs::remove_dir_all(&path).await
+            && e.kind() != io::ErrorKind::NotFound
+        {
+            tracing::warn!("Failed to remove {}: {}", path.display(), e);
+        }
+
+        Ok(())
+    }
+}
+
+async fn capture_report(state: &AppState, event: &ProcessStateEvent) -> anyhow::Result<()> {
+    let Some(the_instance) = instance::Entity::find_by_id(event.instance_id as i32)
+        .one(&state.database)
+        .await?
+    else {
+        return Ok(());
+    };
+
+    // a restarted process has replaced the crashed one, every matching file is kept then
+    let started_at = match state.process_manager.get_process(event.instance_id).await {
+        Some(process) => {
+            let process = process.read().await;
+            Some(process.started_at()).filter(|_| !process.state().is_alive())
+        }
+        None => None,
+    };
+
+    let the_report = crash_report::ActiveModel {
+        id: NotSet,
+        instance_id: Set(the_instance.id),
+        run_id: Set(None),
+        exit_code: Set(event.exit_code),
+        started_at: Set(started_at.unwrap_or(event.at)),
+        crashed_at: Set(event.at),
+        output_size: Set(0),
+        files: Set(CrashFiles::default()),
+    }
+    .insert(&state.database)
+    .await?;
+
+    let target = state.crash_report_manager.get_report_path(the_report.id);
+    let log_path = state.log_manager.get_log_path(event.instance_id);
+    let options = the_instance.crash_reports.clone();
+    let work_dir = PathBuf::from(&the_instance.work_dir);
+    let since = started_at.map_or(SystemTime::UNIX_EPOCH, SystemTime::from);
+    let (output_size, files) = tokio::task::spawn_blocking(move || {
+        fs::create_dir_all(target.join("files"))?;
+        let output_size = copy_output_tail(
+            &log_path,
+            &target.join("output.log"),
+            u64::from(options.output_tail) * 1024,
+        )?;
+        let files = copy_crash_files(&work_dir, &options.files, since, &target.join("files"))?;
+        Ok::<_, io::Error>((output_size, files))
+    })
+    .await??;
+
+    let reason = match event.exit_code {
+        Some(code) => format!("exited with code {}", code),
+        None => "killed by a signal".to_owned(),
+    };
+    let run = state
+        .run_history
+        .record_run(
+            RunDescriptor {
+                instance_id: the_instance.id,
+                schedule_id: None,
+                trigger: RUN_TRIGGER_CRASH,
+                action: "crash-report",
+                scheduled_at: None,
+            },
+            RUN_STATUS_FAILED,
+            Some(format!("{}, crash report {}", reason, the_report.id)),
+        )
+        .await?;
+
+    let mut the_report = the_report.into_active_model();
+    the_report.run_id = Set(Some(run.id));
+    the_report.output_size = Set(output_size as i64);
+    the_report.files = Set(CrashFiles(files));
+    the_report.update(&state.database).await?;
+
+    let outdated = crash_report::Entity::find()
+        .filter(crash_report::Column::InstanceId.eq(the_instance.id))
+        .order_by_desc(crash_report::Column::Id)
+        .offset(MAX_REPORTS_PER_INSTANCE)
+        // sqlite only accepts an offset along with a limit
+        .limit(i64::MAX as u64)
+        .all(&state.database)
+        .await?;
+    for the_report in outdated {
+        state
+            .crash_report_manager
+            .delete_report(&state.database, the_report)
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Copies the last `size` bytes of the log, starting at a line if the log is longer.
+fn copy_output_tail(log_path: &Path, target: &Path, size: u64) -> Result<u64, io::Error> {
+    let mut output = Vec::new();
+    match fs::File::open(log_path) {
+        Ok(mut log) => {
+            let start = log.metadata()?.len().saturating_sub(size);
+            log.seek(SeekFrom::Start(start))?;
+            log.take(size).read_to_end(&mut output)?;
+            if start > 0
+                && let Some(end) = output.iter().position(|x| *x == b'\n')
+            {
+                output.drain(..=end);
+            }
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+
+    fs::write(target, &output)?;
+    Ok(output.len() as u64)
+}
+
+/// Copies files of the work dir matching any of the patterns and modified since the
+/// process started. Unreadable directories are skipped, the report is best effort.
+fn copy_crash_files(
+    work_dir: &Path,
+    patterns: &[String],
+    since: SystemTime,
+    target: &Path,
+) -> Result<Vec<CrashFile>, io::Error> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let glob = GlobBuilder::new(pattern)
+            .literal_separator(true)
+            .build()
+            .map_err(io::Error::other)?;
+        builder.add(glob);
+    }
+    let globs = builder.build().map_err(io::Error::other)?;
+
+    let mut matches = BTreeSet::new();
+    for pattern in patterns {
+        let (base, depth) = walk_root(pattern);
+        walk(work_dir, base, depth, &mut |path, metadata| {
+            let recent = metadata.modified().is_ok_and(|x| x >= since);
+            if recent && globs.is_match(path) {
+                if metadata.len() <= MAX_REPORT_FILE_SIZE {
+                    matches.insert(path.to_owned());
+                } else {
+                    tracing::warn!("Crash file {} is too large to keep", path.display());
+                }
+            }
+        });
+    }
+
+    let mut files = Vec::new();
+    for path in matches.into_iter().take(MAX_REPORT_FILES) {
+        let destination = target.join(&path);
+        if let Some(parent) = destination.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        match copy_crash_file(&work_dir.join(&path), &destination) {
+            Ok(size) => files.push(CrashFile {
+                path: path.to_string_lossy().into_owned(),
+                size,
+            }),
+            Err(e) => tracing::warn!("Failed to keep crash file {}: {}", path.display(), e),
+        }
+    }
+
+    Ok(files)
+}
+
+/// Copies a regular file without following a symlink swapped in since the walk.
+fn copy_crash_file(source: &Path, destination: &Path) -> Result<u64, io::Error> {
+    // non-blocking so a fifo swapped in can't hang the open
+    let source = fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOFOLLOW | libc::O_NONBLOCK)
+        .open(source)?;
+    let metadata = source.metadata()?;
+    if !metadata.is_file() {
+        return Err(io::Error::other("not a regular file"));
+    }
+    if metadata.len() > MAX_REPORT_FILE_SIZE {
+        return Err(io::Error::other("file grew too large to keep"));
+    }
+
+    let mut destination = fs::File::create(destination)?;
+    io::copy(&mut source.take(MAX_REPORT_FILE_SIZE), &mut destination)
+}
+
+/// The literal directory a pattern starts in, and how deep below it matches can be.
+fn walk_root(pattern: &str) -> (PathBuf, Option<usize>) {
+    let components = pattern.split('/').collect::<Vec<_>>();
+    let literal = components
+        .iter()
+        .position(|x| x.contains(['*', '?', '[', '{', '\\']))
+        .unwrap_or(components.len())
+        .min(components.len() - 1);
+
+    let rest = &components[literal..];
+    let depth = (!rest.contains(&"**")).then_some(rest.len());
+    (components[..literal].iter().collect(), depth)
+}
+
+/// Calls `visit` with the relative path of every regular file below `base`, without
+/// following symlinks. Nothing is visited if `base` leads outside of the work dir.
+fn walk(
+    work_dir: &Path,
+    base: PathBuf,
+    depth: Option<usize>,
+    visit: &mut impl FnMut(&Path, &fs::Metadata),
+) {
+    // the literal part of a pattern may go through a symlink
+    let (Ok(root), Ok(start)) = (
+        fs::canonicalize(work_dir),
+        fs::canonicalize(work_dir.join(&base)),
+    ) else {
+        return;
+    };
+    if !start.starts_with(&root) {
+        tracing::warn!(
+            "Crash files below {} are skipped, it leads outside of the work dir",
+            base.display()
+        );
+        return;
+    }
+
+    let mut pending = vec![(base, 1)];
+    while let Some((relative, level)) = pending.pop() {
+        let Ok(entries) = fs::read_dir(work_dir.join(&relative)) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let path = relative.join(entry.file_name());
+            let Ok(metadata) = entry.path().symlink_metadata() else {
+                continue;
+            };
+
+            if metadata.is_file() {
+                visit(&path, &metadata);
+            } else if metadata.is_dir() && depth.is_none_or(|x| level < x) {
+                pending.push((path, level + 1));
+            }
+        }
+    }
+}
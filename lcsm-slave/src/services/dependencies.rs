@@ -0,0 +1,301 @@
+use std::{
+    cmp::Reverse,
+    collections::{BTreeSet, BinaryHeap, HashMap, HashSet},
+    fmt::Display,
+};
+
+use axum::http::StatusCode;
+use sea_orm::{DatabaseConnection, DbErr, EntityTrait};
+
+use crate::{entities::instance, errors::StatusCodeError};
+
+/// Maps every instance to the instances it depends on.
+pub type DependencyGraph = HashMap<i32, Vec<i32>>;
+
+#[derive(Debug)]
+pub enum DependencyError {
+    DbErr(DbErr),
+    UnknownInstance(i32),
+    SelfDependency,
+    Cycle(Vec<i32>),
+}
+
+impl StatusCodeError for DependencyError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Self::DbErr(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            _ => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+impl Display for DependencyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::DbErr(e) => e.fmt(f),
+            Self::UnknownInstance(id) => write!(f, "Instance {} does not exist", id),
+            Self::SelfDependency => write!(f, "Instance cannot depend on itself"),
+            Self::Cycle(path) => {
+                let path = path.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+                write!(f, "Dependency cycle detected: {}", path.join(" -> "))
+            }
+        }
+    }
+}
+
+impl std::error::Error for DependencyError {}
+
+impl From<DbErr> for DependencyError {
+    fn from(value: DbErr) -> Self {
+        Self::DbErr(value)
+    }
+}
+
+pub async fn load_dependency_graph(db: &DatabaseConnection) -> Result<DependencyGraph, DbErr> {
+    Ok(instance::Entity::find()
+        .all(db)
+        .await?
+        .into_iter()
+        .map(|x| (x.id, x.dependencies.0))
+        .collect())
+}
+
+/// Checks the dependencies of an instance before they are saved, `id` is `None` for new instances.
+pub async fn validate_dependencies(
+    db: &DatabaseConnection,
+    id: Option<i32>,
+    dependencies: &[i32],
+) -> Result<(), DependencyError> {
+    let mut graph = load_dependency_graph(db).await?;
+
+    for dependency in dependencies {
+        if Some(*dependency) == id {
+            return Err(DependencyError::SelfDependency);
+        }
+        if !graph.contains_key(dependency) {
+            return Err(DependencyError::UnknownInstance(*dependency));
+        }
+    }
+
+    // nothing depends on a new instance yet, so it cannot be part of a cycle
+    if let Some(id) = id {
+        graph.insert(id, dependencies.to_vec());
+        dependency_order(&graph, [id])?;
+    }
+
+    Ok(())
+}
+
+/// Returns `roots` and everything they depend on, dependencies come before their dependents.
+pub fn dependency_order(
+    graph: &DependencyGraph,
+    roots: impl IntoIterator<Item = i32>,
+) -> Result<Vec<i32>, DependencyError> {
+    fn visit(
+        graph: &DependencyGraph,
+        node: i32,
+        path: &mut Vec<i32>,
+        done: &mut HashSet<i32>,
+        order: &mut Vec<i32>,
+    ) -> Result<(), DependencyError> {
+        if done.contains(&node) {
+            return Ok(());
+        }
+        if let Some(position) = path.iter().position(|x| *x == node) {
+            let mut cycle = path[position..].to_vec();
+            cycle.push(node);
+            return Err(DependencyError::Cycle(cycle));
+        }
+
+        path.push(node);
+        for dependency in graph.get(&node).into_iter().flatten() {
+            // dangling references of deleted instances are ignored
+            if graph.contains_key(dependency) {
+                visit(graph, *dependency, path, done, order)?;
+            }
+        }
+        path.pop();
+
+        done.insert(node);
+        order.push(node);
+        Ok(())
+    }
+
+    let mut path = Vec::new();
+    let mut done = HashSet::new();
+    let mut order = Vec::new();
+    for root in roots {
+        visit(graph, root, &mut path, &mut done, &mut order)?;
+    }
+
+    Ok(order)
+}
+
+/// Returns every instance that directly or transitively depends on `id`.
+pub fn dependents_of(graph: &DependencyGraph, id: i32) -> HashSet<i32> {
+    let mut dependents = HashSet::new();
+    let mut pending = vec![id];
+
+    while let Some(current) = pending.pop() {
+        for (node, dependencies) in graph {
+            if dependencies.contains(&current) && dependents.insert(*node) {
+                pending.push(*node);
+            }
+        }
+    }
+
+    dependents
+}
+
+/// Returns every instance depending on `id` in the order they are stopped, dependents before
+/// their dependencies.
+pub fn stop_order(graph: &DependencyGraph, id: i32) -> Result<Vec<i32>, DependencyError> {
+    let dependents = dependents_of(graph, id);
+    let mut order = dependency_order(graph, dependents.iter().copied())?;
+    order.retain(|x| dependents.contains(x));
+    order.reverse();
+    Ok(order)
+}
+
+/// Sorts `nodes` so dependencies come first, ties are broken by the given priority key.
+/// Only the ordering between the given nodes is considered.
+pub fn prioritized_order<K: Ord + Copy>(
+    graph: &DependencyGraph,
+    nodes: impl IntoIterator<Item = (i32, K)>,
+) -> Result<Vec<i32>, DependencyError> {
+    let priorities: HashMap<i32, K> = nodes.into_iter().collect();
+
+    // edges between the given nodes, following transitive dependencies through other nodes
+    let mut pending_dependencies: HashMap<i32, usize> = HashMap::new();
+    let mut dependents: HashMap<i32, Vec<i32>> = HashMap::new();
+    for node in priorities.keys() {
+        let relevant = dependency_order(graph, [*node])?
+            .into_iter()
+            .filter(|x| x != node && priorities.contains_key(x))
+            .collect::<BTreeSet<_>>();
+
+        pending_dependencies.insert(*node, relevant.len());
+        for dependency in relevant {
+            dependents.entry(dependency).or_default().push(*node);
+        }
+    }
+
+    let mut ready = pending_dependencies
+        .iter()
+        .filter(|(_, count)| **count == 0)
+        .map(|(node, _)| Reverse((priorities[node], *node)))
+        .collect::<BinaryHeap<_>>();
+
+    let mut order = Vec::with_capacity(priorities.len());
+    while let Some(Reverse((_, node))) = ready.pop() {
+        order.push(node);
+        for dependent in dependents.get(&node).into_iter().flatten() {
+            let count = pending_dependencies.get_mut(dependent).unwrap();
+            *count -= 1;
+            if *count == 0 {
+                ready.push(Reverse((priorities[dependent], *dependent)));
+            }
+        }
+    }
+
+    Ok(order)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn graph(edges: &[(i32, &[i32])]) -> DependencyGraph {
+        edges
+            .iter()
+            .map(|(node, dependencies)| (*node, dependencies.to_vec()))
+            .collect()
+    }
+
+    /// 4 depends on 2 and 3, which both depend on 1.
+    fn diamond() -> DependencyGraph {
+        graph(&[(1, &[]), (2, &[1]), (3, &[1]), (4, &[2, 3])])
+    }
+
+    #[test]
+    fn diamond_starts_shared_dependency_once() {
+        let order = dependency_order(&diamond(), [4]).unwrap();
+
+        assert_eq!(order.len(), 4);
+        assert_eq!(order[0], 1);
+        assert_eq!(order[3], 4);
+    }
+
+    #[test]
+    fn dangling_dependencies_are_ignored() {
+        let graph = graph(&[(1, &[]), (2, &[1, 99])]);
+
+        assert_eq!(dependency_order(&graph, [2]).unwrap(), vec![1, 2]);
+    }
+
+    #[test]
+    fn self_dependency_is_a_cycle() {
+        let graph = graph(&[(1, &[1])]);
+
+        assert!(matches!(
+            dependency_order(&graph, [1]),
+            Err(DependencyError::Cycle(path)) if path == vec![1, 1]
+        ));
+    }
+
+    #[test]
+    fn cycles_are_detected() {
+        let graph = graph(&[(1, &[3]), (2, &[1]), (3, &[2]), (4, &[1])]);
+
+        assert!(matches!(
+            dependency_order(&graph, [1]),
+            Err(DependencyError::Cycle(path)) if path == vec![1, 3, 2, 1]
+        ));
+        assert!(matches!(
+            dependency_order(&graph, [4]),
+            Err(DependencyError::Cycle(_))
+        ));
+        assert!(matches!(
+            prioritized_order(&graph, [(1, 0), (2, 0)]),
+            Err(DependencyError::Cycle(_))
+        ));
+    }
+
+    #[test]
+    fn stop_order_reverses_start_order() {
+        let graph = diamond();
+
+        let stop = stop_order(&graph, 1).unwrap();
+        assert_eq!(stop.len(), 3);
+        assert_eq!(stop[0], 4);
+        assert!(!stop.contains(&1));
+
+        let start = dependency_order(&graph, [4]).unwrap();
+        let mut reversed = stop.clone();
+        reversed.reverse();
+        assert_eq!(&start[1..], reversed.as_slice());
+
+        assert_eq!(stop_order(&graph, 4).unwrap(), Vec::<i32>::new());
+    }
+
+    #[test]
+    fn priorities_break_ties_between_independent_instances() {
+        let graph = diamond();
+
+        let order = prioritized_order(&graph, [(1, 5), (2, 9), (3, 0), (4, 0)]).unwrap();
+        assert_eq!(order, vec![1, 3, 2, 4]);
+
+        let graph = self::graph(&[(1, &[]), (2, &[]), (3, &[])]);
+        let order = prioritized_order(&graph, [(1, 2), (2, 1), (3, 1)]).unwrap();
+        assert_eq!(order, vec![2, 3, 1]);
+    }
+
+    #[test]
+    fn prioritized_order_follows_dependencies_through_other_instances() {
+        // 3 depends on 1 through 2, which isn't being ordered
+        let graph = graph(&[(1, &[]), (2, &[1]), (3, &[2])]);
+
+        let order = prioritized_order(&graph, [(3, 0), (1, 9)]).unwrap();
+        assert_eq!(order, vec![1, 3]);
+    }
+}
@@ -0,0 +1,323 @@
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use sea_orm::EntityTrait;
+use serde::Serialize;
+use tokio::sync::{RwLock, broadcast};
+use tracing::Instrument;
+
+use crate::{
+    AppState, AppStateRef,
+    entities::instance::{self, HardLimitAction},
+    services::stop_instance,
+};
+
+/// Scanning large worlds is expensive, so usage is only refreshed this often.
+const SCAN_INTERVAL: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum QuotaLevel {
+    #[default]
+    Normal,
+    SoftExceeded,
+    HardExceeded,
+}
+
+/// Result of the last scan of a work dir.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DiskUsage {
+    pub used_bytes: u64,
+    pub scanned_at: DateTime<Utc>,
+    pub level: QuotaLevel,
+    pub soft_limit: Option<u64>,
+    pub hard_limit: Option<u64>,
+}
+
+/// Sent whenever a scan moves an instance to another quota level.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QuotaEvent {
+    pub instance_id: u64,
+    pub previous_level: QuotaLevel,
+    pub level: QuotaLevel,
+    pub used_bytes: u64,
+    pub at: DateTime<Utc>,
+}
+
+pub struct DiskQuotaService {
+    usage: RwLock<HashMap<u64, DiskUsage>>,
+    events: broadcast::Sender<QuotaEvent>,
+}
+
+impl Default for DiskQuotaService {
+    fn default() -> Self {
+        Self {
+            usage: RwLock::new(HashMap::new()),
+            events: broadcast::channel(64).0,
+        }
+    }
+}
+
+impl DiskQuotaService {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn subscribe_events(&self) -> broadcast::Receiver<QuotaEvent> {
+        self.events.subscribe()
+    }
+
+    pub async fn get_usage(&self, id: u64) -> Option<DiskUsage> {
+        self.usage.read().await.get(&id).cloned()
+    }
+
+    pub fn start(state: AppStateRef) {
+        tokio::spawn(
+            async move {
+                let mut interval = tokio::time::interval(SCAN_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    Self::scan_all(&state).await;
+                }
+            }
+            .instrument(tracing::info_span!(parent: None, "disk quota scanner")),
+        );
+    }
+
+    async fn scan_all(state: &AppState) {
+        let instances = match instance::Entity::find().all(&state.database).await {
+            Ok(v) => v,
+            Err(e) => {
+                tracing::error!("Failed to load instances: {}", e);
+                return;
+            }
+        };
+
+        // forget deleted instances
+        state
+            .quota_manager
+            .usage
+            .write()
+            .await
+            .retain(|id, _| instances.iter().any(|x| x.id as u64 == *id));
+
+        for the_instance in instances {
+            if let Err(e) = Self::scan(state, &the_instance).await {
+                tracing::warn!(
+                    "Failed to scan work dir of instance {}: {}",
+                    the_instance.id,
+                    e
+                );
+            }
+        }
+    }
+
+    /// Measures the work dir now and reacts if the quota level changed.
+    pub async fn scan(
+        state: &AppState,
+        the_instance: &instance::Model,
+    ) -> Result<DiskUsage, io::Error> {
+        let id = the_instance.id as u64;
+        let quota = &the_instance.disk_quota;
+
+        let used_bytes = measure(&the_instance.work_dir).await?;
+
+        let level = if quota.hard_limit.is_some_and(|x| used_bytes >= x) {
+            QuotaLevel::HardExceeded
+        } else if quota.soft_limit.is_some_and(|x| used_bytes >= x) {
+            QuotaLevel::SoftExceeded
+        } else {
+            QuotaLevel::Normal
+        };
+
+        let usage = DiskUsage {
+            used_bytes,
+            scanned_at: Utc::now(),
+            level,
+            soft_limit: quota.soft_limit,
+            hard_limit: quota.hard_limit,
+        };
+        let previous_level = state
+            .quota_manager
+            .usage
+            .write()
+            .await
+            .insert(id, usage.clone())
+            .map(|x| x.level)
+            .unwrap_or_default();
+
+        if previous_level != level {
+            Self::on_level_changed(state, the_instance, &usage, previous_level).await;
+        }
+
+        Ok(usage)
+    }
+
+    async fn on_level_changed(
+        state: &AppState,
+        the_instance: &instance::Model,
+        usage: &DiskUsage,
+        previous_level: QuotaLevel,
+    ) {
+        let id = the_instance.id as u64;
+        tracing::info!(
+            "Instance {} disk quota level changed from {:?} to {:?} ({} bytes used)",
+            id,
+            previous_level,
+            usage.level,
+            usage.used_bytes
+        );
+
+        _ = state.quota_manager.events.send(QuotaEvent {
+            instance_id: id,
+            previous_level,
+            level: usage.level,
+            used_bytes: usage.used_bytes,
+            at: usage.scanned_at,
+        });
+
+        let Some(process) = state.process_manager.get_alive_process(id).await else {
+            return;
+        };
+
+        let notice = match usage.level {
+            QuotaLevel::Normal => return,
+            QuotaLevel::SoftExceeded => format!(
+                "Disk usage of {} bytes exceeds the soft quota of {} bytes",
+                usage.used_bytes,
+                usage.soft_limit.unwrap_or_default()
+            ),
+            QuotaLevel::HardExceeded => format!(
+                "Disk usage of {} bytes exceeds the hard quota of {} bytes, the instance can't be started again until space is freed",
+                usage.used_bytes,
+                usage.hard_limit.unwrap_or_default()
+            ),
+        };
+        process.read().await.write_notice(&notice);
+
+        if usage.level == QuotaLevel::HardExceeded
+            && the_instance.disk_quota.hard_limit_action == HardLimitAction::Stop
+        {
+            tracing::warn!("Stopping instance {} over its hard disk quota", id);
+            if let Err(e) = stop_instance(state, id).await {
+                tracing::error!("Failed to stop instance {}: {}", id, e);
+            }
+        }
+    }
+
+    /// Fails if the work dir is over its hard quota, rescanning first so freed space is noticed.
+    pub async fn ensure_within_quota(
+        state: &AppState,
+        the_instance: &instance::Model,
+    ) -> Result<(), String> {
+        let Some(hard_limit) = the_instance.disk_quota.hard_limit else {
+            return Ok(());
+        };
+
+        let cached = state
+            .quota_manager
+            .get_usage(the_instance.id as u64)
+            .await
+            .filter(|x| x.hard_limit == Some(hard_limit) && x.level != QuotaLevel::HardExceeded);
+        let usage = match cached {
+            Some(v) => v,
+            None => Self::scan(state, the_instance)
+                .await
+                .map_err(|e| format!("failed to scan work dir: {}", e))?,
+        };
+
+        if usage.level == QuotaLevel::HardExceeded {
+            return Err(format!(
+                "work dir uses {} bytes, over the hard quota of {} bytes",
+                usage.used_bytes, hard_limit
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Fails if writing `bytes` more into the work dir would exceed its hard quota.
+    /// Writes by the slave itself are checked up front, the scanner only notices them later.
+    pub async fn check_write(
+        state: &AppState,
+        the_instance: &instance::Model,
+        bytes: u64,
+    ) -> Result<(), String> {
+        let Some(hard_limit) = the_instance.disk_quota.hard_limit else {
+            return Ok(());
+        };
+
+        let fits = |used_bytes: u64| used_bytes.saturating_add(bytes) <= hard_limit;
+        let cached = state
+            .quota_manager
+            .get_usage(the_instance.id as u64)
+            .await
+            .filter(|x| x.hard_limit == Some(hard_limit) && fits(x.used_bytes));
+        let used_bytes = match cached {
+            Some(v) => v.used_bytes,
+            // not recorded, the instance may not be saved yet
+            None => measure(&the_instance.work_dir)
+                .await
+                .map_err(|e| format!("failed to scan work dir: {}", e))?,
+        };
+
+        if !fits(used_bytes) {
+            return Err(format!(
+                "writing {} bytes to a work dir using {} bytes exceeds the hard quota of {} bytes",
+                bytes, used_bytes, hard_limit
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+async fn measure(work_dir: &str) -> Result<u64, io::Error> {
+    if work_dir.is_empty() {
+        return Ok(0);
+    }
+
+    let work_dir = PathBuf::from(work_dir);
+    tokio::task::spawn_blocking(move || dir_size(&work_dir))
+        .await
+        .map_err(io::Error::other)?
+}
+
+/// Apparent size of all files below `path`, symlinks are not followed.
+fn dir_size(path: &Path) -> Result<u64, io::Error> {
+    let mut total = 0;
+    let mut pending = match fs::read_dir(path) {
+        Ok(v) => vec![v],
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+
+    while let Some(entries) = pending.pop() {
+        for entry in entries {
+            // files may vanish or be unreadable while the server runs
+            let Ok(entry) = entry else {
+                continue;
+            };
+            let Ok(metadata) = entry.path().symlink_metadata() else {
+                continue;
+            };
+
+            if metadata.is_dir() {
+                if let Ok(v) = fs::read_dir(entry.path()) {
+                    pending.push(v);
+                }
+            } else {
+                total += metadata.len();
+            }
+        }
+    }
+
+    Ok(total)
+}
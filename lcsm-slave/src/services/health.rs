@@ -0,0 +1,463 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use regex::Regex;
+use serde::Serialize;
+use tokio::{
+    net::TcpStream,
+    sync::{Mutex, RwLock, broadcast, mpsc, watch},
+};
+use tracing::Instrument;
+
+use crate::{
+    AppStateRef,
+    entities::instance::{HealthChecks, Probe, ProbeCheck},
+    services::{
+        ProcessRef, ProcessState, ProcessStatus, RUN_TRIGGER_LIVENESS, RunDescriptor,
+        restart_instance,
+    },
+    transfer::BinarySequence,
+};
+
+/// Output without a line break is checked and dropped once it grows this large.
+const MAX_LINE_LENGTH: usize = 64 * 1024;
+
+#[derive(Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProbeStatus {
+    /// Whether the probe reached its success threshold more recently than its failure threshold.
+    pub passing: bool,
+    pub consecutive_successes: u32,
+    pub consecutive_failures: u32,
+    pub last_checked_at: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HealthStatus {
+    pub readiness: Option<ProbeStatus>,
+    pub liveness: Option<ProbeStatus>,
+}
+
+type StatusMap = Arc<RwLock<HashMap<u64, HealthStatus>>>;
+
+/// Runs the probes of every process and restarts the ones failing their liveness probe.
+pub struct HealthService {
+    statuses: StatusMap,
+    http: reqwest::Client,
+    restart_requests: mpsc::UnboundedSender<(u64, ProcessRef)>,
+    restart_receiver: Mutex<Option<mpsc::UnboundedReceiver<(u64, ProcessRef)>>>,
+}
+
+#[derive(Clone, Copy)]
+enum ProbeKind {
+    Readiness,
+    Liveness,
+}
+
+impl Default for HealthService {
+    fn default() -> Self {
+        let (restart_requests, restart_receiver) = mpsc::unbounded_channel();
+        Self {
+            statuses: Default::default(),
+            http: reqwest::Client::new(),
+            restart_requests,
+            restart_receiver: Mutex::new(Some(restart_receiver)),
+        }
+    }
+}
+
+impl HealthService {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub async fn get_status(&self, id: u64) -> Option<HealthStatus> {
+        self.statuses.read().await.get(&id).cloned()
+    }
+
+    /// Carries out the restarts requested by failing liveness probes.
+    pub fn start(state: AppStateRef) {
+        tokio::spawn(
+            async move {
+                let Some(mut receiver) = state.health_manager.restart_receiver.lock().await.take()
+                else {
+                    return;
+                };
+
+                while let Some((id, process)) = receiver.recv().await {
+                    // the instance may have been restarted by someone else meanwhile
+                    let current = state.process_manager.get_alive_process(id).await;
+                    if !current.is_some_and(|x| Arc::ptr_eq(&x, &process)) {
+                        continue;
+                    }
+
+                    if let Err(e) = Self::restart(&state, id).await {
+                        tracing::error!("Failed to record restart of instance {}: {}", id, e);
+                    }
+                }
+            }
+            .instrument(tracing::info_span!(parent: None, "health restarter")),
+        );
+    }
+
+    async fn restart(state: &AppStateRef, id: u64) -> Result<(), sea_orm::DbErr> {
+        tracing::warn!("Restarting instance {} after failed liveness probe", id);
+
+        let run = state
+            .run_history
+            .begin_run(RunDescriptor {
+                instance_id: id as i32,
+                schedule_id: None,
+                trigger: RUN_TRIGGER_LIVENESS,
+                action: "restart",
+                scheduled_at: None,
+            })
+            .await?;
+
+        let result = restart_instance(state, id)
+            .await
+            .map(|_| None)
+            .map_err(|e| e.to_string());
+        if let Err(e) = &result {
+            tracing::error!("Failed to restart instance {}: {}", id, e);
+        }
+
+        state.run_history.finish_run(run, result).await?;
+        Ok(())
+    }
+
+    /// Starts probing a freshly spawned process, it is marked running once ready.
+    pub async fn watch(&self, id: u64, process: ProcessRef, checks: HealthChecks) {
+        if checks.readiness.is_none() && checks.liveness.is_none() {
+            self.statuses.write().await.remove(&id);
+            return;
+        }
+
+        self.statuses.write().await.insert(
+            id,
+            HealthStatus {
+                readiness: checks.readiness.as_ref().map(|_| ProbeStatus::default()),
+                liveness: checks.liveness.as_ref().map(|_| ProbeStatus::default()),
+            },
+        );
+
+        // subscribed before the output of the process is released, so no line is missed
+        let (status, stdout, stderr) = {
+            let process = process.read().await;
+            (
+                process.subscribe_status(),
+                process.get_stdout(),
+                process.get_stderr(),
+            )
+        };
+
+        let mut patterns = Vec::new();
+        let readiness_matched = output_flag(&checks.readiness, &mut patterns);
+        let liveness_matched = output_flag(&checks.liveness, &mut patterns);
+        if !patterns.is_empty() {
+            let patterns = Arc::new(patterns);
+            for output in [stdout, stderr].into_iter().flatten() {
+                tokio::spawn(match_output(output, patterns.clone()));
+            }
+        }
+
+        let monitor = Monitor {
+            id,
+            process,
+            statuses: self.statuses.clone(),
+            http: self.http.clone(),
+            restart_requests: self.restart_requests.clone(),
+            readiness_matched,
+            liveness_matched,
+        };
+        tokio::spawn(
+            monitor
+                .run(checks, status)
+                .instrument(tracing::info_span!(parent: None, "health monitor", id)),
+        );
+    }
+}
+
+struct Monitor {
+    id: u64,
+    process: ProcessRef,
+    statuses: StatusMap,
+    http: reqwest::Client,
+    restart_requests: mpsc::UnboundedSender<(u64, ProcessRef)>,
+    readiness_matched: Option<Arc<AtomicBool>>,
+    liveness_matched: Option<Arc<AtomicBool>>,
+}
+
+impl Monitor {
+    async fn run(self, checks: HealthChecks, mut status: watch::Receiver<ProcessStatus>) {
+        let probes = async {
+            if let Some(probe) = &checks.readiness {
+                let readiness = async {
+                    initial_delay(probe).await;
+                    self.probe_until(
+                        ProbeKind::Readiness,
+                        probe,
+                        self.readiness_matched.as_ref(),
+                        true,
+                    )
+                    .await;
+                };
+
+                let timeout = Duration::from_secs(checks.readiness_timeout as u64);
+                if checks.readiness_timeout == 0 {
+                    readiness.await;
+                } else if tokio::time::timeout(timeout, readiness).await.is_err() {
+                    self.on_readiness_timed_out(checks.readiness_timeout).await;
+                    return;
+                }
+            }
+
+            {
+                let process = self.process.read().await;
+                if process.state() == ProcessState::Starting {
+                    process.set_state(ProcessState::Running);
+                }
+            }
+
+            let Some(probe) = &checks.liveness else {
+                return;
+            };
+            let matched = self.liveness_matched.as_ref();
+            initial_delay(probe).await;
+            loop {
+                let error = self
+                    .probe_until(ProbeKind::Liveness, probe, matched, false)
+                    .await;
+                self.on_liveness_failed(error, checks.restart_on_failure)
+                    .await;
+                if checks.restart_on_failure {
+                    break;
+                }
+
+                // only warn again after the process recovered
+                self.probe_until(ProbeKind::Liveness, probe, matched, true)
+                    .await;
+            }
+        };
+
+        tokio::select! {
+            _ = probes => {}
+            _ = status.wait_for(|x| !x.state.is_alive()) => {}
+        }
+    }
+
+    /// Checks until the probe reaches the threshold of the wanted outcome, returns the last error.
+    async fn probe_until(
+        &self,
+        kind: ProbeKind,
+        probe: &Probe,
+        matched: Option<&Arc<AtomicBool>>,
+        success: bool,
+    ) -> Option<String> {
+        let mut interval = tokio::time::interval(Duration::from_secs(probe.interval as u64));
+        loop {
+            interval.tick().await;
+
+            let result = self.check(probe, matched).await;
+            let status = self.update_status(kind, probe, &result).await;
+
+            if success && status.consecutive_successes >= probe.success_threshold {
+                return None;
+            }
+            if !success && status.consecutive_failures >= probe.failure_threshold {
+                return result.err();
+            }
+        }
+    }
+
+    async fn check(&self, probe: &Probe, matched: Option<&Arc<AtomicBool>>) -> Result<(), String> {
+        let timeout = Duration::from_secs(probe.timeout as u64);
+
+        match &probe.check {
+            ProbeCheck::Tcp { host, port } => {
+                match tokio::time::timeout(timeout, TcpStream::connect((host.as_str(), *port)))
+                    .await
+                {
+                    Ok(Ok(_)) => Ok(()),
+                    Ok(Err(e)) => Err(format!("connect to {}:{}: {}", host, port, e)),
+                    Err(_) => Err(format!("connect to {}:{} timed out", host, port)),
+                }
+            }
+            ProbeCheck::Http {
+                url,
+                expected_status,
+            } => {
+                let response = self
+                    .http
+                    .get(url)
+                    .timeout(timeout)
+                    .send()
+                    .await
+                    .map_err(|e| format!("request {}: {}", url, e))?;
+
+                let status = response.status();
+                let expected = match expected_status {
+                    Some(x) => status.as_u16() == *x,
+                    None => status.is_success(),
+                };
+                if !expected {
+                    return Err(format!("{} responded with {}", url, status));
+                }
+
+                Ok(())
+            }
+            ProbeCheck::Output { pattern } => {
+                if matched.is_some_and(|x| x.swap(false, Ordering::Relaxed)) {
+                    return Ok(());
+                }
+
+                Err(format!("no output matched `{}`", pattern))
+            }
+        }
+    }
+
+    async fn update_status(
+        &self,
+        kind: ProbeKind,
+        probe: &Probe,
+        result: &Result<(), String>,
+    ) -> ProbeStatus {
+        let mut statuses = self.statuses.write().await;
+        let health = statuses.entry(self.id).or_default();
+        let status = match kind {
+            ProbeKind::Readiness => &mut health.readiness,
+            ProbeKind::Liveness => &mut health.liveness,
+        }
+        .get_or_insert_default();
+
+        status.last_checked_at = Some(Utc::now());
+        match result {
+            Ok(_) => {
+                status.consecutive_successes += 1;
+                status.consecutive_failures = 0;
+                status.last_error = None;
+                if status.consecutive_successes >= probe.success_threshold {
+                    status.passing = true;
+                }
+            }
+            Err(e) => {
+                status.consecutive_failures += 1;
+                status.consecutive_successes = 0;
+                status.last_error = Some(e.clone());
+                if status.consecutive_failures >= probe.failure_threshold {
+                    status.passing = false;
+                }
+            }
+        }
+
+        status.clone()
+    }
+
+    async fn on_readiness_timed_out(&self, timeout: u32) {
+        let error = {
+            let mut statuses = self.statuses.write().await;
+            let status = statuses
+                .entry(self.id)
+                .or_default()
+                .readiness
+                .get_or_insert_default();
+            let error = match &status.last_error {
+                Some(e) => format!("not ready after {}s: {}", timeout, e),
+                None => format!("not ready after {}s", timeout),
+            };
+            status.passing = false;
+            status.last_error = Some(error.clone());
+            error
+        };
+        tracing::warn!("Readiness probe of instance {} failed: {}", self.id, error);
+
+        let process = self.process.read().await;
+        process.write_notice(&format!("Readiness probe failed, stopping: {}", error));
+        if let Err(e) = process.kill_crashed().await {
+            tracing::error!("Failed to kill instance {}: {}", self.id, e);
+        }
+    }
+
+    async fn on_liveness_failed(&self, error: Option<String>, restart: bool) {
+        let error = error.unwrap_or_default();
+        tracing::warn!("Liveness probe of instance {} failed: {}", self.id, error);
+
+        let notice = if restart {
+            format!("Liveness probe failed, restarting: {}", error)
+        } else {
+            format!("Liveness probe failed: {}", error)
+        };
+        self.process.read().await.write_notice(&notice);
+
+        if restart {
+            _ = self.restart_requests.send((self.id, self.process.clone()));
+        }
+    }
+}
+
+async fn initial_delay(probe: &Probe) {
+    tokio::time::sleep(Duration::from_secs(probe.initial_delay as u64)).await;
+}
+
+type OutputPatterns = Vec<(Regex, Arc<AtomicBool>)>;
+
+/// Registers the pattern of an output probe, the flag is set whenever a line matches.
+fn output_flag(probe: &Option<Probe>, patterns: &mut OutputPatterns) -> Option<Arc<AtomicBool>> {
+    let Some(Probe {
+        check: ProbeCheck::Output { pattern },
+        ..
+    }) = probe
+    else {
+        return None;
+    };
+
+    // validated when the instance was saved
+    let pattern = Regex::new(pattern).ok()?;
+    let flag = Arc::new(AtomicBool::new(false));
+    patterns.push((pattern, flag.clone()));
+    Some(flag)
+}
+
+/// Flags every pattern matched by a line of output.
+async fn match_output(
+    mut output: broadcast::Receiver<BinarySequence>,
+    patterns: Arc<OutputPatterns>,
+) {
+    let check_line = |line: &[u8]| {
+        let line = String::from_utf8_lossy(line);
+        for (pattern, flag) in patterns.iter() {
+            if pattern.is_match(&line) {
+                flag.store(true, Ordering::Relaxed);
+            }
+        }
+    };
+
+    let mut buffer = Vec::new();
+    loop {
+        let data = match output.recv().await {
+            Ok(v) => v,
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+
+        buffer.extend_from_slice(&data);
+        while let Some(end) = buffer.iter().position(|x| *x == b'\n') {
+            check_line(&buffer[..end]);
+            buffer.drain(..=end);
+        }
+
+        if buffer.len() > MAX_LINE_LENGTH {
+            check_line(&buffer);
+            buffer.clear();
+        }
+    }
+}
@@ -0,0 +1,294 @@
+use std::{process::Stdio, time::Duration};
+
+use sea_orm::EntityTrait;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    process::Command,
+    sync::broadcast::error::RecvError,
+};
+use tracing::Instrument;
+
+use crate::{
+    AppState, AppStateRef,
+    entities::instance::{self, Hook, Hooks},
+    services::{ProcessState, RUN_TRIGGER_HOOK, RunDescriptor},
+};
+
+/// Output of a hook beyond this is left out of the run history.
+const MAX_HOOK_OUTPUT: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookKind {
+    PreStart,
+    PostStart,
+    PreStop,
+    PostStop,
+    OnCrash,
+}
+
+impl HookKind {
+    /// Used as the action in the run history and passed to the hook as `LCSM_HOOK`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::PreStart => "pre-start",
+            Self::PostStart => "post-start",
+            Self::PreStop => "pre-stop",
+            Self::PostStop => "post-stop",
+            Self::OnCrash => "on-crash",
+        }
+    }
+
+    fn hook<'a>(&self, hooks: &'a Hooks) -> Option<&'a Hook> {
+        match self {
+            Self::PreStart => hooks.pre_start.as_ref(),
+            Self::PostStart => hooks.post_start.as_ref(),
+            Self::PreStop => hooks.pre_stop.as_ref(),
+            Self::PostStop => hooks.post_stop.as_ref(),
+            Self::OnCrash => hooks.on_crash.as_ref(),
+        }
+    }
+}
+
+/// Runs the hooks following process state changes, pre-start and pre-stop hooks are run by
+/// the instance control itself.
+pub struct HookService;
+
+impl HookService {
+    pub fn start(state: AppStateRef) {
+        let mut events = state.process_manager.subscribe_events();
+        tokio::spawn(
+            async move {
+                loop {
+                    let event = match events.recv().await {
+                        Ok(v) => v,
+                        Err(RecvError::Lagged(n)) => {
+                            tracing::warn!("Missed {} process events, their hooks don't run", n);
+                            continue;
+                        }
+                        Err(RecvError::Closed) => break,
+                    };
+
+                    let kind = match (event.previous_state, event.state) {
+                        (ProcessState::Starting, ProcessState::Running) => HookKind::PostStart,
+                        (_, ProcessState::Stopped) => HookKind::PostStop,
+                        (_, ProcessState::Crashed) => HookKind::OnCrash,
+                        _ => continue,
+                    };
+
+                    // hooks of different instances don't wait for each other
+                    let state = state.clone();
+                    tokio::spawn(
+                        async move {
+                            Self::run_for_event(&state, event.instance_id, kind, event.exit_code)
+                                .await
+                        }
+                        .in_current_span(),
+                    );
+                }
+            }
+            .instrument(tracing::info_span!(parent: None, "hook runner")),
+        );
+    }
+
+    async fn run_for_event(state: &AppState, id: u64, kind: HookKind, exit_code: Option<i32>) {
+        let the_instance = match instance::Entity::find_by_id(id as i32)
+            .one(&state.database)
+            .await
+        {
+            Ok(Some(v)) => v,
+            Ok(None) => return,
+            Err(e) => {
+                tracing::error!("Failed to load instance {}: {}", id, e);
+                return;
+            }
+        };
+
+        _ = run_hook(state, &the_instance, kind, exit_code).await;
+    }
+}
+
+/// Runs a hook of the instance if it has one and records it in the run history.
+/// Returns why the hook failed.
+pub async fn run_hook(
+    state: &AppState,
+    the_instance: &instance::Model,
+    kind: HookKind,
+    exit_code: Option<i32>,
+) -> Result<(), String> {
+    let Some(hook) = kind.hook(&the_instance.hooks) else {
+        return Ok(());
+    };
+
+    let run = state
+        .run_history
+        .begin_run(RunDescriptor {
+            instance_id: the_instance.id,
+            schedule_id: None,
+            trigger: RUN_TRIGGER_HOOK,
+            action: kind.name(),
+            scheduled_at: None,
+        })
+        .await;
+
+    let result = execute_hook(state, the_instance, kind, hook, exit_code).await;
+    if let Err(e) = &result {
+        tracing::warn!(
+            "The {} hook of instance {} failed: {}",
+            kind.name(),
+            the_instance.id,
+            e
+        );
+    }
+
+    let recorded = match run {
+        Ok(run) => state
+            .run_history
+            .finish_run(
+                run,
+                result.clone().map(|x| Some(x).filter(|x| !x.is_empty())),
+            )
+            .await
+            .map(|_| ()),
+        Err(e) => Err(e),
+    };
+    if let Err(e) = recorded {
+        tracing::error!(
+            "Failed to record hook of instance {}: {}",
+            the_instance.id,
+            e
+        );
+    }
+
+    result.map(|_| ())
+}
+
+/// Returns the output of the hook, or why it failed followed by the output.
+async fn execute_hook(
+    state: &AppState,
+    the_instance: &instance::Model,
+    kind: HookKind,
+    hook: &Hook,
+    exit_code: Option<i32>,
+) -> Result<String, String> {
+    let run_as = state.run_as_manager.resolve(
+        the_instance.run_as_user.as_deref(),
+        the_instance.run_as_group.as_deref(),
+    )?;
+    let variables = state
+        .secret_manager
+        .open_environment(&the_instance.environment)
+        .map_err(|e| e.to_string())?;
+
+    let mut command = Command::new("sh");
+    command
+        .arg("-c")
+        .arg(&hook.command)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        // the whole group is killed on timeout, not only the shell
+        .process_group(0)
+        .kill_on_drop(true);
+
+    if !the_instance.inherit_env {
+        command.env_clear();
+    }
+    for (name, value) in variables {
+        match value {
+            Some(value) => command.env(name, value),
+            None => command.env_remove(name),
+        };
+    }
+    command
+        .env("LCSM_HOOK", kind.name())
+        .env("LCSM_INSTANCE_ID", the_instance.id.to_string())
+        .env("LCSM_INSTANCE_NAME", &the_instance.name)
+        .env("LCSM_WORK_DIR", &the_instance.work_dir);
+    if let Some(exit_code) = exit_code {
+        command.env("LCSM_EXIT_CODE", exit_code.to_string());
+    }
+
+    if !the_instance.work_dir.is_empty() {
+        command.current_dir(&the_instance.work_dir);
+    }
+    if let Some(run_as) = &run_as {
+        if let Some(gid) = run_as.gid {
+            command.gid(gid);
+        }
+        if let Some(uid) = run_as.uid {
+            command.uid(uid);
+        }
+        if let Some(user) = &run_as.user {
+            command.env("USER", user).env("LOGNAME", user);
+        }
+        if let Some(home) = &run_as.home {
+            command.env("HOME", home);
+        }
+    }
+
+    let mut child = command.spawn().map_err(|e| format!("spawn hook: {}", e))?;
+    let pid = child.id();
+    let (stdout, stderr) = (child.stdout.take(), child.stderr.take());
+
+    let collect = async {
+        let ((mut output, stdout_truncated), (stderr, stderr_truncated)) =
+            tokio::join!(read_capped(stdout), read_capped(stderr));
+        output.extend_from_slice(&stderr);
+        let truncated = stdout_truncated || stderr_truncated;
+        (output, truncated, child.wait().await)
+    };
+    let timeout = Duration::from_secs(hook.timeout.into());
+    let (output, truncated, exit_status) = match tokio::time::timeout(timeout, collect).await {
+        Ok(v) => v,
+        Err(_) => {
+            if let Some(pid) = pid {
+                unsafe {
+                    libc::killpg(pid as libc::pid_t, libc::SIGKILL);
+                }
+            }
+            _ = child.wait().await;
+            return Err(format!("timed out after {} seconds", hook.timeout));
+        }
+    };
+
+    let mut output = String::from_utf8_lossy(&output).into_owned();
+    if truncated {
+        output.push_str("\n(output truncated)");
+    }
+
+    match exit_status {
+        Ok(status) if status.success() => Ok(output),
+        Ok(status) => {
+            let reason = match status.code() {
+                Some(code) => format!("exited with code {}", code),
+                None => "killed by a signal".to_owned(),
+            };
+            Err(format!("{}\n{}", reason, output).trim_end().to_owned())
+        }
+        Err(e) => Err(format!("wait for hook: {}", e)),
+    }
+}
+
+/// Reads until the end, keeping at most half of [`MAX_HOOK_OUTPUT`] so the pipe never fills up.
+/// Also tells whether anything was left out.
+async fn read_capped(reader: Option<impl AsyncRead + Unpin>) -> (Vec<u8>, bool) {
+    let mut kept = Vec::new();
+    let mut truncated = false;
+    let Some(mut reader) = reader else {
+        return (kept, truncated);
+    };
+
+    let mut buf = [0u8; 4096];
+    loop {
+        match reader.read(&mut buf).await {
+            Ok(0) | Err(_) => break,
+            Ok(n) => {
+                let room = (MAX_HOOK_OUTPUT / 2).saturating_sub(kept.len());
+                kept.extend_from_slice(&buf[..n.min(room)]);
+                truncated |= n > room;
+            }
+        }
+    }
+
+    (kept, truncated)
+}
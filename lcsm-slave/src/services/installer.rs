@@ -0,0 +1,684 @@
+use std::{
+    collections::HashSet,
+    fmt::Display,
+    io,
+    os::unix::fs::lchown,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::http::StatusCode;
+use chrono::Utc;
+use sea_orm::{
+    ActiveModelTrait,
+    ActiveValue::{NotSet, Set},
+    ColumnTrait, DbErr, EntityTrait, IntoActiveModel, QueryFilter,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::{io::AsyncWriteExt, sync::RwLock};
+
+use crate::{
+    AppState,
+    entities::{installation, instance, template::is_relative_path},
+    errors::StatusCodeError,
+    services::DiskQuotaService,
+};
+
+/// How long a fetched index is used before it is fetched again.
+const INDEX_TTL: Duration = Duration::from_secs(5 * 60);
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A server that stops sending for this long fails the request, which ends the installation.
+const READ_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Server jars are tens of megabytes and modpacks a few hundred, anything this big is refused.
+const MAX_ARTIFACT_SIZE: u64 = 4 * 1024 * 1024 * 1024;
+
+/// Describes the server software that can be installed, e.g. Paper, Fabric or vanilla.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RepositoryIndex {
+    pub packages: Vec<Package>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Package {
+    pub id: String,
+    pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    /// Where artifacts are saved, relative to the work dir, e.g. `server.jar`.
+    pub file_name: String,
+    /// Newest first, the first one is offered as the upgrade.
+    pub versions: Vec<PackageVersion>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PackageVersion {
+    pub version: String,
+    pub url: String,
+    /// Hex encoded digest of the artifact.
+    pub sha256: String,
+}
+
+impl RepositoryIndex {
+    pub fn validate(&self) -> Result<(), String> {
+        let mut ids = HashSet::new();
+        for package in &self.packages {
+            if package.id.is_empty() || !ids.insert(&package.id) {
+                return Err(format!(
+                    "package id `{}` is empty or not unique",
+                    package.id
+                ));
+            }
+            if !is_relative_path(&package.file_name) {
+                return Err(format!(
+                    "file name of package `{}` must be relative",
+                    package.id
+                ));
+            }
+
+            for version in &package.versions {
+                if !(version.url.starts_with("http://") || version.url.starts_with("https://")) {
+                    return Err(format!("invalid url `{}`", version.url));
+                }
+                if version.sha256.len() != 64
+                    || !version.sha256.chars().all(|x| x.is_ascii_hexdigit())
+                {
+                    return Err(format!(
+                        "invalid sha256 of {} {}",
+                        package.id, version.version
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The latest version is picked without a version.
+    pub fn find_version(
+        &self,
+        package_id: &str,
+        version: Option<&str>,
+    ) -> Option<(&Package, &PackageVersion)> {
+        let package = self.packages.iter().find(|x| x.id == package_id)?;
+        let found = match version {
+            Some(version) => package.versions.iter().find(|x| x.version == version),
+            None => package.versions.first(),
+        };
+
+        found.map(|x| (package, x))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InstallRequest {
+    pub package_id: String,
+    /// Defaults to the latest version.
+    #[serde(default)]
+    pub version: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InstallationStatus {
+    pub installation: Option<installation::Model>,
+    /// Of the installed package, unknown if it is no longer in the index.
+    pub latest_version: Option<String>,
+    pub upgrade_available: bool,
+}
+
+#[derive(Debug)]
+pub enum InstallerError {
+    NotConfigured,
+    NotFound(String),
+    Invalid(String),
+    InstanceRunning,
+    InstallInProgress,
+    IndexError(String),
+    DownloadError(String),
+    ChecksumMismatch { expected: String, actual: String },
+    QuotaExceeded(String),
+    DbErr(DbErr),
+    IoError(io::Error),
+}
+
+impl StatusCodeError for InstallerError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Self::NotConfigured | Self::NotFound(_) => StatusCode::NOT_FOUND,
+            Self::Invalid(_) => StatusCode::BAD_REQUEST,
+            Self::InstanceRunning | Self::InstallInProgress => StatusCode::CONFLICT,
+            Self::IndexError(_) | Self::DownloadError(_) | Self::ChecksumMismatch { .. } => {
+                StatusCode::BAD_GATEWAY
+            }
+            Self::QuotaExceeded(_) => StatusCode::INSUFFICIENT_STORAGE,
+            Self::DbErr(_) | Self::IoError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl Display for InstallerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotConfigured => write!(f, "No repository index is configured"),
+            Self::NotFound(e) => write!(f, "{} not found", e),
+            Self::Invalid(e) => write!(f, "Invalid installation: {}", e),
+            Self::InstanceRunning => write!(f, "Instance is running"),
+            Self::InstallInProgress => write!(f, "Another installation is in progress"),
+            Self::IndexError(e) => write!(f, "Failed to load repository index: {}", e),
+            Self::DownloadError(e) => write!(f, "Failed to download artifact: {}", e),
+            Self::ChecksumMismatch { expected, actual } => {
+                write!(f, "Checksum mismatch, expected {} got {}", expected, actual)
+            }
+            Self::QuotaExceeded(e) => write!(f, "Disk quota exceeded: {}", e),
+            Self::DbErr(e) => e.fmt(f),
+            Self::IoError(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for InstallerError {}
+
+impl From<DbErr> for InstallerError {
+    fn from(value: DbErr) -> Self {
+        Self::DbErr(value)
+    }
+}
+
+impl From<io::Error> for InstallerError {
+    fn from(value: io::Error) -> Self {
+        Self::IoError(value)
+    }
+}
+
+impl From<reqwest::Error> for InstallerError {
+    fn from(value: reqwest::Error) -> Self {
+        Self::DownloadError(value.to_string())
+    }
+}
+
+/// Downloads server software listed in a repository index into work dirs.
+pub struct InstallerService {
+    /// An http(s) url or a local file, optionally as a `file://` url.
+    index_source: Option<String>,
+    http: reqwest::Client,
+    cached_index: RwLock<Option<(Instant, Arc<RepositoryIndex>)>>,
+    installing: Mutex<HashSet<u64>>,
+}
+
+impl InstallerService {
+    pub fn new(index_source: Option<String>) -> Self {
+        Self {
+            index_source,
+            http: reqwest::Client::builder()
+                .connect_timeout(CONNECT_TIMEOUT)
+                .read_timeout(READ_TIMEOUT)
+                .build()
+                .expect("build http client"),
+            cached_index: RwLock::new(None),
+            installing: Mutex::new(HashSet::new()),
+        }
+    }
+
+    pub async fn get_index(&self, refresh: bool) -> Result<Arc<RepositoryIndex>, InstallerError> {
+        let source = self
+            .index_source
+            .as_deref()
+            .ok_or(InstallerError::NotConfigured)?;
+
+        if !refresh
+            && let Some((fetched_at, index)) = self.cached_index.read().await.as_ref()
+            && fetched_at.elapsed() < INDEX_TTL
+        {
+            return Ok(index.clone());
+        }
+
+        let index = Arc::new(self.fetch_index(source).await?);
+        *self.cached_index.write().await = Some((Instant::now(), index.clone()));
+
+        Ok(index)
+    }
+
+    async fn fetch_index(&self, source: &str) -> Result<RepositoryIndex, InstallerError> {
+        let content = if source.starts_with("http://") || source.starts_with("https://") {
+            self.http
+                .get(source)
+                .send()
+                .await
+                .and_then(|x| x.error_for_status())
+                .map_err(|e| InstallerError::IndexError(e.to_string()))?
+                .bytes()
+                .await
+                .map_err(|e| InstallerError::IndexError(e.to_string()))?
+                .to_vec()
+        } else {
+            let path = source.strip_prefix("file://").unwrap_or(source);
+            tokio::fs::read(path)
+                .await
+                .map_err(|e| InstallerError::IndexError(format!("read {}: {}", path, e)))?
+        };
+
+        let index: RepositoryIndex = serde_json::from_slice(&content)
+            .map_err(|e| InstallerError::IndexError(e.to_string()))?;
+        index.validate().map_err(InstallerError::IndexError)?;
+
+        Ok(index)
+    }
+
+    /// Requests the artifact, bodies announced to be larger than the cap are refused up front.
+    async fn request(&self, version: &PackageVersion) -> Result<reqwest::Response, InstallerError> {
+        let response = self
+            .http
+            .get(&version.url)
+            .send()
+            .await?
+            .error_for_status()?;
+        if response
+            .content_length()
+            .is_some_and(|x| x > MAX_ARTIFACT_SIZE)
+        {
+            return Err(artifact_too_large());
+        }
+
+        Ok(response)
+    }
+
+    /// Streams the artifact into `target` while hashing it, the caller removes `target` if
+    /// this fails.
+    async fn download(
+        &self,
+        mut response: reqwest::Response,
+        version: &PackageVersion,
+        target: &Path,
+    ) -> Result<(), InstallerError> {
+        let mut file = match create_download_file(target).await {
+            // left behind by an earlier download, removing a symlink doesn't touch its target
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                tokio::fs::remove_file(target).await?;
+                create_download_file(target).await?
+            }
+            other => other?,
+        };
+        let mut hasher = Sha256::new();
+        let mut size = 0;
+        while let Some(chunk) = response.chunk().await? {
+            size += chunk.len() as u64;
+            if size > MAX_ARTIFACT_SIZE {
+                return Err(artifact_too_large());
+            }
+            hasher.update(&chunk);
+            file.write_all(&chunk).await?;
+        }
+        file.sync_all().await?;
+
+        let actual = format!("{:x}", hasher.finalize());
+        if !actual.eq_ignore_ascii_case(&version.sha256) {
+            return Err(InstallerError::ChecksumMismatch {
+                expected: version.sha256.to_ascii_lowercase(),
+                actual,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+fn artifact_too_large() -> InstallerError {
+    InstallerError::DownloadError(format!(
+        "artifact is larger than {} bytes",
+        MAX_ARTIFACT_SIZE
+    ))
+}
+
+/// Never follows a symlink planted in place of the file, the work dir belongs to the instance.
+async fn create_download_file(path: &Path) -> Result<tokio::fs::File, io::Error> {
+    tokio::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o644)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(path)
+        .await
+}
+
+async fn find_instance(state: &AppState, id: u64) -> Result<instance::Model, InstallerError> {
+    let not_found = || InstallerError::NotFound("Instance".to_owned());
+    instance::Entity::find_by_id(i32::try_from(id).map_err(|_| not_found())?)
+        .one(&state.database)
+        .await?
+        .ok_or_else(not_found)
+}
+
+async fn find_installation(
+    state: &AppState,
+    instance_id: i32,
+) -> Result<Option<installation::Model>, DbErr> {
+    installation::Entity::find()
+        .filter(installation::Column::InstanceId.eq(instance_id))
+        .one(&state.database)
+        .await
+}
+
+/// The installed version and whether the index offers a newer one.
+pub async fn installation_status(
+    state: &AppState,
+    id: u64,
+) -> Result<InstallationStatus, InstallerError> {
+    let the_instance = find_instance(state, id).await?;
+    let Some(installed) = find_installation(state, the_instance.id).await? else {
+        return Ok(InstallationStatus {
+            installation: None,
+            latest_version: None,
+            upgrade_available: false,
+        });
+    };
+
+    let index = state.installer.get_index(false).await?;
+    let latest_version = index
+        .find_version(&installed.package_id, None)
+        .map(|(_, x)| x.version.clone());
+
+    Ok(InstallationStatus {
+        upgrade_available: latest_version
+            .as_ref()
+            .is_some_and(|x| *x != installed.version),
+        latest_version,
+        installation: Some(installed),
+    })
+}
+
+/// Downloads and verifies a package version into the work dir of a stopped instance, the
+/// previous artifact is only replaced once the new one is verified.
+pub async fn install_package(
+    state: &AppState,
+    id: u64,
+    request: InstallRequest,
+) -> Result<installation::Model, InstallerError> {
+    let the_instance = find_instance(state, id).await?;
+    if the_instance.work_dir.is_empty() {
+        return Err(InstallerError::Invalid(
+            "instance has no work dir".to_owned(),
+        ));
+    }
+    if state.process_manager.get_alive_process(id).await.is_some() {
+        return Err(InstallerError::InstanceRunning);
+    }
+
+    if !state.installer.installing.lock().unwrap().insert(id) {
+        return Err(InstallerError::InstallInProgress);
+    }
+    let mut guard = InstallGuard {
+        installing: &state.installer.installing,
+        id,
+        download_path: None,
+    };
+
+    install(state, &the_instance, request, &mut guard).await
+}
+
+/// Ends an installation when dropped, which also happens when the request is cancelled half
+/// way through a download.
+struct InstallGuard<'a> {
+    installing: &'a Mutex<HashSet<u64>>,
+    id: u64,
+    /// The download in progress, removed unless it was moved into place.
+    download_path: Option<PathBuf>,
+}
+
+impl Drop for InstallGuard<'_> {
+    fn drop(&mut self) {
+        if let Some(path) = self.download_path.take() {
+            _ = std::fs::remove_file(path);
+        }
+        self.installing.lock().unwrap().remove(&self.id);
+    }
+}
+
+async fn install(
+    state: &AppState,
+    the_instance: &instance::Model,
+    request: InstallRequest,
+    guard: &mut InstallGuard<'_>,
+) -> Result<installation::Model, InstallerError> {
+    let index = state.installer.get_index(false).await?;
+    let (package, version) = index
+        .find_version(&request.package_id, request.version.as_deref())
+        .ok_or_else(|| {
+            InstallerError::NotFound(format!(
+                "Package {} {}",
+                request.package_id,
+                request.version.as_deref().unwrap_or("(latest)")
+            ))
+        })?;
+
+    let target = artifact_path(&the_instance.work_dir, &package.file_name).await?;
+    let file_name = target
+        .file_name()
+        .map(|x| x.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let download_path = target.with_file_name(format!(".{}.download", file_name));
+
+    let response = state.installer.request(version).await?;
+    // servers may leave the size out, the cap still applies then
+    DiskQuotaService::check_write(
+        state,
+        the_instance,
+        response.content_length().unwrap_or_default(),
+    )
+    .await
+    .map_err(InstallerError::QuotaExceeded)?;
+
+    guard.download_path = Some(download_path.clone());
+    state
+        .installer
+        .download(response, version, &download_path)
+        .await?;
+    tokio::fs::rename(&download_path, &target).await?;
+    guard.download_path = None;
+
+    let run_as = state
+        .run_as_manager
+        .resolve(
+            the_instance.run_as_user.as_deref(),
+            the_instance.run_as_group.as_deref(),
+        )
+        .map_err(InstallerError::Invalid)?;
+    if let Some(run_as) = run_as {
+        lchown(&target, run_as.uid, run_as.gid)?;
+    }
+
+    let existing = find_installation(state, the_instance.id).await?;
+    let exists = existing.is_some();
+    let mut active = match existing {
+        Some(v) => v.into_active_model(),
+        None => installation::ActiveModel {
+            id: NotSet,
+            instance_id: Set(the_instance.id),
+            ..Default::default()
+        },
+    };
+    active.package_id = Set(package.id.clone());
+    active.version = Set(version.version.clone());
+    active.file_name = Set(package.file_name.clone());
+    active.sha256 = Set(version.sha256.to_ascii_lowercase());
+    active.installed_at = Set(Utc::now());
+
+    let res = if exists {
+        active.update(&state.database).await?
+    } else {
+        active.insert(&state.database).await?
+    };
+    Ok(res)
+}
+
+/// Creates the parent directories and makes sure symlinks don't lead outside the work dir.
+async fn artifact_path(work_dir: &str, file_name: &str) -> Result<PathBuf, InstallerError> {
+    let work_dir = tokio::fs::canonicalize(work_dir).await?;
+    let target = work_dir.join(file_name);
+    let parent = target.parent().unwrap_or(&work_dir);
+    tokio::fs::create_dir_all(parent).await?;
+
+    let parent = tokio::fs::canonicalize(parent).await?;
+    if !parent.starts_with(&work_dir) {
+        return Err(InstallerError::Invalid(format!(
+            "{} leads outside of the work dir",
+            file_name
+        )));
+    }
+    if tokio::fs::symlink_metadata(&target)
+        .await
+        .is_ok_and(|x| x.is_symlink())
+    {
+        return Err(InstallerError::Invalid(format!(
+            "{} is a symlink",
+            file_name
+        )));
+    }
+
+    Ok(parent.join(target.file_name().unwrap_or_default()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, os::unix::fs::symlink};
+
+    use axum::{Router, routing::get};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    const ARTIFACT: &[u8] = b"not really a server jar";
+
+    /// Serves the artifact at `/server.jar` until the test ends.
+    async fn serve_artifact() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let app = Router::new().route("/server.jar", get(|| async { ARTIFACT }));
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        format!("http://{}/server.jar", address)
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("lcsm-installer-{}-{}", std::process::id(), name));
+        _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    fn version(url: String, sha256: String) -> PackageVersion {
+        PackageVersion {
+            version: "1.0.0".to_owned(),
+            url,
+            sha256,
+        }
+    }
+
+    async fn download(version: &PackageVersion, target: &Path) -> Result<(), InstallerError> {
+        let installer = InstallerService::new(None);
+        let response = installer.request(version).await?;
+        installer.download(response, version, target).await
+    }
+
+    #[tokio::test]
+    async fn downloads_and_verifies_the_artifact() {
+        let dir = temp_dir("verified");
+        let target = dir.join(".server.jar.download");
+        let sha256 = format!("{:X}", Sha256::digest(ARTIFACT));
+
+        download(&version(serve_artifact().await, sha256), &target)
+            .await
+            .unwrap();
+        assert_eq!(fs::read(&target).unwrap(), ARTIFACT);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejects_a_checksum_mismatch() {
+        let dir = temp_dir("mismatch");
+        let target = dir.join(".server.jar.download");
+
+        let error = download(&version(serve_artifact().await, "00".repeat(32)), &target)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(&error, InstallerError::ChecksumMismatch { actual, .. }
+                if *actual == format!("{:x}", Sha256::digest(ARTIFACT))),
+            "{}",
+            error
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn replaces_a_planted_symlink() {
+        let dir = temp_dir("symlink");
+        let victim = dir.join("victim");
+        fs::write(&victim, "untouched").unwrap();
+        let target = dir.join(".server.jar.download");
+        symlink(&victim, &target).unwrap();
+        let sha256 = format!("{:x}", Sha256::digest(ARTIFACT));
+
+        download(&version(serve_artifact().await, sha256), &target)
+            .await
+            .unwrap();
+        assert!(!fs::symlink_metadata(&target).unwrap().is_symlink());
+        assert_eq!(fs::read(&target).unwrap(), ARTIFACT);
+        assert_eq!(fs::read_to_string(&victim).unwrap(), "untouched");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn reports_server_errors() {
+        let url = serve_artifact().await.replace("server.jar", "missing.jar");
+
+        let error = InstallerService::new(None)
+            .request(&version(url, String::new()))
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(error, InstallerError::DownloadError(_)),
+            "{}",
+            error
+        );
+    }
+
+    #[tokio::test]
+    async fn cancelled_installs_clean_up() {
+        let dir = temp_dir("cancelled");
+        let target = dir.join(".server.jar.download");
+        let installer = InstallerService::new(None);
+        installer.installing.lock().unwrap().insert(1);
+
+        let install = async {
+            let mut guard = InstallGuard {
+                installing: &installer.installing,
+                id: 1,
+                download_path: Some(target.clone()),
+            };
+            fs::write(&target, ARTIFACT).unwrap();
+            // a download that never finishes, like one the client gave up on
+            std::future::pending::<()>().await;
+            guard.download_path = None;
+        };
+        let cancelled = tokio::time::timeout(Duration::from_millis(10), install).await;
+
+        assert!(cancelled.is_err());
+        assert!(!target.exists());
+        assert!(installer.installing.lock().unwrap().is_empty());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,530 @@
+use std::{ffi::OsString, fmt::Display, io, path::PathBuf, time::Duration};
+
+use axum::http::StatusCode;
+use regex::Regex;
+use sea_orm::{DbErr, EntityTrait};
+use serde::Serialize;
+use tokio::sync::{broadcast, mpsc};
+
+use crate::{
+    AppState,
+    entities::instance,
+    errors::StatusCodeError,
+    services::{
+        DependencyError, DependencyGraph, DiskQuotaService, HookKind, ProcessEnvironment,
+        ProcessLaunchOptions, ProcessRef, ProcessState, SecretError, dependency_order,
+        load_dependency_graph, run_hook, stop_order,
+    },
+    transfer::BinarySequence,
+};
+
+/// How long a dependency may take to pass its readiness probe.
+const DEPENDENCY_READY_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Upper bound of the output collected by [`execute_command`].
+const MAX_CAPTURED_OUTPUT: usize = 1024 * 1024;
+
+#[derive(Debug)]
+pub enum InstanceControlError {
+    NotFound,
+    AlreadyRunning,
+    NotRunning,
+    StdinUnavailable,
+    Dependency(String),
+    QuotaExceeded(String),
+    HookFailed(String),
+    DbErr(DbErr),
+    IoError(io::Error),
+    ProcessError(anyhow::Error),
+    SecretError(SecretError),
+}
+
+impl StatusCodeError for InstanceControlError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Self::NotFound | Self::NotRunning => StatusCode::NOT_FOUND,
+            Self::AlreadyRunning => StatusCode::CONFLICT,
+            Self::StdinUnavailable => StatusCode::NOT_ACCEPTABLE,
+            Self::Dependency(_) => StatusCode::FAILED_DEPENDENCY,
+            Self::QuotaExceeded(_) => StatusCode::INSUFFICIENT_STORAGE,
+            Self::HookFailed(_) => StatusCode::PRECONDITION_FAILED,
+            Self::SecretError(e) => e.status_code(),
+            Self::DbErr(_) | Self::IoError(_) | Self::ProcessError(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+    }
+}
+
+impl Display for InstanceControlError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotFound => write!(f, "Instance not found"),
+            Self::AlreadyRunning => write!(f, "Instance is already running"),
+            Self::NotRunning => write!(f, "Instance is not running"),
+            Self::StdinUnavailable => write!(f, "Stdin of the process is unavailable"),
+            Self::Dependency(e) => write!(f, "Dependency error: {}", e),
+            Self::QuotaExceeded(e) => write!(f, "Disk quota exceeded: {}", e),
+            Self::HookFailed(e) => write!(f, "Pre-start hook failed: {}", e),
+            Self::DbErr(e) => e.fmt(f),
+            Self::IoError(e) => e.fmt(f),
+            Self::ProcessError(e) => e.fmt(f),
+            Self::SecretError(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for InstanceControlError {}
+
+impl From<DbErr> for InstanceControlError {
+    fn from(value: DbErr) -> Self {
+        Self::DbErr(value)
+    }
+}
+
+impl From<DependencyError> for InstanceControlError {
+    fn from(value: DependencyError) -> Self {
+        match value {
+            DependencyError::DbErr(e) => Self::DbErr(e),
+            other => Self::Dependency(other.to_string()),
+        }
+    }
+}
+
+impl From<io::Error> for InstanceControlError {
+    fn from(value: io::Error) -> Self {
+        Self::IoError(value)
+    }
+}
+
+async fn find_instance(state: &AppState, id: u64) -> Result<instance::Model, InstanceControlError> {
+    let id = i32::try_from(id).map_err(|_| InstanceControlError::NotFound)?;
+    instance::Entity::find_by_id(id)
+        .one(&state.database)
+        .await?
+        .ok_or(InstanceControlError::NotFound)
+}
+
+fn instance_id(id: u64) -> Result<i32, InstanceControlError> {
+    i32::try_from(id).map_err(|_| InstanceControlError::NotFound)
+}
+
+/// Starts the instance after every dependency of it which is not running yet.
+pub async fn start_instance(state: &AppState, id: u64) -> Result<ProcessRef, InstanceControlError> {
+    // is the process existed?
+    if state.process_manager.get_alive_process(id).await.is_some() {
+        return Err(InstanceControlError::AlreadyRunning);
+    }
+
+    let graph = load_dependency_graph(&state.database).await?;
+    if !graph.contains_key(&instance_id(id)?) {
+        return Err(InstanceControlError::NotFound);
+    }
+
+    for dependency in dependency_order(&graph, [instance_id(id)?])? {
+        let dependency = dependency as u64;
+        if dependency == id
+            || state
+                .process_manager
+                .get_alive_process(dependency)
+                .await
+                .is_some()
+        {
+            continue;
+        }
+
+        let process = match spawn_instance(state, dependency).await {
+            Ok(v) => v,
+            // started by someone else meanwhile
+            Err(InstanceControlError::AlreadyRunning) => {
+                match state.process_manager.get_alive_process(dependency).await {
+                    Some(v) => v,
+                    None => continue,
+                }
+            }
+            Err(e) => {
+                return Err(InstanceControlError::Dependency(format!(
+                    "start instance {}: {}",
+                    dependency, e
+                )));
+            }
+        };
+        wait_until_ready(&process).await.map_err(|e| {
+            InstanceControlError::Dependency(format!("instance {} {}", dependency, e))
+        })?;
+    }
+
+    spawn_instance(state, id).await
+}
+
+/// Waits until the process leaves `starting`, which needs its readiness probe to pass.
+/// Processes without a readiness probe are running right away.
+async fn wait_until_ready(process: &ProcessRef) -> Result<(), String> {
+    let mut status = process.read().await.subscribe_status();
+    let ready = tokio::time::timeout(
+        DEPENDENCY_READY_TIMEOUT,
+        status.wait_for(|x| x.state != ProcessState::Starting),
+    )
+    .await;
+
+    match ready {
+        Ok(Ok(status)) if status.state.is_alive() => Ok(()),
+        Ok(_) => Err("exited before it was ready".to_owned()),
+        Err(_) => Err(format!(
+            "was not ready within {} seconds",
+            DEPENDENCY_READY_TIMEOUT.as_secs()
+        )),
+    }
+}
+
+/// Starts a single instance without looking at its dependencies.
+async fn spawn_instance(state: &AppState, id: u64) -> Result<ProcessRef, InstanceControlError> {
+    // held until the process is tracked, a concurrent start sees it running then
+    let _guard = state
+        .process_manager
+        .begin_start(id)
+        .ok_or(InstanceControlError::AlreadyRunning)?;
+    if state.process_manager.get_alive_process(id).await.is_some() {
+        return Err(InstanceControlError::AlreadyRunning);
+    }
+
+    let the_instance = find_instance(state, id).await?;
+
+    DiskQuotaService::ensure_within_quota(state, &the_instance)
+        .await
+        .map_err(InstanceControlError::QuotaExceeded)?;
+
+    if let Err(e) = run_hook(state, &the_instance, HookKind::PreStart, None).await
+        && the_instance
+            .hooks
+            .pre_start
+            .as_ref()
+            .is_some_and(|x| x.abort_on_failure)
+    {
+        return Err(InstanceControlError::HookFailed(e));
+    }
+
+    let arguments = the_instance
+        .arguments
+        .0
+        .into_iter()
+        .map(|x| x.into())
+        .collect::<Vec<OsString>>();
+
+    // the allow-list may have changed since the instance was saved
+    let run_as = state
+        .run_as_manager
+        .resolve(
+            the_instance.run_as_user.as_deref(),
+            the_instance.run_as_group.as_deref(),
+        )
+        .map_err(|e| InstanceControlError::ProcessError(anyhow::anyhow!(e)))?;
+
+    let environment = ProcessEnvironment {
+        inherit: the_instance.inherit_env,
+        variables: state
+            .secret_manager
+            .open_environment(&the_instance.environment)
+            .map_err(InstanceControlError::SecretError)?,
+    };
+
+    let work_dir = PathBuf::from(the_instance.work_dir);
+    let sandbox = state
+        .sandbox_manager
+        .prepare(&the_instance.sandbox, &work_dir, run_as.as_ref())
+        .map_err(InstanceControlError::IoError)?;
+
+    let process_ref = state
+        .process_manager
+        .new_process(
+            id,
+            the_instance.launch_command,
+            arguments,
+            ProcessLaunchOptions {
+                work_dir,
+                use_shell: the_instance.use_shell,
+                environment,
+                limits: state.limit_manager.prepare(id, &the_instance.limits),
+                run_as,
+                sandbox,
+                wait_for_readiness: the_instance.health_checks.readiness.is_some(),
+            },
+        )
+        .await
+        .map_err(InstanceControlError::ProcessError)?;
+
+    let matcher = state
+        .output_rule_manager
+        .matcher(id, &the_instance.output_rules);
+    _ = state
+        .log_manager
+        .begin_log(id, process_ref.clone(), matcher)
+        .await;
+
+    state
+        .health_manager
+        .watch(id, process_ref.clone(), the_instance.health_checks)
+        .await;
+    process_ref.read().await.release_output();
+
+    Ok(process_ref)
+}
+
+/// Stops the instance after every running instance that depends on it.
+pub async fn stop_instance(state: &AppState, id: u64) -> Result<(), InstanceControlError> {
+    let process = state
+        .process_manager
+        .get_alive_process(id)
+        .await
+        .ok_or(InstanceControlError::NotRunning)?;
+
+    let graph = load_dependency_graph(&state.database).await?;
+    stop_dependents(state, &graph, id).await?;
+
+    kill_process(state, id, process).await
+}
+
+/// Restarts the instance, the dependents stopped along with it are started again afterwards.
+pub async fn restart_instance(
+    state: &AppState,
+    id: u64,
+) -> Result<ProcessRef, InstanceControlError> {
+    let graph = load_dependency_graph(&state.database).await?;
+    let stopped = stop_dependents(state, &graph, id).await?;
+
+    if let Some(process) = state.process_manager.get_alive_process(id).await {
+        process.read().await.set_state(ProcessState::Restarting);
+        kill_process(state, id, process).await?;
+    }
+
+    let process_ref = start_instance(state, id).await?;
+
+    for dependent in stopped {
+        match start_instance(state, dependent).await {
+            Ok(_) | Err(InstanceControlError::AlreadyRunning) => {}
+            Err(e) => {
+                return Err(InstanceControlError::Dependency(format!(
+                    "start instance {}: {}",
+                    dependent, e
+                )));
+            }
+        }
+    }
+
+    Ok(process_ref)
+}
+
+/// Runs the pre-stop hook first, a failing one doesn't keep the process alive.
+async fn kill_process(
+    state: &AppState,
+    id: u64,
+    process: ProcessRef,
+) -> Result<(), InstanceControlError> {
+    if let Ok(the_instance) = find_instance(state, id).await {
+        _ = run_hook(state, &the_instance, HookKind::PreStop, None).await;
+    }
+
+    process
+        .read()
+        .await
+        .kill()
+        .await
+        .map_err(InstanceControlError::ProcessError)
+}
+
+/// Stops running dependents of an instance, returns them in the order they should be started.
+async fn stop_dependents(
+    state: &AppState,
+    graph: &DependencyGraph,
+    id: u64,
+) -> Result<Vec<u64>, InstanceControlError> {
+    let mut stopped = Vec::new();
+    for dependent in stop_order(graph, instance_id(id)?)? {
+        let dependent = dependent as u64;
+        if let Some(process) = state.process_manager.get_alive_process(dependent).await {
+            kill_process(state, dependent, process).await?;
+            stopped.push(dependent);
+        }
+    }
+
+    stopped.reverse();
+    Ok(stopped)
+}
+
+pub async fn send_command(
+    state: &AppState,
+    id: u64,
+    command: impl AsRef<str>,
+) -> Result<(), InstanceControlError> {
+    let process = state
+        .process_manager
+        .get_alive_process(id)
+        .await
+        .ok_or(InstanceControlError::NotRunning)?;
+
+    write_line(&process, command.as_ref()).await
+}
+
+async fn write_line(process: &ProcessRef, command: &str) -> Result<(), InstanceControlError> {
+    let stdin =
+        { process.read().await.get_stdin() }.ok_or(InstanceControlError::StdinUnavailable)?;
+
+    let mut line = command.to_owned().into_bytes();
+    line.push(b'\n');
+
+    stdin
+        .send(line)
+        .await
+        .map_err(|_| InstanceControlError::StdinUnavailable)
+}
+
+/// How the output following a command is collected.
+pub struct CaptureOptions {
+    /// Collection stops after this long, or earlier once `until` matches.
+    pub window: Duration,
+    pub until: Option<Regex>,
+    pub include_stderr: bool,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CapturedOutput {
+    pub output: String,
+    /// Whether `until` matched before the window ran out.
+    pub matched: bool,
+    /// Whether output was dropped because it was too large or came too fast.
+    pub truncated: bool,
+}
+
+/// Sends a command and collects the output emitted in response to it.
+///
+/// Output of anything else the process prints meanwhile ends up in the result too,
+/// the process has no way of telling them apart.
+pub async fn execute_command(
+    state: &AppState,
+    id: u64,
+    command: &str,
+    options: CaptureOptions,
+) -> Result<CapturedOutput, InstanceControlError> {
+    let process = state
+        .process_manager
+        .get_alive_process(id)
+        .await
+        .ok_or(InstanceControlError::NotRunning)?;
+
+    // subscribe before writing so a quick answer isn't missed
+    let (mut status, stdout, stderr) = {
+        let process = process.read().await;
+        (
+            process.subscribe_status(),
+            process.get_stdout(),
+            process.get_stderr().filter(|_| options.include_stderr),
+        )
+    };
+    let (sender, mut output) = mpsc::unbounded_channel();
+    let forwarders = [stdout, stderr]
+        .into_iter()
+        .flatten()
+        .map(|receiver| tokio::spawn(forward_output(receiver, sender.clone())))
+        .collect::<Vec<_>>();
+    drop(sender);
+
+    write_line(&process, command).await?;
+
+    let mut captured = CapturedOutput {
+        output: String::new(),
+        matched: false,
+        truncated: false,
+    };
+    let mut buffer = Vec::new();
+    // only lines not checked yet are matched again
+    let mut scan_from = 0;
+    let collect = async {
+        while let Some(chunk) = output.recv().await {
+            let Some(data) = chunk else {
+                captured.truncated = true;
+                continue;
+            };
+
+            let room = MAX_CAPTURED_OUTPUT - buffer.len();
+            if data.len() > room {
+                captured.truncated = true;
+            }
+            buffer.extend_from_slice(&data[..data.len().min(room)]);
+
+            if let Some(until) = &options.until {
+                let text = String::from_utf8_lossy(&buffer[scan_from..]);
+                if until.is_match(&text) {
+                    captured.matched = true;
+                    return;
+                }
+                if let Some(end) = buffer.iter().rposition(|x| *x == b'\n') {
+                    scan_from = end + 1;
+                }
+            }
+            if buffer.len() >= MAX_CAPTURED_OUTPUT {
+                return;
+            }
+        }
+    };
+
+    tokio::select! {
+        _ = collect => {}
+        _ = tokio::time::sleep(options.window) => {}
+        _ = status.wait_for(|x| !x.state.is_alive()) => {}
+    }
+    for forwarder in forwarders {
+        forwarder.abort();
+    }
+
+    captured.output = String::from_utf8_lossy(&buffer).into_owned();
+    Ok(captured)
+}
+
+/// Relays output chunks, `None` marks chunks lost because the receiver lagged.
+async fn forward_output(
+    mut receiver: broadcast::Receiver<BinarySequence>,
+    sender: mpsc::UnboundedSender<Option<BinarySequence>>,
+) {
+    loop {
+        let chunk = match receiver.recv().await {
+            Ok(v) => Some(v),
+            Err(broadcast::error::RecvError::Lagged(_)) => None,
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+        if sender.send(chunk).is_err() {
+            break;
+        }
+    }
+}
+
+pub async fn backup_instance(state: &AppState, id: u64) -> Result<PathBuf, InstanceControlError> {
+    let the_instance = find_instance(state, id).await?;
+
+    // let clients know a backup is in progress, the process keeps running meanwhile
+    let process = state.process_manager.get_alive_process(id).await;
+    let previous_state = match &process {
+        Some(process) => {
+            let process = process.read().await;
+            let previous_state = process.state();
+            process.set_state(ProcessState::BackingUp);
+            Some(previous_state)
+        }
+        None => None,
+    };
+
+    let result = state
+        .backup_manager
+        .create_backup(id, the_instance.work_dir)
+        .await;
+
+    if let (Some(process), Some(previous_state)) = (process, previous_state) {
+        // a stop or restart requested meanwhile wins
+        process
+            .read()
+            .await
+            .set_state_from(ProcessState::BackingUp, previous_state);
+    }
+
+    Ok(result?)
+}
@@ -0,0 +1,264 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use serde::Serialize;
+use tokio::process::Command;
+
+use crate::entities::instance::ResourceLimits;
+
+const CPU_PERIOD_US: u64 = 100_000;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum LimitEnforcement {
+    Cgroup,
+    Rlimit,
+    #[default]
+    None,
+}
+
+/// The limits that are actually enforced on a process, unsupported ones are left out.
+#[derive(Debug, Default, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EffectiveLimits {
+    pub enforcement: LimitEnforcement,
+    pub memory_max: Option<u64>,
+    pub cpu_quota: Option<u32>,
+    pub cpu_weight: Option<u32>,
+    pub pids_max: Option<u64>,
+    pub open_files: Option<u64>,
+}
+
+/// Limits ready to be applied to a command, see [`PreparedLimits::apply`].
+#[derive(Default)]
+pub struct PreparedLimits {
+    pub effective: EffectiveLimits,
+    cgroup: Option<PathBuf>,
+}
+
+pub struct LimitService {
+    cgroup_root: Option<PathBuf>,
+    /// Every process gets a cgroup of its own, so cleaning up after an exited process
+    /// can't remove the cgroup a restart of the same instance already moved into.
+    generation: AtomicU64,
+}
+
+impl LimitService {
+    /// `cgroup_root` must be a cgroup v2 directory delegated to the slave, or `None` to use rlimits only.
+    pub fn new(cgroup_root: Option<PathBuf>) -> Self {
+        let cgroup_root = cgroup_root.filter(|root| {
+            let usable = cfg!(target_os = "linux") && root.join("cgroup.controllers").exists();
+            if !usable {
+                tracing::warn!(
+                    "{} is not a cgroup v2 directory, falling back to rlimits",
+                    root.display()
+                );
+            }
+            usable
+        });
+
+        Self {
+            cgroup_root,
+            generation: AtomicU64::new(0),
+        }
+    }
+
+    pub fn prepare(&self, id: u64, limits: &ResourceLimits) -> PreparedLimits {
+        if limits.is_empty() {
+            return PreparedLimits::default();
+        }
+
+        if let Some(root) = &self.cgroup_root {
+            let generation = self.generation.fetch_add(1, Ordering::Relaxed);
+            match Self::prepare_cgroup(root, id, generation, limits) {
+                Ok(v) => return v,
+                Err(e) => tracing::warn!(
+                    "Failed to set up cgroup for instance {}, falling back to rlimits: {}",
+                    id,
+                    e
+                ),
+            }
+        }
+
+        // rlimits don't mean the same: RLIMIT_AS caps virtual memory, which breaks JVMs,
+        // RLIMIT_NPROC counts every process of the user and there is none for CPU bandwidth
+        let without_cgroup = ResourceLimits {
+            open_files: None,
+            ..limits.clone()
+        };
+        if !without_cgroup.is_empty() {
+            tracing::warn!(
+                "Memory, CPU and pids limits of instance {} need cgroups, they are not enforced",
+                id
+            );
+        }
+
+        let open_files = limits.open_files.filter(|_| cfg!(unix));
+        PreparedLimits {
+            effective: EffectiveLimits {
+                enforcement: if open_files.is_some() {
+                    LimitEnforcement::Rlimit
+                } else {
+                    LimitEnforcement::None
+                },
+                open_files,
+                ..Default::default()
+            },
+            cgroup: None,
+        }
+    }
+
+    fn prepare_cgroup(
+        root: &Path,
+        id: u64,
+        generation: u64,
+        limits: &ResourceLimits,
+    ) -> Result<PreparedLimits, io::Error> {
+        // controllers must be enabled for children, they may be enabled already
+        for controller in ["+memory", "+cpu", "+pids"] {
+            if let Err(e) = fs::write(root.join("cgroup.subtree_control"), controller) {
+                tracing::debug!("enable cgroup controller {}: {}", controller, e);
+            }
+        }
+
+        let cgroup = root.join(format!("instance-{}-{}", id, generation));
+        if !cgroup.exists() {
+            fs::create_dir(&cgroup)?;
+        }
+
+        let write = |file: &str, value: Option<String>| {
+            fs::write(cgroup.join(file), value.unwrap_or_else(|| "max".to_owned()))
+        };
+        write("memory.max", limits.memory_max.map(|x| x.to_string()))?;
+        write("pids.max", limits.pids_max.map(|x| x.to_string()))?;
+        fs::write(
+            cgroup.join("cpu.max"),
+            match limits.cpu_quota {
+                Some(percent) => {
+                    format!("{} {}", percent as u64 * CPU_PERIOD_US / 100, CPU_PERIOD_US)
+                }
+                None => format!("max {}", CPU_PERIOD_US),
+            },
+        )?;
+        fs::write(
+            cgroup.join("cpu.weight"),
+            limits.cpu_weight.unwrap_or(100).to_string(),
+        )?;
+
+        Ok(PreparedLimits {
+            effective: EffectiveLimits {
+                enforcement: LimitEnforcement::Cgroup,
+                memory_max: limits.memory_max,
+                cpu_quota: limits.cpu_quota,
+                cpu_weight: limits.cpu_weight,
+                pids_max: limits.pids_max,
+                open_files: limits.open_files,
+            },
+            cgroup: Some(cgroup),
+        })
+    }
+}
+
+impl PreparedLimits {
+    /// Installs a pre-exec hook that moves the child into its cgroup or sets rlimits.
+    pub fn apply(&self, command: &mut Command) -> Result<(), io::Error> {
+        #[cfg(unix)]
+        {
+            if self.effective.enforcement == LimitEnforcement::None {
+                return Ok(());
+            }
+
+            let hook = pre_exec::PreExecLimits::new(self)?;
+            unsafe {
+                command.pre_exec(move || hook.run());
+            }
+        }
+
+        #[cfg(not(unix))]
+        let _ = command;
+
+        Ok(())
+    }
+
+    /// Removes the cgroup once the process has exited.
+    pub fn cleanup(&self) {
+        if let Some(cgroup) = &self.cgroup
+            && let Err(e) = fs::remove_dir(cgroup)
+        {
+            tracing::warn!("Failed to remove cgroup {}: {}", cgroup.display(), e);
+        }
+    }
+}
+
+#[cfg(unix)]
+mod pre_exec {
+    use std::{ffi::CString, io, os::unix::ffi::OsStrExt};
+
+    use super::PreparedLimits;
+
+    /// Everything is prepared before fork, the hook itself only makes async-signal-safe calls.
+    pub struct PreExecLimits {
+        cgroup_procs: Option<CString>,
+        open_files: Option<u64>,
+    }
+
+    impl PreExecLimits {
+        pub fn new(limits: &PreparedLimits) -> Result<Self, io::Error> {
+            let cgroup_procs = limits
+                .cgroup
+                .as_ref()
+                .map(|x| CString::new(x.join("cgroup.procs").as_os_str().as_bytes()))
+                .transpose()?;
+
+            Ok(Self {
+                cgroup_procs,
+                open_files: limits.effective.open_files,
+            })
+        }
+
+        pub fn run(&self) -> io::Result<()> {
+            if let Some(cgroup_procs) = &self.cgroup_procs {
+                // writing 0 moves the calling process
+                unsafe {
+                    let fd = libc::open(cgroup_procs.as_ptr(), libc::O_WRONLY);
+                    if fd < 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                    let written = libc::write(fd, b"0".as_ptr().cast(), 1);
+                    libc::close(fd);
+                    if written != 1 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+            }
+
+            set_rlimit(libc::RLIMIT_NOFILE, self.open_files)?;
+
+            Ok(())
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    type Resource = libc::__rlimit_resource_t;
+    #[cfg(not(target_os = "linux"))]
+    type Resource = libc::c_int;
+
+    fn set_rlimit(resource: Resource, value: Option<u64>) -> io::Result<()> {
+        let Some(value) = value else {
+            return Ok(());
+        };
+
+        let limit = libc::rlimit {
+            rlim_cur: value as libc::rlim_t,
+            rlim_max: value as libc::rlim_t,
+        };
+        if unsafe { libc::setrlimit(resource, &limit) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+}
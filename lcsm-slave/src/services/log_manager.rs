@@ -7,7 +7,7 @@ use tokio::{
 };
 use tracing::{Instrument, instrument};
 
-use crate::services::ProcessRef;
+use crate::services::{OutputMatcher, OutputStream, ProcessRef};
 
 pub struct LogService {
     log_path: PathBuf,
@@ -27,8 +27,14 @@ impl LogService {
         Ok(log_file.metadata().await?.len())
     }
 
-    #[instrument(skip(process_ref, self), parent = None)]
-    pub async fn begin_log(&self, id: u64, process_ref: ProcessRef) -> Result<(), io::Error> {
+    /// Writes the output of the process to its log, feeding it to the matcher on the way.
+    #[instrument(skip(process_ref, matcher, self), parent = None)]
+    pub async fn begin_log(
+        &self,
+        id: u64,
+        process_ref: ProcessRef,
+        mut matcher: Option<OutputMatcher>,
+    ) -> Result<(), io::Error> {
         // prepare for streams
         let (mut stdout, mut stderr) = {
             let process = process_ref.read().await;
@@ -49,9 +55,13 @@ impl LogService {
                 tracing::info!("Logger for process {} started", id);
 
                 loop {
-                    let data = tokio::select! {
-                        data = stdout.as_mut().unwrap().recv(), if stdout.is_some() => data,
-                        data = stderr.as_mut().unwrap().recv(), if stderr.is_some() => data,
+                    let (stream, data) = tokio::select! {
+                        data = stdout.as_mut().unwrap().recv(), if stdout.is_some() => {
+                            (OutputStream::Stdout, data)
+                        }
+                        data = stderr.as_mut().unwrap().recv(), if stderr.is_some() => {
+                            (OutputStream::Stderr, data)
+                        }
                         else => break,
                     };
 
@@ -64,6 +74,9 @@ impl LogService {
                         },
 
                         Ok(message) => {
+                            if let Some(matcher) = matcher.as_mut() {
+                                matcher.feed(stream, &message);
+                            }
                             if let Err(e) = file.write(&message).await {
                                 tracing::error!("Logger for process {} error: {}", id, e);
                                 break;
@@ -0,0 +1,313 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::sync::RwLock;
+use tracing::Instrument;
+
+use crate::AppStateRef;
+
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Number of samples kept per process, 10 minutes with the interval above.
+const HISTORY_SIZE: usize = 120;
+
+/// Resource usage of a process and all of its descendants.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ResourceSnapshot {
+    pub sampled_at: DateTime<Utc>,
+    pub process_count: u64,
+    pub cpu_percent: f64,
+    pub rss_bytes: u64,
+    pub threads: u64,
+    pub open_files: u64,
+    pub read_bytes: u64,
+    pub write_bytes: u64,
+}
+
+struct ProcessMetrics {
+    pid: u32,
+    last_cpu_ticks: u64,
+    last_sampled: Instant,
+    history: VecDeque<ResourceSnapshot>,
+}
+
+#[derive(Default)]
+pub struct MetricsService {
+    metrics: RwLock<HashMap<u64, ProcessMetrics>>,
+}
+
+impl MetricsService {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub async fn get_latest(&self, id: u64) -> Option<ResourceSnapshot> {
+        self.metrics
+            .read()
+            .await
+            .get(&id)
+            .and_then(|x| x.history.back().cloned())
+    }
+
+    pub async fn get_history(&self, id: u64) -> Vec<ResourceSnapshot> {
+        self.metrics
+            .read()
+            .await
+            .get(&id)
+            .map(|x| x.history.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn start(state: AppStateRef) {
+        if !cfg!(target_os = "linux") {
+            tracing::warn!("Resource metrics are only available on Linux");
+            return;
+        }
+
+        tokio::spawn(
+            async move {
+                let mut interval = tokio::time::interval(SAMPLE_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    Self::sample_all(&state).await;
+                }
+            }
+            .instrument(tracing::info_span!(parent: None, "metrics sampler")),
+        );
+    }
+
+    async fn sample_all(state: &AppStateRef) {
+        let mut alive = HashMap::new();
+        for (id, process) in state.process_manager.list_processes().await {
+            let process = process.read().await;
+            if !process.state().is_alive() {
+                continue;
+            }
+            if let Some(pid) = process.pid() {
+                alive.insert(id, pid);
+            }
+        }
+
+        let usages = tokio::task::spawn_blocking(move || {
+            let tree = procfs::read_process_tree();
+            alive
+                .into_iter()
+                .filter_map(|(id, pid)| Some((id, pid, procfs::read_tree_usage(&tree, pid)?)))
+                .collect::<Vec<_>>()
+        })
+        .await
+        .unwrap_or_default();
+
+        let now = Instant::now();
+        let mut metrics = state.metrics.metrics.write().await;
+
+        // forget exited processes
+        metrics.retain(|id, _| usages.iter().any(|(x, _, _)| x == id));
+
+        for (id, pid, usage) in usages {
+            let entry = metrics
+                .entry(id)
+                .and_modify(|x| {
+                    // a new process was started for this instance
+                    if x.pid != pid {
+                        x.history.clear();
+                    }
+                })
+                .or_insert_with(|| ProcessMetrics {
+                    pid,
+                    last_cpu_ticks: usage.cpu_ticks,
+                    last_sampled: now,
+                    history: VecDeque::with_capacity(HISTORY_SIZE),
+                });
+
+            let elapsed = now.duration_since(entry.last_sampled).as_secs_f64();
+            let cpu_percent = if entry.pid == pid && elapsed > 0.0 {
+                let ticks = usage.cpu_ticks.saturating_sub(entry.last_cpu_ticks) as f64;
+                ticks / procfs::clock_ticks_per_second() / elapsed * 100.0
+            } else {
+                0.0
+            };
+
+            entry.pid = pid;
+            entry.last_cpu_ticks = usage.cpu_ticks;
+            entry.last_sampled = now;
+
+            if entry.history.len() == HISTORY_SIZE {
+                entry.history.pop_front();
+            }
+            entry.history.push_back(ResourceSnapshot {
+                sampled_at: Utc::now(),
+                process_count: usage.process_count,
+                cpu_percent,
+                rss_bytes: usage.rss_bytes,
+                threads: usage.threads,
+                open_files: usage.open_files,
+                read_bytes: usage.read_bytes,
+                write_bytes: usage.write_bytes,
+            });
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod procfs {
+    use std::{collections::HashMap, fs};
+
+    #[derive(Default)]
+    pub struct TreeUsage {
+        pub process_count: u64,
+        pub cpu_ticks: u64,
+        pub rss_bytes: u64,
+        pub threads: u64,
+        pub open_files: u64,
+        pub read_bytes: u64,
+        pub write_bytes: u64,
+    }
+
+    struct Stat {
+        ppid: u32,
+        cpu_ticks: u64,
+        threads: u64,
+        rss_pages: u64,
+    }
+
+    pub fn clock_ticks_per_second() -> f64 {
+        match unsafe { libc::sysconf(libc::_SC_CLK_TCK) } {
+            n if n > 0 => n as f64,
+            _ => 100.0,
+        }
+    }
+
+    fn page_size() -> u64 {
+        match unsafe { libc::sysconf(libc::_SC_PAGESIZE) } {
+            n if n > 0 => n as u64,
+            _ => 4096,
+        }
+    }
+
+    fn read_stat(pid: u32) -> Option<Stat> {
+        let content = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+
+        // the command name may contain spaces and parentheses, skip to the last `)`
+        let fields = content[content.rfind(')')? + 1..]
+            .split_whitespace()
+            .collect::<Vec<_>>();
+
+        // fields are counted from `state`, which is the 3rd field in proc(5)
+        let field = |n: usize| fields.get(n - 3)?.parse::<u64>().ok();
+
+        Some(Stat {
+            ppid: field(4)? as u32,
+            cpu_ticks: field(14)? + field(15)?,
+            threads: field(20)?,
+            rss_pages: field(24)?,
+        })
+    }
+
+    /// Maps every process to its children.
+    pub fn read_process_tree() -> HashMap<u32, Vec<u32>> {
+        let mut tree: HashMap<u32, Vec<u32>> = HashMap::new();
+        let Ok(entries) = fs::read_dir("/proc") else {
+            return tree;
+        };
+
+        for entry in entries.flatten() {
+            let Some(pid) = entry.file_name().to_str().and_then(|x| x.parse().ok()) else {
+                continue;
+            };
+            if let Some(stat) = read_stat(pid) {
+                tree.entry(stat.ppid).or_default().push(pid);
+            }
+        }
+
+        tree
+    }
+
+    fn read_io(pid: u32) -> (u64, u64) {
+        let Ok(content) = fs::read_to_string(format!("/proc/{}/io", pid)) else {
+            return (0, 0);
+        };
+
+        let mut read_bytes = 0;
+        let mut write_bytes = 0;
+        for line in content.lines() {
+            let Some((key, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim().parse().unwrap_or(0);
+            match key {
+                "read_bytes" => read_bytes = value,
+                "write_bytes" => write_bytes = value,
+                _ => {}
+            }
+        }
+
+        (read_bytes, write_bytes)
+    }
+
+    fn count_open_files(pid: u32) -> u64 {
+        fs::read_dir(format!("/proc/{}/fd", pid))
+            .map(|x| x.count() as u64)
+            .unwrap_or(0)
+    }
+
+    /// Sums the usage of `root` and its descendants, `None` if `root` is gone.
+    pub fn read_tree_usage(tree: &HashMap<u32, Vec<u32>>, root: u32) -> Option<TreeUsage> {
+        read_stat(root)?;
+
+        let page_size = page_size();
+        let mut usage = TreeUsage::default();
+        let mut pending = vec![root];
+
+        while let Some(pid) = pending.pop() {
+            let Some(stat) = read_stat(pid) else {
+                continue;
+            };
+            let (read_bytes, write_bytes) = read_io(pid);
+
+            usage.process_count += 1;
+            usage.cpu_ticks += stat.cpu_ticks;
+            usage.rss_bytes += stat.rss_pages * page_size;
+            usage.threads += stat.threads;
+            usage.open_files += count_open_files(pid);
+            usage.read_bytes += read_bytes;
+            usage.write_bytes += write_bytes;
+
+            pending.extend(tree.get(&pid).into_iter().flatten());
+        }
+
+        Some(usage)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod procfs {
+    use std::collections::HashMap;
+
+    pub struct TreeUsage {
+        pub process_count: u64,
+        pub cpu_ticks: u64,
+        pub rss_bytes: u64,
+        pub threads: u64,
+        pub open_files: u64,
+        pub read_bytes: u64,
+        pub write_bytes: u64,
+    }
+
+    pub fn clock_ticks_per_second() -> f64 {
+        100.0
+    }
+
+    pub fn read_process_tree() -> HashMap<u32, Vec<u32>> {
+        HashMap::new()
+    }
+
+    pub fn read_tree_usage(_tree: &HashMap<u32, Vec<u32>>, _root: u32) -> Option<TreeUsage> {
+        None
+    }
+}
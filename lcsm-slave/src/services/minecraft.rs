@@ -0,0 +1,336 @@
+use std::{
+    fmt::Display,
+    io,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use crate::entities::instance::ServerAddress;
+
+const STATUS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Responses carry a base64 favicon, anything much larger is not a Minecraft server.
+const MAX_PACKET_LENGTH: usize = 2 * 1024 * 1024;
+
+/// Sent in the handshake, servers answer a status request regardless of the version.
+const PROTOCOL_VERSION: i32 = -1;
+
+/// What a Minecraft server reports in the Server List Ping.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerStatus {
+    pub version: String,
+    pub protocol: i32,
+    pub players_online: i64,
+    pub players_max: i64,
+    /// Names of some online players, servers may leave it out.
+    pub player_sample: Vec<String>,
+    /// The description as plain text, without formatting codes.
+    pub motd: String,
+    /// The description as sent, either a string or a chat component.
+    pub description: Value,
+    pub favicon: Option<String>,
+    pub latency_ms: u64,
+}
+
+#[derive(Debug)]
+pub enum StatusError {
+    IoError(io::Error),
+    Timeout,
+    Protocol(String),
+}
+
+impl Display for StatusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::IoError(e) => e.fmt(f),
+            Self::Timeout => write!(f, "Server did not respond in time"),
+            Self::Protocol(e) => write!(f, "Invalid response: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for StatusError {}
+
+impl From<io::Error> for StatusError {
+    fn from(value: io::Error) -> Self {
+        Self::IoError(value)
+    }
+}
+
+#[derive(Deserialize)]
+struct StatusResponse {
+    version: StatusVersion,
+    players: Option<StatusPlayers>,
+    #[serde(default)]
+    description: Value,
+    favicon: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct StatusVersion {
+    name: String,
+    protocol: i32,
+}
+
+#[derive(Deserialize)]
+struct StatusPlayers {
+    max: i64,
+    online: i64,
+    #[serde(default)]
+    sample: Vec<StatusPlayer>,
+}
+
+#[derive(Deserialize)]
+struct StatusPlayer {
+    name: String,
+}
+
+/// Performs the Server List Ping handshake, followed by a ping to measure the latency.
+pub async fn query_server_status(address: &ServerAddress) -> Result<ServerStatus, StatusError> {
+    tokio::time::timeout(STATUS_TIMEOUT, query(address))
+        .await
+        .map_err(|_| StatusError::Timeout)?
+}
+
+async fn query(address: &ServerAddress) -> Result<ServerStatus, StatusError> {
+    let mut stream = TcpStream::connect((address.host.as_str(), address.port)).await?;
+
+    let mut handshake = Vec::new();
+    write_varint(&mut handshake, PROTOCOL_VERSION);
+    write_string(&mut handshake, &address.host);
+    handshake.extend_from_slice(&address.port.to_be_bytes());
+    // next state: status
+    write_varint(&mut handshake, 1);
+    send_packet(&mut stream, 0x00, &handshake).await?;
+    send_packet(&mut stream, 0x00, &[]).await?;
+
+    let (id, payload) = read_packet(&mut stream).await?;
+    if id != 0x00 {
+        return Err(StatusError::Protocol(format!(
+            "unexpected packet {:#x}",
+            id
+        )));
+    }
+    let mut payload = payload.as_slice();
+    let json = read_string(&mut payload).await?;
+    let response: StatusResponse =
+        serde_json::from_str(&json).map_err(|e| StatusError::Protocol(e.to_string()))?;
+
+    let sent_at = Instant::now();
+    let token = chrono::Utc::now().timestamp_millis();
+    send_packet(&mut stream, 0x01, &token.to_be_bytes()).await?;
+    let (id, payload) = read_packet(&mut stream).await?;
+    if id != 0x01 || payload != token.to_be_bytes() {
+        return Err(StatusError::Protocol("unexpected pong".to_owned()));
+    }
+    let latency_ms = sent_at.elapsed().as_millis() as u64;
+
+    let players = response.players.unwrap_or(StatusPlayers {
+        max: 0,
+        online: 0,
+        sample: Vec::new(),
+    });
+    Ok(ServerStatus {
+        version: response.version.name,
+        protocol: response.version.protocol,
+        players_online: players.online,
+        players_max: players.max,
+        player_sample: players.sample.into_iter().map(|x| x.name).collect(),
+        motd: strip_formatting(&component_text(&response.description)),
+        description: response.description,
+        favicon: response.favicon,
+        latency_ms,
+    })
+}
+
+fn write_varint(buffer: &mut Vec<u8>, value: i32) {
+    let mut value = value as u32;
+    loop {
+        if value & !0x7f == 0 {
+            buffer.push(value as u8);
+            return;
+        }
+        buffer.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+}
+
+fn write_string(buffer: &mut Vec<u8>, value: &str) {
+    write_varint(buffer, value.len() as i32);
+    buffer.extend_from_slice(value.as_bytes());
+}
+
+async fn read_varint(reader: &mut (impl AsyncRead + Unpin)) -> Result<i32, StatusError> {
+    let mut value = 0u32;
+    for position in 0..5 {
+        let byte = reader.read_u8().await?;
+        value |= ((byte & 0x7f) as u32) << (position * 7);
+        if byte & 0x80 == 0 {
+            return Ok(value as i32);
+        }
+    }
+
+    Err(StatusError::Protocol("varint is too long".to_owned()))
+}
+
+async fn read_string(reader: &mut (impl AsyncRead + Unpin)) -> Result<String, StatusError> {
+    let length = read_varint(reader).await?;
+    let length = usize::try_from(length)
+        .ok()
+        .filter(|x| *x <= MAX_PACKET_LENGTH)
+        .ok_or_else(|| StatusError::Protocol(format!("invalid string length {}", length)))?;
+
+    let mut buffer = vec![0; length];
+    reader.read_exact(&mut buffer).await?;
+    String::from_utf8(buffer).map_err(|e| StatusError::Protocol(e.to_string()))
+}
+
+async fn send_packet(stream: &mut TcpStream, id: i32, data: &[u8]) -> Result<(), StatusError> {
+    let mut body = Vec::new();
+    write_varint(&mut body, id);
+    body.extend_from_slice(data);
+
+    let mut packet = Vec::new();
+    write_varint(&mut packet, body.len() as i32);
+    packet.extend_from_slice(&body);
+    stream.write_all(&packet).await?;
+
+    Ok(())
+}
+
+async fn read_packet(stream: &mut TcpStream) -> Result<(i32, Vec<u8>), StatusError> {
+    let length = read_varint(stream).await?;
+    let length = usize::try_from(length)
+        .ok()
+        .filter(|x| (1..=MAX_PACKET_LENGTH).contains(x))
+        .ok_or_else(|| StatusError::Protocol(format!("invalid packet length {}", length)))?;
+
+    let mut body = vec![0; length];
+    stream.read_exact(&mut body).await?;
+
+    let mut body = body.as_slice();
+    let id = read_varint(&mut body).await?;
+    Ok((id, body.to_vec()))
+}
+
+/// Flattens a chat component, which is a string, an array or an object with `text` and `extra`.
+fn component_text(component: &Value) -> String {
+    match component {
+        Value::String(text) => text.clone(),
+        Value::Array(parts) => parts.iter().map(component_text).collect(),
+        Value::Object(object) => {
+            let mut text = object
+                .get("text")
+                .and_then(|x| x.as_str())
+                .unwrap_or_default()
+                .to_owned();
+            if let Some(extra) = object.get("extra") {
+                text.push_str(&component_text(extra));
+            }
+            text
+        }
+        _ => String::new(),
+    }
+}
+
+/// Removes legacy `§` formatting codes.
+fn strip_formatting(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '§' {
+            chars.next();
+        } else {
+            result.push(c);
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    /// Answers a single Server List Ping like a vanilla server.
+    async fn serve_status(listener: TcpListener, response: Value) -> Result<(), StatusError> {
+        let (mut stream, _) = listener.accept().await?;
+
+        let (id, handshake) = read_packet(&mut stream).await?;
+        assert_eq!(id, 0x00);
+        let mut handshake = handshake.as_slice();
+        assert_eq!(read_varint(&mut handshake).await?, PROTOCOL_VERSION);
+        assert_eq!(read_string(&mut handshake).await?, "127.0.0.1");
+        handshake.read_u16().await?;
+        assert_eq!(read_varint(&mut handshake).await?, 1);
+
+        let (id, request) = read_packet(&mut stream).await?;
+        assert_eq!((id, request.len()), (0x00, 0));
+        let mut payload = Vec::new();
+        write_string(&mut payload, &response.to_string());
+        send_packet(&mut stream, 0x00, &payload).await?;
+
+        let (id, token) = read_packet(&mut stream).await?;
+        assert_eq!(id, 0x01);
+        send_packet(&mut stream, 0x01, &token).await
+    }
+
+    #[tokio::test]
+    async fn queries_server_list_ping() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = ServerAddress {
+            host: "127.0.0.1".to_owned(),
+            port: listener.local_addr().unwrap().port(),
+        };
+        let server = tokio::spawn(serve_status(
+            listener,
+            json!({
+                "version": { "name": "1.21.4", "protocol": 769 },
+                "players": {
+                    "max": 20,
+                    "online": 2,
+                    "sample": [{ "name": "Alex", "id": "0" }, { "name": "Steve", "id": "1" }],
+                },
+                "description": { "text": "§aA ", "extra": [{ "text": "server" }] },
+            }),
+        ));
+
+        let status = query_server_status(&address).await.unwrap();
+        server.await.unwrap().unwrap();
+
+        assert_eq!(status.version, "1.21.4");
+        assert_eq!(status.protocol, 769);
+        assert_eq!((status.players_online, status.players_max), (2, 20));
+        assert_eq!(status.player_sample, ["Alex", "Steve"]);
+        assert_eq!(status.motd, "A server");
+        assert_eq!(status.favicon, None);
+    }
+
+    #[tokio::test]
+    async fn rejects_an_unexpected_packet() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = ServerAddress {
+            host: "127.0.0.1".to_owned(),
+            port: listener.local_addr().unwrap().port(),
+        };
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await?;
+            read_packet(&mut stream).await?;
+            read_packet(&mut stream).await?;
+            send_packet(&mut stream, 0x02, &[]).await
+        });
+
+        let error = query_server_status(&address).await.unwrap_err();
+        assert!(matches!(error, StatusError::Protocol(_)), "{}", error);
+    }
+}
@@ -1,4 +1,52 @@
+mod autostart;
+mod backup;
+mod bundles;
+mod clone;
+mod config_files;
+mod crash_reports;
+mod dependencies;
+mod disk_quota;
+mod health;
+mod hooks;
+mod installer;
+mod instance_control;
+mod limits;
 mod log_manager;
+mod metrics;
+mod minecraft;
+mod output_rules;
 mod process_manager;
+mod rcon;
+mod run_as;
+mod run_history;
+mod sandbox;
+mod scheduler;
+mod secrets;
+mod templates;
+mod work_dir;
+pub use autostart::*;
+pub use backup::*;
+pub use bundles::*;
+pub use clone::*;
+pub use config_files::*;
+pub use crash_reports::*;
+pub use dependencies::*;
+pub use disk_quota::*;
+pub use health::*;
+pub use hooks::*;
+pub use installer::*;
+pub use instance_control::*;
+pub use limits::*;
 pub use log_manager::*;
+pub use metrics::*;
+pub use minecraft::*;
+pub use output_rules::*;
 pub use process_manager::*;
+pub use rcon::*;
+pub use run_as::*;
+pub use run_history::*;
+pub use sandbox::*;
+pub use scheduler::*;
+pub use secrets::*;
+pub use templates::*;
+pub use work_dir::*;
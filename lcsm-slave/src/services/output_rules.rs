@@ -0,0 +1,429 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use chrono::Utc;
+use regex::Regex;
+use sea_orm::{
+    ActiveModelTrait, ActiveValue::NotSet, ColumnTrait, DbErr, EntityTrait, QueryFilter,
+    QueryOrder, QuerySelect, Set,
+};
+use tokio::sync::{broadcast, mpsc};
+use tracing::Instrument;
+
+use crate::{
+    AppState, AppStateRef,
+    entities::{
+        instance::{OutputRule, OutputRuleAction, OutputRules},
+        output_match,
+    },
+    services::{
+        RUN_TRIGGER_OUTPUT_RULE, RunDescriptor, backup_instance, restart_instance, send_command,
+    },
+};
+
+/// Longer lines are cut off before they are matched.
+const MAX_LINE_LENGTH: usize = 4096;
+/// Older matches of an instance are deleted.
+const MAX_MATCHES_PER_INSTANCE: u64 = 500;
+
+/// Sent by [`OutputMatcher`] whenever a rule fires.
+struct RuleMatch {
+    instance_id: u64,
+    rule: OutputRule,
+    line: String,
+    suppressed: u32,
+}
+
+#[derive(Default)]
+struct Cooldown {
+    until: Option<Instant>,
+    suppressed: u32,
+}
+
+/// Cooldowns outlive the process, so a rule restarting the instance doesn't loop.
+type Cooldowns = Arc<Mutex<HashMap<(u64, String), Cooldown>>>;
+
+/// Stores and acts on lines matched by the output rules of instances.
+pub struct OutputRuleService {
+    cooldowns: Cooldowns,
+    matches: mpsc::UnboundedSender<RuleMatch>,
+    receiver: Mutex<Option<mpsc::UnboundedReceiver<RuleMatch>>>,
+    events: broadcast::Sender<output_match::Model>,
+}
+
+impl Default for OutputRuleService {
+    fn default() -> Self {
+        let (matches, receiver) = mpsc::unbounded_channel();
+        Self {
+            cooldowns: Default::default(),
+            matches,
+            receiver: Mutex::new(Some(receiver)),
+            events: broadcast::channel(64).0,
+        }
+    }
+}
+
+impl OutputRuleService {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn subscribe_events(&self) -> broadcast::Receiver<output_match::Model> {
+        self.events.subscribe()
+    }
+
+    /// Creates the matcher fed by the log worker of a process, `None` without rules.
+    pub fn matcher(&self, id: u64, rules: &OutputRules) -> Option<OutputMatcher> {
+        let rules = rules
+            .0
+            .iter()
+            .filter_map(|rule| match Regex::new(&rule.pattern) {
+                Ok(regex) => Some((rule.clone(), regex)),
+                Err(e) => {
+                    tracing::warn!("Skipping output rule `{}`: {}", rule.name, e);
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+        if rules.is_empty() {
+            return None;
+        }
+
+        Some(OutputMatcher {
+            instance_id: id,
+            rules,
+            cooldowns: self.cooldowns.clone(),
+            matches: self.matches.clone(),
+            pending_stdout: Vec::new(),
+            pending_stderr: Vec::new(),
+        })
+    }
+
+    pub fn start(state: AppStateRef) {
+        let Some(mut receiver) = state.output_rule_manager.receiver.lock().unwrap().take() else {
+            return;
+        };
+
+        tokio::spawn(
+            async move {
+                while let Some(rule_match) = receiver.recv().await {
+                    if let Err(e) = Self::handle(&state, rule_match).await {
+                        tracing::error!("Failed to handle output match: {}", e);
+                    }
+                }
+            }
+            .instrument(tracing::info_span!(parent: None, "output rules")),
+        );
+    }
+
+    async fn handle(state: &AppStateRef, rule_match: RuleMatch) -> Result<(), DbErr> {
+        let id = rule_match.instance_id;
+        let rule = rule_match.rule;
+        tracing::info!(
+            "Output rule `{}` of instance {} matched: {}",
+            rule.name,
+            id,
+            rule_match.line
+        );
+
+        let run = match rule.action {
+            OutputRuleAction::Event => None,
+            _ => Some(
+                state
+                    .run_history
+                    .begin_run(RunDescriptor {
+                        instance_id: id as i32,
+                        schedule_id: None,
+                        trigger: RUN_TRIGGER_OUTPUT_RULE,
+                        action: rule.action.name(),
+                        scheduled_at: None,
+                    })
+                    .await?,
+            ),
+        };
+
+        let the_match = output_match::ActiveModel {
+            id: NotSet,
+            instance_id: Set(id as i32),
+            rule: Set(rule.name.clone()),
+            severity: Set(rule.severity.as_str().to_owned()),
+            action: Set(rule.action.name().to_owned()),
+            line: Set(rule_match.line),
+            suppressed: Set(rule_match.suppressed as i32),
+            run_id: Set(run.as_ref().map(|x| x.id)),
+            matched_at: Set(Utc::now()),
+        }
+        .insert(&state.database)
+        .await?;
+        _ = state.output_rule_manager.events.send(the_match);
+
+        // a restart waits for the logger feeding this, so actions don't block the next match
+        if let Some(run) = run {
+            let state = state.clone();
+            tokio::spawn(
+                async move {
+                    let result = Self::execute(&state, id, &rule.action).await;
+                    if let Err(e) = &result {
+                        tracing::error!(
+                            "Output rule `{}` of instance {} failed: {}",
+                            rule.name,
+                            id,
+                            e
+                        );
+                    }
+                    if let Err(e) = state.run_history.finish_run(run, result).await {
+                        tracing::error!(
+                            "Failed to record run of output rule `{}`: {}",
+                            rule.name,
+                            e
+                        );
+                    }
+                }
+                .in_current_span(),
+            );
+        }
+
+        let outdated = output_match::Entity::find()
+            .select_only()
+            .column(output_match::Column::Id)
+            .filter(output_match::Column::InstanceId.eq(id as i32))
+            .order_by_desc(output_match::Column::Id)
+            .offset(MAX_MATCHES_PER_INSTANCE)
+            // sqlite only accepts an offset along with a limit
+            .limit(i64::MAX as u64)
+            .into_tuple::<i32>()
+            .all(&state.database)
+            .await?;
+        if !outdated.is_empty() {
+            output_match::Entity::delete_many()
+                .filter(output_match::Column::Id.is_in(outdated))
+                .exec(&state.database)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn execute(
+        state: &AppState,
+        id: u64,
+        action: &OutputRuleAction,
+    ) -> Result<Option<String>, String> {
+        let result = match action {
+            OutputRuleAction::Event => Ok(None),
+            OutputRuleAction::Command { command } => {
+                send_command(state, id, command).await.map(|_| None)
+            }
+            OutputRuleAction::Restart => restart_instance(state, id).await.map(|_| None),
+            OutputRuleAction::Backup => backup_instance(state, id)
+                .await
+                .map(|x| Some(format!("backup written to {}", x.display()))),
+        };
+
+        result.map_err(|e| e.to_string())
+    }
+}
+
+/// The pipe a chunk of output was read from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+/// Splits the output of a process into lines and checks them against the rules.
+pub struct OutputMatcher {
+    instance_id: u64,
+    rules: Vec<(OutputRule, Regex)>,
+    cooldowns: Cooldowns,
+    matches: mpsc::UnboundedSender<RuleMatch>,
+    /// The unfinished last line of each stream, chunks of both arrive interleaved.
+    pending_stdout: Vec<u8>,
+    pending_stderr: Vec<u8>,
+}
+
+impl OutputMatcher {
+    pub fn feed(&mut self, stream: OutputStream, data: &[u8]) {
+        let mut rest = data;
+        while let Some(end) = rest.iter().position(|x| *x == b'\n') {
+            let mut line = std::mem::take(self.pending(stream));
+            push_capped(&mut line, &rest[..end]);
+            self.check(&line);
+            rest = &rest[end + 1..];
+        }
+        push_capped(self.pending(stream), rest);
+    }
+
+    fn pending(&mut self, stream: OutputStream) -> &mut Vec<u8> {
+        match stream {
+            OutputStream::Stdout => &mut self.pending_stdout,
+            OutputStream::Stderr => &mut self.pending_stderr,
+        }
+    }
+
+    fn check(&self, line: &[u8]) {
+        let line = String::from_utf8_lossy(line);
+        let line = line.trim_end_matches('\r');
+
+        for (rule, regex) in &self.rules {
+            if !regex.is_match(line) {
+                continue;
+            }
+
+            let now = Instant::now();
+            let suppressed = {
+                let mut cooldowns = self.cooldowns.lock().unwrap();
+                let cooldown = cooldowns
+                    .entry((self.instance_id, rule.name.clone()))
+                    .or_default();
+                if cooldown.until.is_some_and(|x| now < x) {
+                    cooldown.suppressed += 1;
+                    continue;
+                }
+
+                cooldown.until = Some(now + Duration::from_secs(rule.cooldown.into()));
+                std::mem::take(&mut cooldown.suppressed)
+            };
+
+            _ = self.matches.send(RuleMatch {
+                instance_id: self.instance_id,
+                rule: rule.clone(),
+                line: line.to_owned(),
+                suppressed,
+            });
+        }
+    }
+}
+
+/// Appends to a line, anything beyond [`MAX_LINE_LENGTH`] is dropped.
+fn push_capped(line: &mut Vec<u8>, data: &[u8]) {
+    let room = MAX_LINE_LENGTH.saturating_sub(line.len());
+    line.extend_from_slice(&data[..data.len().min(room)]);
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    /// A matcher for instance 1 and the matches it sends.
+    fn matcher(rules: serde_json::Value) -> (OutputMatcher, mpsc::UnboundedReceiver<RuleMatch>) {
+        let service = OutputRuleService::new();
+        let rules: OutputRules = serde_json::from_value(rules).unwrap();
+        let matcher = service.matcher(1, &rules).unwrap();
+        let receiver = service.receiver.lock().unwrap().take().unwrap();
+        (matcher, receiver)
+    }
+
+    fn matched_lines(receiver: &mut mpsc::UnboundedReceiver<RuleMatch>) -> Vec<String> {
+        let mut lines = Vec::new();
+        while let Ok(rule_match) = receiver.try_recv() {
+            lines.push(rule_match.line);
+        }
+        lines
+    }
+
+    #[test]
+    fn lines_are_joined_across_chunks() {
+        let (mut matcher, mut receiver) = matcher(json!([
+            { "name": "oom", "pattern": "OutOfMemoryError", "cooldown": 0 },
+        ]));
+
+        matcher.feed(OutputStream::Stdout, b"[12:00] java.lang.OutOf");
+        assert!(matched_lines(&mut receiver).is_empty());
+        matcher.feed(OutputStream::Stdout, b"MemoryError: Java heap");
+        assert!(matched_lines(&mut receiver).is_empty());
+        matcher.feed(OutputStream::Stdout, b" space\r\nnext line\n");
+
+        assert_eq!(
+            matched_lines(&mut receiver),
+            vec!["[12:00] java.lang.OutOfMemoryError: Java heap space"]
+        );
+    }
+
+    #[test]
+    fn streams_keep_their_own_partial_lines() {
+        let (mut matcher, mut receiver) = matcher(json!([
+            { "name": "error", "pattern": "^ERROR .*$", "cooldown": 0 },
+        ]));
+
+        matcher.feed(OutputStream::Stderr, b"ERROR disk ");
+        matcher.feed(OutputStream::Stdout, b"INFO saving\nINFO ");
+        matcher.feed(OutputStream::Stderr, b"full\nERROR again\n");
+        matcher.feed(OutputStream::Stdout, b"done\n");
+
+        assert_eq!(
+            matched_lines(&mut receiver),
+            vec!["ERROR disk full", "ERROR again"]
+        );
+    }
+
+    #[test]
+    fn cooldown_suppresses_repeated_matches() {
+        let (mut matcher, mut receiver) = matcher(json!([
+            { "name": "lag", "pattern": "Can't keep up", "cooldown": 3600 },
+            { "name": "any", "pattern": ".", "cooldown": 0 },
+        ]));
+
+        matcher.feed(
+            OutputStream::Stdout,
+            b"Can't keep up! 1\nCan't keep up! 2\n",
+        );
+        matcher.feed(OutputStream::Stdout, b"Can't keep up! 3\n");
+
+        let mut lag = Vec::new();
+        let mut any = 0;
+        while let Ok(rule_match) = receiver.try_recv() {
+            match rule_match.rule.name.as_str() {
+                "lag" => lag.push(rule_match.line),
+                _ => any += 1,
+            }
+        }
+        assert_eq!(lag, vec!["Can't keep up! 1"]);
+        assert_eq!(any, 3);
+
+        // the next match after the cooldown carries the count of the suppressed ones
+        matcher
+            .cooldowns
+            .lock()
+            .unwrap()
+            .get_mut(&(1, "lag".to_owned()))
+            .unwrap()
+            .until = None;
+        matcher.feed(OutputStream::Stdout, b"Can't keep up! 4\n");
+        let rule_match = receiver.try_recv().unwrap();
+        assert_eq!(rule_match.line, "Can't keep up! 4");
+        assert_eq!(rule_match.suppressed, 2);
+    }
+
+    #[test]
+    fn long_lines_are_cut_off() {
+        let (mut matcher, mut receiver) = matcher(json!([
+            { "name": "tail", "pattern": "TAIL", "cooldown": 0 },
+            { "name": "head", "pattern": "^HEAD", "cooldown": 0 },
+        ]));
+
+        let mut line = b"HEAD".to_vec();
+        line.resize(MAX_LINE_LENGTH, b'x');
+        matcher.feed(OutputStream::Stdout, &line);
+        matcher.feed(OutputStream::Stdout, b"TAIL\n");
+
+        let lines = matched_lines(&mut receiver);
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0].len(), MAX_LINE_LENGTH);
+    }
+
+    #[test]
+    fn no_matcher_without_rules() {
+        let service = OutputRuleService::new();
+        assert!(service.matcher(1, &OutputRules::default()).is_none());
+
+        // invalid patterns are skipped
+        let rules = serde_json::from_value(json!([{ "name": "bad", "pattern": "(" }])).unwrap();
+        assert!(service.matcher(1, &rules).is_none());
+    }
+}
@@ -1,34 +1,53 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     ffi::{OsStr, OsString},
-    path::Path,
+    io,
+    path::PathBuf,
     process::Stdio,
-    sync::Arc,
+    ptr,
+    sync::{Arc, Mutex},
+    time::Duration,
 };
 
 use anyhow::Result;
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::Serialize;
 
 use tokio::{
     io::{AsyncRead, AsyncWrite},
     process::{Child, Command},
-    sync::{RwLock, broadcast, mpsc},
+    sync::{RwLock, broadcast, mpsc, watch},
 };
 
-use crate::transfer::{BinarySequence, redirect_input, redirect_output};
+use tracing::Instrument;
 
+use crate::{
+    AppStateRef,
+    services::{EffectiveLimits, PreparedLimits, PreparedSandbox, RunAs},
+    transfer::{BinarySequence, redirect_input, redirect_output},
+};
+
+/// Nothing is read from `output` until `released` is set, so subscribers attached right after
+/// the spawn still see the first lines.
 fn create_output_redirect(
     output: impl AsyncRead + Unpin + Sync + Send + 'static,
-    child: Arc<RwLock<Child>>,
-) -> broadcast::Receiver<BinarySequence> {
+    mut released: watch::Receiver<bool>,
+) -> (
+    broadcast::Receiver<BinarySequence>,
+    broadcast::WeakSender<BinarySequence>,
+) {
     let (tx, rx) = broadcast::channel(8);
+    let weak_tx = tx.downgrade();
 
     tokio::spawn(async move {
-        if let Err(e) = redirect_output(output, tx, child).await {
+        // also released when the process is dropped
+        _ = released.wait_for(|x| *x).await;
+        if let Err(e) = redirect_output(output, tx).await {
             tracing::warn!("create output redirect: {}", e);
         }
     });
 
-    return rx;
+    (rx, weak_tx)
 }
 
 fn create_input_redirect(
@@ -45,78 +64,246 @@ fn create_input_redirect(
     return tx;
 }
 
+/// How long an exited process is kept around so clients can still see how it ended.
+const EXITED_RETENTION: TimeDelta = TimeDelta::minutes(10);
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
 pub struct Process {
-    child: Arc<RwLock<Child>>,
+    pid: Option<u32>,
+    started_at: DateTime<Utc>,
+    limits: EffectiveLimits,
+    status: Arc<StatusHandle>,
+    kill_request: mpsc::Sender<()>,
 
     stdout: Option<broadcast::Receiver<Vec<u8>>>,
+    /// Weak so the output still closes when the process exits.
+    notices: Option<broadcast::WeakSender<Vec<u8>>>,
     stderr: Option<broadcast::Receiver<Vec<u8>>>,
     stdin: Option<mpsc::Sender<Vec<u8>>>,
+    output_released: watch::Sender<bool>,
 }
 
-#[derive(PartialEq, Eq, Clone, Copy)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub enum ProcessState {
-    Alive,
-    Dead,
+    Starting,
+    Running,
+    Stopping,
+    Stopped,
+    Crashed,
+    Restarting,
+    BackingUp,
 }
 
 impl ProcessState {
-    pub async fn from_child(child: &Arc<RwLock<Child>>) -> Self {
-        let mut child = child.write().await;
+    pub fn is_alive(&self) -> bool {
+        !matches!(self, Self::Stopped | Self::Crashed)
+    }
+}
 
-        match child.try_wait() {
-            Err(e) => {
-                tracing::warn!("Error while checking status of child process: {}", e);
-                Self::Dead
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProcessStatus {
+    pub state: ProcessState,
+    pub exit_code: Option<i32>,
+    pub state_changed_at: DateTime<Utc>,
+    pub exited_at: Option<DateTime<Utc>>,
+}
+
+/// Sent to subscribers of [`ProcessManagementService::subscribe_events`] on every transition.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProcessStateEvent {
+    pub instance_id: u64,
+    pub previous_state: ProcessState,
+    pub state: ProcessState,
+    pub exit_code: Option<i32>,
+    pub at: DateTime<Utc>,
+}
+
+struct StatusHandle {
+    id: u64,
+    status: watch::Sender<ProcessStatus>,
+    events: broadcast::Sender<ProcessStateEvent>,
+}
+
+impl StatusHandle {
+    fn transition(&self, state: ProcessState, exit_code: Option<i32>) {
+        self.transition_from(None, state, exit_code);
+    }
+
+    /// Transitions only if the process is in `from`, if given.
+    fn transition_from(
+        &self,
+        from: Option<ProcessState>,
+        state: ProcessState,
+        exit_code: Option<i32>,
+    ) {
+        let now = Utc::now();
+        let mut previous_state = state;
+
+        let changed = self.status.send_if_modified(|status| {
+            previous_state = status.state;
+            // nothing leaves a terminal state, a new process is created instead
+            if status.state == state || !status.state.is_alive() {
+                return false;
             }
-            Ok(s) => {
-                if s.is_none() {
-                    Self::Alive
-                } else {
-                    Self::Dead
-                }
+            if from.is_some_and(|x| x != status.state) {
+                return false;
+            }
+
+            status.state = state;
+            status.state_changed_at = now;
+            if !state.is_alive() {
+                status.exit_code = exit_code;
+                status.exited_at = Some(now);
             }
+            true
+        });
+
+        if changed {
+            tracing::info!(
+                "Process {} changed state: {:?} -> {:?}",
+                self.id,
+                previous_state,
+                state
+            );
+            _ = self.events.send(ProcessStateEvent {
+                instance_id: self.id,
+                previous_state,
+                state,
+                exit_code,
+                at: now,
+            });
         }
     }
 }
 
+/// Owns the child until it exits, so killing never waits for a lock.
+async fn wait_child(
+    mut child: Child,
+    status: Arc<StatusHandle>,
+    mut kill_request: mpsc::Receiver<()>,
+) {
+    let exit_status = loop {
+        tokio::select! {
+            result = child.wait() => break result,
+            Some(_) = kill_request.recv() => {
+                if let Err(e) = child.start_kill() {
+                    tracing::warn!("Error while killing process {}: {}", status.id, e);
+                }
+            }
+        }
+    };
+
+    let requested = matches!(
+        status.status.borrow().state,
+        ProcessState::Stopping | ProcessState::Restarting
+    );
+
+    let (state, exit_code) = match exit_status {
+        Ok(exit_status) => {
+            let state = if requested || exit_status.success() {
+                ProcessState::Stopped
+            } else {
+                ProcessState::Crashed
+            };
+            (state, exit_status.code())
+        }
+        Err(e) => {
+            tracing::warn!("Error while waiting for process {}: {}", status.id, e);
+            (ProcessState::Crashed, None)
+        }
+    };
+
+    status.transition(state, exit_code);
+}
+
 impl Process {
-    pub async fn setup(child: Child) -> Self {
-        // create ref
-        let child = Arc::new(RwLock::new(child));
+    pub async fn setup(
+        id: u64,
+        mut child: Child,
+        limits: EffectiveLimits,
+        events: broadcast::Sender<ProcessStateEvent>,
+    ) -> Self {
+        let pid = child.id();
+        let started_at = Utc::now();
 
         // stdio redirect
-        let (stdout, stderr, stdin) = {
-            let child_ref = &child;
-            let mut child = child.write().await;
-
-            let stdout = child
-                .stdout
-                .take()
-                .map(|x| create_output_redirect(x, child_ref.clone()));
-            let stderr = child
-                .stderr
-                .take()
-                .map(|x| create_output_redirect(x, child_ref.clone()));
-            let stdin = child.stdin.take().map(|x| create_input_redirect(x));
-
-            (stdout, stderr, stdin)
-        };
+        let (output_released, released) = watch::channel(false);
+        let (stdout, notices) = child
+            .stdout
+            .take()
+            .map(|x| create_output_redirect(x, released.clone()))
+            .unzip();
+        let stderr = child
+            .stderr
+            .take()
+            .map(|x| create_output_redirect(x, released).0);
+        let stdin = child.stdin.take().map(create_input_redirect);
+
+        // state tracking
+        let (status, _) = watch::channel(ProcessStatus {
+            state: ProcessState::Starting,
+            exit_code: None,
+            state_changed_at: started_at,
+            exited_at: None,
+        });
+        let status = Arc::new(StatusHandle { id, status, events });
+        let (kill_request, kill_receiver) = mpsc::channel(1);
+        tokio::spawn(wait_child(child, status.clone(), kill_receiver));
 
         Self {
-            child,
+            pid,
+            started_at,
+            limits,
+            status,
+            kill_request,
             stdout,
+            notices,
             stderr,
             stdin,
+            output_released,
         }
     }
 
+    /// Starts forwarding the output, until then it waits in the pipes of the process.
+    pub fn release_output(&self) {
+        self.output_released.send_replace(true);
+    }
+
+    /// Kills the process and waits until it has exited.
     pub async fn kill(&self) -> Result<()> {
-        let mut process = self.child.write().await;
-        let exit_code = process.try_wait()?;
-        if exit_code.is_none() {
-            process.kill().await?;
+        if !self.state().is_alive() {
+            return Ok(());
+        }
+
+        if self.state() != ProcessState::Restarting {
+            self.set_state(ProcessState::Stopping);
+        }
+
+        self.wait_killed().await
+    }
+
+    /// Kills the process without requesting it, so it ends up crashed.
+    pub async fn kill_crashed(&self) -> Result<()> {
+        if !self.state().is_alive() {
+            return Ok(());
         }
 
+        self.wait_killed().await
+    }
+
+    async fn wait_killed(&self) -> Result<()> {
+        // the waiter is gone once the process exited
+        _ = self.kill_request.send(()).await;
+
+        self.status
+            .status
+            .subscribe()
+            .wait_for(|x| !x.state.is_alive())
+            .await?;
+
         Ok(())
     }
 
@@ -128,12 +315,53 @@ impl Process {
         self.stderr.as_ref().map(|x| x.resubscribe())
     }
 
+    /// Shows a line from the slave itself in the console and the log of the process.
+    pub fn write_notice(&self, message: &str) {
+        if let Some(sender) = self.notices.as_ref().and_then(|x| x.upgrade()) {
+            _ = sender.send(format!("[lcsm] {}\n", message).into_bytes());
+        }
+    }
+
     pub fn get_stdin(&self) -> Option<mpsc::Sender<BinarySequence>> {
         self.stdin.as_ref().map(|x| x.clone())
     }
 
-    pub async fn state(&self) -> ProcessState {
-        ProcessState::from_child(&self.child).await
+    pub fn pid(&self) -> Option<u32> {
+        self.pid
+    }
+
+    pub fn started_at(&self) -> DateTime<Utc> {
+        self.started_at
+    }
+
+    pub fn limits(&self) -> &EffectiveLimits {
+        &self.limits
+    }
+
+    pub fn state(&self) -> ProcessState {
+        self.status.status.borrow().state
+    }
+
+    pub fn status(&self) -> ProcessStatus {
+        self.status.status.borrow().clone()
+    }
+
+    pub fn subscribe_status(&self) -> watch::Receiver<ProcessStatus> {
+        self.status.status.subscribe()
+    }
+
+    /// Moves an alive process to another state, terminal states are set by the process exiting.
+    pub fn set_state(&self, state: ProcessState) {
+        if state.is_alive() {
+            self.status.transition(state, None);
+        }
+    }
+
+    /// Like [`Self::set_state`], but leaves the process alone unless it is still in `from`.
+    pub fn set_state_from(&self, from: ProcessState, state: ProcessState) {
+        if state.is_alive() {
+            self.status.transition_from(Some(from), state, None);
+        }
     }
 }
 
@@ -145,14 +373,101 @@ impl From<Process> for ProcessRef {
 
 pub type ProcessRef = Arc<RwLock<Process>>;
 
+/// Everything about how a new process is launched besides its command line.
+pub struct ProcessLaunchOptions {
+    pub work_dir: PathBuf,
+    pub use_shell: bool,
+    pub environment: ProcessEnvironment,
+    pub limits: PreparedLimits,
+    pub run_as: Option<RunAs>,
+    pub sandbox: Option<PreparedSandbox>,
+    /// Leaves the process `starting` until a readiness probe marks it running.
+    pub wait_for_readiness: bool,
+}
+
+/// Environment of a new process, a `None` value removes the variable.
+pub struct ProcessEnvironment {
+    pub inherit: bool,
+    pub variables: Vec<(String, Option<String>)>,
+}
+
 pub struct ProcessManagementService {
     processes: RwLock<HashMap<u64, ProcessRef>>,
+    /// Ids of the processes being started, see [`Self::begin_start`].
+    starting: Arc<Mutex<HashSet<u64>>>,
+    events: broadcast::Sender<ProcessStateEvent>,
+}
+
+/// Reserves starting a process until it is dropped.
+pub struct StartGuard {
+    id: u64,
+    starting: Arc<Mutex<HashSet<u64>>>,
+}
+
+impl Drop for StartGuard {
+    fn drop(&mut self) {
+        self.starting.lock().unwrap().remove(&self.id);
+    }
 }
 
 impl ProcessManagementService {
     pub fn new() -> Self {
         Self {
             processes: RwLock::new(HashMap::new()),
+            starting: Default::default(),
+            events: broadcast::channel(64).0,
+        }
+    }
+
+    /// Reserves starting the process, `None` if another start of it is under way. Holding the
+    /// guard from the check for a live process until [`Self::new_process`] returned keeps two
+    /// starts from both spawning it.
+    pub fn begin_start(&self, id: u64) -> Option<StartGuard> {
+        if !self.starting.lock().unwrap().insert(id) {
+            return None;
+        }
+
+        Some(StartGuard {
+            id,
+            starting: self.starting.clone(),
+        })
+    }
+
+    pub fn subscribe_events(&self) -> broadcast::Receiver<ProcessStateEvent> {
+        self.events.subscribe()
+    }
+
+    /// Periodically forgets processes that exited a while ago.
+    pub fn start(state: AppStateRef) {
+        tokio::spawn(
+            async move {
+                let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    state.process_manager.prune_exited().await;
+                }
+            }
+            .instrument(tracing::info_span!(parent: None, "process pruner")),
+        );
+    }
+
+    pub async fn prune_exited(&self) {
+        let now = Utc::now();
+        let mut expired = Vec::new();
+        for (id, process) in self.list_processes().await {
+            let exited_at = process.read().await.status().exited_at;
+            if exited_at.is_some_and(|x| now - x > EXITED_RETENTION) {
+                expired.push((id, process));
+            }
+        }
+
+        let mut processes = self.processes.write().await;
+        for (id, process) in expired {
+            // the instance may have been started again meanwhile
+            if processes.get(&id).is_some_and(|x| Arc::ptr_eq(x, &process)) {
+                processes.remove(&id);
+                tracing::debug!("Pruned exited process {}", id);
+            }
         }
     }
 
@@ -205,31 +520,117 @@ impl ProcessManagementService {
         child
     }
 
+    /// Switches to another identity right before exec, supplementary groups are dropped as well.
+    /// `Command::uid` would switch before any pre-exec hook runs, but moving into the cgroup
+    /// needs root, so this must be installed after the limits.
+    fn drop_privileges(child: &mut Command, run_as: Option<&RunAs>) {
+        let Some(run_as) = run_as else {
+            return;
+        };
+        let (uid, gid) = (run_as.uid, run_as.gid);
+        if uid.is_none() && gid.is_none() {
+            return;
+        }
+
+        unsafe {
+            child.pre_exec(move || {
+                if libc::setgroups(0, ptr::null()) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                if let Some(gid) = gid
+                    && libc::setgid(gid) != 0
+                {
+                    return Err(io::Error::last_os_error());
+                }
+                if let Some(uid) = uid
+                    && libc::setuid(uid) != 0
+                {
+                    return Err(io::Error::last_os_error());
+                }
+
+                Ok(())
+            });
+        }
+    }
+
+    /// Spawns and tracks the process, its output is held back until [`Process::release_output`].
     pub async fn new_process(
         &self,
         id: u64,
         launch_command: impl AsRef<OsStr>,
         arguments: impl IntoIterator<Item = impl AsRef<OsStr>>,
-        work_dir: impl AsRef<Path>,
-        use_shell: bool,
+        options: ProcessLaunchOptions,
     ) -> Result<ProcessRef> {
+        let ProcessLaunchOptions {
+            work_dir,
+            use_shell,
+            environment,
+            limits,
+            run_as,
+            sandbox,
+            wait_for_readiness,
+        } = options;
+
         let mut child = if use_shell {
             Self::generate_command_with_shell(launch_command, arguments)
         } else {
             Self::generate_command(launch_command, arguments)
         };
 
-        let work_dir = work_dir.as_ref();
+        if !environment.inherit {
+            child.env_clear();
+        }
+        if let Some(run_as) = &run_as {
+            if let Some(user) = &run_as.user {
+                child.env("USER", user).env("LOGNAME", user);
+            }
+            if let Some(home) = &run_as.home {
+                child.env("HOME", home);
+            }
+        }
+        for (name, value) in environment.variables {
+            match value {
+                Some(value) => child.env(name, value),
+                None => child.env_remove(name),
+            };
+        }
+
         if !work_dir.as_os_str().is_empty() {
             child.current_dir(work_dir);
         }
 
-        let child = child.spawn()?;
+        limits.apply(&mut child)?;
+        // a sandbox switches the identity itself once its namespaces are set up
+        match sandbox {
+            Some(sandbox) => sandbox.apply(&mut child),
+            None => Self::drop_privileges(&mut child, run_as.as_ref()),
+        }
+
+        let child = match child.spawn() {
+            Ok(v) => v,
+            Err(e) => {
+                limits.cleanup();
+                return Err(e.into());
+            }
+        };
+
+        // NOTICE: code about log_service is not here, you should go to `services/instance_control.rs` to find it
+
+        let process =
+            Process::setup(id, child, limits.effective.clone(), self.events.clone()).await;
+        if !wait_for_readiness {
+            process.set_state(ProcessState::Running);
+        }
 
-        // NOTICE: code about log_service is not here, you should go to `routes/...` to find it
+        // release the cgroup once the process is gone
+        let mut status = process.subscribe_status();
+        tokio::spawn(async move {
+            _ = status.wait_for(|x| !x.state.is_alive()).await;
+            limits.cleanup();
+        });
 
         let process_ref = {
-            let process_ref = ProcessRef::from(Process::setup(child).await);
+            let process_ref = ProcessRef::from(process);
             let mut processes_write = self.processes.write().await;
             processes_write.insert(id, process_ref.clone());
             process_ref
@@ -241,4 +642,22 @@ impl ProcessManagementService {
     pub async fn get_process(&self, id: u64) -> Option<ProcessRef> {
         self.processes.read().await.get(&id).cloned()
     }
+
+    pub async fn list_processes(&self) -> Vec<(u64, ProcessRef)> {
+        self.processes
+            .read()
+            .await
+            .iter()
+            .map(|(id, process)| (*id, process.clone()))
+            .collect()
+    }
+
+    pub async fn get_alive_process(&self, id: u64) -> Option<ProcessRef> {
+        let process = self.get_process(id).await?;
+        if !process.read().await.state().is_alive() {
+            return None;
+        }
+
+        Some(process)
+    }
 }
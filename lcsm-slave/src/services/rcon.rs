@@ -0,0 +1,486 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    io,
+    time::{Duration, Instant},
+};
+
+use axum::http::StatusCode;
+use sea_orm::{DbErr, EntityTrait};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    sync::Mutex,
+};
+
+use crate::{
+    AppState,
+    entities::instance::{self, RconSettings},
+    errors::StatusCodeError,
+    services::{SecretError, SecretService},
+};
+
+const RCON_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Pooled connections unused for longer are dropped instead of reused.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+const MAX_IDLE_CONNECTIONS: usize = 4;
+
+/// A pooled connection failing after being idle this long is assumed to have been closed by
+/// the server before the command reached it.
+const STALE_AFTER: Duration = Duration::from_secs(30);
+
+/// Minecraft rejects longer commands.
+const MAX_COMMAND_LENGTH: usize = 1446;
+
+/// Responses are split into packets of at most 4096 bytes, anything larger is garbage.
+const MAX_PACKET_SIZE: i32 = 64 * 1024;
+
+const SERVERDATA_AUTH: i32 = 3;
+const SERVERDATA_AUTH_RESPONSE: i32 = 2;
+const SERVERDATA_EXECCOMMAND: i32 = 2;
+const SERVERDATA_RESPONSE_VALUE: i32 = 0;
+
+#[derive(Debug)]
+pub enum RconError {
+    NotFound,
+    NotConfigured,
+    InvalidCommand(String),
+    AuthFailed,
+    Timeout,
+    Protocol(String),
+    IoError(io::Error),
+    DbErr(DbErr),
+    SecretError(SecretError),
+}
+
+impl StatusCodeError for RconError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Self::NotFound | Self::NotConfigured => StatusCode::NOT_FOUND,
+            Self::InvalidCommand(_) => StatusCode::BAD_REQUEST,
+            Self::AuthFailed | Self::Protocol(_) | Self::IoError(_) => StatusCode::BAD_GATEWAY,
+            Self::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            Self::SecretError(e) => e.status_code(),
+            Self::DbErr(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl Display for RconError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotFound => write!(f, "Instance not found"),
+            Self::NotConfigured => write!(f, "RCON is not configured for the instance"),
+            Self::InvalidCommand(e) => write!(f, "Invalid command: {}", e),
+            Self::AuthFailed => write!(f, "RCON authentication failed"),
+            Self::Timeout => write!(f, "RCON server did not respond in time"),
+            Self::Protocol(e) => write!(f, "Invalid RCON response: {}", e),
+            Self::IoError(e) => e.fmt(f),
+            Self::DbErr(e) => e.fmt(f),
+            Self::SecretError(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for RconError {}
+
+impl From<io::Error> for RconError {
+    fn from(value: io::Error) -> Self {
+        Self::IoError(value)
+    }
+}
+
+impl From<DbErr> for RconError {
+    fn from(value: DbErr) -> Self {
+        Self::DbErr(value)
+    }
+}
+
+struct RconConnection {
+    stream: TcpStream,
+    next_id: i32,
+    idle_since: Instant,
+}
+
+impl RconConnection {
+    async fn connect(settings: &RconSettings, password: &str) -> Result<Self, RconError> {
+        let stream = TcpStream::connect((settings.host.as_str(), settings.port)).await?;
+        let mut connection = Self {
+            stream,
+            next_id: 1,
+            idle_since: Instant::now(),
+        };
+
+        let id = connection.take_id();
+        connection.send(id, SERVERDATA_AUTH, password).await?;
+        loop {
+            // Source servers send an empty response value before the auth response
+            let (response_id, kind, _) = connection.receive().await?;
+            if kind != SERVERDATA_AUTH_RESPONSE {
+                continue;
+            }
+            if response_id == -1 {
+                return Err(RconError::AuthFailed);
+            }
+            if response_id == id {
+                return Ok(connection);
+            }
+        }
+    }
+
+    fn take_id(&mut self) -> i32 {
+        let id = self.next_id;
+        self.next_id = self.next_id.checked_add(1).unwrap_or(1);
+        id
+    }
+
+    /// Responses may span several packets without a marker for the last one, so an invalid
+    /// request is sent right after the command and its answer ends the response.
+    ///
+    /// The flag of an error tells whether the command may have reached the server.
+    async fn execute(&mut self, command: &str) -> Result<String, (RconError, bool)> {
+        let id = self.take_id();
+        let terminator = self.take_id();
+        self.send(id, SERVERDATA_EXECCOMMAND, command)
+            .await
+            .map_err(|e| (e, false))?;
+        self.receive_output(id, terminator)
+            .await
+            .map_err(|e| (e, true))
+    }
+
+    async fn receive_output(&mut self, id: i32, terminator: i32) -> Result<String, RconError> {
+        self.send(terminator, SERVERDATA_RESPONSE_VALUE, "").await?;
+
+        let mut output = Vec::new();
+        loop {
+            // leftovers of earlier requests are skipped
+            let (response_id, _, body) = self.receive().await?;
+            if response_id == terminator {
+                break;
+            }
+            if response_id == id {
+                output.extend(body);
+            }
+        }
+
+        self.idle_since = Instant::now();
+        Ok(String::from_utf8_lossy(&output).into_owned())
+    }
+
+    async fn send(&mut self, id: i32, kind: i32, body: &str) -> Result<(), RconError> {
+        let mut packet = Vec::with_capacity(body.len() + 14);
+        packet.extend_from_slice(&(body.len() as i32 + 10).to_le_bytes());
+        packet.extend_from_slice(&id.to_le_bytes());
+        packet.extend_from_slice(&kind.to_le_bytes());
+        packet.extend_from_slice(body.as_bytes());
+        packet.extend_from_slice(&[0, 0]);
+        self.stream.write_all(&packet).await?;
+
+        Ok(())
+    }
+
+    async fn receive(&mut self) -> Result<(i32, i32, Vec<u8>), RconError> {
+        let size = self.stream.read_i32_le().await?;
+        if !(10..=MAX_PACKET_SIZE).contains(&size) {
+            return Err(RconError::Protocol(format!("invalid packet size {}", size)));
+        }
+
+        let id = self.stream.read_i32_le().await?;
+        let kind = self.stream.read_i32_le().await?;
+        let mut body = vec![0; size as usize - 8];
+        self.stream.read_exact(&mut body).await?;
+
+        // drop the body terminator and the empty string after it
+        while body.last() == Some(&0) {
+            body.pop();
+        }
+
+        Ok((id, kind, body))
+    }
+}
+
+/// Idle connections of an instance, only reused while the settings stay the same.
+struct ConnectionPool {
+    settings: RconSettings,
+    idle: Vec<RconConnection>,
+}
+
+#[derive(Default)]
+pub struct RconService {
+    pools: Mutex<HashMap<u64, ConnectionPool>>,
+}
+
+impl RconService {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    async fn take_idle(&self, id: u64, settings: &RconSettings) -> Option<RconConnection> {
+        let mut pools = self.pools.lock().await;
+        let pool = pools.get_mut(&id)?;
+        if &pool.settings != settings {
+            pools.remove(&id);
+            return None;
+        }
+
+        pool.idle.retain(|x| x.idle_since.elapsed() < IDLE_TIMEOUT);
+        pool.idle.pop()
+    }
+
+    async fn put_idle(&self, id: u64, settings: &RconSettings, connection: RconConnection) {
+        let mut pools = self.pools.lock().await;
+        let pool = pools.entry(id).or_insert_with(|| ConnectionPool {
+            settings: settings.clone(),
+            idle: Vec::new(),
+        });
+        if &pool.settings == settings && pool.idle.len() < MAX_IDLE_CONNECTIONS {
+            pool.idle.push(connection);
+        }
+    }
+
+    /// Runs a command over a pooled connection, opening a new one if none is usable.
+    async fn execute(
+        &self,
+        secrets: &SecretService,
+        id: u64,
+        settings: &RconSettings,
+        command: &str,
+    ) -> Result<String, RconError> {
+        if let Some(mut connection) = self.take_idle(id, settings).await {
+            let stale = connection.idle_since.elapsed() >= STALE_AFTER;
+            match tokio::time::timeout(RCON_TIMEOUT, connection.execute(command)).await {
+                Ok(Ok(output)) => {
+                    self.put_idle(id, settings, connection).await;
+                    return Ok(output);
+                }
+                // the server closed the idle connection, retried only if nothing was executed
+                // so a command never runs twice
+                Ok(Err((RconError::IoError(e), sent))) if is_closed(&e) && (!sent || stale) => {}
+                Ok(Err((e, _))) => return Err(e),
+                Err(_) => return Err(RconError::Timeout),
+            }
+        }
+
+        let password = settings
+            .password
+            .as_deref()
+            .ok_or(RconError::NotConfigured)?;
+        let password = secrets.open(password).map_err(RconError::SecretError)?;
+
+        let (connection, output) = tokio::time::timeout(RCON_TIMEOUT, async {
+            let mut connection = RconConnection::connect(settings, &password).await?;
+            let output = connection.execute(command).await.map_err(|(e, _)| e)?;
+            Ok::<_, RconError>((connection, output))
+        })
+        .await
+        .map_err(|_| RconError::Timeout)??;
+
+        self.put_idle(id, settings, connection).await;
+        Ok(output)
+    }
+}
+
+fn is_closed(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe
+    )
+}
+
+/// Runs a command through the RCON settings of the instance and returns its output.
+pub async fn rcon_command(state: &AppState, id: u64, command: &str) -> Result<String, RconError> {
+    let command = command.trim();
+    if command.is_empty() {
+        return Err(RconError::InvalidCommand("command is empty".to_owned()));
+    }
+    if command.len() > MAX_COMMAND_LENGTH || command.contains(['\0', '\n', '\r']) {
+        return Err(RconError::InvalidCommand(
+            "command is too long or contains line breaks".to_owned(),
+        ));
+    }
+
+    let instance_id = i32::try_from(id).map_err(|_| RconError::NotFound)?;
+    let the_instance = instance::Entity::find_by_id(instance_id)
+        .one(&state.database)
+        .await?
+        .ok_or(RconError::NotFound)?;
+    let settings = the_instance
+        .minecraft
+        .rcon
+        .ok_or(RconError::NotConfigured)?;
+
+    state
+        .rcon_manager
+        .execute(&state.secret_manager, id, &settings, command)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    };
+
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    const PASSWORD: &str = "hunter2";
+
+    /// Answers like a Minecraft server, a command's output is echoed back in two packets and
+    /// every request with an unknown type is answered like the terminator. Counts the
+    /// connections it accepted.
+    async fn serve_rcon() -> (RconSettings, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let accepted = Arc::new(AtomicUsize::new(0));
+
+        let counter = accepted.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                counter.fetch_add(1, Ordering::Relaxed);
+                tokio::spawn(answer(stream));
+            }
+        });
+
+        let settings = RconSettings {
+            host: address.ip().to_string(),
+            port: address.port(),
+            password: Some(PASSWORD.to_owned()),
+        };
+        (settings, accepted)
+    }
+
+    async fn answer(stream: TcpStream) {
+        let mut connection = RconConnection {
+            stream,
+            next_id: 1,
+            idle_since: Instant::now(),
+        };
+
+        while let Ok((id, kind, body)) = connection.receive().await {
+            let body = String::from_utf8_lossy(&body).into_owned();
+            let result = match kind {
+                SERVERDATA_AUTH => {
+                    let id = if body == PASSWORD { id } else { -1 };
+                    connection
+                        .send(id, SERVERDATA_RESPONSE_VALUE, "")
+                        .await
+                        .and(connection.send(id, SERVERDATA_AUTH_RESPONSE, "").await)
+                }
+                SERVERDATA_EXECCOMMAND => {
+                    let (first, second) = body.split_at(body.len() / 2);
+                    connection
+                        .send(id, SERVERDATA_RESPONSE_VALUE, first)
+                        .await
+                        .and(connection.send(id, SERVERDATA_RESPONSE_VALUE, second).await)
+                }
+                _ => {
+                    connection
+                        .send(id, SERVERDATA_RESPONSE_VALUE, "Unknown request")
+                        .await
+                }
+            };
+            if result.is_err() {
+                break;
+            }
+        }
+    }
+
+    async fn connected_pair() -> (RconConnection, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+
+        let connection = RconConnection {
+            stream: client,
+            next_id: 1,
+            idle_since: Instant::now(),
+        };
+        (connection, server)
+    }
+
+    #[tokio::test]
+    async fn packets_are_framed() {
+        let (mut connection, mut server) = connected_pair().await;
+
+        connection
+            .send(7, SERVERDATA_EXECCOMMAND, "list")
+            .await
+            .unwrap();
+        let mut packet = [0; 18];
+        server.read_exact(&mut packet).await.unwrap();
+        assert_eq!(&packet[..4], &14i32.to_le_bytes());
+        assert_eq!(&packet[4..8], &7i32.to_le_bytes());
+        assert_eq!(&packet[8..12], &SERVERDATA_EXECCOMMAND.to_le_bytes());
+        assert_eq!(&packet[12..], b"list\0\0");
+
+        let mut response = Vec::new();
+        response.extend_from_slice(&13i32.to_le_bytes());
+        response.extend_from_slice(&7i32.to_le_bytes());
+        response.extend_from_slice(&SERVERDATA_RESPONSE_VALUE.to_le_bytes());
+        response.extend_from_slice(b"foo\0\0");
+        server.write_all(&response).await.unwrap();
+        let (id, kind, body) = connection.receive().await.unwrap();
+        assert_eq!(
+            (id, kind, body.as_slice()),
+            (7, SERVERDATA_RESPONSE_VALUE, &b"foo"[..])
+        );
+
+        server.write_all(&4i32.to_le_bytes()).await.unwrap();
+        assert!(matches!(
+            connection.receive().await,
+            Err(RconError::Protocol(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn wrong_password_fails_auth() {
+        let (settings, _) = serve_rcon().await;
+
+        let result = RconConnection::connect(&settings, "wrong").await;
+        assert!(matches!(result, Err(RconError::AuthFailed)));
+    }
+
+    #[tokio::test]
+    async fn responses_span_packets() {
+        let (settings, _) = serve_rcon().await;
+        let mut connection = RconConnection::connect(&settings, PASSWORD).await.unwrap();
+
+        let output = connection.execute("say hello world").await.unwrap();
+        assert_eq!(output, "say hello world");
+
+        // the terminator of the previous command doesn't leak into the next output
+        let output = connection.execute("list").await.unwrap();
+        assert_eq!(output, "list");
+    }
+
+    #[tokio::test]
+    async fn connections_are_reused() {
+        let (settings, accepted) = serve_rcon().await;
+        let secrets = SecretService::new(None::<&str>);
+        let rcon = RconService::new();
+
+        for command in ["list", "time query daytime"] {
+            let output = rcon.execute(&secrets, 1, &settings, command).await.unwrap();
+            assert_eq!(output, command);
+        }
+        assert_eq!(accepted.load(Ordering::Relaxed), 1);
+
+        // other settings don't get the connection of the old ones
+        let (other, other_accepted) = serve_rcon().await;
+        rcon.execute(&secrets, 1, &other, "list").await.unwrap();
+        assert_eq!(other_accepted.load(Ordering::Relaxed), 1);
+        rcon.execute(&secrets, 1, &settings, "list").await.unwrap();
+        assert_eq!(accepted.load(Ordering::Relaxed), 2);
+    }
+}
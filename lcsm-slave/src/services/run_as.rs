@@ -0,0 +1,157 @@
+use std::{
+    fs, io,
+    os::unix::fs::lchown,
+    path::{Component, Path, PathBuf},
+};
+
+use nix::unistd::{Group, User};
+
+/// The identity a process is launched with, resolved from user and group names.
+#[derive(Debug, Clone)]
+pub struct RunAs {
+    pub user: Option<String>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub home: Option<PathBuf>,
+}
+
+/// Lets instances run as other users, but only the ones an administrator allowed.
+pub struct RunAsService {
+    allowed_users: Vec<String>,
+    allowed_groups: Vec<String>,
+}
+
+impl RunAsService {
+    pub fn new(allowed_users: Vec<String>, allowed_groups: Vec<String>) -> Self {
+        Self {
+            allowed_users,
+            allowed_groups,
+        }
+    }
+
+    /// Parses a comma-separated allow-list like `minecraft,games`.
+    pub fn parse_allow_list(value: &str) -> Vec<String> {
+        value
+            .split(',')
+            .map(|x| x.trim())
+            .filter(|x| !x.is_empty())
+            .map(|x| x.to_owned())
+            .collect()
+    }
+
+    /// Checks the names against the allow-lists and looks them up, `None` keeps the slave's identity.
+    pub fn resolve(
+        &self,
+        user: Option<&str>,
+        group: Option<&str>,
+    ) -> Result<Option<RunAs>, String> {
+        if user.is_none() && group.is_none() {
+            return Ok(None);
+        }
+
+        let user = user
+            .map(|name| {
+                if !self.allowed_users.iter().any(|x| x == name) {
+                    return Err(format!("user `{}` is not allowed", name));
+                }
+
+                User::from_name(name)
+                    .map_err(|e| e.to_string())?
+                    .ok_or_else(|| format!("user `{}` does not exist", name))
+            })
+            .transpose()?;
+
+        let gid = match group {
+            Some(name) => {
+                if !self.allowed_groups.iter().any(|x| x == name) {
+                    return Err(format!("group `{}` is not allowed", name));
+                }
+
+                let group = Group::from_name(name)
+                    .map_err(|e| e.to_string())?
+                    .ok_or_else(|| format!("group `{}` does not exist", name))?;
+                Some(group.gid.as_raw())
+            }
+            // fall back to the primary group of the user
+            None => user.as_ref().map(|x| x.gid.as_raw()),
+        };
+
+        Ok(Some(RunAs {
+            uid: user.as_ref().map(|x| x.uid.as_raw()),
+            gid,
+            home: user.as_ref().map(|x| x.dir.clone()),
+            user: user.map(|x| x.name),
+        }))
+    }
+
+    /// Creates the work dir if needed and hands it and everything inside over to the identity.
+    /// The work dir must be below `root`, see [`Self::check_work_dir`].
+    pub async fn prepare_work_dir(
+        work_dir: impl AsRef<Path>,
+        root: impl AsRef<Path>,
+        run_as: RunAs,
+    ) -> Result<(), io::Error> {
+        let work_dir = work_dir.as_ref().to_path_buf();
+        let root = root.as_ref().to_path_buf();
+        if work_dir.as_os_str().is_empty() {
+            return Ok(());
+        }
+
+        tokio::task::spawn_blocking(move || {
+            Self::check_work_dir(&work_dir, &root)?;
+            fs::create_dir_all(&work_dir)?;
+
+            let work_dir = fs::canonicalize(&work_dir)?;
+            Self::check_work_dir(&work_dir, &root)?;
+            chown_recursive(&work_dir, run_as.uid, run_as.gid)
+        })
+        .await
+        .map_err(io::Error::other)?
+    }
+
+    /// Only work dirs below `root` are handed over to another identity, anywhere else it
+    /// could give away files of the host or of the slave itself. Fails with
+    /// [`io::ErrorKind::InvalidInput`] for other paths.
+    pub fn check_work_dir(work_dir: &Path, root: &Path) -> Result<(), io::Error> {
+        let outside = || {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "work dir {} of an instance running as another user must be inside {}",
+                    work_dir.display(),
+                    root.display()
+                ),
+            )
+        };
+        if !work_dir.is_absolute() || work_dir.components().any(|x| x == Component::ParentDir) {
+            return Err(outside());
+        }
+
+        // symlinks are resolved, one below the root may point anywhere
+        let existing = work_dir
+            .ancestors()
+            .find(|x| fs::symlink_metadata(x).is_ok())
+            .unwrap_or(Path::new("/"));
+        let resolved = fs::canonicalize(existing)?
+            .join(work_dir.strip_prefix(existing).map_err(|_| outside())?);
+        let root = fs::canonicalize(root)?;
+        if resolved == root || !resolved.starts_with(&root) {
+            return Err(outside());
+        }
+
+        Ok(())
+    }
+}
+
+fn chown_recursive(path: &Path, uid: Option<u32>, gid: Option<u32>) -> Result<(), io::Error> {
+    // symlinks are not followed, they may point outside of the work dir
+    lchown(path, uid, gid)?;
+
+    if fs::symlink_metadata(path)?.is_dir() {
+        for entry in fs::read_dir(path)? {
+            chown_recursive(&entry?.path(), uid, gid)?;
+        }
+    }
+
+    Ok(())
+}
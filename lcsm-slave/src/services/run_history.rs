@@ -0,0 +1,96 @@
+use chrono::{DateTime, Utc};
+use sea_orm::{
+    ActiveModelTrait, ActiveValue::NotSet, DatabaseConnection, DbErr, IntoActiveModel, Set,
+};
+
+use crate::entities::run_history;
+
+pub const RUN_STATUS_RUNNING: &str = "running";
+pub const RUN_STATUS_SUCCEEDED: &str = "succeeded";
+pub const RUN_STATUS_FAILED: &str = "failed";
+pub const RUN_STATUS_MISSED: &str = "missed";
+
+pub const RUN_TRIGGER_SCHEDULE: &str = "schedule";
+pub const RUN_TRIGGER_AUTOSTART: &str = "autostart";
+pub const RUN_TRIGGER_LIVENESS: &str = "liveness";
+pub const RUN_TRIGGER_HOOK: &str = "hook";
+pub const RUN_TRIGGER_CRASH: &str = "crash";
+pub const RUN_TRIGGER_OUTPUT_RULE: &str = "outputRule";
+
+pub struct RunHistoryService {
+    database: DatabaseConnection,
+}
+
+pub struct RunDescriptor<'a> {
+    pub instance_id: i32,
+    pub schedule_id: Option<i32>,
+    pub trigger: &'a str,
+    pub action: &'a str,
+    pub scheduled_at: Option<DateTime<Utc>>,
+}
+
+impl RunHistoryService {
+    pub fn new(database: DatabaseConnection) -> Self {
+        Self { database }
+    }
+
+    /// Records a run that is about to be executed, finish it with [`Self::finish_run`].
+    pub async fn begin_run(
+        &self,
+        descriptor: RunDescriptor<'_>,
+    ) -> Result<run_history::Model, DbErr> {
+        self.insert(descriptor, RUN_STATUS_RUNNING, None, None)
+            .await
+    }
+
+    pub async fn finish_run(
+        &self,
+        run: run_history::Model,
+        result: Result<Option<String>, String>,
+    ) -> Result<run_history::Model, DbErr> {
+        let (status, message) = match result {
+            Ok(message) => (RUN_STATUS_SUCCEEDED, message),
+            Err(message) => (RUN_STATUS_FAILED, Some(message)),
+        };
+
+        let mut run = run.into_active_model();
+        run.status = Set(status.to_owned());
+        run.message = Set(message);
+        run.finished_at = Set(Some(Utc::now()));
+        run.update(&self.database).await
+    }
+
+    /// Records a run that has already completed (or never happened at all).
+    pub async fn record_run(
+        &self,
+        descriptor: RunDescriptor<'_>,
+        status: &str,
+        message: Option<String>,
+    ) -> Result<run_history::Model, DbErr> {
+        self.insert(descriptor, status, message, Some(Utc::now()))
+            .await
+    }
+
+    async fn insert(
+        &self,
+        descriptor: RunDescriptor<'_>,
+        status: &str,
+        message: Option<String>,
+        finished_at: Option<DateTime<Utc>>,
+    ) -> Result<run_history::Model, DbErr> {
+        run_history::ActiveModel {
+            id: NotSet,
+            instance_id: Set(descriptor.instance_id),
+            schedule_id: Set(descriptor.schedule_id),
+            trigger: Set(descriptor.trigger.to_owned()),
+            action: Set(descriptor.action.to_owned()),
+            status: Set(status.to_owned()),
+            message: Set(message),
+            scheduled_at: Set(descriptor.scheduled_at),
+            started_at: Set(Utc::now()),
+            finished_at: Set(finished_at),
+        }
+        .insert(&self.database)
+        .await
+    }
+}
@@ -0,0 +1,521 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use tokio::process::Command;
+
+use crate::{entities::instance::Sandbox, services::RunAs};
+
+/// Host directories every sandbox gets read-only, missing ones are skipped.
+#[cfg(target_os = "linux")]
+const SYSTEM_PATHS: [&str; 8] = [
+    "/bin", "/sbin", "/lib", "/lib32", "/lib64", "/libx32", "/usr", "/etc",
+];
+
+/// Device nodes bound into the sandbox's private `/dev`.
+#[cfg(target_os = "linux")]
+const DEVICES: [&str; 6] = ["null", "zero", "full", "random", "urandom", "tty"];
+
+/// Launches processes in their own namespaces, the new root is assembled on a tmpfs mounted over `root`.
+pub struct SandboxService {
+    root: PathBuf,
+}
+
+/// A sandbox ready to be applied to a command, see [`PreparedSandbox::apply`].
+pub struct PreparedSandbox {
+    #[cfg(target_os = "linux")]
+    hook: linux::PreExecSandbox,
+}
+
+impl SandboxService {
+    pub fn new(root: PathBuf) -> Self {
+        Self { root }
+    }
+
+    pub fn ensure_path_created(&self) -> Result<(), io::Error> {
+        if !fs::exists(&self.root)? {
+            fs::create_dir(&self.root)?
+        };
+
+        Ok(())
+    }
+
+    /// Plans the mounts for a sandboxed launch, `None` if the sandbox is disabled.
+    /// The identity is switched inside the sandbox, so the command must not set it itself.
+    pub fn prepare(
+        &self,
+        sandbox: &Sandbox,
+        work_dir: &Path,
+        run_as: Option<&RunAs>,
+    ) -> Result<Option<PreparedSandbox>, io::Error> {
+        if !sandbox.enabled {
+            return Ok(None);
+        }
+
+        #[cfg(target_os = "linux")]
+        {
+            let hook = linux::PreExecSandbox::new(&self.root, sandbox, work_dir, run_as)?;
+            Ok(Some(PreparedSandbox { hook }))
+        }
+
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = (work_dir, run_as);
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "sandboxing is only supported on Linux",
+            ))
+        }
+    }
+}
+
+impl PreparedSandbox {
+    /// Installs a pre-exec hook that enters the namespaces, must be applied after the limits.
+    pub fn apply(self, command: &mut Command) {
+        #[cfg(target_os = "linux")]
+        unsafe {
+            let hook = self.hook;
+            command.pre_exec(move || hook.run());
+        }
+
+        #[cfg(not(target_os = "linux"))]
+        let _ = command;
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::{
+        ffi::CString,
+        fs, io,
+        os::unix::ffi::OsStrExt,
+        path::{Path, PathBuf},
+        ptr,
+    };
+
+    use libc::c_ulong;
+
+    use super::{DEVICES, SYSTEM_PATHS};
+    use crate::{entities::instance::Sandbox, services::RunAs};
+
+    enum Step {
+        Mkdir(CString),
+        Touch(CString),
+        Symlink {
+            target: CString,
+            path: CString,
+        },
+        Mount {
+            source: Option<CString>,
+            target: CString,
+            fstype: Option<CString>,
+            flags: c_ulong,
+            data: Option<CString>,
+        },
+    }
+
+    /// Everything is prepared before fork, the hook itself only makes async-signal-safe calls.
+    pub struct PreExecSandbox {
+        namespaces: libc::c_int,
+        steps: Vec<Step>,
+        root: CString,
+        old_root: CString,
+        work_dir: CString,
+        uid: u32,
+        gid: Option<u32>,
+    }
+
+    fn c_path(path: &Path) -> io::Result<CString> {
+        Ok(CString::new(path.as_os_str().as_bytes())?)
+    }
+
+    fn c_str(value: &str) -> CString {
+        CString::new(value).expect("constant without NUL")
+    }
+
+    struct Planner {
+        root: PathBuf,
+        steps: Vec<Step>,
+    }
+
+    impl Planner {
+        fn inside(&self, host_path: &Path) -> PathBuf {
+            self.root
+                .join(host_path.strip_prefix("/").unwrap_or(host_path))
+        }
+
+        /// Creates the directory and its parents inside the new root.
+        fn mkdirs(&mut self, host_path: &Path) -> io::Result<()> {
+            let mut ancestors = host_path
+                .ancestors()
+                .filter(|x| x.parent().is_some())
+                .collect::<Vec<_>>();
+            ancestors.reverse();
+            for ancestor in ancestors {
+                let path = c_path(&self.inside(ancestor))?;
+                self.steps.push(Step::Mkdir(path));
+            }
+
+            Ok(())
+        }
+
+        fn mount(
+            &mut self,
+            source: Option<&str>,
+            target: &Path,
+            fstype: Option<&str>,
+            flags: c_ulong,
+            data: Option<&str>,
+        ) -> io::Result<()> {
+            let target = c_path(&self.inside(target))?;
+            self.steps.push(Step::Mount {
+                source: source.map(c_str),
+                target,
+                fstype: fstype.map(c_str),
+                flags,
+                data: data.map(c_str),
+            });
+
+            Ok(())
+        }
+
+        fn tmpfs(&mut self, host_path: &Path, flags: c_ulong, mode: &str) -> io::Result<()> {
+            self.mkdirs(host_path)?;
+            self.mount(Some("tmpfs"), host_path, Some("tmpfs"), flags, Some(mode))
+        }
+
+        /// Binds a host file or directory to the same path inside the new root.
+        fn bind(&mut self, host_path: &Path, read_only: bool) -> io::Result<()> {
+            if host_path.is_dir() {
+                self.mkdirs(host_path)?;
+            } else {
+                if let Some(parent) = host_path.parent() {
+                    self.mkdirs(parent)?;
+                }
+                let path = c_path(&self.inside(host_path))?;
+                self.steps.push(Step::Touch(path));
+            }
+
+            let source = c_path(host_path)?;
+            let target = c_path(&self.inside(host_path))?;
+            self.steps.push(Step::Mount {
+                source: Some(source),
+                target: target.clone(),
+                fstype: None,
+                flags: libc::MS_BIND | libc::MS_REC,
+                data: None,
+            });
+            if read_only {
+                // a bind mount only becomes read-only after a remount
+                self.steps.push(Step::Mount {
+                    source: None,
+                    target,
+                    fstype: None,
+                    flags: libc::MS_REMOUNT | libc::MS_BIND | libc::MS_RDONLY,
+                    data: None,
+                });
+            }
+
+            Ok(())
+        }
+
+        fn symlink(&mut self, target: &Path, host_path: &Path) -> io::Result<()> {
+            let target = c_path(target)?;
+            let path = c_path(&self.inside(host_path))?;
+            self.steps.push(Step::Symlink { target, path });
+
+            Ok(())
+        }
+    }
+
+    impl PreExecSandbox {
+        pub fn new(
+            root: &Path,
+            sandbox: &Sandbox,
+            work_dir: &Path,
+            run_as: Option<&RunAs>,
+        ) -> Result<Self, io::Error> {
+            // without a user namespace, root inside the sandbox is root on the host
+            let uid = run_as
+                .and_then(|x| x.uid)
+                .filter(|x| *x != 0)
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "a sandbox needs a run as user other than root",
+                    )
+                })?;
+
+            let root = fs::canonicalize(root)?;
+            let work_dir = fs::canonicalize(work_dir)?;
+            let mut planner = Planner {
+                root: root.clone(),
+                steps: Vec::new(),
+            };
+
+            // keep the mounts below from propagating back to the host
+            planner.steps.push(Step::Mount {
+                source: None,
+                target: c_str("/"),
+                fstype: None,
+                flags: libc::MS_REC | libc::MS_PRIVATE,
+                data: None,
+            });
+            planner.steps.push(Step::Mount {
+                source: Some(c_str("tmpfs")),
+                target: c_path(&root)?,
+                fstype: Some(c_str("tmpfs")),
+                flags: libc::MS_NOSUID | libc::MS_NODEV,
+                data: Some(c_str("mode=0755")),
+            });
+
+            for path in SYSTEM_PATHS.map(Path::new) {
+                let Ok(metadata) = fs::symlink_metadata(path) else {
+                    continue;
+                };
+
+                // merged-usr systems link `/bin` and friends into `/usr`
+                if metadata.is_symlink() {
+                    planner.symlink(&fs::read_link(path)?, path)?;
+                } else {
+                    planner.bind(path, true)?;
+                }
+            }
+
+            planner.tmpfs(
+                Path::new("/dev"),
+                libc::MS_NOSUID | libc::MS_NOEXEC,
+                "mode=0755",
+            )?;
+            for device in DEVICES {
+                let path = Path::new("/dev").join(device);
+                if path.exists() {
+                    planner.bind(&path, false)?;
+                }
+            }
+            for (name, target) in [
+                ("fd", "/proc/self/fd"),
+                ("stdin", "/proc/self/fd/0"),
+                ("stdout", "/proc/self/fd/1"),
+                ("stderr", "/proc/self/fd/2"),
+            ] {
+                planner.symlink(Path::new(target), &Path::new("/dev").join(name))?;
+            }
+            planner.tmpfs(
+                Path::new("/dev/shm"),
+                libc::MS_NOSUID | libc::MS_NODEV,
+                "mode=1777",
+            )?;
+            planner.tmpfs(
+                Path::new("/tmp"),
+                libc::MS_NOSUID | libc::MS_NODEV,
+                "mode=1777",
+            )?;
+
+            for path in &sandbox.read_only_paths {
+                planner.bind(&fs::canonicalize(path)?, true)?;
+            }
+
+            // bound last so it stays writable even below a read-only path
+            planner.bind(&work_dir, false)?;
+
+            planner.mkdirs(Path::new("/proc"))?;
+            planner.mount(
+                Some("proc"),
+                Path::new("/proc"),
+                Some("proc"),
+                libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC,
+                None,
+            )?;
+
+            let old_root = root.join(".old");
+            planner.steps.push(Step::Mkdir(c_path(&old_root)?));
+
+            let mut namespaces = libc::CLONE_NEWNS | libc::CLONE_NEWPID | libc::CLONE_NEWIPC;
+            if sandbox.isolate_network {
+                namespaces |= libc::CLONE_NEWNET;
+            }
+
+            Ok(Self {
+                namespaces,
+                steps: planner.steps,
+                root: c_path(&root)?,
+                old_root: c_path(&old_root)?,
+                work_dir: c_path(&work_dir)?,
+                uid,
+                gid: run_as.and_then(|x| x.gid),
+            })
+        }
+
+        /// Runs in the forked child. A PID namespace only applies to children of the caller,
+        /// so the child forks once more and stays behind to relay the exit status.
+        pub fn run(&self) -> io::Result<()> {
+            unsafe {
+                check(libc::unshare(self.namespaces))?;
+
+                let pid = check(libc::fork())?;
+                if pid > 0 {
+                    relay_exit_status(pid);
+                }
+            }
+
+            self.enter()
+        }
+
+        fn enter(&self) -> io::Result<()> {
+            for step in &self.steps {
+                unsafe {
+                    match step {
+                        // the path may exist already, a mount onto it reports real failures
+                        Step::Mkdir(path) => _ = libc::mkdir(path.as_ptr(), 0o755),
+                        Step::Touch(path) => {
+                            let fd = libc::open(
+                                path.as_ptr(),
+                                libc::O_WRONLY | libc::O_CREAT | libc::O_CLOEXEC,
+                                0o644,
+                            );
+                            if fd >= 0 {
+                                libc::close(fd);
+                            }
+                        }
+                        Step::Symlink { target, path } => {
+                            _ = libc::symlink(target.as_ptr(), path.as_ptr())
+                        }
+                        Step::Mount {
+                            source,
+                            target,
+                            fstype,
+                            flags,
+                            data,
+                        } => {
+                            check(libc::mount(
+                                source.as_ref().map_or(ptr::null(), |x| x.as_ptr()),
+                                target.as_ptr(),
+                                fstype.as_ref().map_or(ptr::null(), |x| x.as_ptr()),
+                                *flags,
+                                data.as_ref().map_or(ptr::null(), |x| x.as_ptr().cast()),
+                            ))?;
+                        }
+                    }
+                }
+            }
+
+            unsafe {
+                check(libc::syscall(
+                    libc::SYS_pivot_root,
+                    self.root.as_ptr(),
+                    self.old_root.as_ptr(),
+                ) as libc::c_int)?;
+                check(libc::chdir(c"/".as_ptr()))?;
+                check(libc::umount2(c"/.old".as_ptr(), libc::MNT_DETACH))?;
+                _ = libc::rmdir(c"/.old".as_ptr());
+                check(libc::mount(
+                    ptr::null(),
+                    c"/".as_ptr(),
+                    ptr::null(),
+                    libc::MS_REMOUNT | libc::MS_RDONLY | libc::MS_NOSUID | libc::MS_NODEV,
+                    ptr::null(),
+                ))?;
+                check(libc::chdir(self.work_dir.as_ptr()))?;
+
+                if self.namespaces & libc::CLONE_NEWNET != 0 {
+                    loopback_up()?;
+                }
+
+                // nothing can be gained back by exec, not even through setuid binaries
+                for capability in 0..64 {
+                    if libc::prctl(libc::PR_CAPBSET_DROP, capability as c_ulong) != 0
+                        && io::Error::last_os_error().raw_os_error() != Some(libc::EINVAL)
+                    {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+                check(libc::prctl(
+                    libc::PR_SET_NO_NEW_PRIVS,
+                    1 as c_ulong,
+                    0,
+                    0,
+                    0,
+                ))?;
+
+                check(libc::setgroups(0, ptr::null()))?;
+                if let Some(gid) = self.gid {
+                    check(libc::setgid(gid))?;
+                }
+                check(libc::setuid(self.uid))?;
+
+                // the whole namespace goes down with the relaying parent,
+                // set last since changing credentials clears it
+                check(libc::prctl(
+                    libc::PR_SET_PDEATHSIG,
+                    libc::SIGKILL as libc::c_ulong,
+                ))?;
+            }
+
+            Ok(())
+        }
+    }
+
+    fn check<T: Default + PartialOrd>(result: T) -> io::Result<T> {
+        if result < T::default() {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(result)
+    }
+
+    /// Waits for the sandboxed child and exits the same way, never returns.
+    unsafe fn relay_exit_status(pid: libc::pid_t) -> ! {
+        unsafe {
+            // don't hold the pipe the parent uses to detect a successful exec
+            if libc::syscall(libc::SYS_close_range, 3, libc::c_uint::MAX, 0) != 0 {
+                for fd in 3..1024 {
+                    libc::close(fd);
+                }
+            }
+
+            let mut status = 0;
+            while libc::waitpid(pid, &mut status, 0) != pid {
+                if io::Error::last_os_error().raw_os_error() != Some(libc::EINTR) {
+                    libc::_exit(1);
+                }
+            }
+
+            if libc::WIFSIGNALED(status) {
+                libc::_exit(128 + libc::WTERMSIG(status));
+            }
+            libc::_exit(libc::WEXITSTATUS(status));
+        }
+    }
+
+    /// A new network namespace starts with its loopback interface down.
+    unsafe fn loopback_up() -> io::Result<()> {
+        unsafe {
+            let fd = check(libc::socket(
+                libc::AF_INET,
+                libc::SOCK_DGRAM | libc::SOCK_CLOEXEC,
+                0,
+            ))?;
+
+            let mut request: libc::ifreq = std::mem::zeroed();
+            for (dst, src) in request.ifr_name.iter_mut().zip(b"lo") {
+                *dst = *src as libc::c_char;
+            }
+
+            let mut result = libc::ioctl(fd, libc::SIOCGIFFLAGS as _, &mut request);
+            if result == 0 {
+                request.ifr_ifru.ifru_flags |= libc::IFF_UP as libc::c_short;
+                result = libc::ioctl(fd, libc::SIOCSIFFLAGS as _, &request);
+            }
+            let error = io::Error::last_os_error();
+            libc::close(fd);
+
+            if result != 0 {
+                return Err(error);
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,424 @@
+use std::{str::FromStr, time::Duration};
+
+use chrono::{DateTime, TimeDelta, Utc};
+use chrono_tz::Tz;
+use sea_orm::{
+    ActiveModelTrait, ColumnTrait, DbErr, EntityTrait, IntoActiveModel, QueryFilter, Set,
+};
+use tokio::sync::Notify;
+use tracing::Instrument;
+
+use crate::{
+    AppStateRef,
+    entities::schedule,
+    services::{
+        RUN_STATUS_MISSED, RUN_TRIGGER_SCHEDULE, RunDescriptor, backup_instance, rcon_command,
+        restart_instance, send_command, start_instance, stop_instance,
+    },
+};
+
+/// Upper bound of the time the scheduler sleeps between two checks.
+const MAX_IDLE: Duration = Duration::from_secs(60);
+
+/// A run that starts later than this is treated as missed, e.g. because the slave was down.
+const MISSED_GRACE: TimeDelta = TimeDelta::seconds(60);
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ScheduleAction {
+    Start,
+    Stop,
+    Restart,
+    Command,
+    Rcon,
+    Backup,
+}
+
+impl FromStr for ScheduleAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "start" => Ok(Self::Start),
+            "stop" => Ok(Self::Stop),
+            "restart" => Ok(Self::Restart),
+            "command" => Ok(Self::Command),
+            "rcon" => Ok(Self::Rcon),
+            "backup" => Ok(Self::Backup),
+            other => Err(format!("unknown action `{}`", other)),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum MissedRunPolicy {
+    /// Drop the missed run and wait for the next one.
+    Skip,
+    /// Run once as soon as possible, no matter how many runs were missed.
+    RunOnce,
+}
+
+impl FromStr for MissedRunPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(Self::Skip),
+            "runOnce" => Ok(Self::RunOnce),
+            other => Err(format!("unknown missed run policy `{}`", other)),
+        }
+    }
+}
+
+/// Parses a cron expression, the classic 5-field form is accepted and fires at second 0.
+pub fn parse_cron_expression(expression: &str) -> Result<cron::Schedule, cron::error::Error> {
+    let expression = expression.trim();
+    if expression.split_whitespace().count() == 5 {
+        cron::Schedule::from_str(&format!("0 {}", expression))
+    } else {
+        cron::Schedule::from_str(expression)
+    }
+}
+
+pub fn validate_schedule(model: &schedule::Model) -> Result<(), String> {
+    parse_cron_expression(&model.cron_expression).map_err(|e| e.to_string())?;
+    Tz::from_str(&model.timezone).map_err(|e| e.to_string())?;
+    MissedRunPolicy::from_str(&model.missed_run_policy)?;
+
+    let action = ScheduleAction::from_str(&model.action)?;
+    if matches!(action, ScheduleAction::Command | ScheduleAction::Rcon)
+        && model.payload.as_ref().is_none_or(|x| x.trim().is_empty())
+    {
+        return Err(format!("{} action requires a payload", model.action));
+    }
+
+    Ok(())
+}
+
+/// Computes the first fire time of a schedule strictly after `after`.
+pub fn next_fire_time(model: &schedule::Model, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    let cron = parse_cron_expression(&model.cron_expression).ok()?;
+    let timezone = Tz::from_str(&model.timezone).ok()?;
+
+    cron.after(&after.with_timezone(&timezone))
+        .next()
+        .map(|x| x.with_timezone(&Utc))
+}
+
+/// Whether a run due at `due_at` is carried out at `now`, missed runs only if the policy asks
+/// for it.
+fn should_run(model: &schedule::Model, due_at: DateTime<Utc>, now: DateTime<Utc>) -> bool {
+    let missed = now - due_at > MISSED_GRACE;
+    let policy =
+        MissedRunPolicy::from_str(&model.missed_run_policy).unwrap_or(MissedRunPolicy::Skip);
+    !missed || policy == MissedRunPolicy::RunOnce
+}
+
+#[derive(Default)]
+pub struct SchedulerService {
+    changed: Notify,
+}
+
+impl SchedulerService {
+    pub fn new() -> Self {
+        Self {
+            changed: Notify::new(),
+        }
+    }
+
+    /// Wakes the scheduler up so it picks up created, updated or deleted schedules.
+    pub fn notify_changed(&self) {
+        self.changed.notify_one();
+    }
+
+    pub fn start(state: AppStateRef) {
+        tokio::spawn(
+            async move {
+                tracing::info!("Scheduler started");
+
+                loop {
+                    let now = Utc::now();
+                    let next = match Self::tick(&state, now).await {
+                        Ok(v) => v,
+                        Err(e) => {
+                            tracing::error!("Scheduler tick error: {}", e);
+                            None
+                        }
+                    };
+
+                    let sleep_for = next
+                        .and_then(|x| (x - Utc::now()).to_std().ok())
+                        .unwrap_or(MAX_IDLE)
+                        .min(MAX_IDLE);
+
+                    tokio::select! {
+                        _ = tokio::time::sleep(sleep_for) => {}
+                        _ = state.scheduler.changed.notified() => {}
+                    }
+                }
+            }
+            .instrument(tracing::info_span!(parent: None, "scheduler")),
+        );
+    }
+
+    /// Fires every due schedule and returns the time of the closest upcoming run.
+    async fn tick(state: &AppStateRef, now: DateTime<Utc>) -> Result<Option<DateTime<Utc>>, DbErr> {
+        let db = &state.database;
+        let schedules = schedule::Entity::find()
+            .filter(schedule::Column::Enabled.eq(true))
+            .all(db)
+            .await?;
+
+        let mut closest: Option<DateTime<Utc>> = None;
+
+        for model in schedules {
+            let due_at = match model.next_run_at {
+                Some(v) if v > now => {
+                    closest = Some(closest.map_or(v, |x| x.min(v)));
+                    continue;
+                }
+                Some(v) => v,
+                None => {
+                    // never planned yet, plan it without running
+                    let next_run_at = next_fire_time(&model, now);
+                    let mut active = model.into_active_model();
+                    active.next_run_at = Set(next_run_at);
+                    active.update(db).await?;
+                    if let Some(v) = next_run_at {
+                        closest = Some(closest.map_or(v, |x| x.min(v)));
+                    }
+                    continue;
+                }
+            };
+
+            let should_run = should_run(&model, due_at, now);
+
+            if should_run {
+                Self::spawn_run(state.clone(), model.clone(), due_at);
+            } else {
+                tracing::warn!("Schedule {} missed its run at {}", model.id, due_at);
+                state
+                    .run_history
+                    .record_run(
+                        RunDescriptor {
+                            instance_id: model.instance_id,
+                            schedule_id: Some(model.id),
+                            trigger: RUN_TRIGGER_SCHEDULE,
+                            action: &model.action,
+                            scheduled_at: Some(due_at),
+                        },
+                        RUN_STATUS_MISSED,
+                        None,
+                    )
+                    .await?;
+            }
+
+            let next_run_at = next_fire_time(&model, now);
+            let mut active = model.into_active_model();
+            if should_run {
+                active.last_run_at = Set(Some(now));
+            }
+            active.next_run_at = Set(next_run_at);
+            active.update(db).await?;
+
+            if let Some(v) = next_run_at {
+                closest = Some(closest.map_or(v, |x| x.min(v)));
+            }
+        }
+
+        Ok(closest)
+    }
+
+    fn spawn_run(state: AppStateRef, model: schedule::Model, scheduled_at: DateTime<Utc>) {
+        tokio::spawn(
+            async move {
+                let run = state
+                    .run_history
+                    .begin_run(RunDescriptor {
+                        instance_id: model.instance_id,
+                        schedule_id: Some(model.id),
+                        trigger: RUN_TRIGGER_SCHEDULE,
+                        action: &model.action,
+                        scheduled_at: Some(scheduled_at),
+                    })
+                    .await;
+
+                let result = Self::execute(&state, &model).await;
+                if let Err(e) = &result {
+                    tracing::error!("Schedule {} failed: {}", model.id, e);
+                }
+
+                match run {
+                    Ok(run) => {
+                        if let Err(e) = state.run_history.finish_run(run, result).await {
+                            tracing::error!("Failed to record run of schedule {}: {}", model.id, e);
+                        }
+                    }
+                    Err(e) => {
+                        tracing::error!("Failed to record run of schedule {}: {}", model.id, e)
+                    }
+                }
+            }
+            .instrument(tracing::info_span!(parent: None, "scheduled run")),
+        );
+    }
+
+    async fn execute(
+        state: &AppStateRef,
+        model: &schedule::Model,
+    ) -> Result<Option<String>, String> {
+        let id = u64::try_from(model.instance_id).map_err(|e| e.to_string())?;
+        let action = ScheduleAction::from_str(&model.action)?;
+
+        let result = match action {
+            ScheduleAction::Start => start_instance(state, id).await.map(|_| None),
+            ScheduleAction::Stop => stop_instance(state, id).await.map(|_| None),
+            ScheduleAction::Restart => restart_instance(state, id).await.map(|_| None),
+            ScheduleAction::Command => {
+                send_command(state, id, model.payload.as_deref().unwrap_or_default())
+                    .await
+                    .map(|_| None)
+            }
+            ScheduleAction::Backup => backup_instance(state, id)
+                .await
+                .map(|x| Some(format!("backup written to {}", x.display()))),
+            // the output is kept so it can be read from the run history
+            ScheduleAction::Rcon => {
+                return rcon_command(state, id, model.payload.as_deref().unwrap_or_default())
+                    .await
+                    .map(Some)
+                    .map_err(|e| e.to_string());
+            }
+        };
+
+        result.map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schedule(cron_expression: &str, timezone: &str) -> schedule::Model {
+        schedule::Model {
+            id: 1,
+            instance_id: 1,
+            cron_expression: cron_expression.to_owned(),
+            timezone: timezone.to_owned(),
+            action: "restart".to_owned(),
+            payload: None,
+            enabled: true,
+            missed_run_policy: "skip".to_owned(),
+            last_run_at: None,
+            next_run_at: None,
+        }
+    }
+
+    fn at(value: &str) -> DateTime<Utc> {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn cron_expressions_are_parsed() {
+        // 5 fields fire at second 0, 6 fields carry the seconds themselves
+        let every_minute = schedule("* * * * *", "UTC");
+        assert_eq!(
+            next_fire_time(&every_minute, at("2026-01-01T10:00:30Z")),
+            Some(at("2026-01-01T10:01:00Z"))
+        );
+        let with_seconds = schedule("15 * * * * *", "UTC");
+        assert_eq!(
+            next_fire_time(&with_seconds, at("2026-01-01T10:00:30Z")),
+            Some(at("2026-01-01T10:01:15Z"))
+        );
+
+        assert!(parse_cron_expression("0 4 * * mon-fri").is_ok());
+        assert!(parse_cron_expression("61 * * * *").is_err());
+        assert!(parse_cron_expression("every day").is_err());
+    }
+
+    #[test]
+    fn schedules_are_validated() {
+        assert!(validate_schedule(&schedule("0 4 * * *", "Europe/Berlin")).is_ok());
+        assert!(validate_schedule(&schedule("0 4 * * *", "Mars/Olympus")).is_err());
+        assert!(validate_schedule(&schedule("0 4 * *", "UTC")).is_err());
+
+        let mut model = schedule("0 4 * * *", "UTC");
+        model.missed_run_policy = "runTwice".to_owned();
+        assert!(validate_schedule(&model).is_err());
+
+        let mut model = schedule("0 4 * * *", "UTC");
+        model.action = "rcon".to_owned();
+        assert!(validate_schedule(&model).is_err());
+        model.payload = Some("say hi".to_owned());
+        assert!(validate_schedule(&model).is_ok());
+    }
+
+    #[test]
+    fn next_fire_time_is_strictly_after() {
+        let model = schedule("0 4 * * *", "UTC");
+
+        assert_eq!(
+            next_fire_time(&model, at("2026-01-01T04:00:00Z")),
+            Some(at("2026-01-02T04:00:00Z"))
+        );
+        assert_eq!(
+            next_fire_time(&model, at("2026-01-01T03:59:59Z")),
+            Some(at("2026-01-01T04:00:00Z"))
+        );
+    }
+
+    #[test]
+    fn fire_times_follow_the_timezone() {
+        let model = schedule("0 4 * * *", "Europe/Berlin");
+
+        // CET is UTC+1 in winter, CEST UTC+2 in summer
+        assert_eq!(
+            next_fire_time(&model, at("2026-01-10T00:00:00Z")),
+            Some(at("2026-01-10T03:00:00Z"))
+        );
+        assert_eq!(
+            next_fire_time(&model, at("2026-07-10T00:00:00Z")),
+            Some(at("2026-07-10T02:00:00Z"))
+        );
+
+        let model = schedule("0 4 * * *", "America/New_York");
+        assert_eq!(
+            next_fire_time(&model, at("2026-01-10T00:00:00Z")),
+            Some(at("2026-01-10T09:00:00Z"))
+        );
+    }
+
+    #[test]
+    fn dst_transitions_fire_at_most_once() {
+        let model = schedule("30 2 * * *", "Europe/Berlin");
+
+        // 02:30 doesn't exist when the clocks jump from 02:00 to 03:00, that day is skipped
+        assert_eq!(
+            next_fire_time(&model, at("2026-03-28T12:00:00Z")),
+            Some(at("2026-03-30T00:30:00Z"))
+        );
+
+        // 02:30 happens twice when the clocks go back from 03:00 to 02:00, it only fires once
+        let first = next_fire_time(&model, at("2026-10-24T12:00:00Z")).unwrap();
+        assert_eq!(first, at("2026-10-25T00:30:00Z"));
+        assert_eq!(
+            next_fire_time(&model, first),
+            Some(at("2026-10-26T01:30:00Z"))
+        );
+    }
+
+    #[test]
+    fn missed_runs_follow_the_policy() {
+        let now = at("2026-01-01T12:00:00Z");
+        let mut model = schedule("0 * * * *", "UTC");
+
+        // late within the grace period, e.g. a slow tick
+        assert!(should_run(&model, now - TimeDelta::seconds(30), now));
+
+        // due while the slave was down
+        let due_at = now - TimeDelta::hours(3);
+        assert!(!should_run(&model, due_at, now));
+        model.missed_run_policy = "runOnce".to_owned();
+        assert!(should_run(&model, due_at, now));
+    }
+}
@@ -0,0 +1,304 @@
+use aes_gcm::{
+    Aes256Gcm, Key, KeyInit, Nonce,
+    aead::{Aead, AeadCore, OsRng},
+};
+use std::fmt::Display;
+
+use axum::http::StatusCode;
+use base64::{Engine, prelude::BASE64_STANDARD};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    entities::instance::{self, Environment},
+    errors::StatusCodeError,
+};
+
+const SEALED_PREFIX: &str = "sealed:v1:";
+const NONCE_SIZE: usize = 12;
+
+type Result<T> = std::result::Result<T, SecretError>;
+
+#[derive(Debug)]
+pub enum SecretError {
+    /// No passphrase is configured, values can't be sealed or unsealed.
+    NoKey,
+    Cipher(String),
+}
+
+impl StatusCodeError for SecretError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Self::NoKey => StatusCode::SERVICE_UNAVAILABLE,
+            Self::Cipher(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl Display for SecretError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NoKey => write!(f, "Secrets need LCSM_SECRET_KEY to be set on the slave"),
+            Self::Cipher(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for SecretError {}
+
+/// Encrypts secrets before they are written to the database.
+pub struct SecretService {
+    /// Only instances without secrets can be used without a passphrase.
+    cipher: Option<Aes256Gcm>,
+}
+
+impl SecretService {
+    /// The key is derived from an arbitrary passphrase, so changing it makes stored secrets unreadable.
+    pub fn new(passphrase: Option<impl AsRef<[u8]>>) -> Self {
+        Self {
+            cipher: passphrase.map(|x| {
+                let key = Sha256::digest(x.as_ref());
+                Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key))
+            }),
+        }
+    }
+
+    fn cipher(&self) -> Result<&Aes256Gcm> {
+        self.cipher.as_ref().ok_or(SecretError::NoKey)
+    }
+
+    pub fn is_sealed(value: &str) -> bool {
+        value.starts_with(SEALED_PREFIX)
+    }
+
+    pub fn seal(&self, plaintext: &str) -> Result<String> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher()?
+            .encrypt(&nonce, plaintext.as_bytes())
+            .map_err(|e| SecretError::Cipher(format!("seal secret: {}", e)))?;
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend(ciphertext);
+
+        Ok(format!(
+            "{}{}",
+            SEALED_PREFIX,
+            BASE64_STANDARD.encode(sealed)
+        ))
+    }
+
+    pub fn unseal(&self, value: &str) -> Result<String> {
+        let sealed = value
+            .strip_prefix(SEALED_PREFIX)
+            .ok_or_else(|| SecretError::Cipher("value is not sealed".to_owned()))?;
+        let cipher = self.cipher()?;
+        let sealed = BASE64_STANDARD
+            .decode(sealed)
+            .map_err(|e| SecretError::Cipher(format!("decode sealed value: {}", e)))?;
+        if sealed.len() < NONCE_SIZE {
+            return Err(SecretError::Cipher("sealed value is truncated".to_owned()));
+        }
+
+        let (nonce, ciphertext) = sealed.split_at(NONCE_SIZE);
+        let plaintext = cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|e| SecretError::Cipher(format!("unseal secret: {}", e)))?;
+
+        String::from_utf8(plaintext)
+            .map_err(|e| SecretError::Cipher(format!("unsealed secret: {}", e)))
+    }
+
+    /// Seals values of secret variables, secrets are never unsealed for a client.
+    pub fn seal_environment(&self, environment: &mut Environment) -> Result<()> {
+        for variable in environment.0.iter_mut() {
+            let Some(value) = variable.value.as_ref() else {
+                continue;
+            };
+
+            if variable.secret && !Self::is_sealed(value) {
+                variable.value = Some(self.seal(value)?);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Seals every secret of an instance before it is stored.
+    pub fn seal_instance(&self, model: &mut instance::Model) -> Result<()> {
+        self.seal_environment(&mut model.environment)?;
+
+        if let Some(password) = model
+            .minecraft
+            .rcon
+            .as_mut()
+            .and_then(|x| x.password.as_mut())
+            && !Self::is_sealed(password)
+        {
+            *password = self.seal(password)?;
+        }
+
+        Ok(())
+    }
+
+    /// Unseals a value if it is sealed, plain values are returned as they are.
+    pub fn open(&self, value: &str) -> Result<String> {
+        if Self::is_sealed(value) {
+            return self.unseal(value);
+        }
+
+        Ok(value.to_owned())
+    }
+
+    /// Resolves the environment into plain name/value pairs for launching a process.
+    pub fn open_environment(
+        &self,
+        environment: &Environment,
+    ) -> Result<Vec<(String, Option<String>)>> {
+        environment
+            .0
+            .iter()
+            .map(|variable| {
+                // only values of secret variables were sealed by us
+                let value = match &variable.value {
+                    Some(v) if variable.secret => Some(self.open(v)?),
+                    v => v.clone(),
+                };
+
+                Ok((variable.name.clone(), value))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn secrets() -> SecretService {
+        SecretService::new(Some("correct horse battery staple"))
+    }
+
+    fn the_instance() -> instance::Model {
+        serde_json::from_value(json!({
+            "id": 1,
+            "name": "survival",
+            "launchCommand": "java",
+            "workDir": "/srv/survival",
+            "arguments": ["-jar", "server.jar"],
+            "useShell": false,
+            "environment": [
+                { "name": "JAVA_HOME", "value": "/opt/java" },
+                { "name": "DB_PASSWORD", "value": "hunter2", "secret": true },
+            ],
+            "minecraft": { "rcon": { "password": "rcon-password" } },
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn sealed_values_open_again() {
+        let secrets = secrets();
+
+        let sealed = secrets.seal("hunter2").unwrap();
+        assert!(sealed.starts_with("sealed:v1:"));
+        assert!(SecretService::is_sealed(&sealed));
+        assert!(!sealed.contains("hunter2"));
+        assert_eq!(secrets.unseal(&sealed).unwrap(), "hunter2");
+        assert_eq!(secrets.open(&sealed).unwrap(), "hunter2");
+
+        // every seal gets its own nonce
+        assert_ne!(secrets.seal("hunter2").unwrap(), sealed);
+
+        // plain values pass through
+        assert_eq!(secrets.open("plain").unwrap(), "plain");
+        assert!(secrets.unseal("plain").is_err());
+    }
+
+    #[test]
+    fn wrong_key_fails_to_open() {
+        let sealed = secrets().seal("hunter2").unwrap();
+
+        let other = SecretService::new(Some("another passphrase"));
+        assert!(matches!(other.open(&sealed), Err(SecretError::Cipher(_))));
+    }
+
+    #[test]
+    fn tampered_values_fail_to_open() {
+        let secrets = secrets();
+        let sealed = secrets.seal("hunter2").unwrap();
+        let mut bytes = BASE64_STANDARD
+            .decode(sealed.strip_prefix(SEALED_PREFIX).unwrap())
+            .unwrap();
+
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        let tampered = format!("{}{}", SEALED_PREFIX, BASE64_STANDARD.encode(&bytes));
+        assert!(matches!(
+            secrets.open(&tampered),
+            Err(SecretError::Cipher(_))
+        ));
+
+        let truncated = format!("{}{}", SEALED_PREFIX, BASE64_STANDARD.encode(&bytes[..4]));
+        assert!(secrets.open(&truncated).is_err());
+        assert!(secrets.open("sealed:v1:not base64!").is_err());
+    }
+
+    #[test]
+    fn missing_key_fails_only_for_secrets() {
+        let secrets = SecretService::new(None::<&str>);
+
+        assert!(matches!(secrets.seal("hunter2"), Err(SecretError::NoKey)));
+        assert_eq!(secrets.open("plain").unwrap(), "plain");
+
+        let mut model = the_instance();
+        model.environment.0.retain(|x| !x.secret);
+        model.minecraft.rcon = None;
+        secrets.seal_instance(&mut model).unwrap();
+        assert!(matches!(
+            secrets.seal_instance(&mut the_instance()),
+            Err(SecretError::NoKey)
+        ));
+    }
+
+    #[test]
+    fn instances_are_sealed_and_opened() {
+        let secrets = secrets();
+        let mut model = the_instance();
+
+        secrets.seal_instance(&mut model).unwrap();
+        let variables = &model.environment.0;
+        assert_eq!(variables[0].value.as_deref(), Some("/opt/java"));
+        assert!(SecretService::is_sealed(
+            variables[1].value.as_ref().unwrap()
+        ));
+        let password = model.minecraft.rcon.as_ref().unwrap().password.as_ref();
+        assert!(SecretService::is_sealed(password.unwrap()));
+
+        // sealing twice leaves sealed values alone
+        let sealed = model.clone();
+        secrets.seal_instance(&mut model).unwrap();
+        assert_eq!(model, sealed);
+
+        let environment = secrets.open_environment(&model.environment).unwrap();
+        assert_eq!(
+            environment,
+            vec![
+                ("JAVA_HOME".to_owned(), Some("/opt/java".to_owned())),
+                ("DB_PASSWORD".to_owned(), Some("hunter2".to_owned())),
+            ]
+        );
+    }
+
+    #[test]
+    fn redacted_hides_secrets() {
+        let model = the_instance().redacted();
+
+        let variables = &model.environment.0;
+        assert_eq!(variables[0].value.as_deref(), Some("/opt/java"));
+        assert_eq!(variables[1].name, "DB_PASSWORD");
+        assert_eq!(variables[1].value, None);
+        assert_eq!(model.minecraft.rcon.unwrap().password, None);
+    }
+}
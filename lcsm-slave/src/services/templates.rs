@@ -0,0 +1,364 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    io,
+    path::{Path, PathBuf},
+};
+
+use axum::http::StatusCode;
+use sea_orm::{ActiveModelTrait, ActiveValue::NotSet, DbErr, EntityTrait};
+use serde::Deserialize;
+use tokio::io::AsyncWriteExt;
+
+use crate::{
+    AppState,
+    entities::{
+        instance::{self, Arguments, Environment, EnvironmentVariable},
+        template::{self, BUILTIN_VARIABLES, PLACEHOLDER, is_relative_path},
+    },
+    errors::StatusCodeError,
+    services::{
+        DiskQuotaService, SecretError, create_empty_work_dir, discard_work_dir, new_work_dir,
+    },
+};
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProvisionRequest {
+    pub template_id: i32,
+    pub name: String,
+    /// Defaults to a directory named after the instance in the instances path.
+    #[serde(default)]
+    pub work_dir: Option<String>,
+    #[serde(default)]
+    pub variables: HashMap<String, String>,
+}
+
+#[derive(Debug)]
+pub enum TemplateError {
+    NotFound,
+    Invalid(String),
+    WorkDirNotEmpty(PathBuf),
+    QuotaExceeded(String),
+    DbErr(DbErr),
+    IoError(io::Error),
+    SecretError(SecretError),
+}
+
+impl StatusCodeError for TemplateError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Self::NotFound => StatusCode::NOT_FOUND,
+            Self::Invalid(_) => StatusCode::BAD_REQUEST,
+            Self::WorkDirNotEmpty(_) => StatusCode::CONFLICT,
+            Self::QuotaExceeded(_) => StatusCode::INSUFFICIENT_STORAGE,
+            Self::SecretError(e) => e.status_code(),
+            Self::DbErr(_) | Self::IoError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl Display for TemplateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotFound => write!(f, "Template not found"),
+            Self::Invalid(e) => write!(f, "Invalid provisioning request: {}", e),
+            Self::WorkDirNotEmpty(path) => {
+                write!(f, "Work dir {} exists and is not empty", path.display())
+            }
+            Self::QuotaExceeded(e) => write!(f, "Disk quota exceeded: {}", e),
+            Self::DbErr(e) => e.fmt(f),
+            Self::IoError(e) => e.fmt(f),
+            Self::SecretError(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+impl From<DbErr> for TemplateError {
+    fn from(value: DbErr) -> Self {
+        Self::DbErr(value)
+    }
+}
+
+impl From<io::Error> for TemplateError {
+    fn from(value: io::Error) -> Self {
+        Self::IoError(value)
+    }
+}
+
+/// Creates an instance from a template: renders its settings and files with the supplied
+/// variables, writes the files into a fresh work dir and inserts the instance.
+pub async fn provision_instance(
+    state: &AppState,
+    request: ProvisionRequest,
+) -> Result<instance::Model, TemplateError> {
+    let the_template = template::Entity::find_by_id(request.template_id)
+        .one(&state.database)
+        .await?
+        .ok_or(TemplateError::NotFound)?;
+
+    let work_dir = new_work_dir(state, &request.name, request.work_dir.as_deref())
+        .map_err(TemplateError::Invalid)?;
+
+    let mut variables = resolve_variables(&the_template, &request.variables)?;
+    variables.insert("instanceName".to_owned(), request.name.clone());
+    variables.insert(
+        "workDir".to_owned(),
+        work_dir.to_string_lossy().into_owned(),
+    );
+    let render = |text: &str| render(text, &variables);
+
+    let mut environment = Vec::with_capacity(the_template.environment.0.len());
+    for variable in &the_template.environment.0 {
+        // only secrets are sealed, anything else is used as written
+        let value = match &variable.value {
+            Some(v) if variable.secret => Some(render(
+                &state
+                    .secret_manager
+                    .open(v)
+                    .map_err(TemplateError::SecretError)?,
+            )),
+            Some(v) => Some(render(v)),
+            None => None,
+        };
+        environment.push(EnvironmentVariable {
+            name: variable.name.clone(),
+            value,
+            secret: variable.secret,
+        });
+    }
+
+    let mut model = instance::Model {
+        id: 0,
+        name: request.name.clone(),
+        launch_command: render(&the_template.launch_command),
+        work_dir: work_dir.to_string_lossy().into_owned(),
+        arguments: Arguments(the_template.arguments.0.iter().map(|x| render(x)).collect()),
+        use_shell: the_template.use_shell,
+        inherit_env: the_template.inherit_env,
+        environment: Environment(environment),
+        autostart: false,
+        autostart_delay: 0,
+        autostart_order: 0,
+        dependencies: Default::default(),
+        limits: Default::default(),
+        run_as_user: None,
+        run_as_group: None,
+        sandbox: Default::default(),
+        disk_quota: Default::default(),
+        health_checks: Default::default(),
+        minecraft: Default::default(),
+        hooks: Default::default(),
+        crash_reports: Default::default(),
+        output_rules: Default::default(),
+    };
+    model.validate().map_err(TemplateError::Invalid)?;
+
+    let mut files = Vec::with_capacity(the_template.files.0.len());
+    for file in &the_template.files.0 {
+        let path = render(&file.path);
+        if !is_relative_path(&path) {
+            return Err(TemplateError::Invalid(format!(
+                "rendered file path `{}` must be relative",
+                path
+            )));
+        }
+        files.push((path, render(&file.content), file.executable));
+    }
+
+    state
+        .secret_manager
+        .seal_instance(&mut model)
+        .map_err(TemplateError::SecretError)?;
+
+    let size = files
+        .iter()
+        .map(|(_, content, _)| content.len() as u64)
+        .sum();
+    DiskQuotaService::check_write(state, &model, size)
+        .await
+        .map_err(TemplateError::QuotaExceeded)?;
+
+    let created = create_empty_work_dir(&work_dir)
+        .await
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::DirectoryNotEmpty => TemplateError::WorkDirNotEmpty(work_dir.clone()),
+            _ => e.into(),
+        })?;
+    let result = async {
+        write_files(&work_dir, &files).await?;
+
+        let active = instance::ActiveModel {
+            id: NotSet,
+            ..model.into()
+        };
+        Ok(active.insert(&state.database).await?)
+    }
+    .await;
+
+    if result.is_err() {
+        // leave the work dir as it was found
+        if let Err(e) = discard_work_dir(&work_dir, created).await {
+            tracing::warn!("Failed to clean up {}: {}", work_dir.display(), e);
+        }
+    }
+
+    result
+}
+
+/// Checks the supplied variables against the declared ones and fills in defaults.
+fn resolve_variables(
+    the_template: &template::Model,
+    supplied: &HashMap<String, String>,
+) -> Result<HashMap<String, String>, TemplateError> {
+    if let Some(name) = supplied
+        .keys()
+        .find(|name| !the_template.variables.0.iter().any(|x| x.name == **name))
+    {
+        let reason = if BUILTIN_VARIABLES.contains(&name.as_str()) {
+            "is built in"
+        } else {
+            "is not declared by the template"
+        };
+        return Err(TemplateError::Invalid(format!(
+            "variable `{}` {}",
+            name, reason
+        )));
+    }
+
+    let mut variables = HashMap::new();
+    for variable in &the_template.variables.0 {
+        let value = supplied
+            .get(&variable.name)
+            .or(variable.default.as_ref())
+            .ok_or_else(|| {
+                TemplateError::Invalid(format!(
+                    "missing variable `{}` ({})",
+                    variable.name, variable.prompt
+                ))
+            })?;
+        variable.check(value).map_err(TemplateError::Invalid)?;
+        variables.insert(variable.name.clone(), value.clone());
+    }
+
+    Ok(variables)
+}
+
+/// Templates are validated on save, so every placeholder has a value.
+fn render(text: &str, variables: &HashMap<String, String>) -> String {
+    PLACEHOLDER
+        .replace_all(text, |captures: &regex::Captures| {
+            variables
+                .get(&captures[1])
+                .cloned()
+                .unwrap_or_else(|| captures[0].to_owned())
+        })
+        .into_owned()
+}
+
+async fn write_files(work_dir: &Path, files: &[(String, String, bool)]) -> Result<(), io::Error> {
+    for (path, content, executable) in files {
+        let path = work_dir.join(path);
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        let mut file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(if *executable { 0o755 } else { 0o644 })
+            .open(&path)
+            .await?;
+        file.write_all(content.as_bytes()).await?;
+        file.flush().await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn the_template() -> template::Model {
+        serde_json::from_value(json!({
+            "id": 1,
+            "name": "paper",
+            "launchCommand": "java",
+            "arguments": ["-Xmx{{memory}}", "-jar", "server.jar"],
+            "useShell": false,
+            "variables": [
+                { "name": "memory", "prompt": "Memory", "default": "2G", "pattern": "[0-9]+[MG]" },
+                { "name": "port", "prompt": "Server port", "pattern": "[0-9]{1,5}" },
+            ],
+        }))
+        .unwrap()
+    }
+
+    fn supplied(values: &[(&str, &str)]) -> HashMap<String, String> {
+        values
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn placeholders_are_rendered() {
+        let variables = supplied(&[("port", "25565"), ("instanceName", "survival")]);
+
+        assert_eq!(
+            render("server-port={{port}}\nmotd={{ instanceName }}", &variables),
+            "server-port=25565\nmotd=survival"
+        );
+        assert_eq!(render("{{port}}{{port}}", &variables), "2556525565");
+        // unknown placeholders and other braces are left alone
+        assert_eq!(
+            render("{{unknown}} {port} {{ 1x }}", &variables),
+            "{{unknown}} {port} {{ 1x }}"
+        );
+    }
+
+    #[test]
+    fn defaults_fill_in_missing_variables() {
+        let variables =
+            resolve_variables(&the_template(), &supplied(&[("port", "25566")])).unwrap();
+
+        assert_eq!(variables, supplied(&[("memory", "2G"), ("port", "25566")]));
+    }
+
+    #[test]
+    fn variables_are_checked() {
+        let the_template = the_template();
+        let invalid = |values| {
+            matches!(
+                resolve_variables(&the_template, &supplied(values)),
+                Err(TemplateError::Invalid(_))
+            )
+        };
+
+        // no default for the port
+        assert!(invalid(&[("memory", "4G")]));
+        // the whole value must match the pattern
+        assert!(invalid(&[("port", "25565; rm -rf /")]));
+        assert!(invalid(&[("port", "25565"), ("memory", "lots")]));
+        // only declared variables are accepted
+        assert!(invalid(&[("port", "25565"), ("other", "x")]));
+        assert!(invalid(&[("port", "25565"), ("workDir", "/")]));
+    }
+
+    #[test]
+    fn file_paths_must_stay_relative() {
+        assert!(is_relative_path("server.properties"));
+        assert!(is_relative_path("config/paper.yml"));
+
+        assert!(!is_relative_path(""));
+        assert!(!is_relative_path("/etc/passwd"));
+        assert!(!is_relative_path("../outside"));
+        assert!(!is_relative_path("config/../../outside"));
+        assert!(!is_relative_path("nul\0byte"));
+    }
+}
@@ -0,0 +1,70 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use crate::AppState;
+
+/// Picks the work dir of a new instance, by default a directory named after it in the
+/// instances path.
+pub fn new_work_dir(
+    state: &AppState,
+    name: &str,
+    requested: Option<&str>,
+) -> Result<PathBuf, String> {
+    if name.trim().is_empty() {
+        return Err("name must not be empty".to_owned());
+    }
+
+    match requested {
+        Some(v) if v.starts_with('/') => Ok(PathBuf::from(v)),
+        Some(_) => Err("work dir must be absolute".to_owned()),
+        None => Ok(state.instance_path.join(dir_name(name))),
+    }
+}
+
+/// A file system friendly version of the instance name.
+fn dir_name(name: &str) -> String {
+    let mut result = String::with_capacity(name.len());
+    for c in name.trim().chars() {
+        if c.is_alphanumeric() || c == '-' || c == '_' {
+            result.extend(c.to_lowercase());
+        } else if !result.ends_with('-') {
+            result.push('-');
+        }
+    }
+
+    let result = result.trim_matches('-');
+    if result.is_empty() {
+        "instance".to_owned()
+    } else {
+        result.to_owned()
+    }
+}
+
+/// Creates the work dir, an existing one must be empty. Returns whether it was created.
+pub async fn create_empty_work_dir(path: &Path) -> Result<bool, io::Error> {
+    match tokio::fs::read_dir(path).await {
+        Ok(mut entries) => {
+            if entries.next_entry().await?.is_some() {
+                return Err(io::ErrorKind::DirectoryNotEmpty.into());
+            }
+            Ok(false)
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            tokio::fs::create_dir_all(path).await?;
+            Ok(true)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Undoes [`create_empty_work_dir`] after a failure, leaving the path as it was found.
+pub async fn discard_work_dir(path: &Path, created: bool) -> Result<(), io::Error> {
+    tokio::fs::remove_dir_all(path).await?;
+    if !created {
+        tokio::fs::create_dir(path).await?;
+    }
+
+    Ok(())
+}